@@ -4,12 +4,12 @@ use std::sync::Arc;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
 use client::{download_shards, recover_data};
-use common::{config::StorageConfig, node::Peer};
+use common::{config::ProtocolParams, node::Peer};
 use rand::prelude::SliceRandom;
 use reqwest::Client;
 use tokio::time::{Duration, Instant};
 use tracing_subscriber::fmt::format::FmtSpan;
-use common::contract::ClusterId;
+use common::contract::{Cluster, ClusterId, MockContractClient};
 use common::node::NodeClient;
 use primitives::Val;
 
@@ -31,6 +31,7 @@ const CONCURRENCY: &[usize] = &[1, 4, 8, 16, 32];
 const NUM_REQUESTS: usize = 10;
 
 const VALIDATOR_URL: &str = "http://45.131.67.89:8011";
+const CONTRACT_URL: &str = "http://45.131.67.89:8010";
 
 #[tokio::main]
 async fn main() {
@@ -44,10 +45,17 @@ async fn main() {
         .unwrap();
     let validator = NodeClient::new(VALIDATOR_URL, client.clone());
     let peers = Arc::new(validator.get_info().await.unwrap().peers);
-    let config = StorageConfig::dev();
-    let log_blowup_factor = config.log_blowup_factor();
+    let contract = MockContractClient::new(CONTRACT_URL, client.clone());
+    let mut clusters = HashMap::new();
+    for cluster_id in CLUSTER_IDS {
+        let cluster_id: ClusterId = cluster_id.parse().unwrap();
+        let cluster = contract.get_cluster(&cluster_id).await.unwrap();
+        clusters.insert(cluster_id, cluster);
+    }
+    let clusters = Arc::new(clusters);
+    let params = ProtocolParams::dev();
     
-    let bytes_per_request = config.cluster_size() * size_of::<Val>();
+    let bytes_per_request = params.cluster_size() * size_of::<Val>();
     
     for &concurrency in CONCURRENCY {
         let peers = peers.clone();
@@ -56,8 +64,9 @@ async fn main() {
             let peers = peers.clone();
             let mut rng = rand::thread_rng();
             let cluster_id: ClusterId = CLUSTER_IDS.choose(&mut rng).unwrap().parse().unwrap();
+            let cluster = clusters[&cluster_id].clone();
             let client = client.clone();
-            tasks.push(measure_throughput(move || test(peers.clone(), log_blowup_factor, cluster_id.clone(), client.clone()), NUM_REQUESTS));
+            tasks.push(measure_throughput(move || test(peers.clone(), params, cluster_id.clone(), cluster.clone(), client.clone()), NUM_REQUESTS));
         }
 
         let mut results = Vec::new();
@@ -102,8 +111,8 @@ where
     (throughput, avg.as_secs_f32())
 }
 
-async fn test(peers: Arc<HashMap<usize, Peer>>, log_blowup_factor: usize, cluster_id: ClusterId, client: Client) {
-    let (shards, subcoset_index) = download_shards(cluster_id, &peers, client).await.unwrap();
-    let data = recover_data(shards, subcoset_index, log_blowup_factor).unwrap();
+async fn test(peers: Arc<HashMap<usize, Peer>>, params: ProtocolParams, cluster_id: ClusterId, cluster: Cluster, client: Client) {
+    let (shards, subcoset_index) = download_shards(cluster_id, &cluster, &peers, client, None).await.unwrap();
+    let data = recover_data(shards, subcoset_index, &params).unwrap();
     black_box(data);
 }
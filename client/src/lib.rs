@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 
-use color_eyre::{Report, Result};
+use color_eyre::{eyre::eyre, Report, Result};
 use common::{
-    config::StorageConfig,
-    contract::{ClusterId, MockContractClient, UploadClusterReq},
+    config::ProtocolParams,
+    contract::{Cluster, ClusterId, MockContractClient, UploadClusterReq},
     crypto::{derive_keys, sign},
     encode::{decode, encode_aligned},
     node::{NodeClient, Peer, UploadMessage},
@@ -14,8 +14,13 @@ use primitives::Val;
 use rand::{thread_rng, Rng};
 use reqwest::Client;
 use shards::{compute_commitment, compute_subdomain_indexes, recover_original_data_from_subcoset};
+use spora::commit_cluster;
 use tracing::instrument;
 
+use crate::p2p::P2pClient;
+
+pub mod p2p;
+
 pub async fn upload_cluster(
     data: Vec<u8>,
     mnemonic: &str,
@@ -24,21 +29,21 @@ pub async fn upload_cluster(
 ) -> Result<()> {
     let t_start = std::time::Instant::now();
 
-    let storage_config = StorageConfig::dev();
+    let params = ProtocolParams::dev();
 
-    if data.len() > storage_config.cluster_capacity_bytes() {
+    if data.len() > params.cluster_capacity_bytes() {
         return Err(color_eyre::eyre::eyre!("File too large"));
     }
 
     let serialized_data = bincode::serialize(&data)?;
-    let encoded_data = encode_aligned(&serialized_data, storage_config.cluster_size())?;
+    let encoded_data = encode_aligned(&serialized_data, params.cluster_size())?;
 
     let (private_key, public_key) = derive_keys(mnemonic).unwrap();
     let signature = sign(&encoded_data, private_key);
 
-    let data_matrix = RowMajorMatrix::new(encoded_data.clone(), storage_config.m);
-    let (commit, _shards) =
-        compute_commitment(data_matrix.clone(), storage_config.log_blowup_factor());
+    let data_matrix = RowMajorMatrix::new(encoded_data.clone(), params.m);
+    let (commit, shards) = compute_commitment(data_matrix.clone(), &params);
+    let shard_commits = shards.iter().map(|shard| commit_cluster(shard).0).collect();
 
     let t_commitment_end = t_start.elapsed();
     println!("Computed commitment in {t_commitment_end:?}");
@@ -49,6 +54,7 @@ pub async fn upload_cluster(
         .reserve_cluster(UploadClusterReq {
             owner_pk: public_key,
             commit: commit.pcs_commitment_hash,
+            shard_commits,
         })
         .await?;
 
@@ -72,32 +78,53 @@ pub async fn upload_cluster(
     Ok(())
 }
 
-#[instrument(skip(nodes))]
+/// Downloads enough shards to recover the cluster. Shards are fetched over libp2p if `p2p` is set,
+/// falling back to the HTTP API of the storage node on failure.
+#[instrument(skip(cluster, nodes, p2p))]
 pub async fn download_shards(
     cluster_id: ClusterId,
+    cluster: &Cluster,
     nodes: &HashMap<usize, Peer>,
     client: Client,
+    p2p: Option<&P2pClient>,
 ) -> Result<(Vec<Vec<Val>>, usize)> {
-    let storage_config = StorageConfig::dev();
+    let params = ProtocolParams::dev();
 
-    let log_blowup_factor = storage_config.log_blowup_factor();
+    let log_blowup_factor = params.log_blowup_factor();
     let subcoset_index = thread_rng().gen_range(0..(1 << log_blowup_factor));
-    let subcoset_indices = compute_subdomain_indexes(
-        subcoset_index,
-        log_blowup_factor,
-        storage_config.m.ilog2() as usize,
-    );
+    let subcoset_indices =
+        compute_subdomain_indexes(subcoset_index, log_blowup_factor, params.m.ilog2() as usize);
 
     let num_shards = subcoset_indices.len();
+    let shard_len = (params.shard_size() * size_of::<Val>()) as u64;
     let mut tasks = FuturesUnordered::new();
-    for node_id in subcoset_indices.into_iter().take(storage_config.m) {
-        let node = nodes[&node_id].clone();
+    for shard_index in subcoset_indices.into_iter().take(params.m) {
+        let node_id = *cluster
+            .placement
+            .get(shard_index)
+            .ok_or_else(|| eyre!("Shard {} is not placed", shard_index))?;
+        let node = nodes
+            .get(&(node_id as usize))
+            .ok_or_else(|| eyre!("Node {} storing shard {} is unknown", node_id, shard_index))?
+            .clone();
         let cluster_id = cluster_id.clone();
+        let cluster_index = cluster.index;
         let client = client.clone();
         tasks.push(async move {
+            if let Some(p2p) = p2p {
+                match p2p.download_shard(&node, cluster_index, shard_len).await {
+                    Ok(data) => return Ok((shard_index, data)),
+                    Err(err) => tracing::warn!(
+                        "P2P download from node {} failed, falling back to HTTP: {}",
+                        node_id,
+                        err
+                    ),
+                }
+            }
+
             let node_client = NodeClient::new(&node.api_url, client.clone());
             let data = node_client.download_cluster(cluster_id.clone()).await?;
-            Ok::<_, Report>((node_id, data))
+            Ok::<_, Report>((shard_index, data))
         })
     }
 
@@ -106,7 +133,7 @@ pub async fn download_shards(
         shards.push(result?);
     }
 
-    shards.sort_by_key(|(node_id, _)| *node_id);
+    shards.sort_by_key(|(shard_index, _)| *shard_index);
     let shards = shards.into_iter().map(|(_, data)| data).collect();
 
     Ok((shards, subcoset_index))
@@ -116,23 +143,108 @@ pub async fn download_shards(
 pub fn recover_data(
     shards: Vec<Vec<Val>>,
     subcoset_index: usize,
-    log_blowup_factor: usize,
+    params: &ProtocolParams,
 ) -> Result<Vec<u8>> {
-    let storage_config = StorageConfig::dev();
+    let subcoset_data = RowMajorMatrix::new(shards.into_iter().flatten().collect(), params.n);
 
-    let subcoset_data =
-        RowMajorMatrix::new(shards.into_iter().flatten().collect(), storage_config.n);
+    let recovered_data = recover_original_data_from_subcoset(subcoset_data, subcoset_index, params);
 
-    let recovered_data =
-        recover_original_data_from_subcoset(subcoset_data, subcoset_index, log_blowup_factor);
-
-    let decoded_data = decode(
-        &recovered_data.values,
-        storage_config.cluster_capacity_bytes(),
-    );
+    let decoded_data = decode(&recovered_data.values, params.cluster_capacity_bytes());
 
     let reader = std::io::Cursor::new(decoded_data);
     let deserialized_data: Vec<u8> = bincode::deserialize_from(reader)?;
 
     Ok(deserialized_data)
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use primitives::Hash;
+
+    use super::*;
+    use crate::p2p::tests::{serve_shard, unreachable_peer};
+
+    /// A shard of the dev parameters.
+    fn shard_bytes() -> Vec<u8> {
+        (0..ProtocolParams::dev().n as u32)
+            .flat_map(u32::to_le_bytes)
+            .collect()
+    }
+
+    fn shard_vals() -> Vec<Val> {
+        (0..ProtocolParams::dev().n as u32).map(Val::new).collect()
+    }
+
+    /// Starts the HTTP API of a storage node serving `data` as its shard of every cluster.
+    async fn serve_http(data: Vec<u8>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let app = Router::new().route(
+            "/clusters/:id",
+            get(move || {
+                let data = data.clone();
+                async move { data }
+            }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        url
+    }
+
+    /// A cluster whose shards are all stored by the given peer.
+    fn cluster_on(peer: Peer) -> (Cluster, HashMap<usize, Peer>) {
+        let params = ProtocolParams::dev();
+        let cluster = Cluster {
+            index: 0,
+            owner_pk: Default::default(),
+            commit: Hash::from([Val::new(0); 8]),
+            shard_commits: Vec::new(),
+            placement: (0..params.q as u32).collect(),
+        };
+        let nodes = (0..params.q).map(|node| (node, peer.clone())).collect();
+
+        (cluster, nodes)
+    }
+
+    #[tokio::test]
+    async fn test_download_shards_p2p() {
+        let mut peer = serve_shard(shard_bytes()).await;
+        // HTTP would fail, so the shards can only come over libp2p
+        peer.api_url = "http://127.0.0.1:1".to_string();
+        let (cluster, nodes) = cluster_on(peer);
+
+        let p2p = P2pClient::new(nodes.values()).unwrap();
+        let (shards, _) = download_shards(
+            ClusterId::random(),
+            &cluster,
+            &nodes,
+            Client::new(),
+            Some(&p2p),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(shards, vec![shard_vals(); ProtocolParams::dev().m]);
+    }
+
+    #[tokio::test]
+    async fn test_download_shards_http_fallback() {
+        let api_url = serve_http(shard_bytes()).await;
+        let (cluster, nodes) = cluster_on(unreachable_peer(&api_url));
+
+        let p2p = P2pClient::new(nodes.values()).unwrap();
+        let (shards, _) = download_shards(
+            ClusterId::random(),
+            &cluster,
+            &nodes,
+            Client::new(),
+            Some(&p2p),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(shards, vec![shard_vals(); ProtocolParams::dev().m]);
+    }
+}
@@ -3,7 +3,7 @@ use std::{fs, path::PathBuf};
 use clap::{Parser, Subcommand};
 use color_eyre::{Report, Result};
 use common::{
-    config::StorageConfig,
+    config::ProtocolParams,
     contract::MockContractClient,
     crypto::derive_keys,
     encode::{decode, encode_aligned},
@@ -14,7 +14,7 @@ use primitives::Val;
 use rand::{Rng};
 use reqwest::Client;
 use tracing_subscriber::fmt::format::FmtSpan;
-use client::{download_shards, recover_data};
+use client::{download_shards, p2p::P2pClient, recover_data};
 use shards::{
     compute_commitment, compute_subdomain_indexes,
     recover_original_data_from_subcoset,
@@ -22,8 +22,9 @@ use shards::{
 use common::contract::{ClusterId, UploadClusterReq};
 use common::crypto::sign;
 use common::node::UploadMessage;
+use spora::commit_cluster;
 
-// TODO: Fully libp2p based client
+// TODO: Upload over libp2p
 // TODO: tracing
 
 #[derive(Parser)]
@@ -35,6 +36,9 @@ struct Cli {
     validator_url: String,
     #[arg(short, long)]
     contract_url: String,
+    /// Download shards directly from storage nodes over libp2p, falling back to HTTP.
+    #[arg(long)]
+    p2p: bool,
 }
 
 #[derive(Subcommand)]
@@ -74,7 +78,15 @@ async fn main() -> Result<()> {
             upload_file(file, &mnemonic, &validator_client, &contract_client).await?;
         }
         Commands::Download { id, output } => {
-            download_cluster(id, output, &validator_client, client).await?;
+            download_cluster(
+                id,
+                output,
+                &validator_client,
+                &contract_client,
+                client,
+                cli.p2p,
+            )
+            .await?;
         }
     }
 
@@ -89,22 +101,22 @@ async fn upload_file(
 ) -> Result<()> {
     let t_start = std::time::Instant::now();
 
-    let storage_config = StorageConfig::dev();
+    let params = ProtocolParams::dev();
     let file_data = fs::read(&file_path)?;
 
-    if file_data.len() > storage_config.cluster_capacity_bytes() {
+    if file_data.len() > params.cluster_capacity_bytes() {
         return Err(color_eyre::eyre::eyre!("File too large"));
     }
 
     let serialized_data = bincode::serialize(&file_data)?;
-    let encoded_data = encode_aligned(&serialized_data, storage_config.cluster_size())?;
+    let encoded_data = encode_aligned(&serialized_data, params.cluster_size())?;
     
     let (private_key, public_key) = derive_keys(mnemonic).unwrap();
     let signature = sign(&encoded_data, private_key);
 
-    let data_matrix = RowMajorMatrix::new(encoded_data.clone(), storage_config.m);
-    let (commit, _shards) =
-        compute_commitment(data_matrix.clone(), storage_config.log_blowup_factor());
+    let data_matrix = RowMajorMatrix::new(encoded_data.clone(), params.m);
+    let (commit, shards) = compute_commitment(data_matrix.clone(), &params);
+    let shard_commits = shards.iter().map(|shard| commit_cluster(shard).0).collect();
 
     let t_commitment_end = t_start.elapsed();
     println!("Computed commitment in {t_commitment_end:?}");
@@ -114,6 +126,7 @@ async fn upload_file(
     let cluster_id = contract.reserve_cluster(UploadClusterReq {
         owner_pk: public_key,
         commit: commit.pcs_commitment_hash,
+        shard_commits,
     }).await?;
     
     println!("Uploading file to cluster {}", cluster_id);
@@ -135,14 +148,27 @@ async fn upload_file(
     Ok(())
 }
 
-async fn download_cluster(cluster_id: ClusterId, output: PathBuf, validator: &NodeClient, client: Client) -> Result<()> {
-    let storage_config = StorageConfig::dev();
+async fn download_cluster(
+    cluster_id: ClusterId,
+    output: PathBuf,
+    validator: &NodeClient,
+    contract: &MockContractClient,
+    client: Client,
+    use_p2p: bool,
+) -> Result<()> {
+    let params = ProtocolParams::dev();
     let nodes = validator.get_info().await?.peers;
+    let cluster = contract.get_cluster(&cluster_id).await?;
+
+    let p2p = if use_p2p {
+        Some(P2pClient::new(nodes.values())?)
+    } else {
+        None
+    };
 
     let (shards, subcoset_index) =
-        download_shards(cluster_id, &nodes, client).await?;
-    let data =
-        recover_data(shards, subcoset_index, storage_config.log_blowup_factor())?;
+        download_shards(cluster_id, &cluster, &nodes, client, p2p.as_ref()).await?;
+    let data = recover_data(shards, subcoset_index, &params)?;
 
     fs::write(output.clone(), &data)?;
 
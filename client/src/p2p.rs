@@ -0,0 +1,176 @@
+//! Lightweight libp2p peer used to download shards directly from storage nodes.
+
+use std::time::Duration;
+
+use color_eyre::{eyre::eyre, Result};
+use common::{encode::bytes_to_vals, node::Peer, transfer};
+use libp2p::{
+    dcutr, futures::StreamExt, noise, relay, swarm::NetworkBehaviour, tcp, yamux, Multiaddr,
+    PeerId, StreamProtocol,
+};
+use libp2p_stream::Control;
+use primitives::Val;
+
+const PROTOCOL: StreamProtocol = StreamProtocol::new(transfer::PROTOCOL_NAME);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Storage nodes behind a NAT are only reachable through a relay, hence the relay client and DCUtR.
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    stream: libp2p_stream::Behaviour,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+}
+
+#[derive(Clone)]
+pub struct P2pClient {
+    control: Control,
+}
+
+impl P2pClient {
+    /// Starts a swarm that knows the addresses of the given storage nodes.
+    pub fn new<'a>(peers: impl IntoIterator<Item = &'a Peer>) -> Result<Self> {
+        let stream = libp2p_stream::Behaviour::new();
+        let control = stream.new_control();
+
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_quic()
+            .with_dns()?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| Behaviour {
+                stream,
+                relay_client,
+                dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+            })?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+
+        for peer in peers {
+            let peer_id: PeerId = peer.peer_id.parse()?;
+            let addr: Multiaddr = peer.addr.parse()?;
+            swarm.add_peer_address(peer_id, addr);
+        }
+
+        tokio::spawn(async move {
+            loop {
+                let event = swarm.select_next_some().await;
+                tracing::trace!("Swarm event: {:?}", event);
+            }
+        });
+
+        Ok(Self { control })
+    }
+
+    /// Downloads the shard of the cluster with the given index from a storage node. Fails if the
+    /// node announces a shard of other than `len` bytes.
+    pub async fn download_shard(&self, peer: &Peer, index: u64, len: u64) -> Result<Vec<Val>> {
+        let peer_id: PeerId = peer.peer_id.parse()?;
+        let mut control = self.control.clone();
+
+        let data = tokio::time::timeout(DOWNLOAD_TIMEOUT, async move {
+            let mut stream = control.open_stream(peer_id, PROTOCOL).await?;
+            transfer::download(&mut stream, index, len).await
+        })
+        .await
+        .map_err(|_| eyre!("Download timed out"))??;
+
+        Ok(bytes_to_vals(&data))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use common::transfer::IncomingTransfer;
+    use libp2p::swarm::SwarmEvent;
+
+    use super::*;
+
+    /// Starts a storage node serving `data` as its shard of every cluster over libp2p.
+    pub(crate) async fn serve_shard(data: Vec<u8>) -> Peer {
+        let stream = libp2p_stream::Behaviour::new();
+        let mut control = stream.new_control();
+
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|_| stream)
+            .unwrap()
+            .build();
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+        let peer_id = *swarm.local_peer_id();
+        tokio::spawn(async move {
+            loop {
+                swarm.select_next_some().await;
+            }
+        });
+
+        let mut incoming = control.accept(PROTOCOL).unwrap();
+        tokio::spawn(async move {
+            while let Some((_, stream)) = incoming.next().await {
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let transfer = IncomingTransfer::accept(stream).await.unwrap();
+                    transfer.send(&data).await.unwrap();
+                });
+            }
+        });
+
+        Peer {
+            peer_id: peer_id.to_string(),
+            addr: addr.to_string(),
+            api_url: String::new(),
+        }
+    }
+
+    /// A storage node whose p2p port refuses connections.
+    pub(crate) fn unreachable_peer(api_url: &str) -> Peer {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        Peer {
+            peer_id: PeerId::random().to_string(),
+            addr: format!("/ip4/127.0.0.1/tcp/{}", port),
+            api_url: api_url.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_shard() {
+        let data = (0..1024u32).flat_map(u32::to_le_bytes).collect();
+        let peer = serve_shard(data).await;
+
+        let client = P2pClient::new([&peer]).unwrap();
+        let shard = client.download_shard(&peer, 3, 4096).await.unwrap();
+        assert_eq!(shard, (0..1024).map(Val::new).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_download_shard_unreachable() {
+        let peer = unreachable_peer("");
+
+        let client = P2pClient::new([&peer]).unwrap();
+        assert!(client.download_shard(&peer, 3, 4096).await.is_err());
+    }
+}
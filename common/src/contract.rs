@@ -7,11 +7,25 @@ use serde::{Deserialize, Serialize};
 
 use crate::crypto::PublicKey;
 
+pub type NodeId = u32;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cluster {
     pub index: u64,
     pub owner_pk: PublicKey,
     pub commit: Hash,
+    /// FRI commitment of each shard, see `spora::commit_cluster`. Validators check them against
+    /// the uploaded data together with `commit`, and sealed shards are proven against them.
+    pub shard_commits: Vec<Hash>,
+    /// Storage nodes holding the shards of the cluster, the node at position `i` stores shard `i`.
+    pub placement: Vec<NodeId>,
+}
+
+impl Cluster {
+    /// Returns the index of the shard stored by the node, if any.
+    pub fn shard_of(&self, node_id: NodeId) -> Option<usize> {
+        self.placement.iter().position(|&node| node == node_id)
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
@@ -75,10 +89,65 @@ pub struct MockContractClient {
     client: Client,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeEntry {
+    pub node_id: NodeId,
+    /// Number of shards assigned to the node.
+    pub shards: u64,
+}
+
+/// SPoRA challenge of a mining epoch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MiningEpoch {
+    pub epoch: u64,
+    /// Nonces of the epoch are `0..max_nonce`.
+    pub max_nonce: u64,
+    /// Minimum complexity of an accepted solution.
+    pub log_complexity: usize,
+    /// Number of storage values sampled per nonce.
+    pub n_samples: usize,
+    /// Random challenge, sampled together with the nonce.
+    pub challenge: Hash,
+}
+
+/// Merkle root of the storage a node mines on, see `spora::CommittedStorage`. It is used to verify
+/// the solutions of the node from the next epoch on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageCommitmentReq {
+    pub node_id: NodeId,
+    pub root: Hash,
+    /// Logarithm of the number of values in the storage.
+    pub log_len: usize,
+    /// Sealed shards the storage is made of, in their order in the storage. They are exactly the
+    /// shards assigned to the node.
+    pub shards: Vec<ShardCommitment>,
+}
+
+/// Merkle root of the sealed shard of a cluster, see `spora::CommittedStorage::shard_roots`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardCommitment {
+    pub cluster_index: u64,
+    pub root: Hash,
+    /// Proof that the shard is the cluster's shard sealed by the node, an encoded
+    /// `spora::SealedShardProof`.
+    pub proof: Vec<u8>,
+}
+
+/// Nonce found by a storage node, with the openings of the sampled values.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolutionReq {
+    pub node_id: NodeId,
+    pub epoch: u64,
+    pub nonce: u64,
+    /// Encoded `spora::SolutionProof`.
+    pub proof: Vec<u8>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadClusterReq {
     pub owner_pk: PublicKey,
     pub commit: Hash,
+    pub shard_commits: Vec<Hash>,
 }
 
 impl MockContractClient {
@@ -111,16 +180,104 @@ impl MockContractClient {
             .json(&cluster)
             .send()
             .await?
+            .error_for_status()?
             .json()
             .await?;
 
         Ok(response.cluster_id.parse()?)
     }
 
+    /// Returns all reserved clusters ordered by their index.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_clusters(&self) -> Result<Vec<(ClusterId, Cluster)>> {
+        #[derive(Deserialize)]
+        struct ClusterEntry {
+            cluster_id: String,
+            cluster: Cluster,
+        }
+
+        let url = format!("{}/clusters", self.base_url);
+        let response: Vec<ClusterEntry> = self.client.get(&url).send().await?.json().await?;
+
+        response
+            .into_iter()
+            .map(|entry| Ok((entry.cluster_id.parse()?, entry.cluster)))
+            .collect()
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn get_cluster(&self, cluster_id: &ClusterId) -> Result<Cluster> {
         let url = format!("{}/clusters/{}", self.base_url, cluster_id);
         let response = self.client.get(&url).send().await?;
         Ok(response.json().await?)
     }
+
+    /// Registers a storage node, making it eligible for shard placement.
+    #[tracing::instrument(skip(self))]
+    pub async fn register_node(&self, node_id: NodeId) -> Result<()> {
+        let url = format!("{}/nodes", self.base_url);
+        self.client
+            .post(&url)
+            .json(&serde_json::json!({ "node_id": node_id }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Moves a shard of the cluster to another node and returns the updated cluster.
+    #[tracing::instrument(skip(self))]
+    pub async fn move_shard(&self, cluster_id: &ClusterId, shard: usize, node_id: NodeId) -> Result<Cluster> {
+        let url = format!("{}/clusters/{}/placement", self.base_url, cluster_id);
+        let response = self
+            .client
+            .post(&url)
+            .json(&serde_json::json!({ "shard": shard, "node_id": node_id }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn get_nodes(&self) -> Result<Vec<NodeEntry>> {
+        let url = format!("{}/nodes", self.base_url);
+        let response = self.client.get(&url).send().await?;
+        Ok(response.json().await?)
+    }
+
+    /// Returns the challenge of the current mining epoch.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_mining_epoch(&self) -> Result<MiningEpoch> {
+        let url = format!("{}/mining/epoch", self.base_url);
+        let response = self.client.get(&url).send().await?.error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    /// Commits to the storage mined on from the next epoch.
+    #[tracing::instrument(skip(self))]
+    pub async fn submit_storage_commitment(&self, commitment: &StorageCommitmentReq) -> Result<()> {
+        let url = format!("{}/mining/commitments", self.base_url);
+        self.client
+            .post(&url)
+            .json(commitment)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Submits a mining solution. Fails if the epoch is over, the proof is invalid or the
+    /// complexity is too low.
+    #[tracing::instrument(skip(self, solution), fields(nonce = solution.nonce))]
+    pub async fn submit_solution(&self, solution: &SolutionReq) -> Result<()> {
+        let url = format!("{}/mining/solutions", self.base_url);
+        self.client
+            .post(&url)
+            .json(solution)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
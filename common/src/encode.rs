@@ -59,6 +59,14 @@ pub fn encode_aligned(data: &[u8], n_elements: usize) -> Result<Vec<Mersenne31>,
     Ok(result)
 }
 
+/// Reinterprets little-endian bytes as field elements, the inverse of storing the elements as
+/// `u32`s. Unlike [`decode`], every element takes 4 bytes.
+pub fn bytes_to_vals(data: &[u8]) -> Vec<Mersenne31> {
+    data.chunks_exact(4)
+        .map(|bytes| Mersenne31::from_canonical_u32(u32::from_le_bytes(bytes.try_into().unwrap())))
+        .collect()
+}
+
 pub fn decode(elements: &[Mersenne31], data_size: usize) -> Vec<u8> {
     let mut result = Vec::with_capacity(data_size);
     let mut buffer = 0u64;
@@ -101,4 +109,21 @@ mod tests {
         let decoded = decode(&encoded, bytes.len());
         assert_eq!(bytes, decoded.as_slice());
     }
+
+    #[test]
+    fn test_bytes_to_vals() {
+        // The trailing byte doesn't make a whole element and is dropped
+        let bytes = [
+            1, 0, 0, 0, 0xfe, 0xff, 0xff, 0x7f, 0x78, 0x56, 0x34, 0x12, 9,
+        ];
+        let vals = bytes_to_vals(&bytes);
+        assert_eq!(
+            vals,
+            vec![
+                Mersenne31::one(),
+                Mersenne31::neg_one(),
+                Mersenne31::from_canonical_u32(0x12345678)
+            ]
+        );
+    }
 }
@@ -3,3 +3,5 @@ pub mod contract;
 pub mod crypto;
 pub mod encode;
 pub mod node;
+pub mod placement;
+pub mod transfer;
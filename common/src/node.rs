@@ -1,12 +1,13 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use color_eyre::Result;
 use primitives::Val;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 use crate::contract::ClusterId;
 use crate::crypto::Signature;
+use crate::encode::bytes_to_vals;
 
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Peer {
@@ -20,6 +21,12 @@ pub struct InfoResponse {
     pub peers: HashMap<usize, Peer>,
 }
 
+/// Set on uploads forwarded by another validator, which must be handled by the receiving validator.
+/// Any client can set it, so it only decides who stores an upload, never whether it is checked.
+pub const FORWARDED_HEADER: &str = "x-zpss-forwarded";
+/// Forwarding to an unresponsive validator should fail quickly enough to fall back to the next one.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadMessage {
     /// Original, unencoded data
@@ -43,20 +50,49 @@ impl NodeClient {
 
     #[tracing::instrument(skip(self, msg))]
     pub async fn upload_cluster(&self, cluster_id: ClusterId, msg: UploadMessage) -> Result<()> {
-        let url = format!("{}/clusters/{}", self.base_url, cluster_id);
         let data = bincode::serialize(&msg)?;
-        let form =
-            reqwest::multipart::Form::new().part("file", reqwest::multipart::Part::bytes(data));
+        let status = self.post_cluster(cluster_id, data, false).await?;
 
-        let response = self.client.post(&url).multipart(form).send().await?;
-
-        if response.status().is_success() {
+        if status.is_success() {
             Ok(())
         } else {
-            Err(color_eyre::eyre::eyre!("Failed to upload cluster"))
+            Err(color_eyre::eyre::eyre!(
+                "Failed to upload cluster: {}",
+                status
+            ))
         }
     }
 
+    /// Forwards a serialized [`UploadMessage`] to the validator responsible for the cluster.
+    ///
+    /// Returns the status the validator answered with. Only fails if the validator could not be
+    /// reached or did not answer in time.
+    #[tracing::instrument(skip(self, data))]
+    pub async fn forward_upload(&self, cluster_id: ClusterId, data: Vec<u8>) -> Result<StatusCode> {
+        self.post_cluster(cluster_id, data, true).await
+    }
+
+    async fn post_cluster(
+        &self,
+        cluster_id: ClusterId,
+        data: Vec<u8>,
+        forwarded: bool,
+    ) -> Result<StatusCode> {
+        let url = format!("{}/clusters/{}", self.base_url, cluster_id);
+        let form =
+            reqwest::multipart::Form::new().part("file", reqwest::multipart::Part::bytes(data));
+
+        let mut request = self.client.post(&url).multipart(form);
+        if forwarded {
+            request = request
+                .header(FORWARDED_HEADER, "1")
+                .timeout(FORWARD_TIMEOUT);
+        }
+        let response = request.send().await?;
+
+        Ok(response.status())
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn download_cluster(&self, cluster_id: ClusterId) -> Result<Vec<Val>> {
         let url = format!("{}/clusters/{}", self.base_url, cluster_id);
@@ -65,16 +101,29 @@ impl NodeClient {
         let response = self.client.get(&url).send().instrument(span).await?;
 
         if response.status().is_success() {
-            let data = response.bytes().await?.to_vec();
-            let elements: Vec<Val> = unsafe {
-                data[..].align_to::<Val>().1.to_vec()
-            };
-            Ok(elements)
+            let data = response.bytes().await?;
+            Ok(bytes_to_vals(&data))
         } else {
             Err(color_eyre::eyre::eyre!("Failed to download cluster"))
         }
     }
 
+    /// Asks a storage node to delete its shard of a cluster that was moved to another node.
+    #[tracing::instrument(skip(self))]
+    pub async fn release_cluster(&self, cluster_id: ClusterId) -> Result<()> {
+        let url = format!("{}/clusters/{}", self.base_url, cluster_id);
+        let response = self.client.delete(&url).send().await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(color_eyre::eyre::eyre!(
+                "Failed to release cluster: {}",
+                response.status()
+            ))
+        }
+    }
+
     #[tracing::instrument(skip(self))]
     pub async fn get_info(&self) -> Result<InfoResponse> {
         let url = format!("{}/info", self.base_url);
@@ -0,0 +1,200 @@
+//! Placement of cluster shards on storage nodes.
+//!
+//! Every cluster has its own placement: a list of `q` distinct nodes where the node at position `i`
+//! stores shard `i`. Placements are assigned by the contract when a cluster is reserved, so the
+//! network can grow beyond `q` storage nodes. When nodes join or leave, [`plan_moves`] computes how
+//! to redistribute the shards.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::contract::NodeId;
+
+/// Picks `q` distinct nodes for the shards of a new cluster, preferring the least loaded ones.
+/// `loads` maps registered nodes to the number of shards they store. Returns `None` if fewer than
+/// `q` nodes are registered.
+pub fn assign_shards(loads: &BTreeMap<NodeId, u64>, q: usize) -> Option<Vec<NodeId>> {
+    if loads.len() < q {
+        return None;
+    }
+
+    let mut nodes = loads.iter().map(|(&node, &load)| (load, node)).collect::<Vec<_>>();
+    nodes.sort();
+
+    Some(nodes.into_iter().take(q).map(|(_, node)| node).collect())
+}
+
+/// A shard that has to be moved to another node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMove {
+    /// Position of the cluster in the placements passed to [`plan_moves`].
+    pub cluster: usize,
+    pub shard: usize,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+/// Computes the moves that balance the shards of all clusters over `nodes`.
+///
+/// Shards stored on nodes that are no longer registered are moved first. Then shards are moved from
+/// the most to the least loaded nodes until the loads differ by at most one. Shards that don't have
+/// to move stay where they are, and no node ever gets two shards of the same cluster.
+pub fn plan_moves(placements: &[Vec<NodeId>], nodes: &BTreeSet<NodeId>) -> Vec<ShardMove> {
+    let mut placements = placements.to_vec();
+    let mut loads: BTreeMap<NodeId, u64> = nodes.iter().map(|&node| (node, 0)).collect();
+    for node in placements.iter().flatten() {
+        if let Some(load) = loads.get_mut(node) {
+            *load += 1;
+        }
+    }
+
+    let mut moves = Vec::new();
+
+    for (cluster, placement) in placements.iter_mut().enumerate() {
+        for shard in 0..placement.len() {
+            let from = placement[shard];
+            if loads.contains_key(&from) {
+                continue;
+            }
+
+            let Some(to) = loads
+                .iter()
+                .filter(|(node, _)| !placement.contains(node))
+                .min_by_key(|(&node, &load)| (load, node))
+                .map(|(&node, _)| node)
+            else {
+                // Not enough nodes to keep the shards of the cluster on distinct nodes.
+                continue;
+            };
+
+            *loads.get_mut(&to).unwrap() += 1;
+            placement[shard] = to;
+            moves.push(ShardMove { cluster, shard, from, to });
+        }
+    }
+
+    // Every move decreases the sum of squared loads, so this terminates.
+    'balance: loop {
+        let mut by_load = loads.iter().map(|(&node, &load)| (load, node)).collect::<Vec<_>>();
+        by_load.sort();
+        let Some(&(min_load, _)) = by_load.first() else {
+            break;
+        };
+
+        for &(from_load, from) in by_load.iter().rev() {
+            if from_load <= min_load + 1 {
+                break 'balance;
+            }
+
+            for &(to_load, to) in &by_load {
+                if to_load + 1 >= from_load {
+                    break;
+                }
+
+                let candidate = placements.iter().enumerate().find_map(|(cluster, placement)| {
+                    if placement.contains(&to) {
+                        return None;
+                    }
+                    let shard = placement.iter().position(|&node| node == from)?;
+                    Some((cluster, shard))
+                });
+
+                if let Some((cluster, shard)) = candidate {
+                    placements[cluster][shard] = to;
+                    *loads.get_mut(&from).unwrap() -= 1;
+                    *loads.get_mut(&to).unwrap() += 1;
+                    moves.push(ShardMove { cluster, shard, from, to });
+                    continue 'balance;
+                }
+            }
+        }
+
+        break;
+    }
+
+    moves
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_assign_shards_prefers_least_loaded() {
+        let loads = BTreeMap::from([(0, 3), (1, 1), (2, 0), (3, 2), (4, 1)]);
+        let placement = assign_shards(&loads, 3).unwrap();
+        assert_eq!(placement, vec![2, 1, 4]);
+    }
+
+    #[test]
+    fn test_assign_shards_distinct_nodes() {
+        let loads = (0..40).map(|node| (node, (node % 3) as u64)).collect();
+        let placement = assign_shards(&loads, 16).unwrap();
+        assert_eq!(placement.len(), 16);
+        assert_eq!(placement.iter().collect::<HashSet<_>>().len(), 16);
+    }
+
+    #[test]
+    fn test_assign_shards_not_enough_nodes() {
+        let loads = (0..15).map(|node| (node, 0)).collect();
+        assert!(assign_shards(&loads, 16).is_none());
+    }
+
+    fn apply(placements: &mut [Vec<NodeId>], moves: &[ShardMove]) {
+        for mv in moves {
+            assert_eq!(placements[mv.cluster][mv.shard], mv.from);
+            placements[mv.cluster][mv.shard] = mv.to;
+        }
+    }
+
+    fn loads(placements: &[Vec<NodeId>], nodes: &BTreeSet<NodeId>) -> Vec<usize> {
+        nodes
+            .iter()
+            .map(|node| placements.iter().flatten().filter(|n| *n == node).count())
+            .collect()
+    }
+
+    fn assert_valid(placements: &[Vec<NodeId>], nodes: &BTreeSet<NodeId>) {
+        for placement in placements {
+            assert!(placement.iter().all(|node| nodes.contains(node)));
+            assert_eq!(placement.iter().collect::<HashSet<_>>().len(), placement.len());
+        }
+
+        let loads = loads(placements, nodes);
+        assert!(loads.iter().max().unwrap() - loads.iter().min().unwrap() <= 1);
+    }
+
+    #[test]
+    fn test_plan_moves_balanced() {
+        let placements = vec![vec![0, 1, 2], vec![3, 0, 1], vec![2, 3, 0]];
+        let nodes = BTreeSet::from([0, 1, 2, 3]);
+        assert!(plan_moves(&placements, &nodes).is_empty());
+    }
+
+    #[test]
+    fn test_plan_moves_node_joined() {
+        let mut placements = vec![vec![0, 1, 2, 3]; 4];
+        let nodes = BTreeSet::from([0, 1, 2, 3, 4, 5]);
+
+        let moves = plan_moves(&placements, &nodes);
+        apply(&mut placements, &moves);
+
+        assert_valid(&placements, &nodes);
+        // 16 shards over 6 nodes: the new nodes get 2 or 3 shards each, and nothing else moves.
+        assert!(moves.iter().all(|mv| mv.to >= 4));
+        assert!(moves.len() <= 6);
+    }
+
+    #[test]
+    fn test_plan_moves_node_left() {
+        let mut placements = vec![vec![0, 1, 2], vec![1, 2, 3], vec![2, 3, 4], vec![3, 4, 0]];
+        let nodes = BTreeSet::from([0, 1, 3, 4]);
+
+        let moves = plan_moves(&placements, &nodes);
+        apply(&mut placements, &moves);
+
+        assert_valid(&placements, &nodes);
+        assert!(moves.iter().any(|mv| mv.from == 2));
+    }
+}
@@ -0,0 +1,385 @@
+//! Chunked shard transfer protocol.
+//!
+//! Shards are too large to be sent as a single request-response message, so they are streamed over
+//! a dedicated substream instead. Every transfer starts with a [`TransferRequest`], which the remote
+//! side either accepts or rejects. The payload is then split into chunks of [`CHUNK_SIZE`] bytes,
+//! each carrying its sequence number and a SHA3-256 hash of its contents. The receiver acknowledges
+//! every [`ACK_INTERVAL`] chunks (and the last one), and the sender never keeps more than [`WINDOW`]
+//! unacknowledged chunks in flight.
+//!
+//! Both sides know the size of a shard, so the receiver rejects a transfer announcing any other
+//! length before allocating for it.
+//!
+//! The protocol is transport-agnostic: anything implementing `AsyncRead + AsyncWrite` can be used.
+
+use color_eyre::{eyre::eyre, Result};
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+use crate::contract::ClusterId;
+
+pub const PROTOCOL_NAME: &str = "/zpss/transfer/1";
+
+/// Size of a single data chunk in bytes.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+/// Maximum number of unacknowledged chunks in flight.
+pub const WINDOW: u64 = 16;
+/// The receiver sends an acknowledgement every `ACK_INTERVAL` chunks.
+pub const ACK_INTERVAL: u64 = WINDOW / 2;
+
+const MAX_FRAME_SIZE: usize = CHUNK_SIZE + 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransferRequest {
+    /// Store a shard of the cluster with the given index.
+    Upload { index: u64, id: ClusterId, len: u64 },
+    /// Read the locally stored shard of the cluster with the given index.
+    Download { index: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    Request(TransferRequest),
+    /// The request was accepted, `len` bytes of payload will follow.
+    Accept { len: u64 },
+    Chunk { seq: u64, hash: [u8; 32], data: Vec<u8> },
+    /// All chunks up to and including `seq` were received and verified.
+    Ack { seq: u64 },
+    /// The uploaded data has been persisted.
+    Done,
+    Failure { error: String },
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(io: &mut S, frame: &Frame) -> Result<()> {
+    let bytes = bincode::serialize(frame)?;
+    io.write_all(&(bytes.len() as u32).to_le_bytes()).await?;
+    io.write_all(&bytes).await?;
+    io.flush().await?;
+    Ok(())
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(io: &mut S) -> Result<Frame> {
+    let mut len = [0u8; 4];
+    io.read_exact(&mut len).await?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(eyre!("Frame too large: {} bytes", len));
+    }
+
+    let mut bytes = vec![0u8; len];
+    io.read_exact(&mut bytes).await?;
+    Ok(bincode::deserialize(&bytes)?)
+}
+
+fn chunk_hash(data: &[u8]) -> [u8; 32] {
+    Sha3_256::digest(data).into()
+}
+
+fn num_chunks(len: u64) -> u64 {
+    len.div_ceil(CHUNK_SIZE as u64)
+}
+
+/// Sends `data` in chunks, respecting the flow control window. Returns once the receiver has
+/// acknowledged every chunk.
+async fn send_data<S: AsyncRead + AsyncWrite + Unpin>(io: &mut S, data: &[u8]) -> Result<()> {
+    let total = num_chunks(data.len() as u64);
+    let mut acked = 0;
+
+    for (seq, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        let seq = seq as u64;
+        while seq - acked >= WINDOW {
+            acked = wait_ack(io).await?;
+        }
+
+        let frame = Frame::Chunk {
+            seq,
+            hash: chunk_hash(chunk),
+            data: chunk.to_vec(),
+        };
+        write_frame(io, &frame).await?;
+    }
+
+    while acked < total {
+        acked = wait_ack(io).await?;
+    }
+
+    Ok(())
+}
+
+/// Waits for the next acknowledgement and returns the number of chunks received by the peer.
+async fn wait_ack<S: AsyncRead + Unpin>(io: &mut S) -> Result<u64> {
+    match read_frame(io).await? {
+        Frame::Ack { seq } => Ok(seq + 1),
+        Frame::Failure { error } => Err(eyre!("Transfer aborted by peer: {}", error)),
+        frame => Err(eyre!("Unexpected frame: {:?}", frame)),
+    }
+}
+
+/// Receives `len` bytes sent with [`send_data`], verifying every chunk.
+async fn recv_data<S: AsyncRead + AsyncWrite + Unpin>(io: &mut S, len: u64) -> Result<Vec<u8>> {
+    let total = num_chunks(len);
+    let mut data = Vec::with_capacity(len as usize);
+
+    for expected_seq in 0..total {
+        let (seq, hash, chunk) = match read_frame(io).await? {
+            Frame::Chunk { seq, hash, data } => (seq, hash, data),
+            Frame::Failure { error } => return Err(eyre!("Transfer aborted by peer: {}", error)),
+            frame => return Err(eyre!("Unexpected frame: {:?}", frame)),
+        };
+
+        let expected_len = (len - data.len() as u64).min(CHUNK_SIZE as u64);
+        let error = if seq != expected_seq {
+            Some(format!("Expected chunk {}, got {}", expected_seq, seq))
+        } else if chunk.len() as u64 != expected_len {
+            Some(format!("Chunk {} has invalid length {}", seq, chunk.len()))
+        } else if chunk_hash(&chunk) != hash {
+            Some(format!("Chunk {} hash mismatch", seq))
+        } else {
+            None
+        };
+
+        if let Some(error) = error {
+            write_frame(io, &Frame::Failure { error: error.clone() }).await?;
+            return Err(eyre!(error));
+        }
+
+        data.extend_from_slice(&chunk);
+
+        if (seq + 1) % ACK_INTERVAL == 0 || seq + 1 == total {
+            write_frame(io, &Frame::Ack { seq }).await?;
+        }
+    }
+
+    Ok(data)
+}
+
+/// Uploads a shard to the remote side and waits until it has been persisted.
+pub async fn upload<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    index: u64,
+    id: ClusterId,
+    data: &[u8],
+) -> Result<()> {
+    let len = data.len() as u64;
+    write_frame(io, &Frame::Request(TransferRequest::Upload { index, id, len })).await?;
+
+    match read_frame(io).await? {
+        Frame::Accept { .. } => {}
+        Frame::Failure { error } => return Err(eyre!("Upload rejected: {}", error)),
+        frame => return Err(eyre!("Unexpected frame: {:?}", frame)),
+    }
+
+    send_data(io, data).await?;
+
+    match read_frame(io).await? {
+        Frame::Done => Ok(()),
+        Frame::Failure { error } => Err(eyre!("Upload failed: {}", error)),
+        frame => Err(eyre!("Unexpected frame: {:?}", frame)),
+    }
+}
+
+/// Downloads the shard of the cluster with the given index from the remote side. Fails if the
+/// shard is not `len` bytes long.
+pub async fn download<S: AsyncRead + AsyncWrite + Unpin>(
+    io: &mut S,
+    index: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    write_frame(io, &Frame::Request(TransferRequest::Download { index })).await?;
+
+    match read_frame(io).await? {
+        Frame::Accept { len: announced } if announced == len => {}
+        Frame::Accept { len: announced } => {
+            return Err(eyre!(
+                "Expected {} bytes, peer announced {}",
+                len,
+                announced
+            ))
+        }
+        Frame::Failure { error } => return Err(eyre!("Download rejected: {}", error)),
+        frame => return Err(eyre!("Unexpected frame: {:?}", frame)),
+    };
+
+    recv_data(io, len).await
+}
+
+/// Serving side of an incoming transfer.
+pub struct IncomingTransfer<S> {
+    io: S,
+    request: TransferRequest,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> IncomingTransfer<S> {
+    /// Reads the transfer request from a freshly opened stream.
+    pub async fn accept(mut io: S) -> Result<Self> {
+        match read_frame(&mut io).await? {
+            Frame::Request(request) => Ok(Self { io, request }),
+            frame => Err(eyre!("Unexpected frame: {:?}", frame)),
+        }
+    }
+
+    pub fn request(&self) -> &TransferRequest {
+        &self.request
+    }
+
+    /// Rejects the request with the given error.
+    pub async fn reject(mut self, error: &str) -> Result<()> {
+        write_frame(&mut self.io, &Frame::Failure { error: error.to_string() }).await?;
+        self.io.close().await?;
+        Ok(())
+    }
+
+    /// Accepts an upload request of `expected_len` bytes and receives its payload, or rejects an
+    /// upload of any other length. The caller must persist the data and then confirm it with
+    /// [`IncomingUpload::complete`].
+    pub async fn receive(mut self, expected_len: u64) -> Result<IncomingUpload<S>> {
+        let TransferRequest::Upload { len, .. } = self.request else {
+            return Err(eyre!("Not an upload request"));
+        };
+        if len != expected_len {
+            let error = format!("Expected {} bytes, got an upload of {}", expected_len, len);
+            self.reject(&error).await?;
+            return Err(eyre!(error));
+        }
+
+        write_frame(&mut self.io, &Frame::Accept { len }).await?;
+        let data = recv_data(&mut self.io, len).await?;
+
+        Ok(IncomingUpload { io: self.io, data })
+    }
+
+    /// Accepts a download request and sends `data` as the response.
+    pub async fn send(mut self, data: &[u8]) -> Result<()> {
+        if !matches!(self.request, TransferRequest::Download { .. }) {
+            return Err(eyre!("Not a download request"));
+        }
+
+        let len = data.len() as u64;
+        write_frame(&mut self.io, &Frame::Accept { len }).await?;
+        send_data(&mut self.io, data).await?;
+        self.io.close().await?;
+        Ok(())
+    }
+}
+
+/// An upload whose payload has been received but not yet confirmed.
+pub struct IncomingUpload<S> {
+    io: S,
+    pub data: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> IncomingUpload<S> {
+    /// Reports the outcome of persisting the data to the uploader.
+    pub async fn complete(mut self, result: &Result<()>) -> Result<()> {
+        let frame = match result {
+            Ok(()) => Frame::Done,
+            Err(err) => Frame::Failure { error: err.to_string() },
+        };
+        write_frame(&mut self.io, &frame).await?;
+        self.io.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    use super::*;
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let data = test_data(CHUNK_SIZE * (WINDOW as usize * 2 + 1) + 123);
+        let (client, server) = tokio::io::duplex(4096);
+
+        let client_task = async {
+            let mut client = client.compat();
+            upload(&mut client, 7, ClusterId::random(), &data).await
+        };
+        let server_task = async {
+            let incoming = IncomingTransfer::accept(server.compat()).await?;
+            assert!(matches!(incoming.request(), TransferRequest::Upload { index: 7, .. }));
+            let upload = incoming.receive(data.len() as u64).await?;
+            let received = upload.data.clone();
+            upload.complete(&Ok(())).await?;
+            Ok::<_, color_eyre::Report>(received)
+        };
+
+        let (client_res, server_res) = tokio::join!(client_task, server_task);
+        client_res.unwrap();
+        assert_eq!(server_res.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let data = test_data(CHUNK_SIZE * 3);
+        let (client, server) = tokio::io::duplex(4096);
+
+        let client_task = async { download(&mut client.compat(), 3, data.len() as u64).await };
+        let server_task = async {
+            let incoming = IncomingTransfer::accept(server.compat()).await?;
+            assert!(matches!(incoming.request(), TransferRequest::Download { index: 3 }));
+            incoming.send(&data).await
+        };
+
+        let (client_res, server_res) = tokio::join!(client_task, server_task);
+        server_res.unwrap();
+        assert_eq!(client_res.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_download() {
+        let (client, server) = tokio::io::duplex(4096);
+
+        let client_task = async { download(&mut client.compat(), 3, 1024).await };
+        let server_task = async {
+            let incoming = IncomingTransfer::accept(server.compat()).await?;
+            incoming.reject("not a storage node").await
+        };
+
+        let (client_res, server_res) = tokio::join!(client_task, server_task);
+        server_res.unwrap();
+        assert!(client_res.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_length() {
+        // An upload announcing more than a shard is rejected before anything is allocated
+        let (client, server) = tokio::io::duplex(4096);
+        let client_task = async {
+            let mut client = client.compat();
+            let request = TransferRequest::Upload {
+                index: 7,
+                id: ClusterId::random(),
+                len: u64::MAX,
+            };
+            write_frame(&mut client, &Frame::Request(request)).await?;
+            read_frame(&mut client).await
+        };
+        let server_task = async {
+            let incoming = IncomingTransfer::accept(server.compat()).await?;
+            incoming.receive(1024).await.map(|_| ())
+        };
+
+        let (client_res, server_res) = tokio::join!(client_task, server_task);
+        assert!(matches!(client_res.unwrap(), Frame::Failure { .. }));
+        assert!(server_res.is_err());
+
+        // So is a download of another length
+        let data = test_data(1024);
+        let (client, server) = tokio::io::duplex(4096);
+        let client_task = async { download(&mut client.compat(), 3, u64::MAX).await };
+        let server_task = async {
+            let incoming = IncomingTransfer::accept(server.compat()).await?;
+            incoming.send(&data).await
+        };
+
+        let (client_res, _) = tokio::join!(client_task, server_task);
+        assert!(client_res.is_err());
+    }
+}
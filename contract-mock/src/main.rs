@@ -1,35 +1,150 @@
-use std::{collections::HashMap, fmt::Display, net::SocketAddr, ops::Deref, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use axum::{
     extract::Path,
     http::StatusCode,
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{bail, Result};
 use common::{
-    contract::{Cluster, ClusterId},
+    config::ProtocolParams,
+    contract::{
+        Cluster, ClusterId, MiningEpoch, NodeEntry, NodeId, SolutionReq, StorageCommitmentReq,
+    },
     crypto::PublicKey,
+    placement::assign_shards,
 };
-use primitives::Hash;
+use primitives::{Hash, Val};
+use sealing::{fragment_of_cluster, sealing_fragment};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use spora::{
+    storage_log_len, storage_root, verify_sealed_shard, verify_solution, Nonce, SPoRAConfig,
+    SealedShardProof, SolutionProof,
+};
 use tokio::sync::RwLock;
 use tracing::instrument;
 use tracing_subscriber::fmt::format::FmtSpan;
 
 const STATE_PATH: &str = "data/contract_mock_state.bin";
+/// State files start with the magic and the version of the layout of [`AppState`].
+const STATE_MAGIC: &[u8; 8] = b"zpssmock";
+/// Bumped on every change to the layout of [`AppState`].
+const STATE_VERSION: u32 = 3;
+
+const DEFAULT_EPOCH_DURATION_SECS: u64 = 60;
+/// Number of accepted solutions per epoch the complexity is retargeted to.
+const DEFAULT_TARGET_SOLUTIONS: usize = 64;
+/// SPoRA challenge parameters, every node checks `MAX_NONCE` nonces per epoch and finds about
+/// `MAX_NONCE / 2^log_complexity` solutions.
+const MAX_NONCE: u64 = 1 << 16;
+const INITIAL_LOG_COMPLEXITY: usize = 12;
+/// Complexity is the number of leading zeros of a 31-bit hash element.
+const MAX_LOG_COMPLEXITY: usize = 30;
+const N_SAMPLES: usize = 16;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct AppState {
     clusters: Vec<Cluster>,
     cluster_indices: HashMap<ClusterId, usize>,
+    /// Registered storage nodes and the number of shards assigned to each of them.
+    nodes: BTreeMap<NodeId, u64>,
+    mining: MiningState,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct MiningState {
+    epoch: MiningEpoch,
+    /// Solutions accepted in the current epoch.
+    solutions: Vec<Solution>,
+    /// Latest storage commitments of the nodes.
+    commitments: BTreeMap<NodeId, StorageCommitmentReq>,
+    /// Storage commitments at the start of the current epoch, before its challenge was known.
+    /// Solutions of the epoch are verified against them.
+    epoch_commitments: BTreeMap<NodeId, StorageCommitmentReq>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Solution {
+    node_id: NodeId,
+    nonce: u64,
+    complexity: usize,
+}
+
+impl MiningState {
+    fn new() -> Self {
+        Self {
+            epoch: MiningEpoch {
+                epoch: 0,
+                max_nonce: MAX_NONCE,
+                log_complexity: INITIAL_LOG_COMPLEXITY,
+                n_samples: N_SAMPLES,
+                challenge: random_challenge(),
+            },
+            solutions: Vec::new(),
+            commitments: BTreeMap::new(),
+            epoch_commitments: BTreeMap::new(),
+        }
+    }
+
+    /// Starts the next epoch with a new challenge, retargeting the complexity to
+    /// `target_solutions` solutions per epoch.
+    fn advance(&mut self, target_solutions: usize) {
+        // Nobody mined, the solution rate says nothing about the complexity.
+        if !self.epoch_commitments.is_empty() {
+            self.epoch.log_complexity = retarget(
+                self.epoch.log_complexity,
+                self.solutions.len(),
+                target_solutions,
+            );
+        }
+
+        self.epoch.epoch += 1;
+        self.epoch.challenge = random_challenge();
+        self.solutions.clear();
+        self.epoch_commitments = self.commitments.clone();
+    }
+}
+
+fn random_challenge() -> Hash {
+    Hash::from(rand::random::<[Val; 8]>())
+}
+
+/// The expected number of solutions halves with every step of complexity, so the complexity moves
+/// by the binary logarithm of the ratio of the found solutions to the target. Without solutions it
+/// is only lowered by one step, as the ratio is unknown.
+fn retarget(log_complexity: usize, solutions: usize, target_solutions: usize) -> usize {
+    if solutions == 0 {
+        return log_complexity.saturating_sub(1);
+    }
+
+    let step = (solutions as f64 / target_solutions as f64).log2().round() as i64;
+    (log_complexity as i64 + step).clamp(0, MAX_LOG_COMPLEXITY as i64) as usize
+}
+
+#[derive(Deserialize)]
+struct RegisterNodeReq {
+    node_id: NodeId,
+}
+
+#[derive(Deserialize)]
+struct MoveShardReq {
+    shard: usize,
+    node_id: NodeId,
 }
 
 #[derive(Deserialize)]
 struct UploadClusterReq {
     owner_pk: PublicKey,
     commit: Hash,
+    shard_commits: Vec<Hash>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,20 +152,41 @@ struct UploadClusterRes {
     cluster_id: String,
 }
 
+#[derive(Serialize)]
+struct ClusterEntry {
+    cluster_id: String,
+    cluster: Cluster,
+}
+
 #[instrument(skip_all)]
 async fn reserve_cluster(
     state: axum::extract::State<Arc<RwLock<AppState>>>,
     form: Json<UploadClusterReq>,
 ) -> Result<Json<UploadClusterRes>, StatusCode> {
+    let params = ProtocolParams::dev();
+    if form.shard_commits.len() != params.q {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
     let cluster_id = ClusterId::random();
 
     let mut state = state.write().await;
     let cur_cluster_index = state.clusters.len();
 
+    let placement = assign_shards(&state.nodes, params.q).ok_or_else(|| {
+        tracing::warn!("Not enough storage nodes registered to place a cluster");
+        StatusCode::SERVICE_UNAVAILABLE
+    })?;
+    for node in &placement {
+        *state.nodes.get_mut(node).unwrap() += 1;
+    }
+
     let cluster = Cluster {
         index: cur_cluster_index as u64,
         owner_pk: form.owner_pk,
         commit: form.commit,
+        shard_commits: form.shard_commits.clone(),
+        placement,
     };
 
     state.clusters.push(cluster);
@@ -60,11 +196,7 @@ async fn reserve_cluster(
 
     tracing::info!("Reserved cluster {}", cluster_id);
 
-    // dump the state to disk, ok for a mock
-    let mut file =
-        std::fs::File::create(STATE_PATH).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    bincode::serialize_into(&mut file, state.deref())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    save_state(&state)?;
 
     Ok(Json(UploadClusterRes {
         cluster_id: cluster_id.to_string(),
@@ -89,6 +221,365 @@ async fn get_cluster(
     Ok(Json(cluster.clone()))
 }
 
+#[instrument(skip_all)]
+async fn list_clusters(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+) -> Json<Vec<ClusterEntry>> {
+    let state = state.read().await;
+    let mut entries = state
+        .cluster_indices
+        .iter()
+        .map(|(cluster_id, &index)| ClusterEntry {
+            cluster_id: cluster_id.to_string(),
+            cluster: state.clusters[index].clone(),
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|entry| entry.cluster.index);
+
+    Json(entries)
+}
+
+#[instrument(skip(state))]
+async fn register_node(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+    Json(req): Json<RegisterNodeReq>,
+) -> Result<StatusCode, StatusCode> {
+    let mut state = state.write().await;
+    if state.nodes.contains_key(&req.node_id) {
+        return Ok(StatusCode::OK);
+    }
+
+    state.nodes.insert(req.node_id, 0);
+    tracing::info!("Registered storage node {}", req.node_id);
+
+    save_state(&state)?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Moves a shard of the cluster to another node. Called once the node has stored the shard.
+#[instrument(skip(state))]
+async fn move_shard(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+    Path(cluster_id): Path<String>,
+    Json(req): Json<MoveShardReq>,
+) -> Result<Json<Cluster>, StatusCode> {
+    let mut state = state.write().await;
+    let cluster_id: ClusterId = cluster_id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let index = *state
+        .cluster_indices
+        .get(&cluster_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if !state.nodes.contains_key(&req.node_id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let cluster = &mut state.clusters[index];
+    if req.shard >= cluster.placement.len() || cluster.placement.contains(&req.node_id) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let old_node = std::mem::replace(&mut cluster.placement[req.shard], req.node_id);
+    let cluster = cluster.clone();
+
+    if let Some(load) = state.nodes.get_mut(&old_node) {
+        *load -= 1;
+    }
+    *state.nodes.get_mut(&req.node_id).unwrap() += 1;
+
+    tracing::info!(
+        "Moved shard {} of cluster {} from node {} to node {}",
+        req.shard,
+        cluster_id,
+        old_node,
+        req.node_id
+    );
+
+    save_state(&state)?;
+
+    Ok(Json(cluster))
+}
+
+/// Removes a storage node from the registry. Its shards stay in the placements until they are
+/// moved to other nodes.
+#[instrument(skip(state))]
+async fn deregister_node(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+    Path(node_id): Path<NodeId>,
+) -> Result<StatusCode, StatusCode> {
+    let mut state = state.write().await;
+    state.nodes.remove(&node_id).ok_or(StatusCode::NOT_FOUND)?;
+    tracing::info!("Deregistered storage node {}", node_id);
+
+    save_state(&state)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip_all)]
+async fn list_nodes(state: axum::extract::State<Arc<RwLock<AppState>>>) -> Json<Vec<NodeEntry>> {
+    let state = state.read().await;
+    Json(
+        state
+            .nodes
+            .iter()
+            .map(|(&node_id, &shards)| NodeEntry { node_id, shards })
+            .collect(),
+    )
+}
+
+#[instrument(skip_all)]
+async fn get_mining_epoch(state: axum::extract::State<Arc<RwLock<AppState>>>) -> Json<MiningEpoch> {
+    Json(state.read().await.mining.epoch.clone())
+}
+
+/// Records the storage commitment of a node, used from the next epoch on.
+#[instrument(skip(state))]
+async fn submit_storage_commitment(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+    Json(req): Json<StorageCommitmentReq>,
+) -> Result<StatusCode, StatusCode> {
+    let mut state = state.write().await;
+    if !state.nodes.contains_key(&req.node_id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let params = ProtocolParams::dev();
+    let assigned = assigned_clusters(&state.clusters, req.node_id);
+    if !verify_commitment(&assigned, &req, params.log_shard_size()) {
+        tracing::warn!(
+            "Node {} committed to storage which isn't made of its {} assigned shards",
+            req.node_id,
+            assigned.len()
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !verify_shard_proofs(&state.clusters, &req, &params) {
+        tracing::warn!(
+            "Node {} committed to shards which aren't its sealed shards",
+            req.node_id
+        );
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    tracing::info!("Node {} committed to storage {:?}", req.node_id, req.root);
+    state.mining.commitments.insert(req.node_id, req);
+
+    save_state(&state)?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Indices of the clusters with a shard placed on the node.
+fn assigned_clusters(clusters: &[Cluster], node_id: NodeId) -> BTreeSet<u64> {
+    clusters
+        .iter()
+        .filter(|cluster| cluster.shard_of(node_id).is_some())
+        .map(|cluster| cluster.index)
+        .collect()
+}
+
+/// Checks that the storage of the commitment is made of exactly one sealed shard of every assigned
+/// cluster, and that its root and size are the ones of these shards.
+fn verify_commitment(
+    assigned: &BTreeSet<u64>,
+    req: &StorageCommitmentReq,
+    log_shard_len: usize,
+) -> bool {
+    let committed = req
+        .shards
+        .iter()
+        .map(|shard| shard.cluster_index)
+        .collect::<BTreeSet<_>>();
+    if assigned.is_empty() || committed.len() != req.shards.len() || &committed != assigned {
+        return false;
+    }
+
+    let shard_roots = req
+        .shards
+        .iter()
+        .map(|shard| shard.root)
+        .collect::<Vec<_>>();
+    req.log_len == storage_log_len(shard_roots.len(), log_shard_len)
+        && req.root == storage_root(&shard_roots, log_shard_len)
+}
+
+/// Checks that every committed shard is the shard of its cluster placed on the node, sealed with
+/// the node's sealing fragment, so solutions are only found on sealed cluster data. The shards
+/// should be the assigned ones, see [`verify_commitment`].
+fn verify_shard_proofs(
+    clusters: &[Cluster],
+    req: &StorageCommitmentReq,
+    params: &ProtocolParams,
+) -> bool {
+    // Clusters of a fragment are sealed with the same fragment, which is costly to generate
+    let mut fragment_roots = BTreeMap::new();
+    req.shards.iter().all(|shard| {
+        let Some(source_commit) = clusters
+            .get(shard.cluster_index as usize)
+            .and_then(|cluster| {
+                let shard_index = cluster.shard_of(req.node_id)?;
+                cluster.shard_commits.get(shard_index).copied()
+            })
+        else {
+            return false;
+        };
+        let Ok(proof) = SealedShardProof::from_bytes(&shard.proof) else {
+            return false;
+        };
+
+        let (fragment_index, cluster_in_fragment_index) =
+            fragment_of_cluster(params, shard.cluster_index as usize);
+        let fragment_root = *fragment_roots
+            .entry(fragment_index)
+            .or_insert_with(|| sealing_fragment(params, req.node_id as usize, fragment_index).root);
+
+        verify_sealed_shard(
+            params,
+            shard.root,
+            proof,
+            source_commit,
+            fragment_root,
+            cluster_in_fragment_index,
+        )
+    })
+}
+
+/// Verifies a solution of the current epoch against the storage commitment of the node and
+/// records it.
+#[instrument(skip(state, req), fields(node_id = req.node_id, nonce = req.nonce))]
+async fn submit_solution(
+    state: axum::extract::State<Arc<RwLock<AppState>>>,
+    Json(req): Json<SolutionReq>,
+) -> Result<StatusCode, StatusCode> {
+    let mut state = state.write().await;
+    if !state.nodes.contains_key(&req.node_id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mining = &mut state.mining;
+    if req.epoch != mining.epoch.epoch {
+        return Err(StatusCode::CONFLICT);
+    }
+    if req.nonce >= mining.epoch.max_nonce {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if mining
+        .solutions
+        .iter()
+        .any(|solution| solution.node_id == req.node_id && solution.nonce == req.nonce)
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    let commitment = mining
+        .epoch_commitments
+        .get(&req.node_id)
+        .ok_or(StatusCode::CONFLICT)?;
+
+    let proof = SolutionProof::from_bytes(&req.proof).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let config = SPoRAConfig::new(
+        mining.epoch.challenge,
+        mining.epoch.max_nonce,
+        mining.epoch.log_complexity,
+        mining.epoch.n_samples,
+        commitment.log_len,
+    );
+    let complexity = verify_solution(&config, commitment.root, Nonce::new(req.nonce), &proof)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if complexity < mining.epoch.log_complexity {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    tracing::info!(
+        "Node {} submitted nonce {} with complexity {} in epoch {}",
+        req.node_id,
+        req.nonce,
+        complexity,
+        req.epoch
+    );
+    mining.solutions.push(Solution {
+        node_id: req.node_id,
+        nonce: req.nonce,
+        complexity,
+    });
+
+    save_state(&state)?;
+
+    Ok(StatusCode::CREATED)
+}
+
+/// Starts a new mining epoch every `duration`.
+async fn advance_epochs(
+    state: Arc<RwLock<AppState>>,
+    duration: std::time::Duration,
+    target_solutions: usize,
+) {
+    loop {
+        tokio::time::sleep(duration).await;
+
+        let mut state = state.write().await;
+        let mining = &mut state.mining;
+        tracing::info!(
+            "Mining epoch {} finished with {} solutions",
+            mining.epoch.epoch,
+            mining.solutions.len()
+        );
+        mining.advance(target_solutions);
+        tracing::info!(
+            "Mining epoch {} started with complexity {}",
+            mining.epoch.epoch,
+            mining.epoch.log_complexity
+        );
+
+        if save_state(&state).is_err() {
+            tracing::error!("Failed to save state");
+        }
+    }
+}
+
+/// Dumps the state to disk, ok for a mock.
+fn save_state(state: &AppState) -> Result<(), StatusCode> {
+    let mut file =
+        std::fs::File::create(STATE_PATH).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    file.write_all(STATE_MAGIC)
+        .and_then(|()| file.write_all(&STATE_VERSION.to_le_bytes()))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    bincode::serialize_into(&mut file, state).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Loads the state saved by [`save_state`], `None` if there is no state yet. Fails on states of
+/// another layout instead of overwriting them.
+fn load_state() -> Result<Option<AppState>> {
+    let data = match std::fs::read(STATE_PATH) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => bail!("Failed to read state from {}: {}", STATE_PATH, err),
+    };
+
+    let Some((version, state)) = data
+        .strip_prefix(STATE_MAGIC)
+        .and_then(|data| data.split_first_chunk::<4>())
+    else {
+        bail!(
+            "{} was saved without a version by an older contract mock, remove it to start over",
+            STATE_PATH
+        );
+    };
+    let version = u32::from_le_bytes(*version);
+    if version != STATE_VERSION {
+        bail!(
+            "{} has state version {}, expected {}, remove it to start over",
+            STATE_PATH,
+            version,
+            STATE_VERSION
+        );
+    }
+
+    match bincode::deserialize(state) {
+        Ok(state) => Ok(Some(state)),
+        Err(err) => bail!("Failed to deserialize state from {}: {}", STATE_PATH, err),
+    }
+}
+
 #[instrument(skip_all)]
 async fn info_handler(
     state: axum::extract::State<Arc<RwLock<AppState>>>,
@@ -101,8 +592,14 @@ async fn info_handler(
 pub async fn start_server(state: Arc<RwLock<AppState>>, addr: &str) -> color_eyre::Result<()> {
     let app = Router::new()
         .route("/info", get(info_handler))
-        .route("/clusters", post(reserve_cluster))
+        .route("/clusters", get(list_clusters).post(reserve_cluster))
         .route("/clusters/:cluster_id", get(get_cluster))
+        .route("/clusters/:cluster_id/placement", post(move_shard))
+        .route("/nodes", get(list_nodes).post(register_node))
+        .route("/nodes/:node_id", delete(deregister_node))
+        .route("/mining/epoch", get(get_mining_epoch))
+        .route("/mining/commitments", post(submit_storage_commitment))
+        .route("/mining/solutions", post(submit_solution))
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .with_state(state.clone());
 
@@ -121,29 +618,39 @@ async fn main() -> Result<()> {
         .with_span_events(FmtSpan::CLOSE)
         .init();
 
-    let state_res: Result<AppState> = std::fs::read(STATE_PATH)
-        .map_err(|err| color_eyre::eyre::eyre!("Failed to read state from disk: {}", err))
-        .and_then(|data| {
-            bincode::deserialize(&data)
-                .map_err(|err| color_eyre::eyre::eyre!("Failed to deserialize state: {}", err))
-        });
-    let state = match state_res {
-        Ok(state) => {
+    let state = match load_state()? {
+        Some(state) => {
             tracing::info!("Loaded state from disk. Clusters in state: {}", state.clusters.len());
             state
         },
-        Err(err) => {
-            tracing::warn!("{}. New state initialized.", err);
+        None => {
+            tracing::info!("No state on disk. New state initialized.");
             AppState {
                 clusters: Vec::new(),
                 cluster_indices: HashMap::new(),
+                nodes: BTreeMap::new(),
+                mining: MiningState::new(),
             }
         }
     };
 
     let port = std::env::var("PORT").unwrap_or_else(|_| "80".to_string());
 
+    let epoch_duration = std::env::var("EPOCH_DURATION")
+        .ok()
+        .map(|secs| secs.parse::<u64>().expect("Invalid epoch duration"))
+        .unwrap_or(DEFAULT_EPOCH_DURATION_SECS);
+    let target_solutions = std::env::var("TARGET_SOLUTIONS")
+        .ok()
+        .map(|target| target.parse::<usize>().expect("Invalid target solutions"))
+        .unwrap_or(DEFAULT_TARGET_SOLUTIONS);
+
     let state = Arc::new(RwLock::new(state));
+    tokio::spawn(advance_epochs(
+        state.clone(),
+        std::time::Duration::from_secs(epoch_duration),
+        target_solutions,
+    ));
 
     let addr = format!("0.0.0.0:{}", port);
     tracing::info!("Listening on {}", addr);
@@ -151,3 +658,224 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::contract::ShardCommitment;
+    use spora::{commit_cluster, rlc, CommittedStorage};
+
+    const TARGET: usize = 64;
+
+    fn commitment(node_id: NodeId) -> StorageCommitmentReq {
+        StorageCommitmentReq {
+            node_id,
+            root: Hash::from([Val::new(node_id); 8]),
+            log_len: 10,
+            shards: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_retarget() {
+        // On target
+        assert_eq!(retarget(12, TARGET, TARGET), 12);
+        // Four times too many solutions need two more steps of complexity, and the other way round
+        assert_eq!(retarget(12, 4 * TARGET, TARGET), 14);
+        assert_eq!(retarget(12, TARGET / 4, TARGET), 10);
+
+        // Without solutions the complexity only drops by one step
+        assert_eq!(retarget(12, 0, TARGET), 11);
+        assert_eq!(retarget(0, 0, TARGET), 0);
+
+        // Clamped to the complexities a hash can reach
+        assert_eq!(
+            retarget(MAX_LOG_COMPLEXITY, 8 * TARGET, TARGET),
+            MAX_LOG_COMPLEXITY
+        );
+        assert_eq!(retarget(1, 1, TARGET), 0);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut mining = MiningState::new();
+        let challenge = mining.epoch.challenge;
+        mining.commitments.insert(1, commitment(1));
+
+        // Nobody mined the first epoch, the complexity stays
+        mining.advance(TARGET);
+        assert_eq!(mining.epoch.epoch, 1);
+        assert_eq!(mining.epoch.log_complexity, INITIAL_LOG_COMPLEXITY);
+        assert_ne!(mining.epoch.challenge, challenge);
+        assert_eq!(mining.epoch_commitments, mining.commitments);
+
+        // Committed nodes without solutions lower it by one step
+        mining.advance(TARGET);
+        assert_eq!(mining.epoch.log_complexity, INITIAL_LOG_COMPLEXITY - 1);
+
+        // Too many solutions raise it, and are cleared for the next epoch
+        mining.solutions = (0..4 * TARGET as u64)
+            .map(|nonce| Solution {
+                node_id: 1,
+                nonce,
+                complexity: INITIAL_LOG_COMPLEXITY,
+            })
+            .collect();
+        mining.advance(TARGET);
+        assert_eq!(mining.epoch.epoch, 3);
+        assert_eq!(mining.epoch.log_complexity, INITIAL_LOG_COMPLEXITY + 1);
+        assert!(mining.solutions.is_empty());
+
+        // Commitments made during an epoch are used from the next one on
+        mining.commitments.insert(2, commitment(2));
+        assert_eq!(mining.epoch_commitments.len(), 1);
+        mining.advance(TARGET);
+        assert_eq!(mining.epoch_commitments.len(), 2);
+    }
+
+    #[test]
+    fn test_verify_commitment() {
+        let log_shard_len = ProtocolParams::test().log_shard_size();
+        let shard = |seed: u32| {
+            (0..1 << log_shard_len)
+                .map(|i| Val::new(seed + i))
+                .collect()
+        };
+        let storage = CommittedStorage::from_shards(vec![shard(0), shard(1), shard(2)]);
+        let shards = [3, 5, 8]
+            .into_iter()
+            .zip(storage.shard_roots())
+            .map(|(cluster_index, &root)| ShardCommitment {
+                cluster_index,
+                root,
+                proof: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let req = StorageCommitmentReq {
+            node_id: 1,
+            root: storage.root(),
+            log_len: storage.log_len(),
+            shards,
+        };
+        let assigned = BTreeSet::from([3, 5, 8]);
+        assert!(verify_commitment(&assigned, &req, log_shard_len));
+
+        // Shards of other clusters, missing or repeated shards
+        assert!(!verify_commitment(
+            &BTreeSet::from([3, 5, 9]),
+            &req,
+            log_shard_len
+        ));
+        assert!(!verify_commitment(
+            &BTreeSet::from([3, 5, 8, 9]),
+            &req,
+            log_shard_len
+        ));
+        let mut repeated = req.clone();
+        repeated.shards.push(repeated.shards[0].clone());
+        assert!(!verify_commitment(&assigned, &repeated, log_shard_len));
+
+        // A root or a size which isn't the one of the shards
+        let mut wrong_root = req.clone();
+        wrong_root.shards.swap(0, 1);
+        assert!(!verify_commitment(&assigned, &wrong_root, log_shard_len));
+        let wrong_len = StorageCommitmentReq {
+            log_len: req.log_len + 1,
+            ..req.clone()
+        };
+        assert!(!verify_commitment(&assigned, &wrong_len, log_shard_len));
+
+        // Nodes without shards have nothing to mine
+        assert!(!verify_commitment(&BTreeSet::new(), &req, log_shard_len));
+    }
+
+    #[test]
+    fn test_verify_shard_proofs() {
+        let params = ProtocolParams::test();
+        let node_id = 1;
+        let source = |seed: u32| {
+            (0..params.shard_size() as u32)
+                .map(|i| Val::new(seed * 1000 + i))
+                .collect::<Vec<_>>()
+        };
+        // The node stores shard 1 of every cluster
+        let clusters = (0..3)
+            .map(|index| Cluster {
+                index,
+                owner_pk: Default::default(),
+                commit: Hash::from([Val::new(0); 8]),
+                shard_commits: vec![
+                    Hash::from([Val::new(0); 8]),
+                    commit_cluster(&source(index as u32)).0,
+                ],
+                placement: vec![0, node_id],
+            })
+            .collect::<Vec<_>>();
+
+        let mut sealed = Vec::new();
+        let mut sealings = Vec::new();
+        for cluster in &clusters {
+            let (fragment_index, cluster_in_fragment_index) =
+                fragment_of_cluster(&params, cluster.index as usize);
+            let fragment = sealing_fragment(&params, node_id as usize, fragment_index);
+            let (r, shard) = rlc(
+                &params,
+                &source(cluster.index as u32),
+                &fragment.clusters[cluster_in_fragment_index],
+                fragment.root,
+            );
+            sealed.push(shard);
+            sealings.push((r, fragment, cluster_in_fragment_index));
+        }
+        let storage = CommittedStorage::from_shards(sealed);
+        let shards = sealings
+            .iter()
+            .enumerate()
+            .map(|(index, (r, fragment, cluster_in_fragment_index))| {
+                let proof = storage
+                    .prove_sealed_shard(
+                        &params,
+                        index,
+                        *r,
+                        &fragment.cluster_hashes,
+                        &fragment.clusters[*cluster_in_fragment_index],
+                        *cluster_in_fragment_index,
+                    )
+                    .unwrap();
+                ShardCommitment {
+                    cluster_index: index as u64,
+                    root: storage.shard_roots()[index],
+                    proof: proof.to_bytes(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let req = StorageCommitmentReq {
+            node_id,
+            root: storage.root(),
+            log_len: storage.log_len(),
+            shards,
+        };
+        assert!(verify_shard_proofs(&clusters, &req, &params));
+
+        // Shards sealed by another node, or of other source data
+        let other_node = StorageCommitmentReq {
+            node_id: 0,
+            ..req.clone()
+        };
+        assert!(!verify_shard_proofs(&clusters, &other_node, &params));
+        let mut other_source = clusters.clone();
+        other_source[2].shard_commits[1] = commit_cluster(&source(7)).0;
+        assert!(!verify_shard_proofs(&other_source, &req, &params));
+
+        // Proofs of another shard, or missing
+        let mut swapped = req.clone();
+        let proof = swapped.shards[0].proof.clone();
+        swapped.shards[0].proof = swapped.shards[1].proof.clone();
+        swapped.shards[1].proof = proof;
+        assert!(!verify_shard_proofs(&clusters, &swapped, &params));
+        let mut missing = req.clone();
+        missing.shards[1].proof.clear();
+        assert!(!verify_shard_proofs(&clusters, &missing, &params));
+    }
+}
@@ -1,21 +1,41 @@
-use std::{collections::HashSet, net::SocketAddr, ops::Deref, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    ops::Deref,
+    sync::Arc,
+};
 
 use axum::{
     extract::{Multipart, Path},
-    http::{header, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::get,
     Json, Router,
 };
 use color_eyre::Result;
-use common::{contract::ClusterId, crypto::verify, encode::encode_aligned, node::UploadMessage};
+use common::{
+    contract::ClusterId,
+    crypto::verify,
+    encode::encode_aligned,
+    node::{NodeClient, UploadMessage, FORWARDED_HEADER},
+};
 use m31jubjub::{eddsa::SigParams, m31::M31JubJubSigParams};
 use p3_matrix::dense::RowMajorMatrix;
 use primitives::Val;
 use serde_json::json;
 use shards::compute_commitment;
+use snapshot_db::db::CompactionReport;
+use spora::commit_cluster;
+use tokio::sync::oneshot;
 
-use crate::state::{AppState, Command, NodeState};
+use crate::{
+    coordination::{self, Candidate},
+    mining::MiningStats,
+    rebalance::{self, RebalanceStatus},
+    repair::{self, RepairStatus},
+    scrubber::ScrubStats,
+    state::{AppState, Command, NodeState},
+};
 
 #[tracing::instrument(skip(state), level = "info")]
 async fn download_cluster(
@@ -44,9 +64,9 @@ async fn download_cluster(
 
     match &state.node_state {
         NodeState::Validator => Err(StatusCode::FORBIDDEN),
-        NodeState::Storage { storage } => {
+        NodeState::Storage { storage, .. } => {
             let data = storage
-                .read(1, cluster_index)
+                .read_bytes(cluster_index)
                 .await
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -58,13 +78,19 @@ async fn download_cluster(
     }
 }
 
-#[tracing::instrument(skip(state, multipart), level = "info")]
+/// Uploads are handled by validators only, storage nodes answer with `403 Forbidden`.
+#[tracing::instrument(skip(state, headers, multipart), level = "info")]
 async fn upload_cluster(
     state: axum::extract::State<Arc<AppState>>,
     Path(cluster_id): Path<String>,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
-    let cluster_id = cluster_id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    if !matches!(state.node_state, NodeState::Validator) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let cluster_id: ClusterId = cluster_id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
     while let Some(field) = multipart
         .next_field()
         .await
@@ -77,10 +103,11 @@ async fn upload_cluster(
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         let data = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+
         let msg: UploadMessage =
             bincode::deserialize(&data).map_err(|_| StatusCode::BAD_REQUEST)?;
 
-        let elements = encode_aligned(&msg.data, state.storage_config.cluster_size())
+        let elements = encode_aligned(&msg.data, state.params.cluster_size())
             .map_err(|_| StatusCode::BAD_REQUEST)?;
 
         if !verify(&elements, msg.signature, cluster_metadata.owner_pk) {
@@ -88,30 +115,126 @@ async fn upload_cluster(
             return Err(StatusCode::BAD_REQUEST);
         }
 
-        let matrix = RowMajorMatrix::new(elements, state.storage_config.m);
-        let (commit, shards) = compute_commitment(matrix, state.storage_config.log_blowup_factor());
+        let matrix = RowMajorMatrix::new(elements, state.params.m);
+        let (commit, shards) = compute_commitment(matrix, &state.params);
 
         if cluster_metadata.commit != commit.pcs_commitment_hash {
             tracing::debug!("Invalid commit");
             return Err(StatusCode::BAD_REQUEST);
         }
 
+        // Sealed shards are proven against the shard commitments, so they must be the shards of
+        // this upload
+        let shard_commits_match = shards.len() == cluster_metadata.shard_commits.len()
+            && shards
+                .iter()
+                .zip(&cluster_metadata.shard_commits)
+                .all(|(shard, &shard_commit)| commit_cluster(shard).0 == shard_commit);
+        if !shard_commits_match {
+            tracing::debug!("Invalid shard commits");
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        // Forwarded uploads are always handled locally, the sender has already picked us. This
+        // also prevents forwarding loops between validators with diverging views of the network.
+        // The upload is checked above either way, so a client setting the header itself can only
+        // pick the validator that stores it.
+        if !headers.contains_key(FORWARDED_HEADER) {
+            for candidate in coordination::rank_validators(&state, cluster_metadata.index).await {
+                let Candidate::Remote(validator) = candidate else {
+                    break;
+                };
+
+                let client = NodeClient::new(&validator.api_url, state.http_client.clone());
+                match client
+                    .forward_upload(cluster_id.clone(), data.to_vec())
+                    .await
+                {
+                    Ok(status) if status.is_success() => {
+                        tracing::debug!("Upload forwarded to {}", validator.peer_id);
+                        return Ok((StatusCode::CREATED, Json(json!({ "status": "ok" }))));
+                    }
+                    // The validator handled the upload and rejected it, retrying elsewhere would
+                    // store it behind its back.
+                    Ok(status) => {
+                        tracing::warn!(
+                            "Validator {} rejected forwarded upload: {}",
+                            validator.peer_id,
+                            status
+                        );
+                        return Err(StatusCode::from_u16(status.as_u16())
+                            .unwrap_or(StatusCode::BAD_GATEWAY));
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "Failed to forward upload to {}, trying next validator: {}",
+                            validator.peer_id,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+
+        let (reply, receiver) = oneshot::channel();
         state
             .command_sender
             .send(Command::UploadCluster {
                 index: cluster_metadata.index,
                 id: cluster_id.clone(),
                 shards,
+                placement: cluster_metadata.placement,
+                reply,
             })
             .await
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+        let uploaded = receiver
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Err(err) = uploaded {
+            tracing::error!("Failed to upload cluster {}: {}", cluster_id, err);
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+
         return Ok((StatusCode::CREATED, Json(json!({ "status": "ok" }))));
     }
 
     Err(StatusCode::BAD_REQUEST)
 }
 
+/// Deletes the local shard of a cluster once the contract has moved it to another node.
+#[tracing::instrument(skip(state), level = "info")]
+async fn release_cluster(
+    state: axum::extract::State<Arc<AppState>>,
+    Path(cluster_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let NodeState::Storage { id, storage } = &state.node_state else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let cluster_id: ClusterId = cluster_id.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let cluster = state
+        .contract_client
+        .get_cluster(&cluster_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if cluster.shard_of(*id).is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    if let Err(err) = storage.delete(cluster.index as usize).await {
+        tracing::error!(
+            "Failed to delete shard of cluster {}: {}",
+            cluster.index,
+            err
+        );
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[tracing::instrument(skip(state), level = "info")]
 async fn get_info(state: axum::extract::State<Arc<AppState>>) -> Json<serde_json::Value> {
     // TODO: Get rid of locks in public API
@@ -121,13 +244,131 @@ async fn get_info(state: axum::extract::State<Arc<AppState>>) -> Json<serde_json
     }))
 }
 
+#[tracing::instrument(skip(state), level = "info")]
+async fn start_repair(
+    state: axum::extract::State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    if !matches!(state.node_state, NodeState::Storage { .. }) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if state.repair_status.read().await.running {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let state = state.0.clone();
+    tokio::spawn(async move {
+        if let Err(err) = repair::repair(state).await {
+            tracing::error!("Repair failed: {}", err);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "status": "started" }))))
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn get_repair_status(state: axum::extract::State<Arc<AppState>>) -> Json<RepairStatus> {
+    Json(state.repair_status.read().await.clone())
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn start_rebalance(
+    state: axum::extract::State<Arc<AppState>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    if !matches!(state.node_state, NodeState::Validator) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if state.rebalance_status.read().await.running {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let state = state.0.clone();
+    tokio::spawn(async move {
+        if let Err(err) = rebalance::rebalance(state, false).await {
+            tracing::error!("Rebalance failed: {}", err);
+        }
+    });
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "status": "started" }))))
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn get_rebalance_status(state: axum::extract::State<Arc<AppState>>) -> Json<RebalanceStatus> {
+    Json(state.rebalance_status.read().await.clone())
+}
+
+/// Reports how much space a compaction of every storage namespace would reclaim.
+#[tracing::instrument(skip(state), level = "info")]
+async fn get_compaction_estimate(
+    state: axum::extract::State<Arc<AppState>>,
+) -> Result<Json<BTreeMap<String, CompactionReport>>, StatusCode> {
+    compact_storage(&state, true).await
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn start_compaction(
+    state: axum::extract::State<Arc<AppState>>,
+) -> Result<Json<BTreeMap<String, CompactionReport>>, StatusCode> {
+    compact_storage(&state, false).await
+}
+
+async fn compact_storage(
+    state: &AppState,
+    dry_run: bool,
+) -> Result<Json<BTreeMap<String, CompactionReport>>, StatusCode> {
+    let NodeState::Storage { storage, .. } = &state.node_state else {
+        return Err(StatusCode::FORBIDDEN);
+    };
+
+    let reports = storage.compact(dry_run).await.map_err(|err| {
+        tracing::error!("Compaction failed: {}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !dry_run {
+        for (namespace, report) in &reports {
+            tracing::info!(
+                "Compacted {} storage: moved {} slots, reclaimed {} bytes",
+                namespace,
+                report.moved_slots,
+                report.reclaimable_bytes
+            );
+        }
+    }
+
+    Ok(Json(reports))
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn get_scrub_stats(state: axum::extract::State<Arc<AppState>>) -> Json<ScrubStats> {
+    Json(state.scrub_stats.read().await.clone())
+}
+
+#[tracing::instrument(skip(state), level = "info")]
+async fn get_mining_stats(state: axum::extract::State<Arc<AppState>>) -> Json<MiningStats> {
+    Json(state.mining_stats.read().await.clone())
+}
+
 pub async fn start_server(state: Arc<AppState>, addr: &str) -> Result<()> {
     let app = Router::new()
         .route(
             "/clusters/:cluster_id",
-            get(download_cluster).post(upload_cluster),
+            get(download_cluster)
+                .post(upload_cluster)
+                .delete(release_cluster),
         )
         .route("/info", get(get_info))
+        .route("/admin/repair", get(get_repair_status).post(start_repair))
+        .route(
+            "/admin/rebalance",
+            get(get_rebalance_status).post(start_rebalance),
+        )
+        .route("/admin/scrub", get(get_scrub_stats))
+        .route("/admin/mining", get(get_mining_stats))
+        .route(
+            "/admin/compact",
+            get(get_compaction_estimate).post(start_compaction),
+        )
         .route("/", get(get_info))
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .with_state(state.clone());
@@ -0,0 +1,139 @@
+//! Assignment of clusters to validators.
+//!
+//! Every validator handles uploads of a subset of clusters. The responsible validator is chosen with
+//! rendezvous (highest random weight) hashing over the cluster index, so every validator computes the
+//! same assignment from its view of the validator set, and adding or removing a validator only moves
+//! the clusters assigned to it. Validators ranked lower take over when the ones above them are
+//! unreachable.
+
+use libp2p::PeerId;
+use sha3::{Digest, Sha3_256};
+
+use crate::state::{AppState, Peer};
+
+/// A validator that can handle an upload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Candidate {
+    Local,
+    Remote(Peer),
+}
+
+fn score(peer_id: &PeerId, cluster_index: u64) -> [u8; 32] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(peer_id.to_bytes());
+    hasher.update(cluster_index.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Whether this validator coordinates network-wide tasks, such as rebalancing. That's the validator
+/// responsible for the first cluster.
+pub async fn is_leader(state: &AppState) -> bool {
+    rank_validators(state, 0).await.first() == Some(&Candidate::Local)
+}
+
+/// Returns all known validators, including this one, ordered by their priority for the cluster.
+pub async fn rank_validators(state: &AppState, cluster_index: u64) -> Vec<Candidate> {
+    let validators = state.validators.read().await;
+    rank(&state.local_peer_id, validators.iter(), cluster_index)
+}
+
+fn rank<'a>(
+    local_peer_id: &PeerId,
+    validators: impl IntoIterator<Item = &'a Peer>,
+    cluster_index: u64,
+) -> Vec<Candidate> {
+    let mut candidates: Vec<_> = validators
+        .into_iter()
+        .filter(|peer| peer.peer_id != *local_peer_id)
+        .map(|peer| (score(&peer.peer_id, cluster_index), Candidate::Remote(peer.clone())))
+        .chain([(score(local_peer_id, cluster_index), Candidate::Local)])
+        .collect();
+    candidates.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+    candidates.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const CLUSTERS: u64 = 8000;
+
+    fn peer() -> Peer {
+        Peer {
+            peer_id: PeerId::random(),
+            addr: "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            api_url: String::new(),
+        }
+    }
+
+    /// Number of clusters each validator, identified by its peer ID, is responsible for.
+    fn assignment(validators: &[Peer]) -> HashMap<PeerId, u64> {
+        let local_peer_id = validators[0].peer_id;
+        let mut counts = HashMap::new();
+        for cluster_index in 0..CLUSTERS {
+            let peer_id = match &rank(&local_peer_id, validators, cluster_index)[0] {
+                Candidate::Local => local_peer_id,
+                Candidate::Remote(peer) => peer.peer_id,
+            };
+            *counts.entry(peer_id).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_rank_is_deterministic() {
+        let validators: Vec<_> = (0..5).map(|_| peer()).collect();
+
+        // Every validator computes the same order from its own view of the validator set
+        for cluster_index in 0..100 {
+            let orders: Vec<Vec<PeerId>> = validators
+                .iter()
+                .map(|local| {
+                    rank(&local.peer_id, validators.iter().rev(), cluster_index)
+                        .into_iter()
+                        .map(|candidate| match candidate {
+                            Candidate::Local => local.peer_id,
+                            Candidate::Remote(peer) => peer.peer_id,
+                        })
+                        .collect()
+                })
+                .collect();
+
+            assert_eq!(orders[0].len(), validators.len());
+            assert!(orders.iter().all(|order| *order == orders[0]));
+        }
+    }
+
+    #[test]
+    fn test_rank_spreads_clusters_evenly() {
+        let validators: Vec<_> = (0..8).map(|_| peer()).collect();
+        let counts = assignment(&validators);
+
+        let expected = CLUSTERS / validators.len() as u64;
+        assert_eq!(counts.len(), validators.len());
+        for count in counts.values() {
+            assert!(
+                count.abs_diff(expected) < expected / 5,
+                "{} clusters instead of about {}",
+                count,
+                expected
+            );
+        }
+
+        // Removing a validator only moves the clusters it was responsible for
+        let removed = validators[7].peer_id;
+        let after = assignment(&validators[..7]);
+        let moved = (0..CLUSTERS)
+            .filter(|&cluster_index| {
+                let old = &rank(&validators[0].peer_id, &validators, cluster_index)[0];
+                let new = &rank(&validators[0].peer_id, &validators[..7], cluster_index)[0];
+                old != new
+            })
+            .count() as u64;
+        assert_eq!(moved, counts[&removed]);
+        assert_eq!(after.values().sum::<u64>(), CLUSTERS);
+    }
+}
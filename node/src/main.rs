@@ -1,31 +1,48 @@
 use std::{
     future::IntoFuture,
     hash::{Hash, Hasher},
+    path::PathBuf,
     sync::Arc,
 };
 
 use clap::Parser;
-use color_eyre::eyre::Result;
-use common::{config::StorageConfig, contract::MockContractClient, crypto::derive_keys};
+use color_eyre::eyre::{bail, Result};
+use common::{config::ProtocolParams, contract::MockContractClient, crypto::derive_keys};
 use libp2p::{futures::StreamExt, swarm::NetworkBehaviour};
 use m31jubjub::hdwallet::{priv_key, pub_key};
 use reqwest::Client;
-use primitives::Val;
 use serde::Serialize;
 use tracing_subscriber::fmt::format::FmtSpan;
-use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::{
+    encryption::EncryptionKey, metadata::MetadataBackend, namespaces::SnapshotStoreConfig,
+    storage::IoBackend,
+};
 
-use crate::state::{AppState, NodeId, NodeKind, NodeState};
+use crate::{
+    state::{AppState, NodeId, NodeKind, NodeState},
+    storage::ShardStorage,
+};
 
 mod api;
+mod coordination;
+mod mining;
 mod network;
+mod rebalance;
+mod repair;
+mod scrubber;
 mod state;
+mod storage;
+mod transfer;
 
 // TODO: Might want to extract the validator into a separate crate in the future.
 // TODO: I'm not sure if libp2p is even needed here: we're only using it for transport, encryption,
 //       request/response.
 
 const COMMAND_CHANNEL_CAPACITY: usize = 100;
+const DEFAULT_SCRUB_INTERVAL_SECS: u64 = 24 * 60 * 60;
+/// One thread keeps the remaining cores free for serving the API.
+const DEFAULT_MINING_THREADS: usize = 1;
+const MINING_POLL_INTERVAL_SECS: u64 = 5;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -46,6 +63,21 @@ struct Args {
     node_id: Option<NodeId>,
     #[arg(short = 'c', long)]
     contract_mock_url: Option<String>,
+    /// Interval between integrity scrubs of stored shards, in seconds.
+    #[arg(long)]
+    scrub_interval: Option<u64>,
+    /// Repair corrupted shards found by the scrubber from peers.
+    #[arg(long)]
+    scrub_repair: bool,
+    /// Encrypt stored shards at rest with a key derived from the seed phrase.
+    #[arg(long)]
+    encrypt_storage: bool,
+    /// Number of threads mining SPoRA solutions, 0 disables mining.
+    #[arg(long)]
+    mining_threads: Option<usize>,
+    /// Directory of the keys, addresses and persisted tasks of the node.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -66,10 +98,7 @@ async fn main() -> Result<()> {
         .public_api_url
         .or(std::env::var("PUBLIC_API_URL").ok())
         .expect("Public API URL not set");
-    let external_ip = args
-        .external_ip
-        .or(std::env::var("EXTERNAL_IP").ok())
-        .expect("External IP not set");
+    let external_ip = args.external_ip.or(std::env::var("EXTERNAL_IP").ok());
     let p2p_port = args
         .p2p_port
         .or_else(|| {
@@ -87,6 +116,10 @@ async fn main() -> Result<()> {
             .ok()
             .map(|id| id.parse::<NodeId>().unwrap())
     });
+    let data_dir = args
+        .data_dir
+        .or_else(|| std::env::var("DATA_DIR").ok().map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("data"));
     let boot_node = args
         .boot_node
         .or(std::env::var("BOOT_NODE").ok())
@@ -96,11 +129,47 @@ async fn main() -> Result<()> {
         .or(std::env::var("CONTRACT_MOCK_URL").ok())
         .expect("Contract mock URL not set");
 
+    let scrubber_config = scrubber::Config {
+        interval: std::time::Duration::from_secs(
+            args.scrub_interval
+                .or_else(|| {
+                    std::env::var("SCRUB_INTERVAL")
+                        .ok()
+                        .map(|secs| secs.parse::<u64>().expect("Invalid scrub interval"))
+                })
+                .unwrap_or(DEFAULT_SCRUB_INTERVAL_SECS),
+        ),
+        repair: args.scrub_repair
+            || std::env::var("SCRUB_REPAIR").is_ok_and(|repair| repair == "true"),
+    };
+
+    let mining_config = mining::Config {
+        threads: args
+            .mining_threads
+            .or_else(|| {
+                std::env::var("MINING_THREADS").ok().map(|threads| {
+                    threads
+                        .parse::<usize>()
+                        .expect("Invalid number of mining threads")
+                })
+            })
+            .unwrap_or(DEFAULT_MINING_THREADS),
+        poll_interval: std::time::Duration::from_secs(MINING_POLL_INTERVAL_SECS),
+    };
+
+    let encrypt_storage = args.encrypt_storage
+        || std::env::var("ENCRYPT_STORAGE").is_ok_and(|encrypt| encrypt == "true");
+
     let node_kind = match node_id {
         Some(id) => NodeKind::Storage { id },
         None => NodeKind::Validator,
     };
 
+    // Validators serve as boot nodes and relays, so they must be directly reachable.
+    if matches!(node_kind, NodeKind::Validator) && external_ip.is_none() {
+        bail!("External IP not set, validators must be directly reachable");
+    }
+
     let (sk, pk) = derive_keys(&seed_phrase).expect("Invalid seed phrase");
 
     let network_config = network::Config {
@@ -109,40 +178,81 @@ async fn main() -> Result<()> {
         node_kind: node_kind.clone(),
         public_api_url,
         external_ip,
+        data_dir: data_dir.clone(),
     };
 
-    let storage_config = StorageConfig::dev();
+    let params = ProtocolParams::dev();
 
+    let mut storage_is_empty = false;
     let node_state = match node_kind {
         NodeKind::Validator => NodeState::Validator,
         NodeKind::Storage { id } => {
-            let db_config = SnapshotDbConfig {
-                cluster_size: storage_config.shard_size() * size_of::<Val>(), // FIXME: size of shard
-                num_clusters: storage_config.num_clusters(),
+            let store_config = SnapshotStoreConfig {
+                namespaces: storage::namespaces(&params),
+                metadata_backend: MetadataBackend::from_env()?,
+                io_backend: IoBackend::from_env()?,
+                encryption_key: encrypt_storage
+                    .then(|| EncryptionKey::from_seed_phrase(&seed_phrase)),
+                mmap_reads: true,
             };
-            let storage_dir =
-                std::env::var("STORAGE_DIR").unwrap_or_else(|_| "./data/storage".to_string());
-            let storage = SnapshotDb::new(&storage_dir, db_config).await?;
-            NodeState::Storage { storage }
+            let storage_dir = std::env::var("STORAGE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| data_dir.join("storage"));
+            storage_is_empty = std::fs::read_dir(&storage_dir)
+                .map_or(true, |mut entries| entries.next().is_none());
+            let storage = ShardStorage::new(&storage_dir, store_config, id, params).await?;
+            NodeState::Storage { id, storage }
         }
     };
 
+    let local_key = match &node_kind {
+        NodeKind::Validator => {
+            network::load_or_generate_keypair(&data_dir.join("validator-keypair"))
+        }
+        NodeKind::Storage { id } => {
+            network::load_or_generate_keypair(&data_dir.join(format!("node{}-keypair", id)))
+        }
+    }?;
+
     let client = Client::new();
-    let contract_client = MockContractClient::new(&contract_mock_url, client);
+    let contract_client = MockContractClient::new(&contract_mock_url, client.clone());
+
+    if let NodeKind::Storage { id } = &node_kind {
+        contract_client.register_node(*id).await?;
+    }
 
     let (command_sender, command_receiver) = tokio::sync::mpsc::channel(COMMAND_CHANNEL_CAPACITY);
     let state = Arc::new(AppState::new(
         sk,
         pk,
-        storage_config,
+        params,
         command_sender,
+        local_key.public().to_peer_id(),
         node_state,
         contract_client,
+        client,
+        data_dir,
     ));
 
+    if storage_is_empty {
+        tokio::spawn(repair::repair_on_startup(state.clone()));
+    }
+
+    match state.node_state {
+        NodeState::Validator => {
+            tokio::spawn(rebalance::run(state.clone()));
+        }
+        NodeState::Storage { .. } => {
+            tokio::spawn(scrubber::run(state.clone(), scrubber_config));
+            if mining_config.threads > 0 {
+                tokio::spawn(mining::run(state.clone(), mining_config));
+            }
+        }
+    }
+
     let http_server = api::start_server(state.clone(), &api_addr);
     tokio::pin!(http_server);
-    let network = network::start_network(network_config, state, command_receiver);
+    let network = network::start_network(network_config, local_key, state, command_receiver);
     tokio::pin!(network);
 
     tokio::select! {
@@ -0,0 +1,632 @@
+//! SPoRA mining on the sealed shards.
+//!
+//! The contract announces a SPoRA challenge every epoch. The miner searches the nonces of the
+//! epoch for the ones whose samples of the sealed storage hash to the target complexity, and
+//! submits them to the contract as they are found. The search runs on a configurable number of
+//! dedicated threads, so that mining doesn't starve the API of CPU, and is abandoned when the next
+//! epoch starts.
+//!
+//! Solutions open the sampled values against a Merkle root of the sealed storage, which has to be
+//! committed to the contract before the epoch starts. At every epoch the miner commits to the
+//! current sealed shards of its assigned clusters, and only mines the epoch if the storage is the one
+//! committed before. The storage is kept across epochs, and a snapshot of the sealed shards taken at
+//! every epoch tells which shards changed and have to be hashed again.
+//!
+//! The contract only accepts sealed shards, so every committed shard comes with a proof that it is
+//! the shard of its cluster sealed with the node's sealing fragment. Proofs are made when a shard is
+//! read into the storage, from the shard itself and its unsealed source.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::Range,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use color_eyre::{eyre::eyre, Report, Result};
+use common::{
+    config::ProtocolParams,
+    contract::{MiningEpoch, ShardCommitment, SolutionReq, StorageCommitmentReq},
+    encode::bytes_to_vals,
+};
+use primitives::{Hash, Val};
+use sealing::{fragment_of_cluster, sealing_fragment};
+use serde::Serialize;
+use spora::{
+    commit_cluster, derive_r, spora_range, CommittedStorage, Nonce, SPoRAConfig,
+    UnstructuredStorageReader,
+};
+use tokio::{
+    sync::{mpsc, RwLock},
+    task::JoinHandle,
+};
+
+use crate::state::{AppState, NodeId, NodeState};
+
+/// Number of nonces a thread checks before looking for the end of the epoch.
+const NONCE_CHUNK: u64 = 256;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Number of threads searching nonces.
+    pub threads: usize,
+    /// Interval between checks for a new epoch.
+    pub poll_interval: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MiningStats {
+    /// Epoch being mined.
+    pub epoch: Option<u64>,
+    /// Merkle root of the mined storage.
+    pub storage_root: Option<Hash>,
+    pub nonces_checked: u64,
+    pub solutions_found: u64,
+    pub solutions_accepted: u64,
+    pub submit_errors: u64,
+}
+
+/// Sealed shards the miner commits to, kept across epochs so that only the shards which changed
+/// are read and hashed again.
+struct MinedStorage {
+    /// Clusters of the shards, in the order of the shards in the storage.
+    clusters: Vec<usize>,
+    storage: Arc<CommittedStorage>,
+    /// Encoded proofs that the shards are sealed, by cluster.
+    proofs: BTreeMap<usize, Vec<u8>>,
+    /// Committed snapshot of the sealed namespace the shards were read from.
+    snapshot: usize,
+}
+
+impl MinedStorage {
+    fn commitment(&self, node_id: NodeId) -> StorageCommitmentReq {
+        StorageCommitmentReq {
+            node_id,
+            root: self.storage.root(),
+            log_len: self.storage.log_len(),
+            shards: self
+                .clusters
+                .iter()
+                .zip(self.storage.shard_roots())
+                .map(|(&cluster_index, &root)| ShardCommitment {
+                    cluster_index: cluster_index as u64,
+                    root,
+                    proof: self.proofs[&cluster_index].clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Mines every epoch announced by the contract.
+pub async fn run(state: Arc<AppState>, config: Config) {
+    let NodeState::Storage { id, .. } = &state.node_state else {
+        return;
+    };
+
+    let mut current_epoch = None;
+    let mut mining: Option<(Arc<AtomicBool>, JoinHandle<()>)> = None;
+    let mut mined: Option<MinedStorage> = None;
+    // Root of the storage the contract verifies the solutions of the next epoch against.
+    let mut committed_root = None;
+    loop {
+        match state.contract_client.get_mining_epoch().await {
+            Ok(epoch) if current_epoch != Some(epoch.epoch) => {
+                if let Some((stop, task)) = mining.take() {
+                    stop.store(true, Ordering::Relaxed);
+                    let _ = task.await;
+                }
+                // A failed update leaves the epoch unhandled, so that it is retried at the next poll.
+                if let Err(err) = update_storage(&state, *id, &mut mined).await {
+                    tracing::error!("Failed to load the sealed storage: {}", err);
+                } else {
+                    current_epoch = Some(epoch.epoch);
+                    match &mined {
+                        None => {
+                            tracing::info!("No shards assigned, not mining epoch {}", epoch.epoch)
+                        }
+                        Some(mined) if committed_root == Some(mined.storage.root()) => {
+                            tracing::info!("Mining epoch {}", epoch.epoch);
+                            let stop = Arc::new(AtomicBool::new(false));
+                            let task = tokio::spawn(mine_epoch(
+                                state.clone(),
+                                *id,
+                                config.threads,
+                                epoch.clone(),
+                                mined.storage.clone(),
+                                stop.clone(),
+                            ));
+                            mining = Some((stop, task));
+                        }
+                        Some(mined) => {
+                            let commitment = mined.commitment(*id);
+                            match state
+                                .contract_client
+                                .submit_storage_commitment(&commitment)
+                                .await
+                            {
+                                Ok(()) => {
+                                    tracing::info!(
+                                        "Committed to the sealed storage, mining from epoch {}",
+                                        epoch.epoch + 1
+                                    );
+                                    committed_root = Some(commitment.root);
+                                }
+                                Err(err) => {
+                                    tracing::error!("Failed to commit the sealed storage: {}", err)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => tracing::error!("Failed to fetch the mining epoch: {}", err),
+        }
+
+        tokio::time::sleep(config.poll_interval).await;
+    }
+}
+
+/// Commits a snapshot of the sealed shards and brings the mined storage up to date with it: reads
+/// the shards of newly assigned clusters and the shards which changed since the last snapshot, and
+/// drops the shards of clusters which are no longer assigned. The storage is left untouched if a
+/// shard can't be read, so that the node never commits to storage it doesn't have, and dropped if
+/// a shard can't be proven, so that it is read again from scratch.
+async fn update_storage(
+    state: &AppState,
+    node_id: NodeId,
+    mined: &mut Option<MinedStorage>,
+) -> Result<()> {
+    let NodeState::Storage { storage, .. } = &state.node_state else {
+        unreachable!("Only storage nodes mine");
+    };
+
+    let assigned = state
+        .contract_client
+        .get_clusters()
+        .await?
+        .into_iter()
+        .filter(|(_, cluster)| cluster.shard_of(node_id).is_some())
+        .map(|(_, cluster)| cluster.index as usize)
+        .collect::<BTreeSet<_>>();
+    if assigned.is_empty() {
+        *mined = None;
+        return Ok(());
+    }
+
+    let sealed = storage.sealed()?;
+    sealed.add_snapshot().await?;
+    let snapshot = sealed
+        .latest_committed()
+        .await
+        .ok_or_else(|| eyre!("No committed snapshot of the sealed shards"))?;
+
+    let (kept, changed) = match mined {
+        Some(mined) => {
+            let kept = mined.clusters.iter().copied().collect::<BTreeSet<_>>();
+            let changed = sealed
+                .changed_clusters(snapshot.id(), Some(mined.snapshot))
+                .await?;
+            (kept, changed.into_iter().collect::<BTreeSet<_>>())
+        }
+        None => (BTreeSet::new(), BTreeSet::new()),
+    };
+
+    let mut shards = BTreeMap::new();
+    let mut sources = BTreeMap::new();
+    for &cluster_id in assigned
+        .difference(&kept)
+        .chain(assigned.intersection(&changed))
+    {
+        let data = snapshot.read(cluster_id).await.map_err(|err| {
+            eyre!(
+                "Failed to read the sealed shard of cluster {}: {}",
+                cluster_id,
+                err
+            )
+        })?;
+        shards.insert(cluster_id, bytes_to_vals(&data));
+        let source = storage.read(cluster_id).await.map_err(|err| {
+            eyre!(
+                "Failed to read the shard of cluster {}: {}",
+                cluster_id,
+                err
+            )
+        })?;
+        sources.insert(cluster_id, bytes_to_vals(&source));
+    }
+    let snapshot_id = snapshot.id();
+    drop(snapshot);
+
+    // Older snapshots are only needed to find the changed shards.
+    while *sealed.snapshots().await.start() < snapshot_id {
+        sealed.join_snapshot().await?;
+    }
+
+    let previous = mined.take().filter(|_| shards.len() < assigned.len());
+    let params = state.params;
+    let updated = tokio::task::spawn_blocking(move || {
+        let mut updated = apply_shards(previous, &assigned, shards, snapshot_id)?;
+        prove_shards(&mut updated, &params, node_id, sources)?;
+        Ok::<_, Report>(updated)
+    })
+    .await??;
+    *mined = Some(updated);
+
+    Ok(())
+}
+
+/// Applies the shards read from a snapshot to the mined storage, or commits to them if there is no
+/// mined storage to keep, in which case they are all the assigned shards.
+fn apply_shards(
+    mined: Option<MinedStorage>,
+    assigned: &BTreeSet<usize>,
+    mut shards: BTreeMap<usize, Vec<Val>>,
+    snapshot: usize,
+) -> Result<MinedStorage> {
+    let Some(mut mined) = mined else {
+        let (clusters, values): (Vec<_>, Vec<_>) = shards.into_iter().unzip();
+        return Ok(MinedStorage {
+            clusters,
+            storage: Arc::new(CommittedStorage::from_shards(values)),
+            proofs: BTreeMap::new(),
+            snapshot,
+        });
+    };
+
+    let mut storage = Arc::try_unwrap(mined.storage)
+        .map_err(|_| eyre!("The sealed storage is still being mined"))?;
+    let mut index = 0;
+    while index < mined.clusters.len() {
+        if !assigned.contains(&mined.clusters[index]) {
+            storage.swap_remove_shard(index);
+            mined.proofs.remove(&mined.clusters.swap_remove(index));
+            continue;
+        }
+        if let Some(values) = shards.remove(&mined.clusters[index]) {
+            storage.update_shard(index, values);
+            mined.proofs.remove(&mined.clusters[index]);
+        }
+        index += 1;
+    }
+    for (cluster_id, values) in shards {
+        storage.push_shard(values);
+        mined.clusters.push(cluster_id);
+    }
+
+    Ok(MinedStorage {
+        clusters: mined.clusters,
+        storage: Arc::new(storage),
+        proofs: mined.proofs,
+        snapshot,
+    })
+}
+
+/// Proves that the shards of the clusters are their source shards sealed by the node, see
+/// [`CommittedStorage::prove_sealed_shard`].
+fn prove_shards(
+    mined: &mut MinedStorage,
+    params: &ProtocolParams,
+    node_id: NodeId,
+    sources: BTreeMap<usize, Vec<Val>>,
+) -> Result<()> {
+    // Clusters of a fragment are sealed with the same fragment, which is costly to generate
+    let mut fragments = BTreeMap::new();
+    for (cluster_id, source) in sources {
+        let index = mined
+            .clusters
+            .iter()
+            .position(|&cluster| cluster == cluster_id)
+            .ok_or_else(|| eyre!("Cluster {} isn't mined", cluster_id))?;
+        let (fragment_index, cluster_in_fragment_index) = fragment_of_cluster(params, cluster_id);
+        let fragment = fragments
+            .entry(fragment_index)
+            .or_insert_with(|| sealing_fragment(params, node_id as usize, fragment_index));
+
+        let (source_commit, _) = commit_cluster(&source);
+        let proof = mined
+            .storage
+            .prove_sealed_shard(
+                params,
+                index,
+                derive_r(source_commit, fragment.root),
+                &fragment.cluster_hashes,
+                &fragment.clusters[cluster_in_fragment_index],
+                cluster_in_fragment_index,
+            )
+            .map_err(|err| {
+                eyre!(
+                    "Failed to prove the sealed shard of cluster {}: {}",
+                    cluster_id,
+                    err
+                )
+            })?;
+        mined.proofs.insert(cluster_id, proof.to_bytes());
+    }
+
+    Ok(())
+}
+
+/// Searches the nonces of the epoch and submits the solutions as they are found.
+async fn mine_epoch(
+    state: Arc<AppState>,
+    node_id: NodeId,
+    threads: usize,
+    epoch: MiningEpoch,
+    storage: Arc<CommittedStorage>,
+    stop: Arc<AtomicBool>,
+) {
+    *state.mining_stats.write().await = MiningStats {
+        epoch: Some(epoch.epoch),
+        storage_root: Some(storage.root()),
+        ..Default::default()
+    };
+
+    let (solutions_sender, mut solutions) = mpsc::unbounded_channel();
+    let search_task = tokio::task::spawn_blocking({
+        let state = state.clone();
+        let epoch = epoch.clone();
+        move || {
+            search(
+                &state.mining_stats,
+                threads,
+                &epoch,
+                &storage,
+                &stop,
+                solutions_sender,
+            )
+        }
+    });
+
+    // The channel is closed once the search is over.
+    while let Some((nonce, proof)) = solutions.recv().await {
+        let solution = SolutionReq {
+            node_id,
+            epoch: epoch.epoch,
+            nonce: nonce.as_u64(),
+            proof,
+        };
+        let result = state.contract_client.submit_solution(&solution).await;
+
+        let mut stats = state.mining_stats.write().await;
+        stats.solutions_found += 1;
+        match result {
+            Ok(()) => stats.solutions_accepted += 1,
+            Err(err) => {
+                tracing::error!("Failed to submit nonce {}: {}", solution.nonce, err);
+                stats.submit_errors += 1;
+            }
+        }
+    }
+
+    match search_task.await {
+        Ok(()) => {
+            let stats = state.mining_stats.read().await;
+            tracing::info!(
+                "Mined epoch {}: checked {} nonces, {} of {} solutions accepted",
+                epoch.epoch,
+                stats.nonces_checked,
+                stats.solutions_accepted,
+                stats.solutions_found
+            );
+        }
+        Err(err) => tracing::error!("Mining epoch {} panicked: {}", epoch.epoch, err),
+    }
+}
+
+/// Splits the nonces of the epoch between `threads` threads in chunks, until all are checked or
+/// `stop` is set. Sends the solutions with their encoded proofs.
+fn search(
+    stats: &RwLock<MiningStats>,
+    threads: usize,
+    epoch: &MiningEpoch,
+    storage: &CommittedStorage,
+    stop: &AtomicBool,
+    solutions: mpsc::UnboundedSender<(Nonce, Vec<u8>)>,
+) {
+    let config = SPoRAConfig::new(
+        epoch.challenge,
+        epoch.max_nonce,
+        epoch.log_complexity,
+        epoch.n_samples,
+        storage.log_len(),
+    );
+
+    let next_nonce = AtomicU64::new(0);
+    let next_chunk = || -> Option<Range<u64>> {
+        let start = next_nonce.fetch_add(NONCE_CHUNK, Ordering::Relaxed);
+        (!stop.load(Ordering::Relaxed) && start < epoch.max_nonce)
+            .then(|| start..(start + NONCE_CHUNK).min(epoch.max_nonce))
+    };
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while let Some(nonces) = next_chunk() {
+                    let checked = nonces.end - nonces.start;
+                    for (nonce, _) in spora_range(&config, nonces, storage) {
+                        let proof = storage.prove(&config, nonce).to_bytes();
+                        // The receiver is only dropped if the runtime shuts down.
+                        let _ = solutions.send((nonce, proof));
+                    }
+
+                    stats.blocking_write().nonces_checked += checked;
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use p3_field::PrimeField32;
+    use snapshot_db::namespaces::SnapshotStoreConfig;
+    use spora::{verify_sealed_shard, verify_solution, SealedShardProof, SolutionProof};
+
+    use crate::storage::{self, ShardStorage};
+
+    const LOG_STORAGE_SIZE: usize = 12;
+
+    fn epoch() -> MiningEpoch {
+        MiningEpoch {
+            epoch: 0,
+            max_nonce: 1000,
+            log_complexity: 4,
+            n_samples: 8,
+            challenge: Hash::from([Val::new(3); 8]),
+        }
+    }
+
+    fn storage() -> CommittedStorage {
+        CommittedStorage::new((0..1 << LOG_STORAGE_SIZE).map(Val::new).collect())
+    }
+
+    fn run_search(
+        threads: usize,
+        epoch: &MiningEpoch,
+        storage: &CommittedStorage,
+        stop: &AtomicBool,
+    ) -> (MiningStats, Vec<(Nonce, Vec<u8>)>) {
+        let stats = RwLock::new(MiningStats::default());
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        search(&stats, threads, epoch, storage, stop, sender);
+
+        let mut solutions = Vec::new();
+        while let Ok(solution) = receiver.try_recv() {
+            solutions.push(solution);
+        }
+        (stats.into_inner(), solutions)
+    }
+
+    #[test]
+    fn test_search_finds_all_solutions() {
+        let epoch = epoch();
+        let storage = storage();
+        let config = SPoRAConfig::new(
+            epoch.challenge,
+            epoch.max_nonce,
+            epoch.log_complexity,
+            epoch.n_samples,
+            LOG_STORAGE_SIZE,
+        );
+        let expected = spora_range(&config, 0..epoch.max_nonce, &storage);
+        assert!(!expected.is_empty());
+
+        let (stats, mut solutions) = run_search(3, &epoch, &storage, &AtomicBool::new(false));
+        assert_eq!(stats.nonces_checked, epoch.max_nonce);
+
+        solutions.sort_by_key(|(nonce, _)| nonce.as_u64());
+        assert_eq!(solutions.len(), expected.len());
+        for ((nonce, proof), (expected_nonce, complexity)) in solutions.iter().zip(expected) {
+            assert_eq!(nonce.as_u64(), expected_nonce.as_u64());
+            let proof = SolutionProof::from_bytes(proof).unwrap();
+            assert_eq!(
+                verify_solution(&config, storage.root(), *nonce, &proof),
+                Some(complexity)
+            );
+        }
+    }
+
+    #[test]
+    fn test_search_stops() {
+        let (stats, solutions) = run_search(2, &epoch(), &storage(), &AtomicBool::new(true));
+        assert_eq!(stats.nonces_checked, 0);
+        assert!(solutions.is_empty());
+    }
+
+    fn shard(cluster_id: usize, version: u32) -> Vec<Val> {
+        let seed = cluster_id as u32 * 1000 + version;
+        (0..ProtocolParams::test().shard_size() as u32)
+            .map(|index| Val::new(seed + index))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_shards() {
+        let shards = BTreeMap::from([(1, shard(1, 0)), (4, shard(4, 0)), (7, shard(7, 0))]);
+        let mined = apply_shards(None, &BTreeSet::from([1, 4, 7]), shards, 1).unwrap();
+        assert_eq!(mined.clusters, vec![1, 4, 7]);
+
+        // Cluster 1 is released, 7 changed and 9 is newly assigned
+        let shards = BTreeMap::from([(7, shard(7, 1)), (9, shard(9, 0))]);
+        let mined = apply_shards(Some(mined), &BTreeSet::from([4, 7, 9]), shards, 2).unwrap();
+        assert_eq!(mined.clusters, vec![7, 4, 9]);
+        assert_eq!(mined.snapshot, 2);
+
+        let expected = CommittedStorage::from_shards(vec![shard(7, 1), shard(4, 0), shard(9, 0)]);
+        assert_eq!(mined.storage.root(), expected.root());
+        assert_eq!(mined.storage.log_len(), expected.log_len());
+        assert_eq!(mined.storage.shard_roots(), expected.shard_roots());
+
+        // The storage of a running miner can't change
+        let running = mined.storage.clone();
+        let result = apply_shards(Some(mined), &BTreeSet::from([4, 7, 9]), BTreeMap::new(), 3);
+        assert!(result.is_err());
+        drop(running);
+    }
+
+    #[tokio::test]
+    async fn test_written_shard_is_mined() {
+        let params = ProtocolParams::test();
+        let (node_id, cluster_id) = (5, 6);
+        let dir = tempfile::tempdir().unwrap();
+        let config = SnapshotStoreConfig {
+            namespaces: storage::namespaces(&params),
+            metadata_backend: Default::default(),
+            io_backend: Default::default(),
+            encryption_key: None,
+            mmap_reads: false,
+        };
+        let storage = ShardStorage::new(dir.path(), config, node_id, params)
+            .await
+            .unwrap();
+
+        let source = shard(cluster_id, 0);
+        let data = source
+            .iter()
+            .flat_map(|value| value.as_canonical_u32().to_le_bytes())
+            .collect::<Vec<_>>();
+        storage.write(cluster_id, &data).await.unwrap();
+
+        let sealed = storage.sealed().unwrap();
+        sealed.add_snapshot().await.unwrap();
+        let snapshot = sealed.latest_committed().await.unwrap();
+        let sealed_shard = bytes_to_vals(&snapshot.read(cluster_id).await.unwrap());
+        assert_ne!(sealed_shard, source);
+
+        // The miner commits to it with a proof that it is the shard sealed with the node's sealing
+        // fragment
+        let shards = BTreeMap::from([(cluster_id, sealed_shard)]);
+        let mut mined =
+            apply_shards(None, &BTreeSet::from([cluster_id]), shards, snapshot.id()).unwrap();
+        let sources = BTreeMap::from([(cluster_id, source.clone())]);
+        prove_shards(&mut mined, &params, node_id, sources).unwrap();
+
+        let commitment = mined.commitment(node_id);
+        let (fragment_index, cluster_in_fragment_index) = fragment_of_cluster(&params, cluster_id);
+        let fragment = sealing_fragment(&params, node_id as usize, fragment_index);
+        let proof = SealedShardProof::from_bytes(&commitment.shards[0].proof).unwrap();
+        assert!(verify_sealed_shard(
+            &params,
+            commitment.shards[0].root,
+            proof,
+            commit_cluster(&source).0,
+            fragment.root,
+            cluster_in_fragment_index
+        ));
+
+        // Nor can shards which aren't sealed from the given source
+        let sources = BTreeMap::from([(cluster_id, shard(cluster_id, 1))]);
+        assert!(prove_shards(&mut mined, &params, node_id, sources).is_err());
+
+        // And it is what the miner searches
+        let (_, solutions) = run_search(2, &epoch(), &mined.storage, &AtomicBool::new(false));
+        assert!(!solutions.is_empty());
+    }
+}
@@ -1,23 +1,31 @@
 use std::{
     collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     sync::Arc,
     time::Duration,
 };
 
-use color_eyre::{eyre::Error, Result};
+use color_eyre::{
+    eyre::{eyre, Error},
+    Result,
+};
 use libp2p::{
-    futures::StreamExt,
-    identity,
+    autonat, dcutr,
+    futures::{future::join_all, StreamExt},
+    identify, identity,
     multiaddr::Protocol,
-    request_response,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    Multiaddr, PeerId, StreamProtocol, Swarm,
+    noise, relay, request_response,
+    swarm::{behaviour::toggle::Toggle, ListenerId, NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
 };
-use primitives::Val;
+use libp2p_stream::Control;
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use common::contract::ClusterId;
-use crate::state::{AppState, Command, NodeId, NodeKind, NodeState, Peer};
+
+use crate::{
+    state::{AppState, Command, NodeId, NodeKind, Peer},
+    transfer,
+};
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -25,12 +33,42 @@ pub struct Config {
     pub boot_node: Option<Multiaddr>,
     pub node_kind: NodeKind,
     pub public_api_url: String,
-    pub external_ip: String,
+    /// Publicly reachable IP of the node. If not set, the node detects its reachability with AutoNAT
+    /// and falls back to a relayed address through the boot node when it's behind a NAT.
+    pub external_ip: Option<String>,
+    /// Directory the announced address is dumped to.
+    pub data_dir: PathBuf,
 }
 
+/// Relayed connections carry whole shard transfers until DCUtR manages to upgrade them, so the
+/// default circuit limits of the relay (2 minutes, 128 KiB) are way too low.
+const RELAY_MAX_CIRCUIT_DURATION: Duration = Duration::from_secs(60 * 60);
+const RELAY_MAX_CIRCUIT_BYTES: u64 = 1 << 34;
+/// Pause before asking the boot node for a relay reservation again after it failed.
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     request_response: request_response::cbor::Behaviour<Req, Res>,
+    /// Raw streams used for shard transfers, see [`transfer`].
+    stream: libp2p_stream::Behaviour,
+    identify: identify::Behaviour,
+    autonat: autonat::Behaviour,
+    /// Validators act as circuit relays for nodes behind a NAT.
+    relay: Toggle<relay::Behaviour>,
+    relay_client: relay::client::Behaviour,
+    dcutr: dcutr::Behaviour,
+}
+
+/// How the node can be reached by its peers.
+#[derive(Default)]
+struct Reachability {
+    /// The address announced to the network, set once it is known.
+    announced_addr: Option<Multiaddr>,
+    /// Whether AutoNAT found the node unreachable, so that it needs a relayed address.
+    needs_relay: bool,
+    /// Listener of the relayed address while the reservation is requested or held.
+    relay_listener: Option<ListenerId>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -46,12 +84,6 @@ enum Req {
     },
     /// Notify peers about the new node. An ad-hoc peer discovery solution.
     NewNode { kind: NodeKind, peer: Peer },
-
-    // TODO: Separate this from the basic network messages.
-    /// A request to upload a cluster.
-    UploadCluster { index: u64, id: ClusterId, data: Vec<Val> },
-
-    DownloadCluster { index: u64 },
 }
 
 // TODO: Same naming for variants in Req and Res.
@@ -67,77 +99,63 @@ enum Res {
     InitNodeFailure {
         error: String,
     },
-    UploadClusterSuccess,
-    UploadClusterFailure,
     NewNodeAcknowledged,
-    DownloadClusterSuccess(Vec<u8>),
-    DownloadClusterFailure,
 }
 
 pub async fn start_network(
     config: Config,
+    local_key: identity::Keypair,
     state: Arc<AppState>,
     mut command_receiver: mpsc::Receiver<Command>,
 ) -> Result<()> {
-    let local_key = match config.node_kind {
-        NodeKind::Validator => load_or_generate_keypair("data/validator-keypair"),
-        NodeKind::Storage { id } => load_or_generate_keypair(&format!("data/node{}-keypair", id)),
-    }?;
-
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(local_key.clone())
-        .with_tokio()
-        .with_quic()
-        .with_dns()?
-        .with_behaviour(|_key| Behaviour {
-            request_response: request_response::cbor::Behaviour::new(
-                [(
-                    StreamProtocol::new("/zpss/1"),
-                    request_response::ProtocolSupport::Full,
-                )],
-                request_response::Config::default(),
-            ),
-        })?
-        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
+    let stream_behaviour = libp2p_stream::Behaviour::new();
+    let control = stream_behaviour.new_control();
 
-    // TODO: Proper NAT traversal
-    let full_external_addr = format!(
-        "/ip4/{}/udp/{}/quic-v1",
-        config.external_ip, config.p2p_port
-    )
-    .parse::<Multiaddr>()?
-    .with(Protocol::P2p(*swarm.local_peer_id()));
-
-    // dump the address to file
-    match config.node_kind {
-        NodeKind::Validator => std::fs::write("data/validator_addr", full_external_addr.to_string())?,
-        NodeKind::Storage { id } => std::fs::write(format!("data/node{}_addr", id), full_external_addr.to_string())?,
-    }
+    let is_validator = matches!(config.node_kind, NodeKind::Validator);
+    let mut swarm = build_swarm(local_key, is_validator, stream_behaviour)?;
 
     swarm.listen_on(format!("/ip4/0.0.0.0/udp/{}/quic-v1", config.p2p_port).parse()?)?;
-
-    if let Some(multiaddr) = &config.boot_node {
-        tracing::info!("Bootstrapping from {}", multiaddr);
-        let peer = extract_peer_id_from_addr(&multiaddr)?;
+    swarm.listen_on(format!("/ip4/0.0.0.0/tcp/{}", config.p2p_port).parse()?)?;
+
+    let mut reachability = Reachability::default();
+    if let Some(external_ip) = &config.external_ip {
+        let quic_addr: Multiaddr =
+            format!("/ip4/{}/udp/{}/quic-v1", external_ip, config.p2p_port).parse()?;
+        let tcp_addr: Multiaddr = format!("/ip4/{}/tcp/{}", external_ip, config.p2p_port).parse()?;
+        swarm.add_external_address(quic_addr.clone());
+        swarm.add_external_address(tcp_addr);
+
+        let full_external_addr = quic_addr.with(Protocol::P2p(*swarm.local_peer_id()));
+        announce_addr(&mut swarm, &config, &mut reachability, full_external_addr)?;
+    } else if let Some(multiaddr) = &config.boot_node {
+        // The address will be announced once AutoNAT has figured out whether we're reachable.
+        tracing::info!("No external IP set, probing reachability via {}", multiaddr);
+        let peer = extract_peer_id_from_addr(multiaddr)?;
         swarm.add_peer_address(peer, multiaddr.clone());
-        swarm.behaviour_mut().request_response.send_request(
-            &peer,
-            Req::InitNode {
-                kind: config.node_kind.clone(),
-                api_url: config.public_api_url.clone(),
-                external_addr: full_external_addr,
-            },
-        );
+        swarm
+            .behaviour_mut()
+            .autonat
+            .add_server(peer, Some(multiaddr.clone()));
+        swarm.dial(multiaddr.clone())?;
     }
 
-    let cloned_state = state.clone();
-    let state = cloned_state;
+    tokio::spawn({
+        let control = control.clone();
+        let state = state.clone();
+        async move {
+            if let Err(err) = transfer::serve(control, state).await {
+                tracing::error!("Transfer server failed: {}", err);
+            }
+        }
+    });
 
+    let mut relay_retry = tokio::time::interval(RELAY_RETRY_INTERVAL);
     loop {
         // TODO: Check for heavy blockers inside of the loop
         let res: Result<()> = tokio::select! {
-            event = swarm.select_next_some() => process_event(event, &mut swarm, state.clone(), &config).await,
-            command = command_receiver.recv() => process_command(command, &mut swarm, state.clone()).await,
+            event = swarm.select_next_some() => process_event(event, &mut swarm, state.clone(), &config, &mut reachability).await,
+            command = command_receiver.recv() => process_command(command, &control, state.clone()).await,
+            _ = relay_retry.tick() => retry_relay(&mut swarm, &config, &mut reachability),
         };
 
         if let Err(err) = res {
@@ -146,6 +164,67 @@ pub async fn start_network(
     }
 }
 
+/// Builds the swarm of a node. Validators additionally act as circuit relays.
+fn build_swarm(
+    local_key: identity::Keypair,
+    is_validator: bool,
+    stream_behaviour: libp2p_stream::Behaviour,
+) -> Result<Swarm<Behaviour>> {
+    let swarm = libp2p::SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_dns()?
+        .with_relay_client(noise::Config::new, yamux::Config::default)?
+        .with_behaviour(|key, relay_client| {
+            let local_peer_id = key.public().to_peer_id();
+            let relay = is_validator.then(|| {
+                relay::Behaviour::new(
+                    local_peer_id,
+                    relay::Config {
+                        max_circuit_duration: RELAY_MAX_CIRCUIT_DURATION,
+                        max_circuit_bytes: RELAY_MAX_CIRCUIT_BYTES,
+                        ..Default::default()
+                    },
+                )
+            });
+
+            Behaviour {
+                request_response: request_response::cbor::Behaviour::new(
+                    [(
+                        StreamProtocol::new("/zpss/1"),
+                        request_response::ProtocolSupport::Full,
+                    )],
+                    request_response::Config::default(),
+                ),
+                stream: stream_behaviour,
+                identify: identify::Behaviour::new(identify::Config::new(
+                    "/zpss/1".to_string(),
+                    key.public(),
+                )),
+                autonat: autonat::Behaviour::new(
+                    local_peer_id,
+                    autonat::Config {
+                        // Storage nodes are often deployed to private networks (e.g. Kubernetes).
+                        only_global_ips: false,
+                        ..Default::default()
+                    },
+                ),
+                relay: relay.into(),
+                relay_client,
+                dcutr: dcutr::Behaviour::new(local_peer_id),
+            }
+        })?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+
+    Ok(swarm)
+}
+
 fn extract_peer_id_from_addr(addr: &Multiaddr) -> Result<PeerId> {
     addr.iter()
         .find_map(|addr| match addr {
@@ -155,11 +234,11 @@ fn extract_peer_id_from_addr(addr: &Multiaddr) -> Result<PeerId> {
         .ok_or_else(|| Error::msg("No peer ID in bootstrap address"))
 }
 
-fn load_or_generate_keypair(path: &str) -> Result<identity::Keypair> {
+pub fn load_or_generate_keypair(path: &Path) -> Result<identity::Keypair> {
     let keypair = match std::fs::read(path) {
         Ok(data) => identity::Keypair::from_protobuf_encoding(&data)?,
         Err(_) => {
-            std::fs::create_dir_all(std::path::Path::new(path).parent().unwrap())?;
+            std::fs::create_dir_all(path.parent().unwrap())?;
 
             let keypair = identity::Keypair::generate_ed25519();
             std::fs::write(path, keypair.to_protobuf_encoding()?)?;
@@ -170,11 +249,85 @@ fn load_or_generate_keypair(path: &str) -> Result<identity::Keypair> {
     Ok(keypair)
 }
 
+/// Publishes the address under which the node is reachable: dumps it to a file and registers the
+/// node at the boot node. Only the first address is announced.
+fn announce_addr(
+    swarm: &mut Swarm<Behaviour>,
+    config: &Config,
+    reachability: &mut Reachability,
+    addr: Multiaddr,
+) -> Result<()> {
+    if reachability.announced_addr.is_some() {
+        return Ok(());
+    }
+
+    tracing::info!("Announcing address {}", addr);
+
+    // dump the address to file
+    match config.node_kind {
+        NodeKind::Validator => {
+            std::fs::write(config.data_dir.join("validator_addr"), addr.to_string())?
+        }
+        NodeKind::Storage { id } => std::fs::write(
+            config.data_dir.join(format!("node{}_addr", id)),
+            addr.to_string(),
+        )?,
+    }
+
+    if let Some(multiaddr) = &config.boot_node {
+        tracing::info!("Bootstrapping from {}", multiaddr);
+        let peer = extract_peer_id_from_addr(multiaddr)?;
+        swarm.add_peer_address(peer, multiaddr.clone());
+        swarm.behaviour_mut().request_response.send_request(
+            &peer,
+            Req::InitNode {
+                kind: config.node_kind.clone(),
+                api_url: config.public_api_url.clone(),
+                external_addr: addr.clone(),
+            },
+        );
+    }
+
+    reachability.announced_addr = Some(addr);
+    Ok(())
+}
+
+/// Asks the boot node to relay connections to us. The relayed address is announced once the
+/// reservation is accepted.
+fn request_relay(
+    swarm: &mut Swarm<Behaviour>,
+    config: &Config,
+    reachability: &mut Reachability,
+) -> Result<()> {
+    reachability.needs_relay = true;
+    if let Some(boot_node) = &config.boot_node {
+        let listener = swarm.listen_on(boot_node.clone().with(Protocol::P2pCircuit))?;
+        reachability.relay_listener = Some(listener);
+    }
+
+    Ok(())
+}
+
+/// Requests a relay reservation again if the previous one failed or expired.
+fn retry_relay(
+    swarm: &mut Swarm<Behaviour>,
+    config: &Config,
+    reachability: &mut Reachability,
+) -> Result<()> {
+    if !reachability.needs_relay || reachability.relay_listener.is_some() {
+        return Ok(());
+    }
+
+    tracing::info!("Retrying relay reservation");
+    request_relay(swarm, config, reachability)
+}
+
 async fn process_event(
     event: SwarmEvent<BehaviourEvent>,
     swarm: &mut Swarm<Behaviour>,
     state: Arc<AppState>,
     config: &Config,
+    reachability: &mut Reachability,
 ) -> Result<()> {
     match event {
         SwarmEvent::NewListenAddr { address, .. } => {
@@ -209,6 +362,70 @@ async fn process_event(
         SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
             tracing::debug!("Disconnected from {}: {:?}", peer_id, cause);
         }
+        SwarmEvent::ListenerError { listener_id, error }
+            if reachability.relay_listener == Some(listener_id) =>
+        {
+            tracing::warn!("Relay listener error: {}", error);
+        }
+        SwarmEvent::ListenerClosed {
+            listener_id,
+            reason,
+            ..
+        } if reachability.relay_listener == Some(listener_id) => {
+            // The relay client closes the listener when the reservation is denied or can't be
+            // renewed.
+            tracing::error!(
+                "Relay reservation lost: {:?}, retrying in {:?}",
+                reason,
+                RELAY_RETRY_INTERVAL
+            );
+            reachability.relay_listener = None;
+        }
+        SwarmEvent::ExternalAddrConfirmed { address } => {
+            tracing::info!("External address confirmed: {}", address);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+            ..
+        })) => {
+            tracing::debug!("Identified {} observing us at {}", peer_id, info.observed_addr);
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+            old,
+            new,
+        })) => {
+            tracing::info!("NAT status changed from {:?} to {:?}", old, new);
+            match new {
+                autonat::NatStatus::Public(addr) => {
+                    let addr = addr.with(Protocol::P2p(*swarm.local_peer_id()));
+                    announce_addr(swarm, config, reachability, addr)?;
+                }
+                autonat::NatStatus::Private if !reachability.needs_relay => {
+                    request_relay(swarm, config, reachability)?;
+                }
+                _ => {}
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+        )) => {
+            tracing::info!("Relay reservation accepted by {}", relay_peer_id);
+            if let Some(boot_node) = &config.boot_node {
+                let addr = boot_node
+                    .clone()
+                    .with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(*swarm.local_peer_id()));
+                announce_addr(swarm, config, reachability, addr)?;
+            }
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+            remote_peer_id,
+            result,
+        })) => match result {
+            Ok(_) => tracing::info!("Hole punched to {}", remote_peer_id),
+            Err(err) => tracing::warn!("Hole punching to {} failed: {}", remote_peer_id, err),
+        },
         SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
             request_response::Event::ResponseSent { .. },
         )) => {
@@ -259,6 +476,8 @@ async fn process_event(
                                 )
                             }
                             NodeKind::Validator => {
+                                // A restarted validator may come back with a different address.
+                                validators.retain(|p| p.peer_id != peer);
                                 validators.insert(peer_data.clone());
                                 (
                                     validators
@@ -300,32 +519,6 @@ async fn process_event(
                             );
                         }
                     }
-                    Req::UploadCluster { index, mut data, id } => match &state.node_state {
-                        NodeState::Validator => {
-                            tracing::warn!("Ignoring UploadCluster request in validator mode");
-                            let _ = swarm
-                                .behaviour_mut()
-                                .request_response
-                                .send_response(channel, Res::UploadClusterFailure);
-                        }
-                        NodeState::Storage { storage } => {
-                            tracing::info!("Writing cluster {}", index);
-
-                            // Safety: Vec<Val> can be safely converted to Vec<u8> with length and capacity adjusted.
-                            let data = unsafe {
-                                data[..].align_to::<u8>().1.to_vec()
-                            };
-
-                            storage.write(index as usize, &data).await?;
-
-                            let _ = swarm
-                                .behaviour_mut()
-                                .request_response
-                                .send_response(channel, Res::UploadClusterSuccess);
-
-                            state.cluster_id_cache.write().await.insert(id, index as usize);
-                        }
-                    },
                     Req::NewNode { kind, peer } => {
                         swarm.add_peer_address(peer.peer_id.clone(), peer.addr.clone());
                         match kind {
@@ -335,7 +528,9 @@ async fn process_event(
                             }
                             NodeKind::Validator => {
                                 tracing::info!("New validator connected");
-                                state.validators.write().await.insert(peer);
+                                let mut validators = state.validators.write().await;
+                                validators.retain(|p| p.peer_id != peer.peer_id);
+                                validators.insert(peer);
                             }
                         }
                         let _ = swarm
@@ -343,25 +538,6 @@ async fn process_event(
                             .request_response
                             .send_response(channel, Res::NewNodeAcknowledged);
                     }
-                    Req::DownloadCluster { index } => {
-                        tracing::debug!("Downloading cluster {}", index);
-                        match &state.node_state {
-                            NodeState::Validator => {
-                                tracing::warn!("Ignoring DownloadCluster request in validator mode");
-                                let _ = swarm
-                                    .behaviour_mut()
-                                    .request_response
-                                    .send_response(channel, Res::DownloadClusterFailure);
-                            }
-                            NodeState::Storage { storage } => {
-                                let data = storage.read(1, index as usize).await?;
-                                let _ = swarm
-                                    .behaviour_mut()
-                                    .request_response
-                                    .send_response(channel, Res::DownloadClusterSuccess(data));
-                            }
-                        }
-                    }
                 }
             }
             request_response::Message::Response { response, .. } => {
@@ -407,13 +583,6 @@ async fn process_event(
                     Res::NewNodeAcknowledged => {
                         tracing::debug!("New node acknowledged by {}", peer);
                     }
-                    Res::UploadClusterSuccess => {
-                        tracing::debug!("Cluster uploaded successfully");
-                    }
-                    Res::UploadClusterFailure => {
-                        tracing::error!("Cluster upload failed");
-                    }
-                    _ => {}
                 }
             }
         },
@@ -426,25 +595,244 @@ async fn process_event(
 
 async fn process_command(
     command: Option<Command>,
-    swarm: &mut Swarm<Behaviour>,
+    control: &Control,
     state: Arc<AppState>,
 ) -> Result<()> {
     match command {
-        Some(Command::UploadCluster { index, id, shards }) => {
-            let peers = state.peers.read().await;
-
-            for (shard_index, shard) in shards.into_iter().enumerate() {
-                let peer = peers
-                    .get(&(shard_index as u32))
-                    .ok_or_else(|| Error::msg("Peer not found"))?;
-                swarm
-                    .behaviour_mut()
-                    .request_response
-                    .send_request(&peer.peer_id, Req::UploadCluster { index, id: id.clone(), data: shard });
+        Some(Command::UploadCluster {
+            index,
+            id,
+            shards,
+            placement,
+            reply,
+        }) => {
+            if placement.len() != shards.len() {
+                let _ = reply.send(Err(eyre!("Placement does not match the number of shards")));
+                return Ok(());
+            }
+
+            // Nothing is uploaded unless every shard has a known destination.
+            let peer_ids = {
+                let peers = state.peers.read().await;
+                placement
+                    .iter()
+                    .map(|node_id| peers.get(node_id).map(|peer| peer.peer_id))
+                    .collect::<Vec<_>>()
+            };
+            let unknown = (0..placement.len())
+                .filter(|&shard_index| peer_ids[shard_index].is_none())
+                .collect::<Vec<_>>();
+            if !unknown.is_empty() {
+                let _ = reply.send(Err(eyre!(
+                    "Nodes storing shards {:?} of cluster {} are unknown",
+                    unknown,
+                    index
+                )));
+                return Ok(());
             }
 
+            let uploads = shards
+                .into_iter()
+                .zip(peer_ids.into_iter().flatten())
+                .enumerate()
+                .map(|(shard_index, (shard, peer_id))| {
+                    let mut control = control.clone();
+                    let id = id.clone();
+                    async move {
+                        // Safety: Vec<Val> can be safely reinterpreted as a byte slice.
+                        let data = unsafe { shard[..].align_to::<u8>().1 };
+
+                        let res =
+                            transfer::upload_shard(&mut control, peer_id, index, id, data).await;
+                        match &res {
+                            Ok(()) => tracing::debug!(
+                                "Shard {} of cluster {} uploaded",
+                                shard_index,
+                                index
+                            ),
+                            Err(err) => tracing::error!(
+                                "Upload of shard {} of cluster {} failed: {}",
+                                shard_index,
+                                index,
+                                err
+                            ),
+                        }
+                        (shard_index, res)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            tokio::spawn(async move {
+                let failed = join_all(uploads)
+                    .await
+                    .into_iter()
+                    .filter(|(_, res)| res.is_err())
+                    .map(|(shard_index, _)| shard_index)
+                    .collect::<Vec<_>>();
+
+                let res = if failed.is_empty() {
+                    Ok(())
+                } else {
+                    Err(eyre!(
+                        "Upload of shards {:?} of cluster {} failed",
+                        failed,
+                        index
+                    ))
+                };
+                let _ = reply.send(res);
+            });
+
+            Ok(())
+        }
+        Some(Command::DownloadShard {
+            peer_id,
+            index,
+            len,
+            reply,
+        }) => {
+            let mut control = control.clone();
+            tokio::spawn(async move {
+                let res = transfer::download_shard(&mut control, peer_id, index, len).await;
+                let _ = reply.send(res);
+            });
+
             Ok(())
         }
-        _ => Ok(()),
+        Some(Command::UploadShard {
+            peer_id,
+            index,
+            id,
+            data,
+            reply,
+        }) => {
+            let mut control = control.clone();
+            tokio::spawn(async move {
+                let res = transfer::upload_shard(&mut control, peer_id, index, id, &data).await;
+                let _ = reply.send(res);
+            });
+
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn test_swarm(is_validator: bool) -> Swarm<Behaviour> {
+        let key = identity::Keypair::generate_ed25519();
+        build_swarm(key, is_validator, libp2p_stream::Behaviour::new()).unwrap()
+    }
+
+    /// Listens on a local TCP port and returns the full address of the node.
+    async fn listen(swarm: &mut Swarm<Behaviour>) -> Multiaddr {
+        swarm
+            .listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+        let address = wait_for(swarm, |event| match event {
+            SwarmEvent::NewListenAddr { address, .. } => Some(address),
+            _ => None,
+        })
+        .await;
+
+        address.with(Protocol::P2p(*swarm.local_peer_id()))
+    }
+
+    /// Drives the swarm until `f` picks one of its events.
+    async fn wait_for<T>(
+        swarm: &mut Swarm<Behaviour>,
+        mut f: impl FnMut(SwarmEvent<BehaviourEvent>) -> Option<T>,
+    ) -> T {
+        let events = async {
+            loop {
+                if let Some(res) = f(swarm.select_next_some().await) {
+                    return res;
+                }
+            }
+        };
+
+        tokio::time::timeout(EVENT_TIMEOUT, events)
+            .await
+            .expect("Timed out waiting for a swarm event")
+    }
+
+    fn spawn(mut swarm: Swarm<Behaviour>) {
+        tokio::spawn(async move {
+            loop {
+                swarm.select_next_some().await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_autonat_finds_reachable_node() {
+        let mut validator = test_swarm(true);
+        let validator_addr = listen(&mut validator).await;
+        spawn(validator);
+
+        let mut node = test_swarm(false);
+        listen(&mut node).await;
+        let validator_id = extract_peer_id_from_addr(&validator_addr).unwrap();
+        node.behaviour_mut()
+            .autonat
+            .add_server(validator_id, Some(validator_addr.clone()));
+        node.dial(validator_addr).unwrap();
+
+        wait_for(&mut node, |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                new: autonat::NatStatus::Public(_),
+                ..
+            })) => Some(()),
+            _ => None,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_relayed_connection_is_upgraded() {
+        let mut validator = test_swarm(true);
+        let validator_addr = listen(&mut validator).await;
+        spawn(validator);
+
+        // The node behind the relay still has a direct address for DCUtR to punch through to.
+        let mut private = test_swarm(false);
+        let private_id = *private.local_peer_id();
+        let private_addr = listen(&mut private).await;
+        private.add_external_address(private_addr);
+        private
+            .listen_on(validator_addr.clone().with(Protocol::P2pCircuit))
+            .unwrap();
+        wait_for(&mut private, |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { .. },
+            )) => Some(()),
+            _ => None,
+        })
+        .await;
+        spawn(private);
+
+        let mut dialer = test_swarm(false);
+        let dialer_addr = listen(&mut dialer).await;
+        dialer.add_external_address(dialer_addr);
+        dialer
+            .dial(
+                validator_addr
+                    .with(Protocol::P2pCircuit)
+                    .with(Protocol::P2p(private_id)),
+            )
+            .unwrap();
+
+        wait_for(&mut dialer, |event| match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            })) if remote_peer_id == private_id => Some(result.unwrap()),
+            _ => None,
+        })
+        .await;
     }
 }
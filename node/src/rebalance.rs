@@ -0,0 +1,312 @@
+//! Shard rebalancing.
+//!
+//! When storage nodes join or leave, the leading validator computes the moves that balance the
+//! shards over the registered nodes (see [`common::placement::plan_moves`]) and migrates them one by
+//! one: the shard is downloaded from its current node (or recovered from other shards if that node
+//! is gone or serves a corrupted copy), uploaded to the new node, and only once the new node has
+//! persisted it the placement is updated in the contract and the old node releases its copy. The
+//! pending moves are persisted, so an interrupted rebalance resumes after a restart.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use common::{
+    contract::{Cluster, ClusterId},
+    node::NodeClient,
+    placement::plan_moves,
+};
+use libp2p::PeerId;
+use primitives::Val;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::{
+    coordination, repair,
+    state::{AppState, Command, NodeId, NodeState},
+};
+
+/// File of the pending moves in the data directory of the node.
+const PLAN_FILE: &str = "rebalance.json";
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingMove {
+    pub cluster_id: ClusterId,
+    pub index: u64,
+    pub shard: usize,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RebalanceStatus {
+    pub running: bool,
+    pub completed: usize,
+    pub failed: usize,
+    /// Moves that still have to be done, including failed ones which are retried on the next run.
+    pub pending: Vec<PendingMove>,
+}
+
+/// Rebalances whenever the set of registered storage nodes changes, and resumes interrupted runs.
+pub async fn run(state: Arc<AppState>) {
+    let mut known_nodes = None;
+
+    loop {
+        if coordination::is_leader(&state).await {
+            match state.contract_client.get_nodes().await {
+                Ok(nodes) => {
+                    let nodes = nodes.into_iter().map(|node| node.node_id).collect::<BTreeSet<_>>();
+                    // A persisted plan is resumed after a restart, but is outdated once the node
+                    // set changes.
+                    let replan = known_nodes.as_ref().is_some_and(|known| known != &nodes);
+
+                    if known_nodes.as_ref() != Some(&nodes) || plan_path(&state).exists() {
+                        match rebalance(state.clone(), replan).await {
+                            Ok(()) => known_nodes = Some(nodes),
+                            Err(err) => tracing::error!("Rebalance failed: {}", err),
+                        }
+                    }
+                }
+                Err(err) => tracing::error!("Failed to get storage nodes: {}", err),
+            }
+        }
+
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+
+/// Runs a rebalance, continuing the persisted one unless `replan` is set.
+pub async fn rebalance(state: Arc<AppState>, replan: bool) -> Result<()> {
+    if !matches!(state.node_state, NodeState::Validator) {
+        bail!("Rebalancing is only available on validators");
+    }
+
+    {
+        let mut status = state.rebalance_status.write().await;
+        if status.running {
+            bail!("Rebalance is already running");
+        }
+        *status = RebalanceStatus {
+            running: true,
+            ..Default::default()
+        };
+    }
+
+    let res = run_moves(&state, replan).await;
+    state.rebalance_status.write().await.running = false;
+    res
+}
+
+async fn run_moves(state: &AppState, replan: bool) -> Result<()> {
+    if replan {
+        save_plan(&plan_path(state), &[]).await?;
+    }
+
+    if let Some(moves) = load_plan(&plan_path(state)).await? {
+        tracing::info!("Resuming rebalance, {} moves pending", moves.len());
+        if !execute(state, moves).await? {
+            return Ok(());
+        }
+    }
+
+    // The placements in the contract already include all completed moves, so this only plans what
+    // is left to do.
+    let moves = plan(state).await?;
+    execute(state, moves).await?;
+
+    Ok(())
+}
+
+/// Executes the moves, persisting the progress after each one. Returns whether all moves succeeded.
+async fn execute(state: &AppState, moves: Vec<PendingMove>) -> Result<bool> {
+    let plan_path = plan_path(state);
+    save_plan(&plan_path, &moves).await?;
+    state.rebalance_status.write().await.pending = moves.clone();
+
+    let mut pending = Vec::new();
+    for (i, mv) in moves.iter().enumerate() {
+        match migrate(state, mv).await {
+            Ok(()) => {
+                tracing::info!(
+                    "Moved shard {} of cluster {} from node {} to node {}",
+                    mv.shard,
+                    mv.index,
+                    mv.from,
+                    mv.to
+                );
+                state.rebalance_status.write().await.completed += 1;
+            }
+            Err(err) => {
+                tracing::error!("Failed to move shard {} of cluster {}: {}", mv.shard, mv.index, err);
+                state.rebalance_status.write().await.failed += 1;
+                pending.push(mv.clone());
+            }
+        }
+
+        let remaining = pending.iter().chain(&moves[i + 1..]).cloned().collect::<Vec<_>>();
+        save_plan(&plan_path, &remaining).await?;
+        state.rebalance_status.write().await.pending = remaining;
+    }
+
+    Ok(pending.is_empty())
+}
+
+/// Computes the moves needed to balance the current placements over the registered nodes.
+async fn plan(state: &AppState) -> Result<Vec<PendingMove>> {
+    let clusters = state.contract_client.get_clusters().await?;
+    let nodes = state
+        .contract_client
+        .get_nodes()
+        .await?
+        .into_iter()
+        .map(|node| node.node_id)
+        .collect::<BTreeSet<_>>();
+
+    let placements = clusters
+        .iter()
+        .map(|(_, cluster)| cluster.placement.clone())
+        .collect::<Vec<_>>();
+
+    let moves = plan_moves(&placements, &nodes)
+        .into_iter()
+        .map(|mv| {
+            let (cluster_id, cluster) = &clusters[mv.cluster];
+            PendingMove {
+                cluster_id: cluster_id.clone(),
+                index: cluster.index,
+                shard: mv.shard,
+                from: mv.from,
+                to: mv.to,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    tracing::info!("Planned {} shard moves over {} nodes", moves.len(), nodes.len());
+    Ok(moves)
+}
+
+/// Copies the shard to its new node, updates the placement once the copy is persisted and releases
+/// the shard on the old node.
+async fn migrate(state: &AppState, mv: &PendingMove) -> Result<()> {
+    let cluster = state.contract_client.get_cluster(&mv.cluster_id).await?;
+    match cluster.placement.get(mv.shard) {
+        Some(&node) if node == mv.from => {}
+        Some(&node) if node == mv.to => return Ok(()),
+        _ => {
+            tracing::warn!("Placement of cluster {} changed, skipping move", mv.index);
+            return Ok(());
+        }
+    }
+
+    let (source, destination) = {
+        let peers = state.peers.read().await;
+        (
+            peers.get(&mv.from).cloned(),
+            peers.get(&mv.to).map(|peer| peer.peer_id),
+        )
+    };
+    let destination = destination.ok_or_else(|| eyre!("Node {} is unknown", mv.to))?;
+
+    let source_shard = match &source {
+        Some(source) => download_verified_shard(state, &cluster, mv, source.peer_id).await,
+        None => None,
+    };
+    let shard: Vec<Val> = match source_shard {
+        Some(shard) => shard,
+        None => repair::recover_shard(state, &cluster, mv.shard).await?,
+    };
+
+    // Safety: Vec<Val> can be safely reinterpreted as a byte slice.
+    let data = unsafe { shard[..].align_to::<u8>().1 }.to_vec();
+
+    let (reply, receiver) = oneshot::channel();
+    state
+        .command_sender
+        .send(Command::UploadShard {
+            peer_id: destination,
+            index: mv.index,
+            id: mv.cluster_id.clone(),
+            data,
+            reply,
+        })
+        .await
+        .map_err(|_| eyre!("Network is not running"))?;
+    receiver.await??;
+
+    state
+        .contract_client
+        .move_shard(&mv.cluster_id, mv.shard, mv.to)
+        .await?;
+
+    // The old node refuses to release the shard before the contract has moved it.
+    if let Some(source) = source {
+        let client = NodeClient::new(&source.api_url, state.http_client.clone());
+        if let Err(err) = client.release_cluster(mv.cluster_id.clone()).await {
+            tracing::warn!(
+                "Failed to release shard {} of cluster {} on node {}: {}",
+                mv.shard,
+                mv.index,
+                mv.from,
+                err
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Downloads the shard from its current node and checks it against the cluster commitment.
+/// Returns `None` if the node doesn't serve an intact shard.
+async fn download_verified_shard(
+    state: &AppState,
+    cluster: &Cluster,
+    mv: &PendingMove,
+    peer_id: PeerId,
+) -> Option<Vec<Val>> {
+    let shard = repair::download_shard(state, peer_id, mv.index)
+        .await
+        .inspect_err(|err| tracing::warn!("Failed to download shard from node {}: {}", mv.from, err))
+        .ok()?;
+
+    repair::verify_shard(state, cluster, mv.shard, &shard)
+        .await
+        .inspect_err(|err| tracing::warn!("Shard served by node {} is invalid: {}", mv.from, err))
+        .ok()?;
+
+    Some(shard)
+}
+
+fn plan_path(state: &AppState) -> PathBuf {
+    state.data_dir.join(PLAN_FILE)
+}
+
+async fn load_plan(path: &Path) -> Result<Option<Vec<PendingMove>>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Persists the pending moves, removing the plan once there are none left.
+async fn save_plan(path: &Path, moves: &[PendingMove]) -> Result<()> {
+    if moves.is_empty() {
+        match tokio::fs::remove_file(path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => return Ok(()),
+        }
+    }
+
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(moves)?).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
@@ -0,0 +1,298 @@
+//! Shard repair.
+//!
+//! A storage node that lost its data (or was replaced) re-derives its shards from the shards stored
+//! by other nodes: any `m` shards of a cluster are enough to recover the original data, which is
+//! then extended over the full shards domain again.
+
+use std::{sync::Arc, time::Duration};
+
+use color_eyre::{
+    eyre::{bail, eyre},
+    Result,
+};
+use common::{contract::Cluster, encode::bytes_to_vals};
+use libp2p::{futures::future::join_all, PeerId};
+use p3_matrix::dense::RowMajorMatrix;
+use primitives::{Hash, ProtocolParams, Val};
+use serde::Serialize;
+use shards::{compute_commitment, recover_original_data, recover_original_data_matrix};
+use tokio::sync::oneshot;
+
+use crate::{
+    state::{AppState, Command, NodeId, NodeState},
+    storage::ShardStorage,
+};
+
+const PEER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait for `m` peers on startup before trying to repair with the peers known so far.
+const STARTUP_PEERS_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RepairStatus {
+    pub running: bool,
+    /// Number of clusters known to the contract at the start of the repair.
+    pub total: usize,
+    pub repaired: usize,
+    /// Indexes of clusters that could not be repaired.
+    pub failed: Vec<u64>,
+}
+
+/// Waits until enough peers are known and repairs all local shards.
+pub async fn repair_on_startup(state: Arc<AppState>) {
+    let wait_for_peers = async {
+        while state.peers.read().await.len() < state.params.m {
+            tokio::time::sleep(PEER_POLL_INTERVAL).await;
+        }
+    };
+    if tokio::time::timeout(STARTUP_PEERS_TIMEOUT, wait_for_peers)
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Only {} of {} peers known after {:?}, repairing anyway",
+            state.peers.read().await.len(),
+            state.params.m,
+            STARTUP_PEERS_TIMEOUT
+        );
+    }
+
+    tracing::info!("Local storage is empty, repairing shards from peers");
+    if let Err(err) = repair(state).await {
+        tracing::error!("Repair failed: {}", err);
+    }
+}
+
+/// Re-derives this node's shards of all clusters registered in the contract.
+pub async fn repair(state: Arc<AppState>) -> Result<()> {
+    let NodeState::Storage { id, storage } = &state.node_state else {
+        bail!("Repair is only available on storage nodes");
+    };
+
+    {
+        let mut status = state.repair_status.write().await;
+        if status.running {
+            bail!("Repair is already running");
+        }
+        *status = RepairStatus {
+            running: true,
+            ..Default::default()
+        };
+    }
+
+    let res = repair_clusters(&state, *id, storage).await;
+    state.repair_status.write().await.running = false;
+    res
+}
+
+async fn repair_clusters(state: &AppState, node_id: NodeId, storage: &ShardStorage) -> Result<()> {
+    let clusters = state
+        .contract_client
+        .get_clusters()
+        .await?
+        .into_iter()
+        .filter(|(_, cluster)| cluster.shard_of(node_id).is_some())
+        .collect::<Vec<_>>();
+    state.repair_status.write().await.total = clusters.len();
+
+    for (cluster_id, cluster) in clusters {
+        match repair_cluster(state, node_id, storage, &cluster).await {
+            Ok(()) => {
+                tracing::info!("Repaired cluster {} ({})", cluster.index, cluster_id);
+                state.repair_status.write().await.repaired += 1;
+                state
+                    .cluster_id_cache
+                    .write()
+                    .await
+                    .insert(cluster_id, cluster.index as usize);
+            }
+            Err(err) => {
+                tracing::error!("Failed to repair cluster {} ({}): {}", cluster.index, cluster_id, err);
+                state.repair_status.write().await.failed.push(cluster.index);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Recovers the cluster from `m` shards stored by other nodes and writes the local shard.
+pub async fn repair_cluster(
+    state: &AppState,
+    node_id: NodeId,
+    storage: &ShardStorage,
+    cluster: &Cluster,
+) -> Result<()> {
+    let shard_index = cluster
+        .shard_of(node_id)
+        .ok_or_else(|| eyre!("Node {} stores no shard of cluster {}", node_id, cluster.index))?;
+    let shard = recover_shard(state, cluster, shard_index).await?;
+
+    // Safety: Vec<Val> can be safely reinterpreted as a byte slice.
+    let data = unsafe { shard[..].align_to::<u8>().1 };
+    storage.write(cluster.index as usize, data).await?;
+
+    Ok(())
+}
+
+/// Recomputes a shard of the cluster from `m` other shards, verifying the result against the
+/// cluster commitment.
+pub async fn recover_shard(state: &AppState, cluster: &Cluster, shard_index: usize) -> Result<Vec<Val>> {
+    let params = state.params;
+    let shards = download_shards(state, cluster, shard_index, params.m).await?;
+
+    let commit = cluster.commit;
+
+    tokio::task::spawn_blocking(move || recover_from_shards(&params, commit, shards, shard_index))
+        .await?
+}
+
+/// Checks a shard served by a single node: the cluster is recovered from it and `m - 1` shards of
+/// other nodes, which only matches the cluster commitment if the shard is intact.
+pub async fn verify_shard(
+    state: &AppState,
+    cluster: &Cluster,
+    shard_index: usize,
+    shard: &[Val],
+) -> Result<()> {
+    let params = state.params;
+    let mut shards = download_shards(state, cluster, shard_index, params.m - 1).await?;
+    shards.push((shard_index, shard.to_vec()));
+    let commit = cluster.commit;
+
+    let recovered =
+        tokio::task::spawn_blocking(move || recover_from_shards(&params, commit, shards, shard_index))
+            .await??;
+    if recovered != shard {
+        bail!("Shard {} does not match the cluster commitment", shard_index);
+    }
+
+    Ok(())
+}
+
+/// Recovers the cluster from `m` `(shard index, shard)` pairs and extends it to the shard at
+/// `shard_index`, checking the recovered data against `commit`.
+fn recover_from_shards(
+    params: &ProtocolParams,
+    commit: Hash,
+    shards: Vec<(usize, Vec<Val>)>,
+    shard_index: usize,
+) -> Result<Vec<Val>> {
+    let indexes = shards.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    let shards_matrix = RowMajorMatrix::new(
+        shards.into_iter().flat_map(|(_, shard)| shard).collect(),
+        params.n,
+    );
+
+    let recover_matrix = recover_original_data_matrix(
+        params.m.ilog2() as usize,
+        &indexes,
+        params.log_blowup_factor(),
+    );
+    let data = recover_original_data(shards_matrix, &recover_matrix);
+
+    let (recovered_commit, mut shards) = compute_commitment(data, params);
+    if recovered_commit.pcs_commitment_hash != commit {
+        bail!("Recovered data does not match the cluster commitment");
+    }
+
+    Ok(shards.swap_remove(shard_index))
+}
+
+/// Downloads `count` shards of the cluster other than `own_shard` from the nodes storing them,
+/// skipping unavailable ones.
+async fn download_shards(
+    state: &AppState,
+    cluster: &Cluster,
+    own_shard: usize,
+    count: usize,
+) -> Result<Vec<(usize, Vec<Val>)>> {
+    let index = cluster.index;
+    let candidates = {
+        let peers = state.peers.read().await;
+        cluster
+            .placement
+            .iter()
+            .enumerate()
+            .filter(|(shard_index, _)| *shard_index != own_shard)
+            .filter_map(|(shard_index, node)| Some((shard_index, peers.get(node)?.peer_id)))
+            .collect::<Vec<_>>()
+    };
+
+    let mut shards = Vec::with_capacity(count);
+    let mut candidates = candidates.into_iter();
+    while shards.len() < count {
+        let batch = candidates.by_ref().take(count - shards.len()).collect::<Vec<_>>();
+        if batch.is_empty() {
+            bail!("Not enough shards available: got {}, need {}", shards.len(), count);
+        }
+
+        let results = join_all(batch.into_iter().map(|(shard_index, peer_id)| async move {
+            (shard_index, download_shard(state, peer_id, index).await)
+        }))
+        .await;
+
+        for (shard_index, res) in results {
+            match res {
+                Ok(shard) => shards.push((shard_index, shard)),
+                Err(err) => tracing::warn!("Failed to download shard {} of cluster {}: {}", shard_index, index, err),
+            }
+        }
+    }
+
+    Ok(shards)
+}
+
+pub async fn download_shard(state: &AppState, peer_id: PeerId, index: u64) -> Result<Vec<Val>> {
+    let (reply, receiver) = oneshot::channel();
+    let len = (state.params.shard_size() * size_of::<Val>()) as u64;
+    state
+        .command_sender
+        .send(Command::DownloadShard {
+            peer_id,
+            index,
+            len,
+            reply,
+        })
+        .await
+        .map_err(|_| eyre!("Network is not running"))?;
+
+    let data = receiver.await??;
+
+    Ok(bytes_to_vals(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Shards of a test cluster and its commitment.
+    fn test_cluster(params: &ProtocolParams) -> (Hash, Vec<Vec<Val>>) {
+        let data = (0..params.cluster_size())
+            .map(|i| Val::new(i as u32 * 7 + 1))
+            .collect();
+        let (commit, shards) = compute_commitment(RowMajorMatrix::new(data, params.m), params);
+        (commit.pcs_commitment_hash, shards)
+    }
+
+    #[test]
+    fn test_repair_missing_shard() {
+        let params = ProtocolParams::test();
+        let (commit, shards) = test_cluster(&params);
+        let missing = 5;
+
+        let available = shards
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(index, _)| *index != missing)
+            .rev()
+            .take(params.m)
+            .collect::<Vec<_>>();
+        let shard = recover_from_shards(&params, commit, available.clone(), missing).unwrap();
+        assert_eq!(shard, shards[missing]);
+
+        let mut tampered = available;
+        tampered[0].1[0] += Val::new(1);
+        assert!(recover_from_shards(&params, commit, tampered, missing).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+//! Background integrity scrubbing.
+//!
+//! Periodically re-reads every stored shard and compares its Poseidon2 hash with the one recorded
+//! at write time. Mismatches are logged, counted in [`ScrubStats`] and, if enabled, repaired from
+//! peers.
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use common::encode::bytes_to_vals;
+use primitives::poseidon2_hash_slice;
+use serde::Serialize;
+use snapshot_db::error::DbError;
+use tokio::sync::RwLock;
+
+use crate::{
+    repair,
+    state::{AppState, NodeId, NodeState},
+    storage::ShardStorage,
+};
+
+/// Pause between two shards, so that scrubbing doesn't starve the API of disk bandwidth.
+const SHARD_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub interval: Duration,
+    /// Repair corrupted shards from peers.
+    pub repair: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScrubStats {
+    /// Number of completed scrub passes.
+    pub runs: u64,
+    /// Unix timestamp of the last completed pass.
+    pub last_run: Option<u64>,
+    pub shards_checked: u64,
+    pub mismatches: u64,
+    pub read_errors: u64,
+    pub repaired: u64,
+    /// Indexes of clusters whose shard is currently known to be corrupted.
+    pub corrupted: Vec<u64>,
+}
+
+pub async fn run(state: Arc<AppState>, config: Config) {
+    loop {
+        tokio::time::sleep(config.interval).await;
+
+        if let Err(err) = scrub(&state, &config).await {
+            tracing::error!("Scrub failed: {}", err);
+        }
+    }
+}
+
+/// Checks all stored shards once.
+async fn scrub(state: &AppState, config: &Config) -> Result<()> {
+    let NodeState::Storage { id, storage } = &state.node_state else {
+        return Ok(());
+    };
+
+    tracing::info!("Starting scrub");
+    let repair = |cluster_index| repair_shard(state, *id, storage, cluster_index);
+    let corrupted = scrub_storage(
+        storage,
+        &state.scrub_stats,
+        config.repair.then_some(repair),
+        SHARD_DELAY,
+    )
+    .await;
+
+    let mut stats = state.scrub_stats.write().await;
+    stats.runs += 1;
+    stats.last_run = Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    stats.corrupted = corrupted;
+    tracing::info!("Scrub finished, {} corrupted shards", stats.corrupted.len());
+
+    Ok(())
+}
+
+/// Checks every shard with a recorded hash and repairs the corrupted ones with `repair`, if given.
+/// Returns the indexes of the clusters whose shard is still corrupted.
+async fn scrub_storage<F, Fut>(
+    storage: &ShardStorage,
+    stats: &RwLock<ScrubStats>,
+    repair: Option<F>,
+    shard_delay: Duration,
+) -> Vec<u64>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut corrupted = Vec::new();
+
+    for cluster_index in storage.hashed_clusters().await {
+        // Writes change the shard and its hash together, so they can't be mistaken for corruption.
+        let Ok(lock) = storage.lock(cluster_index).await else {
+            continue;
+        };
+        let Some(expected_hash) = storage.expected_hash(cluster_index).await else {
+            continue;
+        };
+
+        let ok = match storage.read(cluster_index).await {
+            Ok(data) => {
+                let valid = poseidon2_hash_slice(bytes_to_vals(&data)) == expected_hash;
+                if !valid {
+                    tracing::error!("Shard of cluster {} is corrupted", cluster_index);
+                    stats.write().await.mismatches += 1;
+                }
+                valid
+            }
+            Err(err) if is_checksum_mismatch(&err) => {
+                tracing::error!("Shard of cluster {} is corrupted: {}", cluster_index, err);
+                stats.write().await.mismatches += 1;
+                false
+            }
+            Err(err) => {
+                tracing::error!("Failed to read shard of cluster {}: {}", cluster_index, err);
+                stats.write().await.read_errors += 1;
+                false
+            }
+        };
+
+        stats.write().await.shards_checked += 1;
+        drop(lock);
+
+        if !ok {
+            match &repair {
+                Some(repair) => match repair(cluster_index as u64).await {
+                    Ok(()) => {
+                        tracing::info!("Repaired shard of cluster {}", cluster_index);
+                        stats.write().await.repaired += 1;
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            "Failed to repair shard of cluster {}: {}",
+                            cluster_index,
+                            err
+                        );
+                        corrupted.push(cluster_index as u64);
+                    }
+                },
+                None => corrupted.push(cluster_index as u64),
+            }
+        }
+
+        tokio::time::sleep(shard_delay).await;
+    }
+
+    corrupted
+}
+
+async fn repair_shard(
+    state: &AppState,
+    node_id: NodeId,
+    storage: &ShardStorage,
+    cluster_index: u64,
+) -> Result<()> {
+    let cluster = state
+        .contract_client
+        .get_clusters()
+        .await?
+        .into_iter()
+        .map(|(_, cluster)| cluster)
+        .find(|cluster| cluster.index == cluster_index)
+        .ok_or_else(|| eyre!("Cluster {} is not registered", cluster_index))?;
+
+    repair::repair_cluster(state, node_id, storage, &cluster).await
+}
+
+/// Whether the read failed because the database detected a checksum mismatch.
+fn is_checksum_mismatch(err: &color_eyre::Report) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .and_then(DbError::from_io)
+        .is_some_and(|err| matches!(err, DbError::ChecksumMismatch { .. }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Ready, path::Path, sync::Mutex};
+
+    use common::config::ProtocolParams;
+    use snapshot_db::namespaces::SnapshotStoreConfig;
+
+    use super::*;
+    use crate::storage;
+
+    const NO_REPAIR: Option<fn(u64) -> Ready<Result<()>>> = None;
+
+    async fn test_storage(path: &Path) -> ShardStorage {
+        let config = SnapshotStoreConfig {
+            namespaces: storage::namespaces(&ProtocolParams::test()),
+            metadata_backend: Default::default(),
+            io_backend: Default::default(),
+            encryption_key: None,
+            mmap_reads: false,
+        };
+        ShardStorage::new(path, config, 0, ProtocolParams::test())
+            .await
+            .unwrap()
+    }
+
+    fn shard(seed: u32) -> Vec<u8> {
+        (0..ProtocolParams::test().shard_size() as u32)
+            .flat_map(|i| (i * 31 + seed).to_le_bytes())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_corrupted_shard_is_detected_and_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = test_storage(dir.path()).await;
+        storage.write(0, &shard(0)).await.unwrap();
+        storage.write(1, &shard(1)).await.unwrap();
+        storage.write_unrecorded(1, &shard(2)).await.unwrap();
+
+        let stats = RwLock::new(ScrubStats::default());
+        let corrupted = scrub_storage(&storage, &stats, NO_REPAIR, Duration::ZERO).await;
+        assert_eq!(corrupted, vec![1]);
+        assert_eq!(stats.read().await.mismatches, 1);
+
+        let repaired = Mutex::new(Vec::new());
+        let repair = |cluster_index: u64| {
+            let (storage, repaired) = (&storage, &repaired);
+            async move {
+                repaired.lock().unwrap().push(cluster_index);
+                storage
+                    .write(cluster_index as usize, &shard(cluster_index as u32))
+                    .await
+            }
+        };
+        let corrupted = scrub_storage(&storage, &stats, Some(repair), Duration::ZERO).await;
+        assert!(corrupted.is_empty());
+        assert_eq!(*repaired.lock().unwrap(), vec![1]);
+        assert_eq!(storage.read(1).await.unwrap(), shard(1));
+
+        // The repaired shard passes the next scrub
+        let corrupted = scrub_storage(&storage, &stats, NO_REPAIR, Duration::ZERO).await;
+        assert!(corrupted.is_empty());
+
+        let stats = stats.read().await;
+        assert_eq!(stats.shards_checked, 6);
+        assert_eq!(stats.mismatches, 2);
+        assert_eq!(stats.repaired, 1);
+    }
+}
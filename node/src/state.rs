@@ -1,14 +1,23 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
-use common::{config::StorageConfig, contract::MockContractClient};
+use color_eyre::Result;
+use common::{config::ProtocolParams, contract::MockContractClient};
 use libp2p::{Multiaddr, PeerId};
 use m31jubjub::m31::{Fq, Fs};
 use primitives::Val;
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use snapshot_db::db::SnapshotDb;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot, RwLock};
 use common::contract::ClusterId;
 
+use crate::{
+    mining::MiningStats, rebalance::RebalanceStatus, repair::RepairStatus, scrubber::ScrubStats,
+    storage::ShardStorage,
+};
+
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Peer {
     pub peer_id: PeerId,
@@ -16,11 +25,36 @@ pub struct Peer {
     pub api_url: String,
 }
 
-pub type NodeId = u32;
+pub use common::contract::NodeId;
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub enum Command {
-    UploadCluster { index: u64, id: ClusterId, shards: Vec<Vec<Val>> },
+    /// Send shard `i` of the cluster to the node at `placement[i]`. Replies once all nodes have
+    /// persisted their shard, or with the shards that could not be uploaded.
+    UploadCluster {
+        index: u64,
+        id: ClusterId,
+        shards: Vec<Vec<Val>>,
+        placement: Vec<NodeId>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    /// Download the shard of the cluster with the given index stored by `peer_id`.
+    DownloadShard {
+        peer_id: PeerId,
+        index: u64,
+        /// Size of the shard in bytes, a peer announcing another size is rejected.
+        len: u64,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    /// Store a shard of the cluster with the given index on `peer_id`. Replies once the peer has
+    /// persisted it.
+    UploadShard {
+        peer_id: PeerId,
+        index: u64,
+        id: ClusterId,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<()>>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,7 +65,7 @@ pub enum NodeKind {
 
 pub enum NodeState {
     Validator,
-    Storage { storage: SnapshotDb },
+    Storage { id: NodeId, storage: ShardStorage },
 }
 
 pub struct AppState {
@@ -40,34 +74,52 @@ pub struct AppState {
     // node with some ad-hoc replication is acceptable for now.
     pub peers: RwLock<HashMap<NodeId, Peer>>,
     pub validators: RwLock<HashSet<Peer>>,
+    pub local_peer_id: PeerId,
     pub node_state: NodeState,
     pub sk: Fs,
     pub pk: Fq,
-    pub storage_config: StorageConfig,
+    pub params: ProtocolParams,
     pub command_sender: mpsc::Sender<Command>,
     pub contract_client: MockContractClient,
+    pub http_client: Client,
+    /// Directory of the keys, addresses and persisted tasks of the node.
+    pub data_dir: PathBuf,
     pub cluster_id_cache: RwLock<HashMap<ClusterId, usize>>,
+    pub repair_status: RwLock<RepairStatus>,
+    pub rebalance_status: RwLock<RebalanceStatus>,
+    pub scrub_stats: RwLock<ScrubStats>,
+    pub mining_stats: RwLock<MiningStats>,
 }
 
 impl AppState {
     pub fn new(
         sk: Fs,
         pk: Fq,
-        storage_config: StorageConfig,
+        params: ProtocolParams,
         command_sender: mpsc::Sender<Command>,
+        local_peer_id: PeerId,
         node_state: NodeState,
         contract_client: MockContractClient,
+        http_client: Client,
+        data_dir: PathBuf,
     ) -> Self {
         Self {
             peers: Default::default(),
             validators: Default::default(),
+            local_peer_id,
             node_state,
             sk,
             pk,
-            storage_config,
+            params,
             command_sender,
             contract_client,
+            http_client,
+            data_dir,
             cluster_id_cache: Default::default(),
+            repair_status: Default::default(),
+            rebalance_status: Default::default(),
+            scrub_stats: Default::default(),
+            mining_stats: Default::default(),
         }
     }
 }
@@ -0,0 +1,356 @@
+//! Shard storage of a storage node: a [`SnapshotStore`] with namespaces for shards, sealed shards and
+//! proofs, plus the Poseidon2 hash of every written shard, which is used by the
+//! [scrubber](crate::scrubber) to detect silent data corruption.
+//!
+//! Every written shard is sealed with the node's sealing fragment of its cluster, see
+//! [`sealing::sealing_fragment`], and the sealed shard is what the [miner](crate::mining) mines.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::OpenOptions,
+    io::{Read, Write},
+    path::Path,
+};
+
+use bytes::Bytes;
+use color_eyre::{eyre::eyre, Result};
+use common::{config::ProtocolParams, contract::NodeId, encode::bytes_to_vals};
+use p3_field::PrimeField32;
+use primitives::{poseidon2_hash_slice, Hash, Val};
+use sealing::{fragment_of_cluster, sealing_fragment};
+use snapshot_db::{
+    db::{CompactionReport, SnapshotDb},
+    namespaces::{NamespaceConfig, SnapshotStore, SnapshotStoreConfig},
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::{Mutex, MutexGuard, RwLock},
+};
+
+/// Shards assigned to the node
+pub const SHARDS: &str = "shards";
+/// Sealed shards, which are mined on
+pub const SEALED: &str = "sealed";
+/// Storage proofs, one per cluster, zero padded to [`PROOF_SLOT_SIZE`]
+pub const PROOFS: &str = "proofs";
+
+const PROOF_SLOT_SIZE: usize = 64 * 1024;
+const CHECKSUM_BLOCK_SIZE: usize = 64 * 1024;
+
+const HASHES_FILE: &str = "shard_hashes.log";
+/// Cluster index followed by 8 hash elements.
+const HASH_RECORD_SIZE: usize = 8 + 8 * 4;
+/// Set in the cluster index of a record that drops the hash of a deleted shard.
+const TOMBSTONE: u64 = 1 << 63;
+
+/// Namespaces of the shard storage for the storage parameters
+pub fn namespaces(params: &ProtocolParams) -> Vec<NamespaceConfig> {
+    let shard_size = params.shard_size() * size_of::<Val>();
+    let namespace = |name: &str, cluster_size: usize| NamespaceConfig {
+        name: name.to_string(),
+        num_clusters: params.num_clusters(),
+        cluster_size,
+        checksum_block_size: Some(CHECKSUM_BLOCK_SIZE.min(cluster_size)),
+    };
+
+    vec![
+        namespace(SHARDS, shard_size),
+        namespace(SEALED, shard_size),
+        namespace(PROOFS, PROOF_SLOT_SIZE),
+    ]
+}
+
+pub struct ShardStorage {
+    store: SnapshotStore,
+    hashes: RwLock<HashMap<usize, Hash>>,
+    /// Append-only log of `(cluster index, shard hash)` records, the latest record wins. It is
+    /// rewritten with the live records only on startup.
+    hashes_log: Mutex<File>,
+    /// Held while a shard and its hash change or are checked against each other.
+    cluster_locks: Vec<Mutex<()>>,
+    /// Node the shards are sealed for.
+    node_id: NodeId,
+    params: ProtocolParams,
+}
+
+impl ShardStorage {
+    pub async fn new(
+        path: impl AsRef<Path>,
+        config: SnapshotStoreConfig,
+        node_id: NodeId,
+        params: ProtocolParams,
+    ) -> Result<Self> {
+        let store = SnapshotStore::new(&path, config).await?;
+        let num_clusters = store.namespace(SHARDS)?.config().num_clusters;
+
+        let log_path = path.as_ref().join(HASHES_FILE);
+        let (hashes_log, hashes) =
+            tokio::task::spawn_blocking(move || load_hashes(&log_path)).await??;
+
+        Ok(Self {
+            store,
+            hashes: RwLock::new(hashes),
+            hashes_log: Mutex::new(File::from_std(hashes_log)),
+            cluster_locks: (0..num_clusters).map(|_| Mutex::new(())).collect(),
+            node_id,
+            params,
+        })
+    }
+
+    /// Locks the shard of the cluster, so that its data and its recorded hash stay consistent
+    /// while the guard is held.
+    pub async fn lock(&self, cluster_id: usize) -> Result<MutexGuard<'_, ()>> {
+        let lock = self
+            .cluster_locks
+            .get(cluster_id)
+            .ok_or_else(|| eyre!("Cluster {} is out of range", cluster_id))?;
+        Ok(lock.lock().await)
+    }
+
+    /// Writes a shard and its sealed copy, and records its hash.
+    ///
+    /// The hash is recorded before the data, so that a crash in between leaves a shard which fails
+    /// its check and is repaired, rather than a shard which is never checked.
+    pub async fn write(&self, cluster_id: usize, data: &[u8]) -> Result<()> {
+        let hash = poseidon2_hash_slice(bytes_to_vals(data));
+        let sealed = self.seal(cluster_id, data).await?;
+        let _lock = self.lock(cluster_id).await?;
+
+        self.append_hash_record(&hash_record(cluster_id, hash))
+            .await?;
+        self.hashes.write().await.insert(cluster_id, hash);
+
+        self.store
+            .namespace(SHARDS)?
+            .write(cluster_id, data)
+            .await?;
+        self.store
+            .namespace(SEALED)?
+            .write(cluster_id, &sealed)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Seals a shard with the sealing cluster of its cluster, see [`spora::rlc`].
+    async fn seal(&self, cluster_id: usize, data: &[u8]) -> Result<Vec<u8>> {
+        let params = self.params;
+        if data.len() != params.shard_size() * size_of::<Val>() {
+            return Err(eyre!(
+                "Shard of cluster {} is {} bytes long",
+                cluster_id,
+                data.len()
+            ));
+        }
+
+        let node_id = self.node_id as usize;
+        let shard = bytes_to_vals(data);
+        let sealed = tokio::task::spawn_blocking(move || {
+            let (fragment_index, cluster_in_fragment_index) =
+                fragment_of_cluster(&params, cluster_id);
+            let fragment = sealing_fragment(&params, node_id, fragment_index);
+            let (_, sealed) = spora::rlc(
+                &params,
+                &shard,
+                &fragment.clusters[cluster_in_fragment_index],
+                fragment.root,
+            );
+            sealed
+        })
+        .await?;
+
+        Ok(sealed
+            .into_iter()
+            .flat_map(|value| value.as_canonical_u32().to_le_bytes())
+            .collect())
+    }
+
+    /// Deletes a shard that moved to another node, along with its sealed copy and proof.
+    pub async fn delete(&self, cluster_id: usize) -> Result<()> {
+        let _lock = self.lock(cluster_id).await?;
+        let mut record = vec![0; HASH_RECORD_SIZE];
+        record[..8].copy_from_slice(&(cluster_id as u64 | TOMBSTONE).to_le_bytes());
+        self.append_hash_record(&record).await?;
+        self.hashes.write().await.remove(&cluster_id);
+
+        for namespace in [SHARDS, SEALED, PROOFS] {
+            self.store.namespace(namespace)?.delete(cluster_id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn append_hash_record(&self, record: &[u8]) -> Result<()> {
+        let mut log = self.hashes_log.lock().await;
+        log.write_all(record).await?;
+        log.sync_data().await?;
+        Ok(())
+    }
+
+    /// Overwrites a shard without recording its hash, like silent corruption would.
+    #[cfg(test)]
+    pub async fn write_unrecorded(&self, cluster_id: usize, data: &[u8]) -> Result<()> {
+        Ok(self
+            .store
+            .namespace(SHARDS)?
+            .write(cluster_id, data)
+            .await?)
+    }
+
+    /// Reads the latest version of a shard, from the pending snapshot.
+    pub async fn read(&self, cluster_id: usize) -> Result<Vec<u8>> {
+        Ok(self
+            .store
+            .namespace(SHARDS)?
+            .pending()
+            .await
+            .read(cluster_id)
+            .await?)
+    }
+
+    /// Reads the latest version of a shard without copying it, for sending it over the network.
+    pub async fn read_bytes(&self, cluster_id: usize) -> Result<Bytes> {
+        let shards = self.store.namespace(SHARDS)?;
+        Ok(shards.pending().await.read_bytes(cluster_id).await?)
+    }
+
+    /// Sealed shards, which are sampled by the [miner](crate::mining).
+    pub fn sealed(&self) -> Result<&SnapshotDb> {
+        Ok(self.store.namespace(SEALED)?)
+    }
+
+    /// Reclaims unused space of the storage files, see [`SnapshotStore::compact`].
+    pub async fn compact(&self, dry_run: bool) -> Result<BTreeMap<String, CompactionReport>> {
+        Ok(self.store.compact(dry_run).await?)
+    }
+
+    /// Returns the hash recorded when the shard was written.
+    pub async fn expected_hash(&self, cluster_id: usize) -> Option<Hash> {
+        self.hashes.read().await.get(&cluster_id).copied()
+    }
+
+    /// Returns indexes of all clusters with a recorded hash, in ascending order.
+    pub async fn hashed_clusters(&self) -> Vec<usize> {
+        let mut clusters = self.hashes.read().await.keys().copied().collect::<Vec<_>>();
+        clusters.sort_unstable();
+        clusters
+    }
+}
+
+fn hash_record(cluster_id: usize, hash: Hash) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HASH_RECORD_SIZE);
+    record.extend_from_slice(&(cluster_id as u64).to_le_bytes());
+    let hash_values: [Val; 8] = hash.into();
+    for value in hash_values {
+        record.extend_from_slice(&value.as_canonical_u32().to_le_bytes());
+    }
+    record
+}
+
+/// Opens the hashes log and replays its records. The log is rewritten with one record per live
+/// hash if it holds any other record, so that it doesn't grow with every write.
+fn load_hashes(path: &Path) -> Result<(std::fs::File, HashMap<usize, Hash>)> {
+    let mut buf = Vec::new();
+    if path.exists() {
+        std::fs::File::open(path)?.read_to_end(&mut buf)?;
+    }
+
+    // A torn record left by a crash is dropped with the rewrite.
+    let valid_len = buf.len() / HASH_RECORD_SIZE * HASH_RECORD_SIZE;
+    let hashes = replay_hashes(&buf[..valid_len]);
+
+    if buf.len() != hashes.len() * HASH_RECORD_SIZE {
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp = std::fs::File::create(&tmp_path)?;
+        let mut clusters = hashes.keys().copied().collect::<Vec<_>>();
+        clusters.sort_unstable();
+        for cluster_id in clusters {
+            tmp.write_all(&hash_record(cluster_id, hashes[&cluster_id]))?;
+        }
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent() {
+            std::fs::File::open(dir)?.sync_all()?;
+        }
+    }
+
+    let hashes_log = OpenOptions::new().append(true).create(true).open(path)?;
+    Ok((hashes_log, hashes))
+}
+
+fn replay_hashes(records: &[u8]) -> HashMap<usize, Hash> {
+    let mut hashes = HashMap::new();
+    for record in records.chunks_exact(HASH_RECORD_SIZE) {
+        let index = u64::from_le_bytes(record[..8].try_into().unwrap());
+        if index & TOMBSTONE != 0 {
+            hashes.remove(&((index & !TOMBSTONE) as usize));
+            continue;
+        }
+
+        let hash: [Val; 8] = bytes_to_vals(&record[8..]).try_into().unwrap();
+        hashes.insert(index as usize, hash.into());
+    }
+
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use snapshot_db::namespaces::SnapshotStoreConfig;
+
+    use super::*;
+
+    async fn test_storage(path: &Path) -> ShardStorage {
+        let config = SnapshotStoreConfig {
+            namespaces: namespaces(&ProtocolParams::test()),
+            metadata_backend: Default::default(),
+            io_backend: Default::default(),
+            encryption_key: None,
+            mmap_reads: false,
+        };
+        ShardStorage::new(path, config, 0, ProtocolParams::test())
+            .await
+            .unwrap()
+    }
+
+    fn shard(seed: u32) -> Vec<u8> {
+        (0..ProtocolParams::test().shard_size() as u32)
+            .flat_map(|i| (i * 31 + seed).to_le_bytes())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_hashes_log_is_compacted_on_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = test_storage(dir.path()).await;
+        for seed in 0..3 {
+            storage.write(0, &shard(seed)).await.unwrap();
+        }
+        storage.write(1, &shard(1)).await.unwrap();
+        storage.write(2, &shard(2)).await.unwrap();
+        storage.delete(2).await.unwrap();
+        let expected = storage.expected_hash(0).await;
+        drop(storage);
+
+        let log_path = dir.path().join(HASHES_FILE);
+        assert_eq!(
+            std::fs::metadata(&log_path).unwrap().len(),
+            6 * HASH_RECORD_SIZE as u64
+        );
+
+        let storage = test_storage(dir.path()).await;
+        assert_eq!(storage.hashed_clusters().await, vec![0, 1]);
+        assert_eq!(storage.expected_hash(0).await, expected);
+        assert_eq!(
+            std::fs::metadata(&log_path).unwrap().len(),
+            2 * HASH_RECORD_SIZE as u64
+        );
+
+        // New records are appended to the rewritten log
+        storage.write(3, &shard(3)).await.unwrap();
+        drop(storage);
+        let storage = test_storage(dir.path()).await;
+        assert_eq!(storage.hashed_clusters().await, vec![0, 1, 3]);
+    }
+}
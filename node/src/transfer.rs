@@ -0,0 +1,88 @@
+//! libp2p glue for the chunked shard transfer protocol defined in [`common::transfer`].
+
+use std::sync::Arc;
+
+use color_eyre::Result;
+use common::{
+    contract::ClusterId,
+    transfer::{self, IncomingTransfer, TransferRequest},
+};
+use libp2p::{futures::StreamExt, PeerId, Stream, StreamProtocol};
+use libp2p_stream::Control;
+use primitives::Val;
+
+use crate::state::{AppState, NodeState};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new(transfer::PROTOCOL_NAME);
+
+/// Accepts incoming transfer streams and serves them until the swarm shuts down.
+pub async fn serve(mut control: Control, state: Arc<AppState>) -> Result<()> {
+    let mut incoming = control.accept(PROTOCOL)?;
+
+    while let Some((peer, stream)) = incoming.next().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_stream(stream, state).await {
+                tracing::error!("Transfer with {} failed: {}", peer, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn handle_stream(stream: Stream, state: Arc<AppState>) -> Result<()> {
+    let incoming = IncomingTransfer::accept(stream).await?;
+
+    let NodeState::Storage { storage, .. } = &state.node_state else {
+        tracing::warn!("Ignoring transfer request in validator mode");
+        return incoming.reject("Not a storage node").await;
+    };
+
+    match incoming.request().clone() {
+        TransferRequest::Upload { index, id, .. } => {
+            let shard_len = state.params.shard_size() * size_of::<Val>();
+            let upload = incoming.receive(shard_len as u64).await?;
+
+            tracing::info!("Writing cluster {}", index);
+            let res = storage.write(index as usize, &upload.data).await;
+            upload.complete(&res).await?;
+            res?;
+
+            state.cluster_id_cache.write().await.insert(id, index as usize);
+        }
+        TransferRequest::Download { index } => {
+            tracing::debug!("Downloading cluster {}", index);
+            match storage.read(index as usize).await {
+                Ok(data) => incoming.send(&data).await?,
+                Err(err) => incoming.reject(&err.to_string()).await?,
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams a shard to a storage node and waits until it is persisted.
+pub async fn upload_shard(
+    control: &mut Control,
+    peer: PeerId,
+    index: u64,
+    id: ClusterId,
+    data: &[u8],
+) -> Result<()> {
+    let mut stream = control.open_stream(peer, PROTOCOL).await?;
+    transfer::upload(&mut stream, index, id, data).await
+}
+
+/// Downloads the shard of the cluster with the given index stored by `peer`, which is `len`
+/// bytes long.
+pub async fn download_shard(
+    control: &mut Control,
+    peer: PeerId,
+    index: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let mut stream = control.open_stream(peer, PROTOCOL).await?;
+    transfer::download(&mut stream, index, len).await
+}
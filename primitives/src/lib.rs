@@ -5,10 +5,12 @@ extern crate alloc;
 mod consts;
 mod config;
 mod utils;
+mod params;
 
 
 
 pub use consts::*;
 pub use config::*;
 pub use utils::*;
+pub use params::*;
 
@@ -0,0 +1,139 @@
+//! Protocol parameters shared by sharding, sealing and SPoRA
+//!
+//! A cluster of user data is a matrix of `m` rows and `n` columns. Sharding extends it to `q` rows,
+//! and every row is a shard of `n` values stored by one node. Nodes seal their shards with sealing
+//! fragments of `2^log_fragment_size` values and prove that they store a sealed shard with SPoRA, so
+//! the clusters of the SPoRA prover are shards.
+//!
+//! Fragments are grouped into segments and segments into volumes, which only matters for deriving
+//! the sealing seeds.
+
+/// Sizes of the protocol, all counted in field elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolParams {
+    /// columns
+    pub n: usize,
+    /// rows
+    pub m: usize,
+    /// rows after blowup
+    pub q: usize,
+    /// number of clusters
+    pub k: usize,
+    pub log_fragment_size: usize,
+    pub log_segment_size: usize,
+    pub log_volume_size: usize,
+}
+
+impl ProtocolParams {
+    pub const fn prod() -> Self {
+        Self {
+            n: 16384,
+            m: 64,
+            q: 512,
+            k: 33554432,
+            log_fragment_size: 16,
+            log_segment_size: 22,
+            log_volume_size: 31,
+        }
+    }
+
+    pub const fn dev() -> Self {
+        Self {
+            n: 65536,
+            m: 4,
+            q: 16,
+            k: 2097152, // FIXME: incorrect, but doesn't matter for now
+            log_fragment_size: 18,
+            log_segment_size: 24,
+            log_volume_size: 33,
+        }
+    }
+
+    /// Tiny sizes for tests, proofs take milliseconds
+    pub const fn test() -> Self {
+        Self {
+            n: 256,
+            m: 4,
+            q: 16,
+            k: 16,
+            log_fragment_size: 10,
+            log_segment_size: 12,
+            log_volume_size: 14,
+        }
+    }
+
+    /// Checks the relations between the sizes which the protocol relies on
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !self.n.is_power_of_two() || !self.m.is_power_of_two() || !self.q.is_power_of_two() {
+            return Err("n, m and q must be powers of two");
+        }
+        if self.q <= self.m {
+            return Err("q must be larger than m");
+        }
+        if self.n < 4 {
+            return Err("Shards must hold at least one quadruple of values");
+        }
+        if self.log_fragment_size < self.log_shard_size()
+            || self.log_segment_size < self.log_fragment_size
+            || self.log_volume_size < self.log_segment_size
+        {
+            return Err("Shards, fragments, segments and volumes must be nested");
+        }
+        Ok(())
+    }
+
+    pub fn num_clusters(&self) -> usize {
+        self.q
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.n * self.m
+    }
+
+    pub fn cluster_capacity_bytes(&self) -> usize {
+        self.n * self.m * 30 / 8
+    }
+
+    pub fn log_blowup_factor(&self) -> usize {
+        (self.q / self.m).ilog2() as usize
+    }
+
+    pub fn shard_size(&self) -> usize {
+        self.n
+    }
+
+    pub fn log_shard_size(&self) -> usize {
+        self.n.ilog2() as usize
+    }
+
+    pub fn fragment_size(&self) -> usize {
+        1 << self.log_fragment_size
+    }
+
+    pub fn shards_per_fragment(&self) -> usize {
+        1 << (self.log_fragment_size - self.log_shard_size())
+    }
+
+    pub fn fragments_per_segment(&self) -> usize {
+        1 << (self.log_segment_size - self.log_fragment_size)
+    }
+
+    pub fn segments_per_volume(&self) -> usize {
+        1 << (self.log_volume_size - self.log_segment_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_are_valid() {
+        for params in [ProtocolParams::prod(), ProtocolParams::dev(), ProtocolParams::test()] {
+            assert_eq!(params.validate(), Ok(()), "{:?}", params);
+        }
+
+        let params = ProtocolParams { log_fragment_size: 4, ..ProtocolParams::test() };
+        assert!(params.validate().is_err());
+    }
+}
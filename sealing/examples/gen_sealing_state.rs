@@ -1,4 +1,4 @@
-use primitives::{LOG_SEGMENT_SIZE, LOG_VOLUME_SIZE, LOG_FRAGMENT_SIZE, NUM_NODES, POSEIDON2_HASH, Hash};
+use primitives::{ProtocolParams, POSEIDON2_HASH, Hash};
 use itertools::{iproduct, Itertools};
 use p3_maybe_rayon::prelude::*;
 use p3_field::Field;
@@ -69,12 +69,15 @@ where
 }
 
 fn main() {
-    const NUM_SEGMENTS_IN_VOLUME: usize = 1 << (LOG_VOLUME_SIZE - LOG_SEGMENT_SIZE);
-    const NUM_FRAGMENTS_IN_SEGMENT: usize = 1 << (LOG_SEGMENT_SIZE - LOG_FRAGMENT_SIZE);
     const CHUNK_SIZE: usize = 128;
 
+    let params = ProtocolParams::dev();
+    let num_nodes = params.q;
+    let num_segments_in_volume = params.segments_per_volume();
+    let num_fragments_in_segment = params.fragments_per_segment();
+
     // Calculate total number of iterations for progress bar
-    let total_iterations = NUM_NODES * NUM_VOLUMES * NUM_SEGMENTS_IN_VOLUME * NUM_FRAGMENTS_IN_SEGMENT;
+    let total_iterations = num_nodes * NUM_VOLUMES * num_segments_in_volume * num_fragments_in_segment;
     
     // Initialize progress bar
     let pb = ProgressBar::new(total_iterations as u64);
@@ -92,10 +95,10 @@ fn main() {
         .expect("Failed to create hashes.bin");
 
     let chunks = iproduct!(
-        0..NUM_NODES, 
+        0..num_nodes, 
         0..NUM_VOLUMES, 
-        0..NUM_SEGMENTS_IN_VOLUME, 
-        0..NUM_FRAGMENTS_IN_SEGMENT
+        0..num_segments_in_volume, 
+        0..num_fragments_in_segment
     )
     .chunks(CHUNK_SIZE);
 
@@ -107,7 +110,7 @@ fn main() {
         let hashes: Vec<Hash> = chunk.into_par_iter()
             .map(|(node_id, volume_id, segment_id, fragment_id)| {
                 let seed = get_fragment_seed(node_id, volume_id, segment_id, fragment_id);
-                let data = sealing_vec(seed);
+                let data = sealing_vec(&params, seed);
                 let root = compute_merkle_root(&*POSEIDON2_HASH, &data);
                 root.into()
             })
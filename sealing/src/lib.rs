@@ -5,18 +5,23 @@ extern crate alloc;
 
 
 use p3_circle::{CircleDomain,CircleEvaluations};
+use p3_commit::Mmcs;
 use p3_matrix::{dense::RowMajorMatrix, Matrix};
 use itertools::Itertools;
 use alloc::vec::Vec;
 
 
-use primitives::{POSEIDON2_PERM, M31StreamCipher, Val, LOG_FRAGMENT_SIZE, FRAGMENT_SIZE, Hash, poseidon2_hash_slice};
+use primitives::{POSEIDON2_PERM, POSEIDON2_MMCS, M31StreamCipher, Val, ProtocolParams, Hash, QuadVal, array_to_quadval, quadval_to_array, poseidon2_hash_slice};
 
 
-pub fn sealing_vec(seed: Hash) -> Vec<Val> {
+/// Sealing fragment of `params.fragment_size()` values
+///
+/// Panics if `params` are invalid, see [`ProtocolParams::validate`].
+pub fn sealing_vec(params: &ProtocolParams, seed: Hash) -> Vec<Val> {
+    params.validate().expect("Invalid protocol parameters");
     let stream = M31StreamCipher::new(POSEIDON2_PERM.clone());
-    let data = stream.cipher(seed.as_ref()).take(FRAGMENT_SIZE as usize).collect_vec();
-    let data_domain = CircleDomain::<Val>::standard(LOG_FRAGMENT_SIZE);
+    let data = stream.cipher(seed.as_ref()).take(params.fragment_size()).collect_vec();
+    let data_domain = CircleDomain::<Val>::standard(params.log_fragment_size);
     let data_coeffs = RowMajorMatrix::new(data, 1);
     let data_evals = CircleEvaluations::evaluate(data_domain, data_coeffs).to_natural_order().to_row_major_matrix();
     data_evals.values
@@ -27,3 +32,45 @@ pub fn get_fragment_seed(node_id:usize, volume_id:usize, segment_id:usize, fragm
     poseidon2_hash_slice(preimage)
 }
 
+
+/// Sealing fragment split into the sealing clusters of its shards, with the Merkle commitments the
+/// SPoRA prover opens
+pub struct SealingFragment {
+    /// `params.shards_per_fragment()` sealing clusters of `params.shard_size() / 4` quadruples
+    pub clusters: Vec<Vec<QuadVal>>,
+    /// Merkle root of every sealing cluster, as a matrix of quadruples
+    pub cluster_hashes: Vec<Hash>,
+    /// Merkle root of the cluster hashes
+    pub root: Hash,
+}
+
+/// Fragment of the node's sealing that seals the shard stored at `cluster_index`, and the index of
+/// its sealing cluster in the fragment
+pub fn fragment_of_cluster(params: &ProtocolParams, cluster_index: usize) -> (usize, usize) {
+    (cluster_index / params.shards_per_fragment(), cluster_index % params.shards_per_fragment())
+}
+
+/// Sealing fragment number `fragment_index` of a node, counted across segments and volumes
+pub fn sealing_fragment(params: &ProtocolParams, node_id: usize, fragment_index: usize) -> SealingFragment {
+    let segment_index = fragment_index / params.fragments_per_segment();
+    let seed = get_fragment_seed(
+        node_id,
+        segment_index / params.segments_per_volume(),
+        segment_index % params.segments_per_volume(),
+        fragment_index % params.fragments_per_segment(),
+    );
+
+    let clusters = sealing_vec(params, seed)
+        .chunks_exact(params.shard_size())
+        .map(|cluster| cluster.chunks_exact(4).map(|quad| array_to_quadval(quad.try_into().unwrap())).collect_vec())
+        .collect_vec();
+    let cluster_hashes = clusters.iter().map(|cluster| {
+        let values = cluster.iter().copied().flat_map(quadval_to_array).collect_vec();
+        let (hash, _) = POSEIDON2_MMCS.commit_matrix(RowMajorMatrix::new(values, 4));
+        hash
+    }).collect_vec();
+    let fragment_matrix = RowMajorMatrix::new(cluster_hashes.iter().copied().flatten().collect(), 8);
+    let (root, _) = POSEIDON2_MMCS.commit_matrix(fragment_matrix);
+
+    SealingFragment { clusters, cluster_hashes, root }
+}
@@ -91,8 +91,13 @@ pub fn compute_subdomain_indexes(index: usize, log_blowup_factor: usize, log_dim
 ///
 /// # Arguments
 ///
-/// * `data_matrix` - The input data matrix.
-/// * `log_blowup_factor` - The logarithm of the blowup factor.
+/// * `data_matrix` - The input data matrix of `params.n` rows and `params.m` columns.
+/// * `params` - The protocol parameters, defining the number of shards.
+///
+/// # Panics
+///
+/// Panics if `params` are invalid, see [`ProtocolParams::validate`], or if the data matrix doesn't
+/// have the dimensions of `params`.
 ///
 /// # Returns
 ///
@@ -100,8 +105,13 @@ pub fn compute_subdomain_indexes(index: usize, log_blowup_factor: usize, log_dim
 /// - `OptimisticCorrectableCommitment`: The computed commitment.
 /// - `Vec<Vec<Val>>`: The generated shards.
 #[must_use]
-pub fn compute_commitment<M: Matrix<Val>>(data_matrix: M, log_blowup_factor: usize) -> (OptimisticCorrectableCommitment, Vec<Vec<Val>>) {
+pub fn compute_commitment<M: Matrix<Val>>(data_matrix: M, params: &ProtocolParams) -> (OptimisticCorrectableCommitment, Vec<Vec<Val>>) {
+    params.validate().expect("Invalid protocol parameters");
+    assert_eq!(data_matrix.width(), params.m, "Data matrix width should be equal to m");
+    assert_eq!(data_matrix.height(), params.n, "Data matrix height should be equal to n");
+
     let mmcs = POSEIDON2_MMCS.clone();
+    let log_blowup_factor = params.log_blowup_factor();
 
     let data_width = data_matrix.width();
     let data_height = data_matrix.height();
@@ -161,18 +171,21 @@ pub fn compute_commitment<M: Matrix<Val>>(data_matrix: M, log_blowup_factor: usi
 ///
 /// * `shards_matrix` - The matrix of shards where each row corresponds to a shard.
 /// * `subcoset_index` - The index of the subcoset to use for recovery.
-/// * `log_blowup_factor` - The logarithm of the blowup factor.
+/// * `params` - The protocol parameters the shards were computed with.
 ///
 /// # Panics
 ///
-/// Panics if `subcoset_index` is not less than `2^log_blowup_factor`.
+/// Panics if `subcoset_index` is not less than `q / m`, or if there aren't `m` shards of `n` values.
 ///
 /// # Returns
 ///
 /// The recovered data as a row-major matrix.
 #[must_use]
-pub fn recover_original_data_from_subcoset<M: Matrix<Val>>(shards_matrix: M, subcoset_index: usize, log_blowup_factor: usize) -> RowMajorMatrix<Val> {
+pub fn recover_original_data_from_subcoset<M: Matrix<Val>>(shards_matrix: M, subcoset_index: usize, params: &ProtocolParams) -> RowMajorMatrix<Val> {
+    let log_blowup_factor = params.log_blowup_factor();
     assert!(subcoset_index < (1 << log_blowup_factor), "Subcoset index out of bounds");
+    assert_eq!(shards_matrix.height(), params.m, "Recovery needs m shards");
+    assert_eq!(shards_matrix.width(), params.n, "Shards should have n values");
 
     let log_dimension = log2_strict_usize(shards_matrix.height());
     let source_domain = compute_subdomain(subcoset_index, log_blowup_factor, log_dimension);
@@ -263,6 +276,16 @@ mod tests {
     use rand::prelude::*;
     use rand::seq::IteratorRandom;
 
+    /// Test parameters for an arbitrary matrix of `2^log_height` rows and `2^log_dimension` columns
+    fn test_params(log_blowup_factor: usize, log_dimension: usize, log_height: usize) -> ProtocolParams {
+        ProtocolParams {
+            n: 1 << log_height,
+            m: 1 << log_dimension,
+            q: 1 << (log_dimension + log_blowup_factor),
+            ..ProtocolParams::test()
+        }
+    }
+
     /// Tests that points over a subcoset are correctly selected from the target domain.
     #[test]
     fn test_subcoset_points_selection() {
@@ -292,10 +315,12 @@ mod tests {
         let log_dimension = 4;
         let log_height = 2;
 
+        let params = test_params(log_blowup_factor, log_dimension, log_height);
+
         let original_data = RowMajorMatrix::<Val>::rand(&mut rng, 1 << log_height, 1 << log_dimension);
         let subcoset_index = rng.gen_range(0..(1 << log_blowup_factor));
 
-        let (_, shards) = compute_commitment(original_data.clone(), log_blowup_factor);
+        let (_, shards) = compute_commitment(original_data.clone(), &params);
 
         let subcoset_indexes = compute_subdomain_indexes(subcoset_index, log_blowup_factor, log_dimension);
 
@@ -304,7 +329,7 @@ mod tests {
             1 << log_height,
         );
 
-        let recovered_data = recover_original_data_from_subcoset(subcoset_data, subcoset_index, log_blowup_factor);
+        let recovered_data = recover_original_data_from_subcoset(subcoset_data, subcoset_index, &params);
 
         assert_eq!(recovered_data, original_data, "Recovered data does not match the original data");
     }
@@ -318,12 +343,14 @@ mod tests {
         let log_dimension = 4;
         let log_height = 5;
 
+        let params = test_params(log_blowup_factor, log_dimension, log_height);
+
         let original_data = RowMajorMatrix::<Val>::rand(&mut rng, 1 << log_height, 1 << log_dimension);
         
         let shards_indexes = (0..(1<<(log_blowup_factor + log_dimension))).choose_multiple(&mut rng, 1<<log_dimension);
 
 
-        let (_, shards) = compute_commitment(original_data.clone(), log_blowup_factor);
+        let (_, shards) = compute_commitment(original_data.clone(), &params);
 
 
         let shards_data = RowMajorMatrix::new(
@@ -3,11 +3,24 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::task::JoinSet;
 use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
 
 const CLUSTER_SIZE: usize = 1024 * 1024; // 1 MB
 const NUM_CLUSTERS: usize = 1024;
 const WRITE_THREADS: usize = 12;
 const READ_THREADS: usize = 24;
+const READ_ROUNDS: usize = 4;
+const BACKENDS: [IoBackend; 3] = [IoBackend::Blocking, IoBackend::Uring { direct_io: false }, IoBackend::Uring { direct_io: true }];
+
+/// Writes every cluster and commits them, so that the reads of the benchmark hit the disk
+async fn prefill(db: &SnapshotDb, test_data: &[u8]) {
+    for batch_start in (0..NUM_CLUSTERS).step_by(64) {
+        let writes = (batch_start..NUM_CLUSTERS.min(batch_start + 64)).map(|i| (i, test_data)).collect::<Vec<_>>();
+        db.write_batch(&writes).await.unwrap();
+    }
+    db.add_snapshot().await.unwrap();
+}
 
 async fn run_concurrent_read_write_benchmark(db: Arc<SnapshotDb>, test_data: &[u8]) {
     println!("\nStarting concurrent read/write benchmark...");
@@ -35,7 +48,7 @@ async fn run_concurrent_read_write_benchmark(db: Arc<SnapshotDb>, test_data: &[u
             let cloned_db = db.clone();
             let read_index = fastrand::usize(..NUM_CLUSTERS); // Random index from already written
             read_set.spawn(async move {
-                let _data = cloned_db.read(0, read_index).await.unwrap();
+                let _data = cloned_db.read(1, read_index).await.unwrap();
             });
 
             // Limit the number of concurrent read tasks
@@ -72,23 +85,86 @@ async fn run_concurrent_read_write_benchmark(db: Arc<SnapshotDb>, test_data: &[u
     println!("Combined throughput: {:.2} MB/s", write_throughput + read_throughput);
 }
 
-#[tokio::main]
-async fn main() {
-    let path = PathBuf::from("benchmark_rw");
-    
-    // Create directory if it does not exist
-    std::fs::create_dir_all(&path).unwrap();
+/// Reads every committed cluster `READ_ROUNDS` times, copying or from memory mappings, and checks
+/// whether the throughput would saturate a 1 Gbps NIC
+async fn run_read_benchmark(db: Arc<SnapshotDb>, mmap: bool) {
+    let start = Instant::now();
+    let mut read_set = JoinSet::new();
 
-    let config = SnapshotDbConfig {
-        cluster_size: CLUSTER_SIZE,
-        num_clusters: NUM_CLUSTERS,
-    };
+    for i in 0..NUM_CLUSTERS * READ_ROUNDS {
+        let cloned_db = db.clone();
+        read_set.spawn(async move {
+            let cluster_id = i % NUM_CLUSTERS;
+            let data = if mmap {
+                cloned_db.read_bytes(1, cluster_id).await.unwrap()
+            } else {
+                cloned_db.read(1, cluster_id).await.unwrap().into()
+            };
+            // Touch every page, like sending the data would
+            data.iter().step_by(4096).fold(0u8, |acc, byte| acc ^ byte)
+        });
 
-    let db = Arc::new(SnapshotDb::new(&path, config).await.unwrap());
-    
+        if read_set.len() >= READ_THREADS {
+            read_set.join_next().await.unwrap().unwrap();
+        }
+    }
+
+    while let Some(result) = read_set.join_next().await {
+        result.unwrap();
+    }
+
+    let duration = start.elapsed();
+    let read_volume = (NUM_CLUSTERS * READ_ROUNDS * CLUSTER_SIZE) as f64;
+    let read_throughput = read_volume / duration.as_secs_f64() / (1024.0 * 1024.0);
+    let gbps = read_volume * 8.0 / duration.as_secs_f64() / 1e9;
+
+    println!(
+        "{} reads: {:.2} MB/s ({:.2} Gbit/s, {} a 1 Gbps NIC)",
+        if mmap { "Memory-mapped" } else { "Copying" },
+        read_throughput,
+        gbps,
+        if gbps >= 1.0 { "saturates" } else { "does not saturate" },
+    );
+}
+
+#[tokio::main]
+async fn main() {
     // Create test data
     let test_data = vec![42u8; CLUSTER_SIZE];
 
-    // Run benchmark
-    run_concurrent_read_write_benchmark(Arc::clone(&db), &test_data).await;
-}
\ No newline at end of file
+    for io_backend in BACKENDS {
+        println!("\n=== {} I/O backend ===", io_backend);
+
+        // Start from an empty directory for every backend
+        let path = PathBuf::from("benchmark_rw").join(io_backend.to_string());
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+
+        let config = SnapshotDbConfig {
+            cluster_size: CLUSTER_SIZE,
+            num_clusters: NUM_CLUSTERS,
+            checksum_block_size: None,
+            metadata_backend: MetadataBackend::from_env().unwrap(),
+            io_backend,
+            encryption_key: None,
+            mmap_reads: true,
+        };
+
+        let db = match SnapshotDb::new(&path, config).await {
+            Ok(db) => Arc::new(db),
+            Err(err) => {
+                println!("Skipping: {}", err);
+                continue;
+            }
+        };
+
+        prefill(&db, &test_data).await;
+
+        // Run benchmark
+        run_concurrent_read_write_benchmark(Arc::clone(&db), &test_data).await;
+
+        println!("\nRead throughput of snapshot 1, {} threads:", READ_THREADS);
+        run_read_benchmark(Arc::clone(&db), false).await;
+        run_read_benchmark(Arc::clone(&db), true).await;
+    }
+}
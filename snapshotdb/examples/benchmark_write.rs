@@ -3,10 +3,13 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::task::JoinSet;
 use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
 
 const CLUSTER_SIZE: usize = 1024 * 1024; // 1 MB
 const NUM_CLUSTERS: usize = 1024;
 const NUM_THREADS: usize = 12;
+const BATCH_SIZE: usize = 64;
 
 async fn run_single_threaded_benchmark(db: Arc<SnapshotDb>, test_data: &[u8]) {
     println!("Starting single-threaded write test...");
@@ -49,6 +52,22 @@ async fn run_multi_threaded_benchmark(db: Arc<SnapshotDb>, test_data: &[u8]) {
     println!("{}-threaded write: {:.2} MB/s", NUM_THREADS, throughput);
 }
 
+async fn run_batched_benchmark(db: Arc<SnapshotDb>, test_data: &[u8]) {
+    println!("\nStarting batched write test ({} clusters per batch)...", BATCH_SIZE);
+    let start = Instant::now();
+
+    for batch_start in (0..NUM_CLUSTERS).step_by(BATCH_SIZE) {
+        let writes = (batch_start..NUM_CLUSTERS.min(batch_start + BATCH_SIZE))
+            .map(|i| (i, test_data))
+            .collect::<Vec<_>>();
+        db.write_batch(&writes).await.unwrap();
+    }
+
+    let duration = start.elapsed();
+    let throughput = (NUM_CLUSTERS * CLUSTER_SIZE) as f64 / duration.as_secs_f64() / (1024.0 * 1024.0);
+    println!("Batched write: {:.2} MB/s", throughput);
+}
+
 #[tokio::main]
 async fn main() {
     let path = PathBuf::from("benchmark");
@@ -59,6 +78,11 @@ async fn main() {
     let config = SnapshotDbConfig {
         cluster_size: CLUSTER_SIZE,
         num_clusters: NUM_CLUSTERS,
+        checksum_block_size: None,
+        metadata_backend: MetadataBackend::from_env().unwrap(),
+        io_backend: IoBackend::from_env().unwrap(),
+        encryption_key: None,
+        mmap_reads: false,
     };
 
     let db = Arc::new(SnapshotDb::new(&path, config).await.unwrap());
@@ -69,4 +93,5 @@ async fn main() {
     // Run benchmarks
     run_single_threaded_benchmark(Arc::clone(&db), &test_data).await;
     run_multi_threaded_benchmark(Arc::clone(&db), &test_data).await;
+    run_batched_benchmark(Arc::clone(&db), &test_data).await;
 }
\ No newline at end of file
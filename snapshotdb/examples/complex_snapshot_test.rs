@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
 use rand::{Rng, SeedableRng};
 use rand::rngs::StdRng;
 
@@ -32,6 +34,11 @@ async fn main() -> std::io::Result<()> {
     let config = SnapshotDbConfig {
         num_clusters: NUM_CLUSTERS,
         cluster_size: CLUSTER_SIZE,
+        checksum_block_size: None,
+        metadata_backend: MetadataBackend::from_env()?,
+        io_backend: IoBackend::from_env()?,
+        encryption_key: None,
+        mmap_reads: false,
     };
 
     // Clean up any existing test databases
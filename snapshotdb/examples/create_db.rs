@@ -1,5 +1,7 @@
 use std::path::PathBuf;
 use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -7,6 +9,11 @@ async fn main() -> std::io::Result<()> {
     let config = SnapshotDbConfig {
         num_clusters: 1000,    // Number of clusters
         cluster_size: 4096,    // Size of each cluster in bytes
+        checksum_block_size: None,
+        metadata_backend: MetadataBackend::from_env()?,
+        io_backend: IoBackend::from_env()?,
+        encryption_key: None,
+        mmap_reads: false,
     };
 
     // Create database path
@@ -63,31 +63,24 @@ impl Allocator {
     /// # Returns
     /// A new `Allocator` instance
     pub fn from_link_counter(link_counter: Vec<usize>) -> Self {
-        let len = link_counter.len();
         let (tx, rx) = unbounded();
+        let link_counter = fill_free_slots(link_counter, &tx);
 
-        let mut num_free_slots = 0;
-        for (i, &item) in link_counter.iter().enumerate() {
-            if item == 0 {
-                tx.send(i).unwrap();
-                num_free_slots += 1;
-            }
-        }
-
-        let mut link_counter = link_counter.into_iter().map(AtomicUsize::new).collect::<Vec<_>>();
-
-        if num_free_slots < FREE_SLOTS_MIN_RESERVE {
-            for i in len .. len + FREE_SLOTS_MIN_RESERVE {
-                tx.send(i).unwrap();
-            }
-
-            link_counter.resize_with(len + FREE_SLOTS_MIN_RESERVE, || AtomicUsize::new(0));
-        }
-
-        
         Self { link_counter: RwLock::new(link_counter), free_slots: (tx, rx) }
     }
 
+    /// Replaces all reference counts, e.g. after slots were relocated.
+    ///
+    /// Must not run concurrently with other operations of the allocator.
+    ///
+    /// # Arguments
+    /// * `link_counter` - Vector where each element represents a slot's reference count
+    pub async fn reset(&self, link_counter: Vec<usize>) {
+        let mut guard = self.link_counter.write().await;
+        self.free_slots.1.drain();
+        *guard = fill_free_slots(link_counter, &self.free_slots.0);
+    }
+
     /// Allocates a new slot and increments its reference count.
     ///
     /// If the number of free slots falls below `FREE_SLOTS_MIN_RESERVE`,
@@ -111,9 +104,15 @@ impl Allocator {
             });
         }
 
-        let slot = self.free_slots.1.recv_async().await.unwrap();
-        self.inc(slot).await;
-        slot
+        // A slot can be queued more than once, e.g. when it was freed by a pending `dec_many` while
+        // the allocator was reset, so it is only taken if it is still free.
+        loop {
+            let slot = self.free_slots.1.recv_async().await.unwrap();
+            let guard = self.link_counter.read().await;
+            if guard.get(slot).is_some_and(|counter| counter.compare_exchange(0, 1, Ordering::Relaxed, Ordering::Relaxed).is_ok()) {
+                return slot;
+            }
+        }
     }
 
     /// Increments the reference count for a given slot.
@@ -184,3 +183,29 @@ impl Allocator {
         self.len().await == 0
     }
 }
+
+/// Queues the slots without references as free, adding `FREE_SLOTS_MIN_RESERVE` slots if there are
+/// not enough of them.
+fn fill_free_slots(link_counter: Vec<usize>, tx: &Sender<usize>) -> Vec<AtomicUsize> {
+    let len = link_counter.len();
+
+    let mut num_free_slots = 0;
+    for (i, &item) in link_counter.iter().enumerate() {
+        if item == 0 {
+            tx.send(i).unwrap();
+            num_free_slots += 1;
+        }
+    }
+
+    let mut link_counter = link_counter.into_iter().map(AtomicUsize::new).collect::<Vec<_>>();
+
+    if num_free_slots < FREE_SLOTS_MIN_RESERVE {
+        for i in len .. len + FREE_SLOTS_MIN_RESERVE {
+            tx.send(i).unwrap();
+        }
+
+        link_counter.resize_with(len + FREE_SLOTS_MIN_RESERVE, || AtomicUsize::new(0));
+    }
+
+    link_counter
+}
@@ -0,0 +1,157 @@
+//! Full and differential export/import of snapshots
+//!
+//! An archive holds the clusters of one snapshot, either all of them (full export) or only those
+//! written after a base snapshot (differential export, see [`SnapshotDb::changed_clusters`]).
+//! Importing a full archive and then the differential archives in order rebuilds the snapshots in
+//! another database, without copying the whole storage file.
+//!
+//! # Format
+//! - magic `SDBARCH\0` and the format version (u32 LE)
+//! - length (u64 LE) of the bincode-encoded [`ArchiveManifest`], followed by the manifest
+//! - for every cluster of the manifest, in order: cluster id (u64 LE), CRC32 of the data (u32 LE)
+//!   and the cluster data
+
+use std::io::{Error, ErrorKind, Result};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::db::SnapshotDb;
+
+const MAGIC: &[u8; 8] = b"SDBARCH\0";
+const VERSION: u32 = 1;
+/// Number of clusters imported with one `write_batch`
+const IMPORT_BATCH_SIZE: usize = 64;
+
+/// Describes the content of an archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub cluster_size: usize,
+    pub num_clusters: usize,
+    /// Snapshot the archive is relative to, `None` for a full export
+    pub base_snapshot: Option<usize>,
+    /// Exported snapshot
+    pub snapshot: usize,
+    /// Exported clusters, in the order of the records
+    pub clusters: Vec<usize>,
+}
+
+impl SnapshotDb {
+    /// Exports the clusters of `snapshot` which changed after `base_snapshot`, or all clusters if
+    /// it is `None`. The snapshot should be committed, writes to the pending snapshot during the
+    /// export may or may not be included.
+    ///
+    /// # Arguments
+    /// * `base_snapshot` - Snapshot the export is relative to, may already be joined
+    /// * `snapshot` - Snapshot to export
+    /// * `writer` - Destination of the archive
+    ///
+    /// # Returns
+    /// * `Result<ArchiveManifest>` - Manifest of the written archive, IO or database error
+    pub async fn export<W: AsyncWrite + Unpin>(&self, base_snapshot: Option<usize>, snapshot: usize, writer: &mut W) -> Result<ArchiveManifest> {
+        if base_snapshot.is_some_and(|base| base >= snapshot) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Base snapshot must be older than the exported snapshot"));
+        }
+
+        // Keeps the snapshot from being joined during the export
+        let handle = self.get(snapshot).await?;
+
+        let manifest = ArchiveManifest {
+            cluster_size: self.config().cluster_size,
+            num_clusters: self.config().num_clusters,
+            base_snapshot,
+            snapshot,
+            clusters: self.changed_clusters(snapshot, base_snapshot).await?,
+        };
+        let manifest_bytes = bincode::serialize(&manifest).unwrap();
+
+        writer.write_all(MAGIC).await?;
+        writer.write_u32_le(VERSION).await?;
+        writer.write_u64_le(manifest_bytes.len() as u64).await?;
+        writer.write_all(&manifest_bytes).await?;
+
+        for &cluster_id in &manifest.clusters {
+            let data = handle.read(cluster_id).await?;
+            writer.write_u64_le(cluster_id as u64).await?;
+            writer.write_u32_le(crc32fast::hash(&data)).await?;
+            writer.write_all(&data).await?;
+        }
+        writer.flush().await?;
+
+        Ok(manifest)
+    }
+
+    /// Imports an archive into the pending snapshot. Archives must be imported in the order they
+    /// were exported, typically adding a snapshot after each one.
+    ///
+    /// The clusters are written in batches as the archive is read, so if the archive turns out to
+    /// be corrupted, the pending snapshot may contain a part of it.
+    ///
+    /// # Arguments
+    /// * `reader` - Source of the archive
+    ///
+    /// # Returns
+    /// * `Result<ArchiveManifest>` - Manifest of the imported archive, IO or database error
+    pub async fn import<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<ArchiveManifest> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a SnapshotDb archive"));
+        }
+        let version = reader.read_u32_le().await?;
+        if version != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Unsupported archive version {}", version)));
+        }
+
+        // The manifest lists each cluster at most once
+        let manifest_len = reader.read_u64_le().await?;
+        if manifest_len > 64 + 8 * self.config().num_clusters as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, "Archive manifest is too large"));
+        }
+        let mut manifest_bytes = vec![0u8; manifest_len as usize];
+        reader.read_exact(&mut manifest_bytes).await?;
+        let manifest: ArchiveManifest = bincode::deserialize(&manifest_bytes)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+        if manifest.cluster_size != self.config().cluster_size || manifest.num_clusters != self.config().num_clusters {
+            return Err(Error::new(ErrorKind::InvalidInput, "Archive was exported from a database with another layout"));
+        }
+
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for &expected_cluster_id in &manifest.clusters {
+            let cluster_id = reader.read_u64_le().await? as usize;
+            if cluster_id != expected_cluster_id {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Unexpected cluster {} in archive", cluster_id)));
+            }
+
+            let checksum = reader.read_u32_le().await?;
+            let mut data = vec![0u8; manifest.cluster_size];
+            reader.read_exact(&mut data).await?;
+            if crc32fast::hash(&data) != checksum {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Checksum mismatch of cluster {} in archive", cluster_id)));
+            }
+
+            batch.push((cluster_id, data));
+            if batch.len() == IMPORT_BATCH_SIZE {
+                self.import_batch(&mut batch).await?;
+            }
+        }
+        self.import_batch(&mut batch).await?;
+
+        Ok(manifest)
+    }
+
+    /// Writes the clusters of the batch, deleting the all-zero ones so that they take no space
+    async fn import_batch(&self, batch: &mut Vec<(usize, Vec<u8>)>) -> Result<()> {
+        let (zeros, writes): (Vec<_>, Vec<_>) = batch
+            .iter()
+            .map(|(cluster_id, data)| (*cluster_id, &data[..]))
+            .partition(|(_, data)| data.iter().all(|&byte| byte == 0));
+        self.write_batch(&writes).await?;
+        for (cluster_id, _) in zeros {
+            self.delete(cluster_id).await?;
+        }
+        batch.clear();
+        Ok(())
+    }
+}
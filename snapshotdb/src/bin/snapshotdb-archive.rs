@@ -0,0 +1,123 @@
+//! Exports snapshots of a SnapshotDb directory that is not in use to archive files, or imports
+//! archives into the pending snapshot of one.
+//!
+//! Usage:
+//!   snapshotdb-archive export <path> <archive> --cluster-size <bytes> --num-clusters <n> --snapshot <id> [--base <id>] [--backend sled|redb]
+//!   snapshotdb-archive import <path> <archive> --cluster-size <bytes> --num-clusters <n> [--commit] [--backend sled|redb]
+//!
+//! `--base` makes a differential export of the clusters written after the base snapshot, `--commit`
+//! adds a snapshot after the import so that the next archive lands in a new one.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
+
+const USAGE: &str = "Usage:
+  snapshotdb-archive export <path> <archive> --cluster-size <bytes> --num-clusters <n> --snapshot <id> [--base <id>] [--backend sled|redb]
+  snapshotdb-archive import <path> <archive> --cluster-size <bytes> --num-clusters <n> [--commit] [--backend sled|redb]";
+
+enum Command {
+    Export { snapshot: usize, base: Option<usize> },
+    Import { commit: bool },
+}
+
+struct Args {
+    command: Command,
+    path: PathBuf,
+    archive: PathBuf,
+    config: SnapshotDbConfig,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next().ok_or(USAGE)?;
+
+    let mut paths = Vec::new();
+    let mut cluster_size = None;
+    let mut num_clusters = None;
+    let mut snapshot = None;
+    let mut base = None;
+    let mut commit = false;
+    let mut backend = MetadataBackend::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cluster-size" => cluster_size = args.next().and_then(|v| v.parse().ok()),
+            "--num-clusters" => num_clusters = args.next().and_then(|v| v.parse().ok()),
+            "--snapshot" => snapshot = Some(args.next().and_then(|v| v.parse().ok()).ok_or("Invalid --snapshot")?),
+            "--base" => base = Some(args.next().and_then(|v| v.parse().ok()).ok_or("Invalid --base")?),
+            "--commit" => commit = true,
+            "--backend" => backend = args.next().ok_or(USAGE)?.parse().map_err(|err| format!("{}", err))?,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if paths.len() < 2 && !arg.starts_with('-') => paths.push(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let command = match command.as_str() {
+        "export" => Command::Export { snapshot: snapshot.ok_or("Missing --snapshot")?, base },
+        "import" => Command::Import { commit },
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let [path, archive]: [PathBuf; 2] = paths.try_into().map_err(|_| USAGE)?;
+    Ok(Args {
+        command,
+        path,
+        archive,
+        config: SnapshotDbConfig {
+            cluster_size: cluster_size.ok_or("Missing or invalid --cluster-size")?,
+            num_clusters: num_clusters.ok_or("Missing or invalid --num-clusters")?,
+            checksum_block_size: None,
+            metadata_backend: backend,
+            io_backend: IoBackend::default(),
+            encryption_key: None,
+            mmap_reads: false,
+        },
+    })
+}
+
+async fn run(args: Args) -> std::io::Result<()> {
+    let db = SnapshotDb::new(&args.path, args.config).await?;
+
+    match args.command {
+        Command::Export { snapshot, base } => {
+            let mut file = tokio::fs::File::create(&args.archive).await?;
+            let manifest = db.export(base, snapshot, &mut file).await?;
+            file.sync_all().await?;
+            println!("exported {} clusters of snapshot {}", manifest.clusters.len(), snapshot);
+        }
+        Command::Import { commit } => {
+            let mut file = tokio::fs::File::open(&args.archive).await?;
+            let manifest = db.import(&mut file).await?;
+            println!("imported {} clusters of snapshot {}", manifest.clusters.len(), manifest.snapshot);
+            if commit {
+                db.add_snapshot().await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Checks (and optionally repairs) a SnapshotDb directory that is not in use.
+//!
+//! Usage: snapshotdb-fsck <path> --cluster-size <bytes> --num-clusters <n> [--backend sled|redb] [--repair]
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use snapshot_db::db::SnapshotDbConfig;
+use snapshot_db::fsck::{self, FsckReport};
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
+
+const USAGE: &str = "Usage: snapshotdb-fsck <path> --cluster-size <bytes> --num-clusters <n> [--backend sled|redb] [--repair]";
+
+struct Args {
+    path: PathBuf,
+    config: SnapshotDbConfig,
+    repair: bool,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut cluster_size = None;
+    let mut num_clusters = None;
+    let mut backend = MetadataBackend::default();
+    let mut repair = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cluster-size" => cluster_size = args.next().and_then(|v| v.parse().ok()),
+            "--num-clusters" => num_clusters = args.next().and_then(|v| v.parse().ok()),
+            "--backend" => backend = args.next().ok_or(USAGE)?.parse().map_err(|err| format!("{}", err))?,
+            "--repair" => repair = true,
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+
+    Ok(Args {
+        path: path.ok_or(USAGE)?,
+        config: SnapshotDbConfig {
+            cluster_size: cluster_size.ok_or("Missing or invalid --cluster-size")?,
+            num_clusters: num_clusters.ok_or("Missing or invalid --num-clusters")?,
+            checksum_block_size: None,
+            metadata_backend: backend,
+            io_backend: IoBackend::default(),
+            encryption_key: None,
+            mmap_reads: false,
+        },
+        repair,
+    })
+}
+
+fn print_report(report: &FsckReport) {
+    println!("snapshots: {}..={}", report.snapshot_start, report.snapshot_pending);
+    println!("slots: {}", report.num_slots);
+    println!("live entries: {}", report.live_entries);
+
+    for key in &report.stale_entries {
+        println!("stale entry: {:?}", key);
+    }
+    for slot in &report.leaked_checksums {
+        println!("leaked checksum: slot {}", slot);
+    }
+    for (slot, keys) in &report.double_referenced {
+        println!("slot {} referenced by {:?}", slot, keys);
+    }
+    for key in &report.out_of_range {
+        println!("out of range: {:?}", key);
+    }
+    for cluster_id in &report.missing_clusters {
+        println!("missing cluster: {}", cluster_id);
+    }
+    if let Some(snapshot) = report.pending_behind {
+        println!("pending snapshot behind newest entry ({})", snapshot);
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    let res = if args.repair {
+        fsck::repair(&args.path, &args.config)
+    } else {
+        fsck::check(&args.path, &args.config)
+    };
+
+    let report = match res {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("fsck failed: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    print_report(&report);
+    if report.is_clean() {
+        println!("clean");
+        return ExitCode::SUCCESS;
+    }
+
+    if !args.repair {
+        return ExitCode::FAILURE;
+    }
+
+    match fsck::check(&args.path, &args.config) {
+        Ok(report) if report.is_clean() => {
+            println!("repaired");
+            ExitCode::SUCCESS
+        }
+        Ok(report) => {
+            println!("unrepairable issues remain:");
+            print_report(&report);
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("fsck failed: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
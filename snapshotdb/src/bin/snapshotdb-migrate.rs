@@ -0,0 +1,71 @@
+//! Copies the metadata of a SnapshotDb directory that is not in use to another backend.
+//!
+//! Usage: snapshotdb-migrate <path> --from sled|redb --to sled|redb
+//!
+//! The source store is left in place, the database can be opened with the new backend afterwards.
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use snapshot_db::metadata::{self, MetadataBackend};
+
+const USAGE: &str = "Usage: snapshotdb-migrate <path> --from sled|redb --to sled|redb";
+
+struct Args {
+    path: PathBuf,
+    from: MetadataBackend,
+    to: MetadataBackend,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut path = None;
+    let mut from = None;
+    let mut to = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = Some(args.next().ok_or(USAGE)?.parse().map_err(|err| format!("{}", err))?),
+            "--to" => to = Some(args.next().ok_or(USAGE)?.parse().map_err(|err| format!("{}", err))?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if path.is_none() && !arg.starts_with('-') => path = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}\n{}", arg, USAGE)),
+        }
+    }
+
+    let args = Args {
+        path: path.ok_or(USAGE)?,
+        from: from.ok_or("Missing --from")?,
+        to: to.ok_or("Missing --to")?,
+    };
+    if args.from == args.to {
+        return Err("Source and target backend are the same".to_string());
+    }
+
+    Ok(args)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    let res = metadata::open(&args.path, args.from)
+        .and_then(|from| Ok((from, metadata::open(&args.path, args.to)?)))
+        .and_then(|(from, to)| metadata::migrate(from.as_ref(), to.as_ref()));
+
+    match res {
+        Ok(entries) => {
+            println!("copied {} entries from {} to {}", entries, args.from, args.to);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("migration failed: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -9,26 +9,58 @@
 //! - Snapshot management for data versioning
 //! - Concurrent read/write operations using tokio async runtime
 //! - Efficient space allocation and deallocation
-//! - Persistent storage with sled backend
+//! - Persistent metadata in sled or redb
+//! - Blocking or io_uring storage I/O (see [`crate::storage`])
+//! - Optional encryption at rest (see [`crate::encryption`])
+//! - Optional block-level CRC32 checksums, verified on reads
+//! - Optional zero-copy reads of whole clusters from memory mappings (see [`crate::mmap`])
+//! - Online compaction of the storage file
+//! - Snapshot handles which keep their snapshot from being joined
+//! - Cluster deletion; deleted and never written clusters read as zeros and take no space
+//! - Full and differential export/import of snapshots (see [`crate::archive`])
+//! - Namespaces with their own cluster sizes sharing one metadata store and their snapshots (see
+//!   [`crate::namespaces`])
 //!
 //! # Architecture
 //! The system consists of several key components:
 //! - SnapshotDb: Main database structure managing storage and snapshots
 //! - OffsetTable: Manages data location mapping for different snapshots
 //! - Allocator: Handles space allocation and deallocation
-//! - SledWrapper: Provides persistent storage capabilities
+//! - MetadataStore: Persists the offset table and snapshot bounds (sled or redb backend)
 
+use bytes::Bytes;
 use hashbrown::HashMap;
+use serde::Serialize;
 use tokio::sync::{RwLock, Mutex};
+use tokio::task::JoinSet;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
 use std::io::Result;
 use std::path::Path;
-use std::sync::atomic::AtomicUsize;
-use std::fs::{OpenOptions, File};
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::fs::File;
 
 use crate::allocator::{Allocator, FREE_SLOTS_MIN_RESERVE};
-use crate::sledwrapper::{OffsetTableEntry, SledWrapper, SledKey};
-use crate::utils::{custom_sync_range, mutex_vec_values, to_mutex_vec, read_exact_at, write_all_at};
+use crate::encryption::{self, EncryptionKey, Generations};
+use crate::error::DbError;
+use crate::metadata::{self, MetadataBackend, MetadataBatch, MetadataStore};
+use crate::mmap::{self, Leases};
+use crate::sledwrapper::{OffsetTableEntry, SledKey, ZERO_SLOT};
+use crate::snapshot::Snapshot;
+use crate::storage::{IoBackend, Storage};
+use crate::utils::{mutex_vec_values, to_mutex_vec};
+#[cfg(feature = "failpoints")]
+use crate::failpoints::{self, FailPoints};
+
+/// Fails the operation if the failpoint is armed, see [`crate::failpoints`].
+macro_rules! fail_point {
+    ($self:expr, $name:expr) => {
+        #[cfg(feature = "failpoints")]
+        $self.failpoints.check($name)?;
+    };
+}
 
 /// Configuration for the SnapshotDb instance
 #[derive(Debug, Clone, Copy)]
@@ -37,12 +69,25 @@ pub struct SnapshotDbConfig {
     pub num_clusters: usize,
     /// Size of each cluster in bytes
     pub cluster_size: usize,
+    /// Size of the blocks covered by a CRC32 checksum. Checksums are computed on write and verified
+    /// on reads, `None` disables them.
+    pub checksum_block_size: Option<usize>,
+    /// Backend keeping the offset table and the other metadata
+    pub metadata_backend: MetadataBackend,
+    /// Backend reading and writing the storage file
+    pub io_backend: IoBackend,
+    /// Key encrypting the storage file, `None` stores the data in plain. Must stay the same over
+    /// the lifetime of the database.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Serve [`SnapshotDb::read_bytes`] from memory mappings of the storage file instead of
+    /// copying the data. Encrypted data is always copied, as it has to be decrypted.
+    pub mmap_reads: bool,
 }
 
 /// Main database structure managing storage and snapshots
 pub struct SnapshotDb {
-    /// Persistent storage backend
-    db: SledWrapper,
+    /// Persistent metadata store
+    db: Box<dyn MetadataStore>,
     /// Database configuration
     config: SnapshotDbConfig,
     /// Table mapping snapshots to their data locations
@@ -51,8 +96,20 @@ pub struct SnapshotDb {
     allocator: Allocator,
     /// Total number of available slots
     num_slots: AtomicUsize,
-    /// File handle for data storage
-    storage: Arc<File>
+    /// Storage file holding the cluster data
+    storage: Storage,
+    /// Write generations of encrypted slots, `None` without encryption
+    generations: Option<Generations>,
+    /// Held shared by operations modifying the offset table or allocating slots, and exclusively
+    /// by compaction
+    operations: RwLock<()>,
+    /// Number of handles of every snapshot with at least one handle
+    pins: std::sync::Mutex<HashMap<usize, usize>>,
+    /// Slots mapped by [`SnapshotDb::read_bytes`]
+    leases: Arc<Leases>,
+    /// Armed failpoints
+    #[cfg(feature = "failpoints")]
+    failpoints: FailPoints,
 }
 
 /// Manages mapping between snapshots and their data locations
@@ -65,6 +122,32 @@ pub struct OffsetTable {
     inner: HashMap<usize, Vec<Mutex<OffsetTableEntry>>>
 }
 
+impl OffsetTable {
+    /// Entry of a cluster in a snapshot
+    fn entry(&self, snapshot: usize, cluster_id: usize) -> std::result::Result<&Mutex<OffsetTableEntry>, DbError> {
+        self.inner
+            .get(&snapshot)
+            .ok_or(DbError::UnknownSnapshot(snapshot))?
+            .get(cluster_id)
+            .ok_or(DbError::UnknownCluster(cluster_id))
+    }
+}
+
+/// Outcome of [`SnapshotDb::compact`]
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CompactionReport {
+    /// Size of the storage file before the compaction
+    pub file_size: u64,
+    /// Number of slots referenced by the snapshots
+    pub live_slots: usize,
+    /// Number of live slots relocated from the tail of the storage file into free slots
+    pub moved_slots: usize,
+    /// Number of offset table entries and checksums no snapshot references anymore
+    pub stale_keys: usize,
+    /// Bytes freed by truncating the storage file
+    pub reclaimable_bytes: u64,
+}
+
 impl SnapshotDb {
     /// Creates a new SnapshotDb instance with the specified path and configuration
     ///
@@ -75,44 +158,43 @@ impl SnapshotDb {
     /// # Returns
     /// * `Result<Self>` - New SnapshotDb instance or IO error
     pub async fn new(path: impl AsRef<Path>, config: SnapshotDbConfig) -> Result<Self> {
-        let sled_path = path.as_ref().join("sled");
-        let storage_path = path.as_ref().join("storage");
-
-        let db = SledWrapper::new(sled::open(sled_path)?);
-        let storage = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(false)
-            .create(true)
-            .open(storage_path)?;
+        let db = metadata::open(&path, config.metadata_backend)?;
+        Self::with_metadata(db, path.as_ref().join("storage"), config).await
+    }
 
+    /// Opens a database keeping its metadata in `db` and its data in the storage file at
+    /// `storage_path`. The metadata backend of the configuration is not used.
+    pub(crate) async fn with_metadata(db: Box<dyn MetadataStore>, storage_path: impl AsRef<Path>, config: SnapshotDbConfig) -> Result<Self> {
+        let storage = Storage::open(storage_path, config.io_backend, config.cluster_size)?;
 
         if db.is_empty()? {
-            init_db(&db, &storage, &config).await?;
+            init_db(db.as_ref(), storage.file(), &config).await?;
         }
+        encryption::check_key(db.as_ref(), config.encryption_key.as_ref())?;
+        let generations = config.encryption_key.map(|_| Generations::new(db.as_ref())).transpose()?;
 
         let num_slots = db.get_num_slots()?;
-        let offset_table_vec = init_offset_table(&db, &config)?;
+        let offset_table_vec = init_offset_table(db.as_ref(), &config)?;
 
         let mut link_counter = vec![0; num_slots];
 
-        for entry in offset_table_vec.iter().flatten().flatten() {
-            link_counter[entry.offset as usize] += 1;
+        for slot in offset_table_vec.iter().flatten().flatten().filter_map(OffsetTableEntry::slot) {
+            link_counter[slot] += 1;
         }
 
         let allocator = Allocator::from_link_counter(link_counter);
 
+        let snapshot_start = db.get_snapshot_start()?;
+        let snapshot_pending = db.get_snapshot_pending()?;
+
         let mut offset_table = HashMap::new();
 
         for (i, item) in offset_table_vec.iter().enumerate() {
             let slot = item.clone().into_iter().map(|entry| Mutex::new(entry.unwrap())).collect();
-            offset_table.insert(i, slot);
+            offset_table.insert(snapshot_start + i, slot);
         }
 
-        let snapshot_start = db.get_snapshot_start()?;
-        let snapshot_pending = db.get_snapshot_pending()?;
-
-        Ok(Self { db, config, offset_table: RwLock::new(OffsetTable { snapshot_start, snapshot_pending, inner: offset_table }), allocator, num_slots: AtomicUsize::new(num_slots), storage: Arc::new(storage) })
+        Ok(Self { db, config, offset_table: RwLock::new(OffsetTable { snapshot_start, snapshot_pending, inner: offset_table }), allocator, num_slots: AtomicUsize::new(num_slots), storage, generations, operations: RwLock::new(()), pins: Default::default(), leases: Default::default(), #[cfg(feature = "failpoints")] failpoints: FailPoints::default() })
         
     }
 
@@ -128,14 +210,29 @@ impl SnapshotDb {
         if data.len() != self.config.cluster_size {
             return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Data size does not match cluster size"));
         }
-        
+        if cluster_id >= self.config.num_clusters {
+            return Err(DbError::UnknownCluster(cluster_id).into());
+        }
+
+        let _operation = self.operations.read().await;
+        self.release_leases().await;
         let slot = self.allocator.pop().await;
 
+        if let Some(block_size) = self.config.checksum_block_size {
+            self.db.set_checksums(slot, &block_checksums(data, block_size))?;
+        }
+        let (data, generation) = self.seal(slot, data)?;
+        if let Some(generation) = generation {
+            self.db.set_generation(slot, generation)?;
+        }
+
         let raw_offset = slot as u64 * self.config.cluster_size as u64;
     
-        write_all_at(self.storage.clone(), data, raw_offset).await?;
+        self.storage.write_all_at(&data, raw_offset).await?;
+        fail_point!(self, failpoints::WRITE_AFTER_DATA);
 
-        custom_sync_range(self.storage.clone(), raw_offset, data.len() as u64).await?;
+        self.storage.sync_range(raw_offset, data.len() as u64).await?;
+        fail_point!(self, failpoints::WRITE_AFTER_SYNC);
 
         let (snapshot_pending, dec_offset) = {
             let offset_table = self.offset_table.read().await;
@@ -150,7 +247,8 @@ impl SnapshotDb {
             let mut entry = snapshot.get(cluster_id).unwrap().lock().await;
 
 
-            let dec_offset = (entry.db_snapshot == snapshot_pending as u64).then_some(entry.offset as usize);
+            // The pending snapshot holds a reference to the old slot, even if it inherited it
+            let dec_offset = entry.slot();
 
             *entry = OffsetTableEntry { db_snapshot: snapshot_pending as u64, offset: slot as u64 };
 
@@ -158,32 +256,291 @@ impl SnapshotDb {
         };
 
         self.db.set_offset(snapshot_pending, cluster_id, slot)?;
+        fail_point!(self, failpoints::WRITE_AFTER_SET_OFFSET);
         
         if let Some(offset) = dec_offset {
             self.allocator.dec(offset).await;
         }
 
+        self.persist_num_slots().await?;
+
+        fail_point!(self, failpoints::WRITE_BEFORE_FLUSH);
+        self.db.flush()?;
+        
+        Ok(())
+    }
+
+    /// Writes data to several clusters with a single sync and a single metadata commit
+    ///
+    /// The data is written in parallel and synced once, then all offset table entries and checksums
+    /// are committed in one metadata batch, so after a crash either all or none of the writes are
+    /// visible. If a cluster appears more than once, the last write wins.
+    ///
+    /// # Arguments
+    /// * `writes` - Target cluster identifiers and data (must match cluster size)
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success or IO error
+    pub async fn write_batch(&self, writes: &[(usize, &[u8])]) -> Result<()> {
+        if writes.iter().any(|(_, data)| data.len() != self.config.cluster_size) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Data size does not match cluster size"));
+        }
+        if let Some(&(cluster_id, _)) = writes.iter().find(|(cluster_id, _)| *cluster_id >= self.config.num_clusters) {
+            return Err(DbError::UnknownCluster(cluster_id).into());
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let _operation = self.operations.read().await;
+        self.release_leases().await;
+        let mut slots = Vec::with_capacity(writes.len());
+        for _ in writes {
+            slots.push(self.allocator.pop().await);
+        }
+
+        let cluster_size = self.config.cluster_size as u64;
+        let mut generations = Vec::with_capacity(writes.len());
+        let mut tasks = JoinSet::new();
+        for (&(_, data), &slot) in writes.iter().zip(&slots) {
+            let (data, generation) = self.seal(slot, data)?;
+            generations.push(generation);
+            tasks.spawn(self.storage.write_all_at(&data, slot as u64 * cluster_size));
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.map_err(std::io::Error::other)??;
+        }
+
+        // One sync over the range spanning all written slots
+        let first_slot = *slots.iter().min().unwrap() as u64;
+        let last_slot = *slots.iter().max().unwrap() as u64;
+        self.storage.sync_range(first_slot * cluster_size, (last_slot - first_slot + 1) * cluster_size).await?;
+        fail_point!(self, failpoints::WRITE_BATCH_AFTER_SYNC);
+
+        let mut batch = MetadataBatch::default();
+        let mut dec_offsets = Vec::new();
+        {
+            let offset_table = self.offset_table.read().await;
+            let snapshot_pending = offset_table.snapshot_pending;
+            let snapshot = offset_table.inner.get(&snapshot_pending).unwrap();
+
+            for ((&(cluster_id, data), &slot), generation) in writes.iter().zip(&slots).zip(generations) {
+                if let Some(block_size) = self.config.checksum_block_size {
+                    batch.set_checksums(slot, &block_checksums(data, block_size));
+                }
+                if let Some(generation) = generation {
+                    batch.set_generation(slot, generation);
+                }
+                batch.set_offset(snapshot_pending, cluster_id, slot);
+
+                let mut entry = snapshot.get(cluster_id).unwrap().lock().await;
+                dec_offsets.extend(entry.slot());
+                *entry = OffsetTableEntry { db_snapshot: snapshot_pending as u64, offset: slot as u64 };
+            }
+        }
+
+        self.db.apply_batch(batch)?;
+        fail_point!(self, failpoints::WRITE_BATCH_BEFORE_FLUSH);
+
+        self.allocator.dec_many(&dec_offsets).await;
+        self.persist_num_slots().await?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// Deletes a cluster from the pending snapshot
+    ///
+    /// The cluster then reads as zeros and takes no space. Its old slot is released once no
+    /// snapshot refers to it anymore.
+    ///
+    /// # Arguments
+    /// * `cluster_id` - Target cluster identifier
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success, IO error or `DbError::UnknownCluster`
+    pub async fn delete(&self, cluster_id: usize) -> Result<()> {
+        if cluster_id >= self.config.num_clusters {
+            return Err(DbError::UnknownCluster(cluster_id).into());
+        }
+
+        let _operation = self.operations.read().await;
+        let offset_table = self.offset_table.read().await;
+        let snapshot_pending = offset_table.snapshot_pending;
+        let mut entry = offset_table.inner.get(&snapshot_pending).unwrap().get(cluster_id).unwrap().lock().await;
+
+        let Some(dec_offset) = entry.slot() else {
+            return Ok(());
+        };
+
+        self.db.set_offset(snapshot_pending, cluster_id, ZERO_SLOT)?;
+        *entry = OffsetTableEntry::new(snapshot_pending, ZERO_SLOT);
+        drop(entry);
+        drop(offset_table);
+        fail_point!(self, failpoints::DELETE_BEFORE_FLUSH);
+
+        self.allocator.dec(dec_offset).await;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    /// Encrypts the data to write to a slot if encryption is enabled, returning it together with
+    /// its write generation
+    fn seal<'a>(&self, slot: usize, data: &'a [u8]) -> Result<(Cow<'a, [u8]>, Option<u64>)> {
+        let (Some(key), Some(generations)) = (&self.config.encryption_key, &self.generations) else {
+            return Ok((Cow::Borrowed(data), None));
+        };
+
+        let generation = generations.next(self.db.as_ref())?;
+        let mut data = data.to_vec();
+        key.apply_keystream(slot, generation, 0, &mut data);
+        Ok((Cow::Owned(data), Some(generation)))
+    }
+
+    /// Decrypts data read from `offset` in a slot if encryption is enabled
+    fn unseal(&self, slot: usize, offset: usize, data: &mut [u8]) -> Result<()> {
+        if let Some(key) = &self.config.encryption_key {
+            let generation = self.db.get_generation(slot)?.ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Missing write generation of slot {}", slot))
+            })?;
+            key.apply_keystream(slot, generation, offset, data);
+        }
+        Ok(())
+    }
+
+    /// Drops the allocator references of the slots of dropped memory mappings
+    async fn release_leases(&self) {
+        let released = self.leases.take_released();
+        if !released.is_empty() {
+            self.allocator.dec_many(&released).await;
+        }
+    }
+
+    /// Persists the slot count if the allocator grew
+    async fn persist_num_slots(&self) -> Result<()> {
         let num_slots = self.allocator.len().await;
         let old_num_slots = self.num_slots.swap(num_slots, std::sync::atomic::Ordering::Relaxed);
         if old_num_slots != num_slots {
             self.db.set_num_slots(num_slots)?;
         }
-
-        self.db.flush()?;
-        
         Ok(())
     }
 
-    /// Reads entire cluster data from a specific snapshot
+    /// Failpoints of this instance, see [`crate::failpoints`]
+    #[cfg(feature = "failpoints")]
+    pub fn failpoints(&self) -> &FailPoints {
+        &self.failpoints
+    }
+
+    /// Reads entire cluster data from a specific snapshot, verifying its checksums if enabled
     ///
     /// # Arguments
     /// * `snapshot` - Snapshot identifier
     /// * `cluster_id` - Target cluster identifier
     ///
     /// # Returns
-    /// * `Result<Vec<u8>>` - Cluster data or IO error
+    /// * `Result<Vec<u8>>` - Cluster data, IO error, `DbError::ChecksumMismatch` or
+    ///   `DbError::UnknownSnapshot`/`DbError::UnknownCluster`
     pub async fn read(&self, snapshot: usize, cluster_id: usize) -> Result<Vec<u8>> {
-        self.read_exact(snapshot, cluster_id, 0, self.config.cluster_size).await
+        self.read_exact_verified(snapshot, cluster_id, 0, self.config.cluster_size).await
+    }
+
+    /// Reads a specific range of data from a cluster in a snapshot, verifying the checksums of all
+    /// blocks overlapping the range. The whole blocks are read from disk, so the range should be
+    /// aligned to the checksum block size to avoid extra IO.
+    ///
+    /// Behaves like [`SnapshotDb::read_exact`] if checksums are disabled or were not recorded for
+    /// the slot (e.g. clusters that were never written).
+    ///
+    /// # Arguments
+    /// * `snapshot` - Snapshot identifier
+    /// * `cluster_id` - Target cluster identifier
+    /// * `from` - Start offset within the cluster
+    /// * `len` - Number of bytes to read
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - Requested data, IO error, `DbError::ChecksumMismatch` or
+    ///   `DbError::UnknownSnapshot`/`DbError::UnknownCluster`
+    pub async fn read_exact_verified(&self, snapshot: usize, cluster_id: usize, from: usize, len: usize) -> Result<Vec<u8>> {
+        let Some(block_size) = self.config.checksum_block_size else {
+            return self.read_exact(snapshot, cluster_id, from, len).await;
+        };
+
+        if from + len > self.config.cluster_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Range exceeds cluster size"));
+        }
+
+        let offset_table = self.offset_table.read().await;
+        let Some(slot) = offset_table.entry(snapshot, cluster_id)?.lock().await.slot() else {
+            return Ok(vec![0; len]);
+        };
+
+        let Some(checksums) = self.db.get_checksums(slot)? else {
+            let raw_offset = slot as u64 * self.config.cluster_size as u64 + from as u64;
+            let mut data = self.storage.read_exact_at(raw_offset, len).await?;
+            self.unseal(slot, from, &mut data)?;
+            return Ok(data);
+        };
+
+        let first_block = from / block_size;
+        let block_from = first_block * block_size;
+        let block_to = (from + len).div_ceil(block_size).saturating_mul(block_size).min(self.config.cluster_size);
+
+        let raw_offset = slot as u64 * self.config.cluster_size as u64 + block_from as u64;
+        let mut data = self.storage.read_exact_at(raw_offset, block_to - block_from).await?;
+        self.unseal(slot, block_from, &mut data)?;
+        drop(offset_table);
+
+        verify_checksums(slot, first_block, &data, block_size, &checksums)?;
+
+        Ok(data[from - block_from..from - block_from + len].to_vec())
+    }
+
+    /// Reads entire cluster data from a specific snapshot like [`SnapshotDb::read`], but without
+    /// copying it if memory-mapped reads are enabled (see [`crate::mmap`])
+    ///
+    /// The returned data stays valid after the snapshot is joined or the cluster is overwritten.
+    /// Its slot is not reused until the data is dropped and the next write or compaction starts.
+    ///
+    /// # Arguments
+    /// * `snapshot` - Snapshot identifier
+    /// * `cluster_id` - Target cluster identifier
+    ///
+    /// # Returns
+    /// * `Result<Bytes>` - Cluster data, IO error, `DbError::ChecksumMismatch` or
+    ///   `DbError::UnknownSnapshot`/`DbError::UnknownCluster`
+    pub async fn read_bytes(&self, snapshot: usize, cluster_id: usize) -> Result<Bytes> {
+        if !self.config.mmap_reads || self.config.encryption_key.is_some() {
+            return Ok(self.read(snapshot, cluster_id).await?.into());
+        }
+
+        let (slot, data) = {
+            // Compaction must see every lease it could move data into or truncate
+            let _operation = self.operations.read().await;
+            let offset_table = self.offset_table.read().await;
+            // Holding the entry keeps the slot referenced until the lease takes its own reference
+            let entry = offset_table.entry(snapshot, cluster_id)?.lock().await;
+            let Some(slot) = entry.slot() else {
+                return Ok(vec![0; self.config.cluster_size].into());
+            };
+
+            let data = mmap::map_slot(self.storage.file(), slot, self.config.cluster_size, &self.leases)?;
+            self.allocator.inc(slot).await;
+            (slot, data)
+        };
+
+        let (Some(block_size), Some(checksums)) = (self.config.checksum_block_size, self.db.get_checksums(slot)?) else {
+            return Ok(data);
+        };
+
+        // Checking the checksums faults in the whole slot, which may hit the disk
+        tokio::task::spawn_blocking(move || {
+            verify_checksums(slot, 0, &data, block_size, &checksums)?;
+            Ok(data)
+        })
+        .await
+        .map_err(std::io::Error::other)?
     }
 
     /// Reads a specific range of data from a cluster in a snapshot
@@ -195,13 +552,16 @@ impl SnapshotDb {
     /// * `len` - Number of bytes to read
     ///
     /// # Returns
-    /// * `Result<Vec<u8>>` - Requested data or IO error
+    /// * `Result<Vec<u8>>` - Requested data, IO error or `DbError::UnknownSnapshot`/`DbError::UnknownCluster`
     pub async fn read_exact(&self, snapshot: usize, cluster_id: usize, from:usize, len:usize) -> Result<Vec<u8>> {
         let offset_table = self.offset_table.read().await;
-        let offset = offset_table.inner.get(&snapshot).unwrap().get(cluster_id).unwrap().lock().await.offset as usize;
+        let Some(offset) = offset_table.entry(snapshot, cluster_id)?.lock().await.slot() else {
+            return Ok(vec![0; len]);
+        };
         //let mut storage = File::from_std(self.storage.try_clone()?);
         let raw_offset = offset as u64 * self.config.cluster_size as u64 + from as u64;
-        let data = read_exact_at(self.storage.clone(), raw_offset, len).await?;
+        let mut data = self.storage.read_exact_at(raw_offset, len).await?;
+        self.unseal(offset, from, &mut data)?;
 
         Ok(data)
     }
@@ -211,6 +571,7 @@ impl SnapshotDb {
     /// # Returns
     /// * `Result<()>` - Success or IO error
     pub async fn add_snapshot(&self) -> Result<()> {
+        let _operation = self.operations.read().await;
         let pending = {
             let mut offset_table = self.offset_table.write().await;
             let old_pending_snapshot_id = offset_table.snapshot_pending;
@@ -220,7 +581,7 @@ impl SnapshotDb {
             let old_pending_snapshot = to_mutex_vec(&old_pending_snapshot_values);
             let cloned_pending_snapshot = to_mutex_vec(&old_pending_snapshot_values);
 
-            let slots_to_inc = old_pending_snapshot_values.iter().map(|item| item.offset as usize).collect::<Vec<_>>();
+            let slots_to_inc = old_pending_snapshot_values.iter().filter_map(OffsetTableEntry::slot).collect::<Vec<_>>();
 
             offset_table.inner.insert(old_pending_snapshot_id, old_pending_snapshot);
             offset_table.inner.insert(old_pending_snapshot_id+1, cloned_pending_snapshot);
@@ -232,6 +593,7 @@ impl SnapshotDb {
             offset_table.snapshot_pending
         };
 
+        fail_point!(self, failpoints::ADD_SNAPSHOT_BEFORE_PERSIST);
         self.db.set_snapshot_pending(pending)?;
         Ok(())
     }
@@ -239,27 +601,44 @@ impl SnapshotDb {
     /// Finalizes and removes the oldest snapshot
     ///
     /// # Returns
-    /// * `Result<()>` - Success or IO error
+    /// * `Result<()>` - Success, IO error or `DbError::SnapshotInUse` if a [`Snapshot`] handle of
+    ///   the oldest snapshot exists
     pub async fn join_snapshot(&self) -> Result<()> {
+        let _operation = self.operations.read().await;
         let (removed_snapshot, removed_snapshot_id, offset_table) = {
             let mut offset_table = self.offset_table.write().await;
             let removed_snapshot_id = offset_table.snapshot_start;
+            if removed_snapshot_id == offset_table.snapshot_pending {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The pending snapshot cannot be joined"));
+            }
+            // Handles are only created while holding the offset table lock, so none can appear now
+            if self.pins.lock().unwrap().contains_key(&removed_snapshot_id) {
+                return Err(DbError::SnapshotInUse(removed_snapshot_id).into());
+            }
             let removed_snapshot = offset_table.inner.remove(&removed_snapshot_id).unwrap();
             offset_table.snapshot_start += 1; 
             (removed_snapshot, removed_snapshot_id, offset_table.downgrade())
         };
 
         let start = removed_snapshot_id+1;
+        // Entries of the removed snapshot which are still present on restart are collapsed into the
+        // new start snapshot by `init_offset_table`, so persisting the start first is safe.
+        self.db.set_snapshot_start(start)?;
+        fail_point!(self, failpoints::JOIN_SNAPSHOT_AFTER_START);
+
         let mut keys_to_remove = vec![];
         let mut offsets_to_dec = vec![];
 
-        let start_snapshot = offset_table.inner.get(&start).unwrap();
+        let start_snapshot = &offset_table.inner[&start];
 
         for (cluster_id, entry) in removed_snapshot.into_iter().enumerate() {
             let entry = entry.into_inner();
+            // The removed snapshot held a reference to its slot, even if the new start inherits it
+            offsets_to_dec.extend(entry.slot());
+
+            // An inherited entry is still needed by the new start
             let start_entry = *start_snapshot.get(cluster_id).unwrap().lock().await;
             if start_entry.db_snapshot == start as u64 {
-                offsets_to_dec.push(entry.offset as usize);
                 keys_to_remove.push(SledKey::OffsetTable(entry.db_snapshot, cluster_id as u64));
             }
         }
@@ -268,33 +647,264 @@ impl SnapshotDb {
 
         self.allocator.dec_many(&offsets_to_dec).await;
         self.db.remove_keys(&keys_to_remove)?;
+        fail_point!(self, failpoints::JOIN_SNAPSHOT_BEFORE_FLUSH);
         self.db.flush()?;
 
         Ok(())
     }
+
+    /// Range of the live snapshots, the last one is the pending snapshot
+    pub async fn snapshots(&self) -> RangeInclusive<usize> {
+        let offset_table = self.offset_table.read().await;
+        offset_table.snapshot_start..=offset_table.snapshot_pending
+    }
+
+    /// Handle of a live snapshot
+    ///
+    /// # Returns
+    /// * `Result<Snapshot>` - Handle or `DbError::UnknownSnapshot`
+    pub async fn get(&self, snapshot: usize) -> Result<Snapshot<'_>> {
+        let offset_table = self.offset_table.read().await;
+        if !offset_table.inner.contains_key(&snapshot) {
+            return Err(DbError::UnknownSnapshot(snapshot).into());
+        }
+        Ok(Snapshot::new(self, snapshot))
+    }
+
+    /// Handle of the pending snapshot, which receives the writes
+    pub async fn pending(&self) -> Snapshot<'_> {
+        let offset_table = self.offset_table.read().await;
+        Snapshot::new(self, offset_table.snapshot_pending)
+    }
+
+    /// Handle of the newest snapshot which no longer receives writes, if any
+    pub async fn latest_committed(&self) -> Option<Snapshot<'_>> {
+        let offset_table = self.offset_table.read().await;
+        (offset_table.snapshot_pending > offset_table.snapshot_start)
+            .then(|| Snapshot::new(self, offset_table.snapshot_pending - 1))
+    }
+
+    /// Clusters of the snapshot written after the `base` snapshot was committed, or all clusters if
+    /// `base` is `None`. The base snapshot may already be joined.
+    ///
+    /// # Returns
+    /// * `Result<Vec<usize>>` - Cluster identifiers in ascending order or `DbError::UnknownSnapshot`
+    pub async fn changed_clusters(&self, snapshot: usize, base: Option<usize>) -> Result<Vec<usize>> {
+        let offset_table = self.offset_table.read().await;
+        let entries = offset_table.inner.get(&snapshot).ok_or(DbError::UnknownSnapshot(snapshot))?;
+
+        let mut changed = Vec::new();
+        for (cluster_id, entry) in entries.iter().enumerate() {
+            let db_snapshot = entry.lock().await.db_snapshot as usize;
+            if base.is_none_or(|base| db_snapshot > base) {
+                changed.push(cluster_id);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Database configuration
+    pub fn config(&self) -> &SnapshotDbConfig {
+        &self.config
+    }
+
+    pub(crate) fn pin(&self, snapshot: usize) {
+        *self.pins.lock().unwrap().entry(snapshot).or_insert(0) += 1;
+    }
+
+    pub(crate) fn unpin(&self, snapshot: usize) {
+        let mut pins = self.pins.lock().unwrap();
+        let count = pins.get_mut(&snapshot).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&snapshot);
+        }
+    }
+
+    /// Relocates live slots from the tail of the storage file into free slots and truncates the
+    /// file to the live slots
+    ///
+    /// Writes and snapshot changes wait until the compaction is done, reads wait only while the
+    /// in-memory offset table is switched to the new slots. The offset table is updated in a single
+    /// sled batch, so after a crash the database has either the old or the new layout. Slots leased
+    /// by [`SnapshotDb::read_bytes`] are neither overwritten nor truncated. Offset table
+    /// entries, checksums and generations which no snapshot references are removed in the same
+    /// batch. Moved encrypted slots are re-encrypted under a new generation.
+    ///
+    /// # Arguments
+    /// * `dry_run` - Only report what the compaction would do
+    ///
+    /// # Returns
+    /// * `Result<CompactionReport>` - Report of the (planned) compaction or IO error
+    pub async fn compact(&self, dry_run: bool) -> Result<CompactionReport> {
+        let _operations = self.operations.write().await;
+        // A compaction resets the allocator, which drops the references of queued releases
+        let leased = if dry_run { self.leases.leased() } else { self.leases.reset() };
+
+        let cluster_size = self.config.cluster_size as u64;
+        let file_size = self.storage.file().metadata()?.len();
+
+        // Entries referencing every live slot, as (snapshot, cluster_id, db_snapshot)
+        let mut references: BTreeMap<usize, Vec<(usize, usize, u64)>> = BTreeMap::new();
+        let mut live_keys = BTreeSet::new();
+        {
+            let offset_table = self.offset_table.read().await;
+            for snapshot in offset_table.snapshot_start..=offset_table.snapshot_pending {
+                for (cluster_id, entry) in offset_table.inner.get(&snapshot).unwrap().iter().enumerate() {
+                    let entry = *entry.lock().await;
+                    if let Some(slot) = entry.slot() {
+                        references.entry(slot).or_default().push((snapshot, cluster_id, entry.db_snapshot));
+                    }
+                    live_keys.insert(SledKey::OffsetTable(entry.db_snapshot, cluster_id as u64));
+                }
+            }
+        }
+
+        let live_slots = references.len();
+        // Leased slots may still be read through their mappings, so no data is moved into them
+        let free_slots = (0..live_slots).filter(|slot| !references.contains_key(slot) && !leased.contains_key(slot));
+        let moves = references.range(live_slots..).map(|(&slot, _)| slot).zip(free_slots).collect::<Vec<_>>();
+
+        // Without leases, the moves fill all slots below the live slot count
+        let moved = moves.iter().map(|&(from, _)| from).collect::<BTreeSet<_>>();
+        let num_slots = references.keys()
+            .filter(|slot| !moved.contains(slot))
+            .chain(moves.iter().map(|(_, to)| to))
+            .chain(leased.keys())
+            .max()
+            .map_or(0, |slot| slot + 1);
+
+        let stale_keys = self.db.offset_table_entries_iter()
+            .map(|(key, _)| key)
+            .filter(|key| !live_keys.contains(key))
+            .chain(self.db.checksum_entries_iter()
+                .map(|(slot, _)| slot)
+                .filter(|slot| !references.contains_key(slot))
+                .map(|slot| SledKey::Checksum(slot as u64)))
+            .chain(self.db.generation_entries_iter()
+                .map(|(slot, _)| slot)
+                .filter(|slot| !references.contains_key(slot))
+                .map(|slot| SledKey::Generation(slot as u64)))
+            .collect::<Vec<_>>();
+
+        let new_file_size = file_size.min(num_slots as u64 * cluster_size);
+        let report = CompactionReport {
+            file_size,
+            live_slots,
+            moved_slots: moves.len(),
+            stale_keys: stale_keys.len(),
+            reclaimable_bytes: file_size - new_file_size,
+        };
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        // The destination slots are free, so copying into them does not affect any snapshot.
+        // Encrypted slots are re-encrypted for their new slot.
+        let mut generations = Vec::with_capacity(moves.len());
+        for &(from, to) in &moves {
+            let mut data = self.storage.read_exact_at(from as u64 * cluster_size, self.config.cluster_size).await?;
+            self.unseal(from, 0, &mut data)?;
+            let (data, generation) = self.seal(to, &data)?;
+            generations.push(generation);
+            self.storage.write_all_at(&data, to as u64 * cluster_size).await?;
+            self.storage.sync_range(to as u64 * cluster_size, cluster_size).await?;
+        }
+        fail_point!(self, failpoints::COMPACT_AFTER_COPY);
+
+        let mut batch = MetadataBatch::default();
+        for key in &stale_keys {
+            batch.remove_key(key);
+        }
+        for (&(from, to), generation) in moves.iter().zip(generations) {
+            if let Some(checksums) = self.db.get_checksums(from)? {
+                batch.set_checksums(to, &checksums);
+            }
+            batch.remove_key(&SledKey::Checksum(from as u64));
+            if let Some(generation) = generation {
+                batch.set_generation(to, generation);
+                batch.remove_key(&SledKey::Generation(from as u64));
+            }
+            for &(_, cluster_id, db_snapshot) in &references[&from] {
+                batch.set_offset(db_snapshot as usize, cluster_id, to);
+            }
+        }
+        batch.set_num_slots(num_slots);
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+
+        let mut link_counter = vec![0; num_slots];
+        {
+            let offset_table = self.offset_table.write().await;
+            let moves = moves.into_iter().collect::<BTreeMap<_, _>>();
+            for (slot, entries) in &references {
+                let slot = moves.get(slot).copied().unwrap_or(*slot);
+                link_counter[slot] = entries.len();
+
+                for &(snapshot, cluster_id, _) in entries {
+                    offset_table.inner.get(&snapshot).unwrap()[cluster_id].lock().await.offset = slot as u64;
+                }
+            }
+            for (&slot, &count) in &leased {
+                link_counter[slot] += count;
+            }
+            fail_point!(self, failpoints::COMPACT_BEFORE_TRUNCATE);
+
+            // Readers use the new slots from now on
+            self.storage.file().set_len(new_file_size)?;
+            self.storage.file().sync_all()?;
+        }
+
+        self.allocator.reset(link_counter).await;
+        self.num_slots.store(num_slots, std::sync::atomic::Ordering::Relaxed);
+
+        Ok(report)
+    }
 }
 
 
-async fn init_db(db: &SledWrapper, fp: &File, config: &SnapshotDbConfig) -> Result<()> {
+fn block_checksums(data: &[u8], block_size: usize) -> Vec<u32> {
+    data.chunks(block_size).map(crc32fast::hash).collect()
+}
+
+/// Checks the blocks of `data`, the first of which is block `first_block` of the slot
+fn verify_checksums(slot: usize, first_block: usize, data: &[u8], block_size: usize, checksums: &[u32]) -> Result<()> {
+    for (i, block) in data.chunks(block_size).enumerate() {
+        let block_index = first_block + i;
+        if checksums.get(block_index) != Some(&crc32fast::hash(block)) {
+            return Err(DbError::ChecksumMismatch { slot, block: block_index }.into());
+        }
+    }
+    Ok(())
+}
+
+
+async fn init_db(db: &dyn MetadataStore, fp: &File, config: &SnapshotDbConfig) -> Result<()> {
+    // The snapshot start marks the database as initialized, so the key check goes first
+    if let Some(key) = &config.encryption_key {
+        db.set_key_check(&key.check_value())?;
+    }
     db.set_snapshot_start(0)?;
     db.set_snapshot_pending(1)?;
-    db.set_num_slots(FREE_SLOTS_MIN_RESERVE + config.num_clusters)?;
+    db.set_num_slots(FREE_SLOTS_MIN_RESERVE)?;
 
+    // All clusters start out as zeros, the storage file only grows as they are written
+    let mut batch = MetadataBatch::default();
     for i in 0..config.num_clusters {
-        db.set_offset(0, i, i)?;
+        batch.set_offset(0, i, ZERO_SLOT);
     }
+    db.apply_batch(batch)?;
 
     db.flush()?;
 
-    // TODO: replace with default values
     fp.set_len(0)?;
-    fp.set_len(config.cluster_size as u64 * config.num_clusters as u64)?;
 
     Ok(())
 }
 
 
-fn init_offset_table(db: &SledWrapper, config: &SnapshotDbConfig) -> Result<Vec<Vec<Option<OffsetTableEntry>>>> {
+fn init_offset_table(db: &dyn MetadataStore, config: &SnapshotDbConfig) -> Result<Vec<Vec<Option<OffsetTableEntry>>>> {
     
     let start = db.get_snapshot_start()?;
     let pending = db.get_snapshot_pending()?;
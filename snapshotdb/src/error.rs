@@ -0,0 +1,57 @@
+//! Typed errors of the database.
+//!
+//! The public API returns `std::io::Result`, so these errors are wrapped into `std::io::Error`. Use
+//! [`DbError::from_io`] to get them back.
+
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    /// The data stored in a slot does not match its recorded checksum.
+    ChecksumMismatch { slot: usize, block: usize },
+    /// The snapshot was joined or does not exist yet.
+    UnknownSnapshot(usize),
+    /// The cluster id is not below the number of clusters.
+    UnknownCluster(usize),
+    /// The snapshot cannot be joined while a handle to it exists.
+    SnapshotInUse(usize),
+    /// The store has no namespace with this name.
+    UnknownNamespace(String),
+}
+
+impl DbError {
+    /// Returns the database error wrapped into the IO error, if any.
+    pub fn from_io(err: &io::Error) -> Option<&DbError> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<DbError>())
+    }
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::ChecksumMismatch { slot, block } => {
+                write!(f, "checksum mismatch in slot {} at block {}", slot, block)
+            }
+            DbError::UnknownSnapshot(snapshot) => write!(f, "unknown snapshot {}", snapshot),
+            DbError::UnknownCluster(cluster_id) => write!(f, "unknown cluster {}", cluster_id),
+            DbError::SnapshotInUse(snapshot) => write!(f, "snapshot {} is in use", snapshot),
+            DbError::UnknownNamespace(name) => write!(f, "unknown namespace {}", name),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<DbError> for io::Error {
+    fn from(err: DbError) -> Self {
+        let kind = match err {
+            DbError::ChecksumMismatch { .. } => io::ErrorKind::InvalidData,
+            DbError::UnknownSnapshot(_) => io::ErrorKind::NotFound,
+            DbError::UnknownCluster(_) => io::ErrorKind::InvalidInput,
+            DbError::SnapshotInUse(_) => io::ErrorKind::ResourceBusy,
+            DbError::UnknownNamespace(_) => io::ErrorKind::NotFound,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,78 @@
+//! Fault injection for crash-consistency testing, available with the `failpoints` feature.
+//!
+//! A failpoint names a step between two persistence operations of a multi-step database operation
+//! (see the constants below). When a failpoint is armed, the operation stops right there and returns
+//! an error, leaving the files on disk as a crash at that point would. Dropping the database and
+//! opening it again then exercises the recovery path.
+//!
+//! Note that sled keeps writes issued before the failpoint even if they were not flushed, so this
+//! models crashes after which sled's own log was intact.
+
+use std::collections::HashSet;
+use std::io::{Error, Result};
+use std::sync::Mutex;
+
+/// `write`: the data was written to the storage file but not synced.
+pub const WRITE_AFTER_DATA: &str = "write::after_data";
+/// `write`: the data was synced, the offset table was not updated.
+pub const WRITE_AFTER_SYNC: &str = "write::after_sync";
+/// `write`: the offset table entry was inserted, the allocator was not updated.
+pub const WRITE_AFTER_SET_OFFSET: &str = "write::after_set_offset";
+/// `write`: everything was done except the final sled flush.
+pub const WRITE_BEFORE_FLUSH: &str = "write::before_flush";
+/// `write_batch`: the data of all clusters was synced, the offset table was not updated.
+pub const WRITE_BATCH_AFTER_SYNC: &str = "write_batch::after_sync";
+/// `write_batch`: the offset table was updated, the allocator was not updated and sled was not
+/// flushed.
+pub const WRITE_BATCH_BEFORE_FLUSH: &str = "write_batch::before_flush";
+/// `delete`: the zero entry was inserted, the allocator was not updated and sled was not flushed.
+pub const DELETE_BEFORE_FLUSH: &str = "delete::before_flush";
+/// `add_snapshot`: the snapshot exists in memory, the pending snapshot id was not persisted.
+pub const ADD_SNAPSHOT_BEFORE_PERSIST: &str = "add_snapshot::before_persist";
+/// `join_snapshot`: the new snapshot start was persisted, the old entries were not removed.
+pub const JOIN_SNAPSHOT_AFTER_START: &str = "join_snapshot::after_start";
+/// `join_snapshot`: the old entries were removed, sled was not flushed.
+pub const JOIN_SNAPSHOT_BEFORE_FLUSH: &str = "join_snapshot::before_flush";
+/// `compact`: the live slots were copied, the offset table was not updated.
+pub const COMPACT_AFTER_COPY: &str = "compact::after_copy";
+/// `compact`: the offset table was updated, the storage file was not truncated.
+pub const COMPACT_BEFORE_TRUNCATE: &str = "compact::before_truncate";
+
+pub const ALL: &[&str] = &[
+    WRITE_AFTER_DATA,
+    WRITE_AFTER_SYNC,
+    WRITE_AFTER_SET_OFFSET,
+    WRITE_BEFORE_FLUSH,
+    WRITE_BATCH_AFTER_SYNC,
+    WRITE_BATCH_BEFORE_FLUSH,
+    DELETE_BEFORE_FLUSH,
+    ADD_SNAPSHOT_BEFORE_PERSIST,
+    JOIN_SNAPSHOT_AFTER_START,
+    JOIN_SNAPSHOT_BEFORE_FLUSH,
+    COMPACT_AFTER_COPY,
+    COMPACT_BEFORE_TRUNCATE,
+];
+
+/// Set of armed failpoints of a database instance
+#[derive(Debug, Default)]
+pub struct FailPoints {
+    armed: Mutex<HashSet<&'static str>>,
+}
+
+impl FailPoints {
+    /// Makes operations fail when they reach the failpoint
+    pub fn arm(&self, name: &'static str) {
+        self.armed.lock().unwrap().insert(name);
+    }
+
+    pub fn disarm(&self, name: &'static str) {
+        self.armed.lock().unwrap().remove(name);
+    }
+
+    pub(crate) fn check(&self, name: &'static str) -> Result<()> {
+        if self.armed.lock().unwrap().contains(name) {
+            return Err(Error::other(format!("failpoint {} triggered", name)));
+        }
+        Ok(())
+    }
+}
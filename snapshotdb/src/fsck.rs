@@ -0,0 +1,203 @@
+//! Offline consistency check of a database directory
+//!
+//! Scans the offset table and the storage file of a database that is not currently open and
+//! reports inconsistencies that a crash in the middle of an operation can leave behind:
+//! - stale offset table entries that are no longer visible in any snapshot
+//! - checksums recorded for slots that no live entry references
+//! - slots referenced by more than one live entry
+//! - entries pointing past the allocated slots or the end of the storage file
+//! - clusters with no entry at all
+//! - a persisted pending snapshot older than the newest entry
+//!
+//! [`repair`] fixes everything except missing data: stale entries and checksums are removed,
+//! double-referenced slots are copied so that every entry owns its slot, and the slot count and
+//! pending snapshot are raised to cover all entries. Copying encrypted slots requires the
+//! encryption key in the configuration.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions};
+use std::io::Result;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::db::SnapshotDbConfig;
+use crate::encryption::{self, Generations};
+use crate::metadata::{self, MetadataStore};
+use crate::sledwrapper::{SledKey, ZERO_SLOT};
+
+/// Result of a consistency check
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub snapshot_start: usize,
+    pub snapshot_pending: usize,
+    pub num_slots: usize,
+    /// Number of live offset table entries
+    pub live_entries: usize,
+    /// Entries shadowed by newer entries of the same cluster, which no snapshot can see
+    pub stale_entries: Vec<SledKey>,
+    /// Slots with a recorded checksum that no live entry references
+    pub leaked_checksums: Vec<usize>,
+    /// Slots referenced by more than one live entry
+    pub double_referenced: BTreeMap<usize, Vec<SledKey>>,
+    /// Live entries pointing to slots beyond the slot count or the storage file
+    pub out_of_range: Vec<SledKey>,
+    /// Clusters without an entry in the first snapshot
+    pub missing_clusters: Vec<usize>,
+    /// The newest snapshot with an entry, if it is newer than the persisted pending snapshot
+    pub pending_behind: Option<usize>,
+}
+
+impl FsckReport {
+    /// Whether the check found nothing to report
+    pub fn is_clean(&self) -> bool {
+        self.stale_entries.is_empty()
+            && self.leaked_checksums.is_empty()
+            && self.double_referenced.is_empty()
+            && self.out_of_range.is_empty()
+            && self.missing_clusters.is_empty()
+            && self.pending_behind.is_none()
+    }
+}
+
+struct Scan {
+    report: FsckReport,
+    /// Live entries mapped to their slots
+    live: BTreeMap<SledKey, usize>,
+}
+
+fn open(path: &Path, config: &SnapshotDbConfig) -> Result<(Box<dyn MetadataStore>, File)> {
+    let db = metadata::open(path, config.metadata_backend)?;
+    let storage = OpenOptions::new().read(true).write(true).open(path.join("storage"))?;
+    Ok((db, storage))
+}
+
+fn scan(db: &dyn MetadataStore, storage: &File, config: &SnapshotDbConfig) -> Result<Scan> {
+    let snapshot_start = db.get_snapshot_start()?;
+    let snapshot_pending = db.get_snapshot_pending()?;
+    let num_slots = db.get_num_slots()?;
+    let num_storage_slots = (storage.metadata()?.len() / config.cluster_size as u64) as usize;
+
+    let mut report = FsckReport { snapshot_start, snapshot_pending, num_slots, ..Default::default() };
+
+    // Entries of every cluster ordered by snapshot
+    let mut clusters: BTreeMap<usize, BTreeMap<usize, usize>> = BTreeMap::new();
+    for (key, slot) in db.offset_table_entries_iter() {
+        if let SledKey::OffsetTable(db_snapshot, cluster_id) = key {
+            clusters.entry(cluster_id as usize).or_default().insert(db_snapshot as usize, slot);
+        }
+    }
+
+    let mut live = BTreeMap::new();
+    let mut newest_snapshot = 0;
+    for (&cluster_id, entries) in &clusters {
+        // Only the newest entry before the first snapshot is visible
+        let base = entries.range(..=snapshot_start).next_back().map(|(&snapshot, _)| snapshot);
+        for (&db_snapshot, &slot) in entries {
+            let key = SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64);
+            if db_snapshot < snapshot_start && Some(db_snapshot) != base {
+                report.stale_entries.push(key);
+            } else {
+                live.insert(key, slot);
+                newest_snapshot = newest_snapshot.max(db_snapshot);
+            }
+        }
+    }
+
+    report.missing_clusters = (0..config.num_clusters)
+        .filter(|cluster_id| {
+            clusters.get(cluster_id).is_none_or(|entries| entries.range(..=snapshot_start).next().is_none())
+        })
+        .collect();
+
+    if newest_snapshot > snapshot_pending {
+        report.pending_behind = Some(newest_snapshot);
+    }
+
+    let mut references: BTreeMap<usize, Vec<SledKey>> = BTreeMap::new();
+    // Zero clusters take no slot
+    for (&key, &slot) in live.iter().filter(|(_, &slot)| slot != ZERO_SLOT) {
+        references.entry(slot).or_default().push(key);
+        if slot >= num_slots || slot >= num_storage_slots {
+            report.out_of_range.push(key);
+        }
+    }
+
+    report.leaked_checksums = db
+        .checksum_entries_iter()
+        .map(|(slot, _)| slot)
+        .filter(|slot| !references.contains_key(slot))
+        .collect();
+
+    report.double_referenced = references.into_iter().filter(|(_, keys)| keys.len() > 1).collect();
+    report.live_entries = live.len();
+
+    Ok(Scan { report, live })
+}
+
+/// Checks the database at `path`, which must not be open
+pub fn check(path: impl AsRef<Path>, config: &SnapshotDbConfig) -> Result<FsckReport> {
+    let (db, storage) = open(path.as_ref(), config)?;
+    Ok(scan(db.as_ref(), &storage, config)?.report)
+}
+
+/// Checks the database at `path`, which must not be open, and repairs what can be repaired.
+/// Returns the report of the check before the repair.
+pub fn repair(path: impl AsRef<Path>, config: &SnapshotDbConfig) -> Result<FsckReport> {
+    let (db, storage) = open(path.as_ref(), config)?;
+    let Scan { report, live } = scan(db.as_ref(), &storage, config)?;
+
+    db.remove_keys(&report.stale_entries)?;
+    db.remove_keys(&report.leaked_checksums.iter().map(|&slot| SledKey::Checksum(slot as u64)).collect::<Vec<_>>())?;
+
+    if let Some(newest_snapshot) = report.pending_behind {
+        db.set_snapshot_pending(newest_snapshot)?;
+    }
+
+    let used_slots = live.values().copied().filter(|&slot| slot != ZERO_SLOT).collect::<BTreeSet<_>>();
+    let mut num_slots = report.num_slots.max(used_slots.last().map_or(0, |slot| slot + 1));
+
+    // Copies of encrypted slots are re-encrypted for their new slot
+    let generations = if report.double_referenced.is_empty() {
+        None
+    } else {
+        encryption::check_key(db.as_ref(), config.encryption_key.as_ref())?;
+        config.encryption_key.map(|_| Generations::new(db.as_ref())).transpose()?
+    };
+
+    // The first entry keeps the slot, the others get a copy of its data
+    for (&slot, keys) in &report.double_referenced {
+        let mut data = vec![0u8; config.cluster_size];
+        storage.read_exact_at(&mut data, slot as u64 * config.cluster_size as u64)?;
+        let checksums = db.get_checksums(slot)?;
+        let generation = db.get_generation(slot)?;
+
+        for key in &keys[1..] {
+            let SledKey::OffsetTable(db_snapshot, cluster_id) = *key else {
+                unreachable!();
+            };
+
+            let new_slot = num_slots;
+            num_slots += 1;
+
+            let mut copy = data.clone();
+            if let (Some(key), Some(generations), Some(generation)) = (&config.encryption_key, &generations, generation) {
+                key.apply_keystream(slot, generation, 0, &mut copy);
+                let new_generation = generations.next(db.as_ref())?;
+                key.apply_keystream(new_slot, new_generation, 0, &mut copy);
+                db.set_generation(new_slot, new_generation)?;
+            }
+
+            storage.write_all_at(&copy, new_slot as u64 * config.cluster_size as u64)?;
+            storage.sync_data()?;
+            if let Some(checksums) = &checksums {
+                db.set_checksums(new_slot, checksums)?;
+            }
+            db.set_offset(db_snapshot as usize, cluster_id as usize, new_slot)?;
+        }
+    }
+
+    db.set_num_slots(num_slots)?;
+    db.flush()?;
+
+    Ok(report)
+}
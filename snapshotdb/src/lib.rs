@@ -1,4 +1,19 @@
 pub mod allocator;
+pub mod archive;
 pub mod db;
+pub mod encryption;
+pub mod error;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+pub mod fsck;
+pub mod metadata;
+pub mod mmap;
+pub mod namespaces;
+#[cfg(feature = "redb")]
+pub mod redbstore;
 pub mod sledwrapper;
+pub mod snapshot;
+pub mod storage;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 pub mod utils;
\ No newline at end of file
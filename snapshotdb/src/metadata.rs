@@ -0,0 +1,266 @@
+//! Metadata store of a database: snapshot bounds, slot count, offset table, checksums and the
+//! encryption state
+//!
+//! [`MetadataStore`] abstracts over the key-value store keeping the metadata. Two backends are
+//! available:
+//! - sled ([`SledWrapper`]), stored in `<path>/sled`
+//! - redb ([`crate::redbstore::RedbStore`], `redb` feature), stored in `<path>/metadata.redb`
+//!
+//! Both store the same keys ([`SledKey`]) and values, so [`migrate`] simply copies all entries.
+//! Namespaces of a [`crate::namespaces::SnapshotStore`] keep their keys in a sled tree or redb table
+//! of their own.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::str::FromStr;
+
+use bincode::serialize;
+
+#[cfg(feature = "redb")]
+use crate::redbstore::RedbStore;
+use crate::sledwrapper::{SledKey, SledWrapper};
+
+/// Environment variable selecting the backend, see [`MetadataBackend::from_env`]
+pub const BACKEND_ENV: &str = "SNAPSHOTDB_BACKEND";
+
+/// Key-value store keeping the metadata of a database
+pub trait MetadataStore: Send + Sync {
+    /// Whether the store was not initialized yet
+    fn is_empty(&self) -> Result<bool>;
+
+    fn get_snapshot_start(&self) -> Result<usize>;
+    fn set_snapshot_start(&self, snapshot_start: usize) -> Result<()>;
+
+    fn get_snapshot_pending(&self) -> Result<usize>;
+    fn set_snapshot_pending(&self, snapshot_pending: usize) -> Result<()>;
+
+    fn get_num_slots(&self) -> Result<usize>;
+    fn set_num_slots(&self, num_slots: usize) -> Result<()>;
+
+    fn get_offset(&self, db_snapshot: usize, cluster_id: usize) -> Result<Option<usize>>;
+    fn set_offset(&self, db_snapshot: usize, cluster_id: usize, offset: usize) -> Result<()>;
+
+    fn get_checksums(&self, slot: usize) -> Result<Option<Vec<u32>>>;
+    fn set_checksums(&self, slot: usize, checksums: &[u32]) -> Result<()>;
+
+    /// Write generation of an encrypted slot, see [`crate::encryption`]
+    fn get_generation(&self, slot: usize) -> Result<Option<u64>>;
+    fn set_generation(&self, slot: usize, generation: u64) -> Result<()>;
+
+    /// End of the write generations reserved so far, 0 if none were
+    fn get_generation_reserve(&self) -> Result<u64>;
+    fn set_generation_reserve(&self, reserve: u64) -> Result<()>;
+
+    /// Digest of the encryption key, `None` for unencrypted databases
+    fn get_key_check(&self) -> Result<Option<[u8; 32]>>;
+    fn set_key_check(&self, check: &[u8; 32]) -> Result<()>;
+
+    /// Iterates over all offset table entries, returned as their key and slot
+    fn offset_table_entries_iter(&self) -> Box<dyn Iterator<Item = (SledKey, usize)> + '_>;
+
+    /// Iterates over all recorded slot checksums
+    fn checksum_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, Vec<u32>)> + '_>;
+
+    /// Iterates over all recorded slot generations
+    fn generation_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, u64)> + '_>;
+
+    /// Separate keyspace of a namespace in the same store, see [`crate::namespaces`]
+    fn namespace(&self, name: &str) -> Result<Box<dyn MetadataStore>>;
+
+    /// Applies all updates of the batch, atomically if the backend supports it
+    fn apply_batch(&self, batch: MetadataBatch) -> Result<()>;
+
+    fn remove_keys(&self, keys: &[SledKey]) -> Result<()> {
+        let mut batch = MetadataBatch::default();
+        for key in keys {
+            batch.remove_key(key);
+        }
+        self.apply_batch(batch)
+    }
+
+    /// Makes all previous updates durable
+    fn flush(&self) -> Result<()>;
+}
+
+/// Update of a [`MetadataBatch`], with the key and value already encoded
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// Updates applied together by [`MetadataStore::apply_batch`]
+#[derive(Debug, Clone, Default)]
+pub struct MetadataBatch(Vec<BatchOp>);
+
+impl MetadataBatch {
+    pub fn set_snapshot_start(&mut self, snapshot_start: usize) {
+        self.insert(SledKey::SnapshotStart, encode_usize(snapshot_start).to_vec());
+    }
+
+    pub fn set_snapshot_pending(&mut self, snapshot_pending: usize) {
+        self.insert(SledKey::SnapshotPending, encode_usize(snapshot_pending).to_vec());
+    }
+
+    pub fn set_num_slots(&mut self, num_slots: usize) {
+        self.insert(SledKey::NumSlots, encode_usize(num_slots).to_vec());
+    }
+
+    pub fn set_offset(&mut self, db_snapshot: usize, cluster_id: usize, offset: usize) {
+        self.insert(SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64), encode_usize(offset).to_vec());
+    }
+
+    pub fn set_checksums(&mut self, slot: usize, checksums: &[u32]) {
+        self.insert(SledKey::Checksum(slot as u64), serialize(checksums).unwrap());
+    }
+
+    pub fn set_generation(&mut self, slot: usize, generation: u64) {
+        self.insert(SledKey::Generation(slot as u64), generation.to_le_bytes().to_vec());
+    }
+
+    pub fn set_generation_reserve(&mut self, reserve: u64) {
+        self.insert(SledKey::GenerationReserve, reserve.to_le_bytes().to_vec());
+    }
+
+    pub fn set_key_check(&mut self, check: &[u8; 32]) {
+        self.insert(SledKey::KeyCheck, check.to_vec());
+    }
+
+    pub fn remove_key(&mut self, key: &SledKey) {
+        self.0.push(BatchOp::Remove(key.bytes()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Updates in the order they were added
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.0
+    }
+
+    fn insert(&mut self, key: SledKey, value: Vec<u8>) {
+        self.0.push(BatchOp::Insert(key.bytes(), value));
+    }
+}
+
+/// Backend keeping the metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetadataBackend {
+    #[default]
+    Sled,
+    Redb,
+}
+
+impl MetadataBackend {
+    /// Backend named by the `SNAPSHOTDB_BACKEND` environment variable, sled if it is not set
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(BACKEND_ENV) {
+            Ok(backend) => backend.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+impl FromStr for MetadataBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sled" => Ok(Self::Sled),
+            "redb" => Ok(Self::Redb),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown metadata backend {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for MetadataBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sled => write!(f, "sled"),
+            Self::Redb => write!(f, "redb"),
+        }
+    }
+}
+
+/// Opens (or creates) the metadata store of the database at `path`
+pub fn open(path: impl AsRef<Path>, backend: MetadataBackend) -> Result<Box<dyn MetadataStore>> {
+    match backend {
+        MetadataBackend::Sled => Ok(Box::new(SledWrapper::new(open_sled(&path.as_ref().join("sled"))?))),
+        #[cfg(feature = "redb")]
+        MetadataBackend::Redb => Ok(Box::new(RedbStore::open(path.as_ref().join("metadata.redb"))?)),
+        #[cfg(not(feature = "redb"))]
+        MetadataBackend::Redb => Err(Error::new(ErrorKind::Unsupported, "snapshot-db was built without the `redb` feature")),
+    }
+}
+
+/// sled releases its file lock from background threads, so reopening a store right after dropping
+/// it can briefly fail
+fn open_sled(path: &Path) -> Result<sled::Db> {
+    const ATTEMPTS: usize = 50;
+
+    for _ in 1..ATTEMPTS {
+        match sled::open(path) {
+            Err(sled::Error::Io(err)) if err.to_string().starts_with("could not acquire lock") => {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+            }
+            result => return Ok(result?),
+        }
+    }
+    Ok(sled::open(path)?)
+}
+
+/// Copies all metadata to an empty store, returning the number of copied entries
+pub fn migrate(from: &dyn MetadataStore, to: &dyn MetadataStore) -> Result<usize> {
+    if from.is_empty()? {
+        return Err(Error::new(ErrorKind::InvalidInput, "source metadata store is empty"));
+    }
+    if !to.is_empty()? {
+        return Err(Error::new(ErrorKind::AlreadyExists, "target metadata store is not empty"));
+    }
+
+    let mut batch = MetadataBatch::default();
+    for (key, offset) in from.offset_table_entries_iter() {
+        if let SledKey::OffsetTable(db_snapshot, cluster_id) = key {
+            batch.set_offset(db_snapshot as usize, cluster_id as usize, offset);
+        }
+    }
+    for (slot, checksums) in from.checksum_entries_iter() {
+        batch.set_checksums(slot, &checksums);
+    }
+    for (slot, generation) in from.generation_entries_iter() {
+        batch.set_generation(slot, generation);
+    }
+    batch.set_generation_reserve(from.get_generation_reserve()?);
+    if let Some(check) = from.get_key_check()? {
+        batch.set_key_check(&check);
+    }
+    batch.set_num_slots(from.get_num_slots()?);
+    batch.set_snapshot_pending(from.get_snapshot_pending()?);
+    // The snapshot start marks the store as initialized, so it goes last for backends without
+    // atomic batches
+    batch.set_snapshot_start(from.get_snapshot_start()?);
+
+    let entries = batch.len();
+    to.apply_batch(batch)?;
+    to.flush()?;
+
+    Ok(entries)
+}
+
+pub(crate) fn encode_usize(value: usize) -> [u8; 8] {
+    u64::to_le_bytes(value as u64)
+}
+
+pub(crate) fn decode_usize(bytes: &[u8]) -> usize {
+    decode_u64(bytes) as usize
+}
+
+pub(crate) fn decode_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap())
+}
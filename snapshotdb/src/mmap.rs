@@ -0,0 +1,97 @@
+//! Memory-mapped reads of whole slots
+//!
+//! [`crate::db::SnapshotDb::read_bytes`] maps the slot of a cluster and returns the mapping as
+//! [`Bytes`], so the data is served from the page cache without copying. The mapping holds a lease
+//! on its slot: the lease keeps a reference in the allocator, so the slot is not reused after the
+//! snapshot is joined or the cluster is overwritten, and compaction neither moves data into a
+//! leased slot nor truncates the storage file below it.
+//!
+//! Dropping a mapping only queues the release of its lease, because the allocator cannot be
+//! locked from `Drop`. The database releases queued leases before it allocates slots or compacts.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Result;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use memmap2::{Advice, Mmap, MmapOptions};
+
+/// Leased slots and the leases waiting to be released
+#[derive(Default)]
+pub(crate) struct Leases {
+    state: Mutex<LeaseState>,
+}
+
+#[derive(Default)]
+struct LeaseState {
+    /// Number of mappings of every leased slot
+    leased: BTreeMap<usize, usize>,
+    /// Slots of dropped mappings whose allocator reference was not released yet
+    released: Vec<usize>,
+}
+
+impl Leases {
+    /// Slots of dropped mappings, whose allocator references the caller has to release
+    pub fn take_released(&self) -> Vec<usize> {
+        std::mem::take(&mut self.state.lock().unwrap().released)
+    }
+
+    /// Number of mappings of every leased slot
+    pub fn leased(&self) -> BTreeMap<usize, usize> {
+        self.state.lock().unwrap().leased.clone()
+    }
+
+    /// Discards the queued releases and returns the number of mappings of every leased slot, for
+    /// callers recomputing all allocator references
+    pub fn reset(&self) -> BTreeMap<usize, usize> {
+        let mut state = self.state.lock().unwrap();
+        state.released.clear();
+        state.leased.clone()
+    }
+
+    fn lease(&self, slot: usize) {
+        *self.state.lock().unwrap().leased.entry(slot).or_insert(0) += 1;
+    }
+
+    fn release(&self, slot: usize) {
+        let mut state = self.state.lock().unwrap();
+        let count = state.leased.get_mut(&slot).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            state.leased.remove(&slot);
+        }
+        state.released.push(slot);
+    }
+}
+
+/// Mapping of a slot, holding a lease on it
+struct SlotMapping {
+    mmap: Mmap,
+    slot: usize,
+    leases: Arc<Leases>,
+}
+
+impl AsRef<[u8]> for SlotMapping {
+    fn as_ref(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+impl Drop for SlotMapping {
+    fn drop(&mut self) {
+        self.leases.release(self.slot);
+    }
+}
+
+/// Maps a whole slot. The caller must hold an allocator reference to the slot, which is released
+/// through [`Leases::take_released`] after the mapping is dropped.
+pub(crate) fn map_slot(file: &File, slot: usize, cluster_size: usize, leases: &Arc<Leases>) -> Result<Bytes> {
+    // The slot cannot be reused while the mapping exists, so its data does not change under it
+    let mmap = unsafe { MmapOptions::new().offset(slot as u64 * cluster_size as u64).len(cluster_size).map(file)? };
+    // Hot clusters are in the page cache already, cold ones are read ahead in one go
+    mmap.advise(Advice::WillNeed)?;
+
+    leases.lease(slot);
+    Ok(Bytes::from_owner(SlotMapping { mmap, slot, leases: leases.clone() }))
+}
@@ -0,0 +1,201 @@
+//! Several databases sharing one metadata store and their snapshots
+//!
+//! A [`SnapshotStore`] keeps one [`SnapshotDb`] per namespace, each with its own cluster size,
+//! cluster count and storage file (`<path>/storage-<name>`). The metadata of all namespaces lives
+//! in one metadata store, in a sled tree or redb table per namespace.
+//!
+//! Snapshot ids are shared: [`SnapshotStore::add_snapshot`] and [`SnapshotStore::join_snapshot`]
+//! apply to every namespace, so a snapshot id refers to the same point in time in all of them.
+//! The namespaces are advanced one after another, so a crash or a snapshot in use can leave some
+//! of them behind. The store catches lagging namespaces up to the newest snapshot bounds when it is
+//! opened and before every snapshot change, which also brings namespaces added to an existing store
+//! up to date.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use tokio::sync::Mutex;
+
+use crate::db::{CompactionReport, SnapshotDb, SnapshotDbConfig};
+use crate::encryption::EncryptionKey;
+use crate::error::DbError;
+use crate::metadata::{self, MetadataBackend};
+use crate::storage::IoBackend;
+
+/// Layout of the clusters of a namespace
+#[derive(Debug, Clone)]
+pub struct NamespaceConfig {
+    /// Name of the namespace, made of ASCII letters, digits, `-` and `_`
+    pub name: String,
+    /// Number of clusters in the namespace
+    pub num_clusters: usize,
+    /// Size of each cluster in bytes
+    pub cluster_size: usize,
+    /// Size of the blocks covered by a CRC32 checksum, `None` disables them
+    pub checksum_block_size: Option<usize>,
+}
+
+/// Configuration for the SnapshotStore instance
+#[derive(Debug, Clone)]
+pub struct SnapshotStoreConfig {
+    /// Namespaces of the store. Namespaces can be added to an existing store, but their layout must
+    /// stay the same.
+    pub namespaces: Vec<NamespaceConfig>,
+    /// Backend keeping the metadata of all namespaces
+    pub metadata_backend: MetadataBackend,
+    /// Backend reading and writing the storage files
+    pub io_backend: IoBackend,
+    /// Key encrypting the storage files, `None` stores the data in plain. Every namespace uses a key
+    /// derived from it.
+    pub encryption_key: Option<EncryptionKey>,
+    /// Serve [`SnapshotDb::read_bytes`] from memory mappings, see [`SnapshotDbConfig::mmap_reads`]
+    pub mmap_reads: bool,
+}
+
+/// Databases of several namespaces with shared snapshots
+pub struct SnapshotStore {
+    /// Database of every namespace, by name
+    namespaces: BTreeMap<String, SnapshotDb>,
+    /// Held while changing the snapshots of the namespaces
+    snapshots: Mutex<()>,
+}
+
+impl SnapshotStore {
+    /// Opens (or creates) the store at `path` with the namespaces of the configuration
+    pub async fn new(path: impl AsRef<Path>, config: SnapshotStoreConfig) -> Result<Self> {
+        if config.namespaces.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "A store needs at least one namespace"));
+        }
+
+        let root = metadata::open(&path, config.metadata_backend)?;
+
+        let mut namespaces = BTreeMap::new();
+        for namespace in config.namespaces {
+            check_name(&namespace.name)?;
+            if namespaces.contains_key(&namespace.name) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Duplicate namespace {}", namespace.name)));
+            }
+
+            let db_config = SnapshotDbConfig {
+                num_clusters: namespace.num_clusters,
+                cluster_size: namespace.cluster_size,
+                checksum_block_size: namespace.checksum_block_size,
+                metadata_backend: config.metadata_backend,
+                io_backend: config.io_backend,
+                encryption_key: config.encryption_key.map(|key| key.for_namespace(&namespace.name)),
+                mmap_reads: config.mmap_reads,
+            };
+            let storage_path = path.as_ref().join(format!("storage-{}", namespace.name));
+            let db = SnapshotDb::with_metadata(root.namespace(&namespace.name)?, storage_path, db_config).await?;
+            namespaces.insert(namespace.name, db);
+        }
+
+        let store = Self { namespaces, snapshots: Mutex::new(()) };
+        store.catch_up().await?;
+
+        Ok(store)
+    }
+
+    /// Database of a namespace
+    ///
+    /// # Returns
+    /// * `Result<&SnapshotDb>` - Database or `DbError::UnknownNamespace`
+    pub fn namespace(&self, name: &str) -> Result<&SnapshotDb> {
+        Ok(self.namespaces.get(name).ok_or_else(|| DbError::UnknownNamespace(name.to_string()))?)
+    }
+
+    /// Names and databases of all namespaces, ordered by name
+    pub fn namespaces(&self) -> impl Iterator<Item = (&str, &SnapshotDb)> {
+        self.namespaces.iter().map(|(name, db)| (name.as_str(), db))
+    }
+
+    /// Range of the snapshots which are live in every namespace, the last one is the pending
+    /// snapshot
+    pub async fn snapshots(&self) -> RangeInclusive<usize> {
+        let mut start = 0;
+        let mut pending = usize::MAX;
+        for db in self.namespaces.values() {
+            let snapshots = db.snapshots().await;
+            start = start.max(*snapshots.start());
+            pending = pending.min(*snapshots.end());
+        }
+        start..=pending
+    }
+
+    /// Creates a new snapshot of the current state of every namespace, see
+    /// [`SnapshotDb::add_snapshot`]
+    pub async fn add_snapshot(&self) -> Result<()> {
+        let _snapshots = self.snapshots.lock().await;
+        self.catch_up().await?;
+        for db in self.namespaces.values() {
+            db.add_snapshot().await?;
+        }
+        Ok(())
+    }
+
+    /// Finalizes and removes the oldest snapshot of every namespace, see
+    /// [`SnapshotDb::join_snapshot`]
+    ///
+    /// # Returns
+    /// * `Result<()>` - Success, IO error or `DbError::SnapshotInUse` if a handle of the oldest
+    ///   snapshot exists in a namespace. Namespaces before it are joined already, the next snapshot
+    ///   change joins the rest.
+    pub async fn join_snapshot(&self) -> Result<()> {
+        let _snapshots = self.snapshots.lock().await;
+        self.catch_up().await?;
+        for db in self.namespaces.values() {
+            db.join_snapshot().await?;
+        }
+        Ok(())
+    }
+
+    /// Compacts the storage file of every namespace, see [`SnapshotDb::compact`]
+    ///
+    /// # Returns
+    /// * `Result<BTreeMap<String, CompactionReport>>` - Report of every namespace or IO error
+    pub async fn compact(&self, dry_run: bool) -> Result<BTreeMap<String, CompactionReport>> {
+        let mut reports = BTreeMap::new();
+        for (name, db) in &self.namespaces {
+            reports.insert(name.clone(), db.compact(dry_run).await?);
+        }
+        Ok(reports)
+    }
+
+    /// Brings all namespaces to the newest snapshot bounds of any of them
+    async fn catch_up(&self) -> Result<()> {
+        let mut target_start = 0;
+        let mut target_pending = 0;
+        for db in self.namespaces.values() {
+            let snapshots = db.snapshots().await;
+            target_start = target_start.max(*snapshots.start());
+            target_pending = target_pending.max(*snapshots.end());
+        }
+
+        for db in self.namespaces.values() {
+            // Joining as early as possible keeps at most two snapshots in memory
+            loop {
+                let snapshots = db.snapshots().await;
+                if *snapshots.start() < target_start && snapshots.start() < snapshots.end() {
+                    db.join_snapshot().await?;
+                } else if *snapshots.end() < target_pending {
+                    db.add_snapshot().await?;
+                } else {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Names become sled trees, redb tables and file names, so they are kept simple
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid namespace name {:?}", name)));
+    }
+    Ok(())
+}
@@ -0,0 +1,204 @@
+//! redb backend of the [`MetadataStore`]
+//!
+//! All metadata lives in a single table with the same keys and values as the sled backend, each
+//! namespace has a table of its own. Single
+//! updates are committed with eventual durability and made durable by [`MetadataStore::flush`],
+//! which matches sled's behaviour; batches are committed in one transaction.
+
+use std::io::{Error, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+use bincode::{deserialize, serialize};
+use redb::{Database, Durability, TableDefinition};
+
+use crate::metadata::{decode_u64, decode_usize, encode_usize, BatchOp, MetadataBatch, MetadataStore};
+use crate::sledwrapper::SledKey;
+
+const METADATA: &str = "metadata";
+
+pub struct RedbStore {
+    db: Arc<Database>,
+    /// Table holding the keys, the metadata table or the table of a namespace
+    table: String,
+}
+
+impl RedbStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::with_table(Arc::new(Database::create(path).map_err(to_io)?), METADATA.to_string())
+    }
+
+    fn with_table(db: Arc<Database>, table: String) -> Result<Self> {
+        let store = Self { db, table };
+
+        // Create the table up front, so that read transactions always find it
+        let txn = store.db.begin_write().map_err(to_io)?;
+        txn.open_table(store.table()).map_err(to_io)?;
+        txn.commit().map_err(to_io)?;
+
+        Ok(store)
+    }
+
+    fn table(&self) -> TableDefinition<'_, &'static [u8], &'static [u8]> {
+        TableDefinition::new(&self.table)
+    }
+
+    fn get(&self, key: &SledKey) -> Result<Option<Vec<u8>>> {
+        let txn = self.db.begin_read().map_err(to_io)?;
+        let table = txn.open_table(self.table()).map_err(to_io)?;
+        let value = table.get(key.bytes().as_slice()).map_err(to_io)?;
+        Ok(value.map(|value| value.value().to_vec()))
+    }
+
+    fn insert(&self, key: &SledKey, value: &[u8]) -> Result<()> {
+        let mut txn = self.db.begin_write().map_err(to_io)?;
+        txn.set_durability(Durability::Eventual);
+        txn.open_table(self.table()).map_err(to_io)?.insert(key.bytes().as_slice(), value).map_err(to_io)?;
+        txn.commit().map_err(to_io)
+    }
+
+    fn get_usize(&self, key: SledKey) -> Result<usize> {
+        Ok(decode_usize(&self.get(&key)?.unwrap()))
+    }
+
+    /// All entries whose key starts with the prefix of `key`
+    fn scan_prefix(&self, key: SledKey) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let prefix = key.prefix_bytes();
+        let mut end = prefix.clone();
+        *end.last_mut().unwrap() += 1;
+
+        let txn = self.db.begin_read().map_err(to_io)?;
+        let table = txn.open_table(self.table()).map_err(to_io)?;
+        let entries = table
+            .range(prefix.as_slice()..end.as_slice())
+            .map_err(to_io)?
+            .map(|entry| {
+                let (k, v) = entry.map_err(to_io)?;
+                Ok((k.value().to_vec(), v.value().to_vec()))
+            })
+            .collect();
+        entries
+    }
+}
+
+impl MetadataStore for RedbStore {
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.get(&SledKey::SnapshotStart)?.is_none())
+    }
+
+    fn get_snapshot_start(&self) -> Result<usize> {
+        self.get_usize(SledKey::SnapshotStart)
+    }
+
+    fn set_snapshot_start(&self, snapshot_start: usize) -> Result<()> {
+        self.insert(&SledKey::SnapshotStart, &encode_usize(snapshot_start))
+    }
+
+    fn get_snapshot_pending(&self) -> Result<usize> {
+        self.get_usize(SledKey::SnapshotPending)
+    }
+
+    fn set_snapshot_pending(&self, snapshot_pending: usize) -> Result<()> {
+        self.insert(&SledKey::SnapshotPending, &encode_usize(snapshot_pending))
+    }
+
+    fn get_num_slots(&self) -> Result<usize> {
+        self.get_usize(SledKey::NumSlots)
+    }
+
+    fn set_num_slots(&self, num_slots: usize) -> Result<()> {
+        self.insert(&SledKey::NumSlots, &encode_usize(num_slots))
+    }
+
+    fn get_offset(&self, db_snapshot: usize, cluster_id: usize) -> Result<Option<usize>> {
+        let value = self.get(&SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64))?;
+        Ok(value.map(|v| decode_usize(&v)))
+    }
+
+    fn set_offset(&self, db_snapshot: usize, cluster_id: usize, offset: usize) -> Result<()> {
+        self.insert(&SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64), &encode_usize(offset))
+    }
+
+    fn get_checksums(&self, slot: usize) -> Result<Option<Vec<u32>>> {
+        let value = self.get(&SledKey::Checksum(slot as u64))?;
+        Ok(value.map(|v| deserialize(&v).unwrap()))
+    }
+
+    fn set_checksums(&self, slot: usize, checksums: &[u32]) -> Result<()> {
+        self.insert(&SledKey::Checksum(slot as u64), &serialize(checksums).unwrap())
+    }
+
+    fn get_generation(&self, slot: usize) -> Result<Option<u64>> {
+        let value = self.get(&SledKey::Generation(slot as u64))?;
+        Ok(value.map(|v| decode_u64(&v)))
+    }
+
+    fn set_generation(&self, slot: usize, generation: u64) -> Result<()> {
+        self.insert(&SledKey::Generation(slot as u64), &generation.to_le_bytes())
+    }
+
+    fn get_generation_reserve(&self) -> Result<u64> {
+        Ok(self.get(&SledKey::GenerationReserve)?.map_or(0, |v| decode_u64(&v)))
+    }
+
+    fn set_generation_reserve(&self, reserve: u64) -> Result<()> {
+        self.insert(&SledKey::GenerationReserve, &reserve.to_le_bytes())
+    }
+
+    fn get_key_check(&self) -> Result<Option<[u8; 32]>> {
+        Ok(self.get(&SledKey::KeyCheck)?.map(|v| v.as_slice().try_into().unwrap()))
+    }
+
+    fn set_key_check(&self, check: &[u8; 32]) -> Result<()> {
+        self.insert(&SledKey::KeyCheck, check)
+    }
+
+    fn offset_table_entries_iter(&self) -> Box<dyn Iterator<Item = (SledKey, usize)> + '_> {
+        let entries = self.scan_prefix(SledKey::OffsetTable(0, 0)).unwrap();
+        Box::new(entries.into_iter().map(|(k, v)| (deserialize(&k).unwrap(), decode_usize(&v))))
+    }
+
+    fn checksum_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, Vec<u32>)> + '_> {
+        let entries = self.scan_prefix(SledKey::Checksum(0)).unwrap();
+        Box::new(entries.into_iter().map(|(k, v)| match deserialize(&k).unwrap() {
+            SledKey::Checksum(slot) => (slot as usize, deserialize(&v).unwrap()),
+            key => unreachable!("unexpected key {:?}", key),
+        }))
+    }
+
+    fn generation_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, u64)> + '_> {
+        let entries = self.scan_prefix(SledKey::Generation(0)).unwrap();
+        Box::new(entries.into_iter().map(|(k, v)| match deserialize(&k).unwrap() {
+            SledKey::Generation(slot) => (slot as usize, decode_u64(&v)),
+            key => unreachable!("unexpected key {:?}", key),
+        }))
+    }
+
+    fn namespace(&self, name: &str) -> Result<Box<dyn MetadataStore>> {
+        Ok(Box::new(Self::with_table(self.db.clone(), format!("namespace/{}", name))?))
+    }
+
+    fn apply_batch(&self, batch: MetadataBatch) -> Result<()> {
+        let txn = self.db.begin_write().map_err(to_io)?;
+        {
+            let mut table = txn.open_table(self.table()).map_err(to_io)?;
+            for op in batch.into_ops() {
+                match op {
+                    BatchOp::Insert(key, value) => table.insert(key.as_slice(), value.as_slice()).map_err(to_io)?,
+                    BatchOp::Remove(key) => table.remove(key.as_slice()).map_err(to_io)?,
+                };
+            }
+        }
+        txn.commit().map_err(to_io)
+    }
+
+    fn flush(&self) -> Result<()> {
+        // An immediate commit also persists all eventual commits before it
+        let txn = self.db.begin_write().map_err(to_io)?;
+        txn.commit().map_err(to_io)
+    }
+}
+
+fn to_io(err: impl Into<redb::Error>) -> Error {
+    Error::other(err.into())
+}
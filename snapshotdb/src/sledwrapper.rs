@@ -1,6 +1,8 @@
 use bincode::{serialize, deserialize};
 use serde::{Serialize, Deserialize};
-use sled::Db;
+use sled::{Batch, Db, Tree};
+
+use crate::metadata::{decode_u64, decode_usize, encode_usize, BatchOp, MetadataBatch, MetadataStore};
 use std::io::Result;
 
 
@@ -10,126 +12,230 @@ pub struct OffsetTableEntry {
     pub offset: u64,
 }
 
+/// Offset of clusters which are deleted or were never written. Their data is all zeros and takes
+/// no slot.
+pub const ZERO_SLOT: usize = usize::MAX;
+
 impl OffsetTableEntry {
     pub fn new(db_snapshot: usize, offset: usize) -> Self {
         Self { db_snapshot: db_snapshot as u64, offset: offset as u64 }
     }
+
+    /// Slot holding the data, `None` for zero clusters
+    pub fn slot(&self) -> Option<usize> {
+        (self.offset != ZERO_SLOT as u64).then_some(self.offset as usize)
+    }
 }
 
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum SledKey {
     SnapshotStart,
     SnapshotPending,
     NumSlots,
-    OffsetTable(u64,u64)
+    OffsetTable(u64,u64),
+    /// CRC32 checksums of the blocks of a slot
+    Checksum(u64),
+    /// Write generation of an encrypted slot
+    Generation(u64),
+    /// End of the reserved write generations
+    GenerationReserve,
+    /// Digest of the encryption key
+    KeyCheck,
 }
 
 impl SledKey {
-    fn prefix_bytes(&self) -> Vec<u8> {
+    pub(crate) fn prefix_bytes(&self) -> Vec<u8> {
         match self {
             SledKey::SnapshotStart => vec![0],
             SledKey::SnapshotPending => vec![1],
             SledKey::NumSlots => vec![2],
             SledKey::OffsetTable(_, _) => vec![3],
+            SledKey::Checksum(_) => vec![4],
+            SledKey::Generation(_) => vec![5],
+            SledKey::GenerationReserve => vec![6],
+            SledKey::KeyCheck => vec![7],
         }
     }
 
-    fn bytes(&self) -> Vec<u8> {
+    pub(crate) fn bytes(&self) -> Vec<u8> {
         serialize(self).unwrap()
     }
 }
 
 
 
+/// sled backend of the [`MetadataStore`]
 #[derive(Debug, Clone)]
-pub struct SledWrapper(Db);
+pub struct SledWrapper {
+    db: Db,
+    /// Tree holding the keys, the default tree or the tree of a namespace
+    tree: Tree,
+}
 
 
 impl SledWrapper {
     pub fn new(db: Db) -> Self {
-        Self(db)
+        Self { tree: (*db).clone(), db }
     }
 
     pub fn into_inner(self) -> Db {
-        self.0
+        self.db
     }
 
     pub fn as_inner(&self) -> &Db {
-        &self.0
+        &self.db
     }
 
-    pub fn is_empty(&self) -> Result<bool> {
-        Ok(self.0.get(SledKey::SnapshotStart.bytes())?.is_none())
+    pub fn remove_key(&self, key: &SledKey) -> Result<()> {
+        self.tree.remove(key.bytes())?;
+        Ok(())
     }
 
-    pub fn get_snapshot_start(&self) -> Result<usize> {
-        let buff = self.0.get(SledKey::SnapshotStart.bytes())?.unwrap();
-        Ok(u64::from_le_bytes(buff.as_ref().try_into().unwrap()) as usize)
+    fn get_usize(&self, key: SledKey) -> Result<usize> {
+        let buff = self.tree.get(key.bytes())?.unwrap();
+        Ok(decode_usize(&buff))
     }
 
-    pub fn set_snapshot_start(&self, snapshot_start: usize) -> Result<()> {
-        self.0.insert(SledKey::SnapshotStart.bytes(), &u64::to_le_bytes(snapshot_start as u64))?;
+    fn set_usize(&self, key: SledKey, value: usize) -> Result<()> {
+        self.tree.insert(key.bytes(), &encode_usize(value))?;
         Ok(())
     }
+}
 
-    pub fn get_snapshot_pending(&self) -> Result<usize> {
-        let buff = self.0.get(SledKey::SnapshotPending.bytes())?.unwrap();
-        Ok(u64::from_le_bytes(buff.as_ref().try_into().unwrap()) as usize)
+impl MetadataStore for SledWrapper {
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.tree.get(SledKey::SnapshotStart.bytes())?.is_none())
     }
 
-    pub fn set_snapshot_pending(&self, snapshot_pending: usize) -> Result<()> {
-        self.0.insert(SledKey::SnapshotPending.bytes(), &u64::to_le_bytes(snapshot_pending as u64))?;
-        Ok(())
+    fn get_snapshot_start(&self) -> Result<usize> {
+        self.get_usize(SledKey::SnapshotStart)
+    }
+
+    fn set_snapshot_start(&self, snapshot_start: usize) -> Result<()> {
+        self.set_usize(SledKey::SnapshotStart, snapshot_start)
+    }
+
+    fn get_snapshot_pending(&self) -> Result<usize> {
+        self.get_usize(SledKey::SnapshotPending)
+    }
+
+    fn set_snapshot_pending(&self, snapshot_pending: usize) -> Result<()> {
+        self.set_usize(SledKey::SnapshotPending, snapshot_pending)
+    }
+
+    fn get_num_slots(&self) -> Result<usize> {
+        self.get_usize(SledKey::NumSlots)
+    }
+
+    fn set_num_slots(&self, num_slots: usize) -> Result<()> {
+        self.set_usize(SledKey::NumSlots, num_slots)
     }
 
+    fn get_offset(&self, db_snapshot: usize,cluster_id: usize) -> Result<Option<usize>> {
+        let buff = self.tree.get(SledKey::OffsetTable(db_snapshot as u64,cluster_id as u64).bytes())?;
+        Ok(buff.map(|b| decode_usize(&b)))
+    }
+
+    fn set_offset(&self, db_snapshot: usize,cluster_id: usize, offset: usize) -> Result<()> {
+        self.set_usize(SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64), offset)
+    }
+
+    fn get_checksums(&self, slot: usize) -> Result<Option<Vec<u32>>> {
+        let buff = self.tree.get(SledKey::Checksum(slot as u64).bytes())?;
+        Ok(buff.map(|b| deserialize(&b).unwrap()))
+    }
 
-    pub fn get_num_slots(&self) -> Result<usize> {
-        let buff = self.0.get(SledKey::NumSlots.bytes())?.unwrap();
-        Ok(u64::from_le_bytes(buff.as_ref().try_into().unwrap()) as usize)
+    fn set_checksums(&self, slot: usize, checksums: &[u32]) -> Result<()> {
+        self.tree.insert(SledKey::Checksum(slot as u64).bytes(), serialize(checksums).unwrap())?;
+        Ok(())
+    }
+
+    fn get_generation(&self, slot: usize) -> Result<Option<u64>> {
+        let buff = self.tree.get(SledKey::Generation(slot as u64).bytes())?;
+        Ok(buff.map(|b| decode_u64(&b)))
     }
 
-    pub fn set_num_slots(&self, num_slots: usize) -> Result<()> {
-        self.0.insert(SledKey::NumSlots.bytes(), &u64::to_le_bytes(num_slots as u64))?;
+    fn set_generation(&self, slot: usize, generation: u64) -> Result<()> {
+        self.tree.insert(SledKey::Generation(slot as u64).bytes(), &generation.to_le_bytes())?;
         Ok(())
     }
 
-    pub fn get_offset(&self, db_snapshot: usize,cluster_id: usize) -> Result<Option<usize>> {
-        let buff = self.0.get(SledKey::OffsetTable(db_snapshot as u64,cluster_id as u64).bytes())?;
-        Ok(buff.map(|b| u64::from_le_bytes(b.as_ref().try_into().unwrap()) as usize))
+    fn get_generation_reserve(&self) -> Result<u64> {
+        let buff = self.tree.get(SledKey::GenerationReserve.bytes())?;
+        Ok(buff.map_or(0, |b| decode_u64(&b)))
     }
 
-    pub fn set_offset(&self, db_snapshot: usize,cluster_id: usize, offset: usize) -> Result<()> {
-        self.0.insert(SledKey::OffsetTable(db_snapshot as u64, cluster_id as u64).bytes(), &u64::to_le_bytes(offset as u64))?;
+    fn set_generation_reserve(&self, reserve: u64) -> Result<()> {
+        self.tree.insert(SledKey::GenerationReserve.bytes(), &reserve.to_le_bytes())?;
         Ok(())
     }
 
-    pub fn flush(&self) -> Result<()> {
-        self.0.flush()?;
+    fn get_key_check(&self) -> Result<Option<[u8; 32]>> {
+        let buff = self.tree.get(SledKey::KeyCheck.bytes())?;
+        Ok(buff.map(|b| b.as_ref().try_into().unwrap()))
+    }
+
+    fn set_key_check(&self, check: &[u8; 32]) -> Result<()> {
+        self.tree.insert(SledKey::KeyCheck.bytes(), check)?;
         Ok(())
     }
 
     // Search all offset table entries in db and return a iterator over all entries
-    pub fn offset_table_entries_iter(&self) -> impl Iterator<Item = (SledKey, usize)> {
+    fn offset_table_entries_iter(&self) -> Box<dyn Iterator<Item = (SledKey, usize)> + '_> {
         let prefix = SledKey::OffsetTable(0, 0).prefix_bytes();
 
-        self.0.scan_prefix(prefix).map(|e|{
+        Box::new(self.tree.scan_prefix(prefix).map(|e|{
             let (k,v) = e.unwrap();
-            (deserialize(&k).unwrap(), u64::from_le_bytes(v.as_ref().try_into().unwrap()) as usize)
-        })
-        
+            (deserialize(&k).unwrap(), decode_usize(&v))
+        }))
     }
 
-    pub fn remove_keys(&self, keys: &[SledKey]) -> Result<()> {
-        for key in keys {
-            self.0.remove(key.bytes())?;
+    // Iterate over all recorded slot checksums
+    fn checksum_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, Vec<u32>)> + '_> {
+        let prefix = SledKey::Checksum(0).prefix_bytes();
+
+        Box::new(self.tree.scan_prefix(prefix).map(|e| {
+            let (k, v) = e.unwrap();
+            match deserialize(&k).unwrap() {
+                SledKey::Checksum(slot) => (slot as usize, deserialize(&v).unwrap()),
+                key => unreachable!("unexpected key {:?}", key),
+            }
+        }))
+    }
+
+    // Iterate over all recorded slot generations
+    fn generation_entries_iter(&self) -> Box<dyn Iterator<Item = (usize, u64)> + '_> {
+        let prefix = SledKey::Generation(0).prefix_bytes();
+
+        Box::new(self.tree.scan_prefix(prefix).map(|e| {
+            let (k, v) = e.unwrap();
+            match deserialize(&k).unwrap() {
+                SledKey::Generation(slot) => (slot as usize, decode_u64(&v)),
+                key => unreachable!("unexpected key {:?}", key),
+            }
+        }))
+    }
+
+    fn namespace(&self, name: &str) -> Result<Box<dyn MetadataStore>> {
+        let tree = self.db.open_tree(format!("namespace/{}", name))?;
+        Ok(Box::new(Self { db: self.db.clone(), tree }))
+    }
+
+    fn apply_batch(&self, batch: MetadataBatch) -> Result<()> {
+        let mut sled_batch = Batch::default();
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Insert(key, value) => sled_batch.insert(key, value),
+                BatchOp::Remove(key) => sled_batch.remove(key),
+            }
         }
+        self.tree.apply_batch(sled_batch)?;
         Ok(())
     }
 
-    pub fn remove_key(&self, key: &SledKey) -> Result<()> {
-        self.0.remove(key.bytes())?;
+    fn flush(&self) -> Result<()> {
+        self.tree.flush()?;
         Ok(())
     }
-
 }
@@ -0,0 +1,70 @@
+//! Handles of live snapshots
+//!
+//! A [`Snapshot`] is obtained from [`SnapshotDb::get`], [`SnapshotDb::pending`] or
+//! [`SnapshotDb::latest_committed`]. While a handle exists, [`SnapshotDb::join_snapshot`] refuses to
+//! join its snapshot, so reads through the handle never hit reclaimed slots.
+
+use std::fmt;
+use std::io::Result;
+
+use bytes::Bytes;
+
+use crate::db::SnapshotDb;
+
+pub struct Snapshot<'a> {
+    db: &'a SnapshotDb,
+    id: usize,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Must be called while holding the offset table lock, after checking that the snapshot exists
+    pub(crate) fn new(db: &'a SnapshotDb, id: usize) -> Self {
+        db.pin(id);
+        Self { db, id }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Reads entire cluster data, see [`SnapshotDb::read`]
+    pub async fn read(&self, cluster_id: usize) -> Result<Vec<u8>> {
+        self.db.read(self.id, cluster_id).await
+    }
+
+    /// Reads entire cluster data without copying it, see [`SnapshotDb::read_bytes`]
+    pub async fn read_bytes(&self, cluster_id: usize) -> Result<Bytes> {
+        self.db.read_bytes(self.id, cluster_id).await
+    }
+
+    /// Reads a range of data from a cluster, see [`SnapshotDb::read_exact`]
+    pub async fn read_exact(&self, cluster_id: usize, from: usize, len: usize) -> Result<Vec<u8>> {
+        self.db.read_exact(self.id, cluster_id, from, len).await
+    }
+
+    /// Reads a range of data from a cluster verifying its checksums, see
+    /// [`SnapshotDb::read_exact_verified`]
+    pub async fn read_exact_verified(&self, cluster_id: usize, from: usize, len: usize) -> Result<Vec<u8>> {
+        self.db.read_exact_verified(self.id, cluster_id, from, len).await
+    }
+}
+
+impl Clone for Snapshot<'_> {
+    fn clone(&self) -> Self {
+        // The snapshot is pinned by `self`, so it is still alive
+        self.db.pin(self.id);
+        Self { db: self.db, id: self.id }
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.db.unpin(self.id);
+    }
+}
+
+impl fmt::Debug for Snapshot<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot").field("id", &self.id).finish()
+    }
+}
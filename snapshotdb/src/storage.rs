@@ -0,0 +1,143 @@
+//! Storage file I/O
+//!
+//! [`IoBackend`] selects how cluster data is read, written and synced:
+//! - blocking file I/O on tokio's blocking thread pool (default)
+//! - io_uring ([`crate::uring::UringFile`], `io-uring` feature, Linux only), optionally with
+//!   `O_DIRECT`, which requires the cluster size to be a multiple of
+//!   [`crate::uring::DIRECT_IO_ALIGNMENT`]
+//!
+//! Resizing and full syncs always go through the regular file handle.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::{UringFile, DIRECT_IO_ALIGNMENT};
+use crate::utils::{custom_sync_range, read_exact_at, write_all_at};
+
+/// Environment variable selecting the backend, see [`IoBackend::from_env`]
+pub const IO_BACKEND_ENV: &str = "SNAPSHOTDB_IO";
+
+/// Backend reading and writing the storage file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IoBackend {
+    #[default]
+    Blocking,
+    Uring {
+        /// Bypass the page cache with `O_DIRECT`
+        direct_io: bool,
+    },
+}
+
+impl IoBackend {
+    /// Backend named by the `SNAPSHOTDB_IO` environment variable, blocking I/O if it is not set
+    pub fn from_env() -> Result<Self> {
+        match std::env::var(IO_BACKEND_ENV) {
+            Ok(backend) => backend.parse(),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+impl FromStr for IoBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blocking" => Ok(Self::Blocking),
+            "uring" => Ok(Self::Uring { direct_io: false }),
+            "uring-direct" => Ok(Self::Uring { direct_io: true }),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown I/O backend {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Blocking => write!(f, "blocking"),
+            Self::Uring { direct_io: false } => write!(f, "uring"),
+            Self::Uring { direct_io: true } => write!(f, "uring-direct"),
+        }
+    }
+}
+
+/// Storage file of a database
+pub(crate) struct Storage {
+    file: Arc<File>,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<UringFile>,
+}
+
+impl Storage {
+    /// Opens (or creates) the storage file at `path`
+    pub fn open(path: impl AsRef<Path>, backend: IoBackend, cluster_size: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(&path)?;
+
+        match backend {
+            IoBackend::Blocking => Ok(Self {
+                file: Arc::new(file),
+                #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                uring: None,
+            }),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            IoBackend::Uring { direct_io } => {
+                if direct_io && !cluster_size.is_multiple_of(DIRECT_IO_ALIGNMENT) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("Direct I/O requires the cluster size to be a multiple of {}", DIRECT_IO_ALIGNMENT),
+                    ));
+                }
+                Ok(Self { file: Arc::new(file), uring: Some(UringFile::open(&path, direct_io)?) })
+            }
+            #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+            IoBackend::Uring { .. } => {
+                let _ = cluster_size;
+                Err(Error::new(ErrorKind::Unsupported, "snapshot-db was built without the `io-uring` feature"))
+            }
+        }
+    }
+
+    /// Regular file handle, for operations the backends have in common
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub async fn read_exact_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &self.uring {
+            return uring.read_exact_at(offset, len).await;
+        }
+        read_exact_at(self.file.clone(), offset, len).await
+    }
+
+    /// Writes the buffer at the offset. The buffer is copied before the returned future is created,
+    /// so the future can be spawned.
+    pub fn write_all_at(&self, buf: &[u8], offset: u64) -> Pin<Box<dyn Future<Output = Result<()>> + Send + 'static>> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &self.uring {
+            return Box::pin(uring.write_all_at(buf, offset));
+        }
+        Box::pin(write_all_at(self.file.clone(), buf, offset))
+    }
+
+    /// Waits until the data in the range is written to disk
+    pub async fn sync_range(&self, offset: u64, len: u64) -> Result<()> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &self.uring {
+            return uring.sync_range(offset, len).await;
+        }
+        custom_sync_range(self.file.clone(), offset, len).await
+    }
+}
@@ -0,0 +1,298 @@
+//! io_uring backend of the storage file (`io-uring` feature, Linux only)
+//!
+//! A dedicated thread owns the ring. Operations are sent to it together with their buffer, submitted
+//! in batches and answered through a oneshot channel, so no blocking thread is held per operation.
+//! Short transfers are resubmitted for the remaining bytes.
+//!
+//! With direct I/O the file is opened with `O_DIRECT`, bypassing the page cache. All transfers then
+//! go through buffers aligned to [`DIRECT_IO_ALIGNMENT`]; reads of unaligned ranges read the
+//! enclosing aligned range, writes must be aligned.
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use hashbrown::HashMap;
+use io_uring::{opcode, squeue, types, IoUring};
+use tokio::sync::oneshot;
+
+/// Alignment of offsets, lengths and buffers of direct I/O
+pub const DIRECT_IO_ALIGNMENT: usize = 4096;
+/// Size of the submission queue
+const RING_ENTRIES: u32 = 256;
+
+/// Heap buffer aligned to [`DIRECT_IO_ALIGNMENT`]
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// The buffer is uniquely owned, like a `Vec<u8>`
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn zeroed(len: usize) -> Self {
+        // Zero-sized allocations are not allowed
+        let layout = Self::layout(len.max(1));
+        let ptr = unsafe { alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, DIRECT_IO_ALIGNMENT).unwrap()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr.as_ptr(), Self::layout(self.len.max(1))) }
+    }
+}
+
+/// Buffer of an operation, owned by the ring thread while the operation is in flight
+enum Buffer {
+    Vec(Vec<u8>),
+    Aligned(AlignedBuf),
+}
+
+impl Buffer {
+    fn len(&self) -> usize {
+        match self {
+            Buffer::Vec(buf) => buf.len(),
+            Buffer::Aligned(buf) => buf.len(),
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            Buffer::Vec(buf) => buf,
+            Buffer::Aligned(buf) => buf,
+        }
+    }
+}
+
+enum Op {
+    Read { buf: Buffer, offset: u64 },
+    Write { buf: Buffer, offset: u64 },
+    SyncRange { offset: u64, len: u64 },
+}
+
+struct Request {
+    op: Op,
+    /// Bytes transferred so far
+    done: usize,
+    reply: oneshot::Sender<Result<Option<Buffer>>>,
+}
+
+impl Request {
+    /// Submission entry for the remaining part of the operation
+    fn entry(&mut self, fd: types::Fd) -> squeue::Entry {
+        let done = self.done;
+        match &mut self.op {
+            Op::Read { buf, offset } => {
+                let buf = &mut buf.as_mut_slice()[done..];
+                opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32).offset(*offset + done as u64).build()
+            }
+            Op::Write { buf, offset } => {
+                let buf = &buf.as_mut_slice()[done..];
+                opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32).offset(*offset + done as u64).build()
+            }
+            Op::SyncRange { offset, len } => {
+                // A length of 0 syncs up to the end of the file
+                opcode::SyncFileRange::new(fd, u32::try_from(*len).unwrap_or(0))
+                    .offset(*offset)
+                    .flags(libc::SYNC_FILE_RANGE_WAIT_BEFORE | libc::SYNC_FILE_RANGE_WRITE | libc::SYNC_FILE_RANGE_WAIT_AFTER)
+                    .build()
+            }
+        }
+    }
+
+    /// Total bytes to transfer
+    fn len(&self) -> usize {
+        match &self.op {
+            Op::Read { buf, .. } | Op::Write { buf, .. } => buf.len(),
+            Op::SyncRange { .. } => 0,
+        }
+    }
+
+    fn finish(self, result: Result<()>) {
+        let result = result.map(|()| match self.op {
+            Op::Read { buf, .. } => Some(buf),
+            Op::Write { .. } | Op::SyncRange { .. } => None,
+        });
+        // The caller may have given up waiting
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Storage file accessed through an io_uring
+pub struct UringFile {
+    direct_io: bool,
+    sender: flume::Sender<Request>,
+}
+
+impl UringFile {
+    /// Opens the file at `path`, which must exist, and starts the ring thread
+    pub fn open(path: impl AsRef<Path>, direct_io: bool) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        if direct_io {
+            options.custom_flags(libc::O_DIRECT);
+        }
+        let file = options.open(path)?;
+        let ring = IoUring::new(RING_ENTRIES)?;
+
+        let (sender, receiver) = flume::unbounded();
+        std::thread::Builder::new()
+            .name("snapshotdb-uring".to_string())
+            .spawn(move || run(ring, Arc::new(file), receiver))?;
+
+        Ok(Self { direct_io, sender })
+    }
+
+    pub async fn read_exact_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        if !self.direct_io {
+            return match self.send(Op::Read { buf: Buffer::Vec(vec![0u8; len]), offset }).await? {
+                Some(Buffer::Vec(buf)) => Ok(buf),
+                _ => unreachable!(),
+            };
+        }
+
+        let start = offset - offset % DIRECT_IO_ALIGNMENT as u64;
+        let end = (offset + len as u64).next_multiple_of(DIRECT_IO_ALIGNMENT as u64);
+        let buf = AlignedBuf::zeroed((end - start) as usize);
+        match self.send(Op::Read { buf: Buffer::Aligned(buf), offset: start }).await? {
+            Some(Buffer::Aligned(buf)) => {
+                let from = (offset - start) as usize;
+                Ok(buf[from..from + len].to_vec())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Writes the buffer at the offset. The write is submitted right away, the returned future only
+    /// waits for its completion.
+    pub fn write_all_at(&self, buf: &[u8], offset: u64) -> impl Future<Output = Result<()>> + Send + 'static {
+        let write = self.write_buffer(buf, offset).map(|buf| self.send(Op::Write { buf, offset }));
+        async move { write?.await.map(|_| ()) }
+    }
+
+    pub async fn sync_range(&self, offset: u64, len: u64) -> Result<()> {
+        self.send(Op::SyncRange { offset, len }).await.map(|_| ())
+    }
+
+    /// Copy of the data to write, aligned for direct I/O
+    fn write_buffer(&self, buf: &[u8], offset: u64) -> Result<Buffer> {
+        if !self.direct_io {
+            return Ok(Buffer::Vec(buf.to_vec()));
+        }
+        if !offset.is_multiple_of(DIRECT_IO_ALIGNMENT as u64) || !buf.len().is_multiple_of(DIRECT_IO_ALIGNMENT) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Direct I/O writes must be aligned"));
+        }
+        let mut aligned = AlignedBuf::zeroed(buf.len());
+        aligned.copy_from_slice(buf);
+        Ok(Buffer::Aligned(aligned))
+    }
+
+    fn send(&self, op: Op) -> impl Future<Output = Result<Option<Buffer>>> + Send + 'static {
+        let (reply, response) = oneshot::channel();
+        let sent = self.sender.send(Request { op, done: 0, reply }).is_ok();
+        async move {
+            if !sent {
+                return Err(ring_stopped());
+            }
+            response.await.map_err(|_| ring_stopped())?
+        }
+    }
+}
+
+fn ring_stopped() -> Error {
+    Error::other("io_uring thread stopped")
+}
+
+/// Ring thread: submits requests as they come in and answers them on completion. Exits once all
+/// senders are gone and no operation is in flight.
+fn run(mut ring: IoUring, file: Arc<File>, receiver: flume::Receiver<Request>) {
+    let fd = types::Fd(file.as_raw_fd());
+    let mut queued = VecDeque::new();
+    let mut in_flight: HashMap<u64, Request> = HashMap::new();
+    let mut next_id = 0u64;
+
+    loop {
+        if in_flight.is_empty() && queued.is_empty() {
+            match receiver.recv() {
+                Ok(request) => queued.push_back(request),
+                Err(_) => return,
+            }
+        }
+        queued.extend(receiver.try_iter());
+
+        while in_flight.len() < RING_ENTRIES as usize {
+            let Some(mut request) = queued.pop_front() else {
+                break;
+            };
+            let entry = request.entry(fd).user_data(next_id);
+            // The buffer lives on the heap, so moving the request into the map keeps it in place
+            unsafe { ring.submission().push(&entry).expect("submission queue is full") };
+            in_flight.insert(next_id, request);
+            next_id = next_id.wrapping_add(1);
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            // The kernel may still use the buffers of the submitted operations, so they must not be
+            // freed
+            Err(err) => {
+                std::mem::forget(in_flight);
+                panic!("io_uring submission failed: {}", err);
+            }
+        }
+
+        let completions = ring.completion().map(|cqe| (cqe.user_data(), cqe.result())).collect::<Vec<_>>();
+        for (id, result) in completions {
+            let mut request = in_flight.remove(&id).unwrap();
+            if result == -libc::EINTR || result == -libc::EAGAIN {
+                queued.push_front(request);
+            } else if result < 0 {
+                request.finish(Err(Error::from_raw_os_error(-result)));
+            } else if matches!(request.op, Op::SyncRange { .. }) {
+                request.finish(Ok(()));
+            } else if result == 0 {
+                let kind = if matches!(request.op, Op::Read { .. }) { ErrorKind::UnexpectedEof } else { ErrorKind::WriteZero };
+                request.finish(Err(Error::from(kind)));
+            } else {
+                request.done += result as usize;
+                if request.done < request.len() {
+                    queued.push_front(request);
+                } else {
+                    request.finish(Ok(()));
+                }
+            }
+        }
+    }
+}
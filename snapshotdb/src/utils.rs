@@ -1,5 +1,6 @@
 use std::io::Result;
 use std::fs::File;
+use std::future::Future;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 #[cfg(target_family = "unix")]
@@ -70,18 +71,20 @@ pub async fn read_exact_at(file: Arc<File>, offset: u64, len: usize) -> Result<V
     }
 }
 
-pub async fn write_all_at(file: Arc<File>, buf: &[u8], offset: u64) -> Result<()> {
+/// Writes the buffer at the offset. The buffer is copied before the returned future is created, so
+/// the future can be spawned.
+pub fn write_all_at(file: Arc<File>, buf: &[u8], offset: u64) -> impl Future<Output = Result<()>> + Send + 'static {
     #[cfg(target_family = "unix")]
     {
         let buf = buf.to_vec();
         asyncify(move || {
             file.write_all_at(&buf, offset)?;
             Ok(())
-        }).await
+        })
     }
 
     #[cfg(not(target_family = "unix"))]
     {
-        unimplemented!("write_at is only implemented for Unix systems")
+        async { unimplemented!("write_at is only implemented for Unix systems") }
     }
-}
\ No newline at end of file
+}
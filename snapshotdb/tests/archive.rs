@@ -0,0 +1,96 @@
+//! Full and differential export/import between databases.
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+
+mod common;
+
+use common::{snapshot_data, CLUSTER_SIZE, CONFIG, NUM_CLUSTERS};
+
+async fn export_to_file(db: &SnapshotDb, base: Option<usize>, snapshot: usize, path: &Path) -> Vec<usize> {
+    let mut file = tokio::fs::File::create(path).await.unwrap();
+    db.export(base, snapshot, &mut file).await.unwrap().clusters
+}
+
+async fn import_from_file(db: &SnapshotDb, path: &Path) -> std::io::Result<Vec<usize>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    Ok(db.import(&mut file).await?.clusters)
+}
+
+async fn assert_same(a: &SnapshotDb, a_snapshot: usize, b: &SnapshotDb, b_snapshot: usize) {
+    for cluster_id in 0..NUM_CLUSTERS {
+        assert_eq!(
+            a.read(a_snapshot, cluster_id).await.unwrap(),
+            b.read(b_snapshot, cluster_id).await.unwrap(),
+            "cluster {}",
+            cluster_id
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_full_and_differential_export() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = SnapshotDb::new(dir.path().join("source"), CONFIG).await.unwrap();
+
+    // Snapshot 1 writes half of the clusters, snapshot 2 a few, snapshot 3 is pending
+    for cluster_id in 0..NUM_CLUSTERS / 2 {
+        source.write(cluster_id, &snapshot_data(1, cluster_id)).await.unwrap();
+    }
+    source.add_snapshot().await.unwrap();
+    for cluster_id in [3, 9, 12] {
+        source.write(cluster_id, &snapshot_data(2, cluster_id)).await.unwrap();
+    }
+    source.add_snapshot().await.unwrap();
+    source.join_snapshot().await.unwrap();
+
+    let full = dir.path().join("full.archive");
+    let diff = dir.path().join("diff.archive");
+    assert_eq!(export_to_file(&source, None, 1, &full).await.len(), NUM_CLUSTERS);
+    assert_eq!(export_to_file(&source, Some(1), 2, &diff).await, vec![3, 9, 12]);
+    assert_eq!(source.changed_clusters(2, Some(0)).await.unwrap(), vec![0, 1, 2, 3, 4, 5, 6, 7, 9, 12]);
+
+    let target = SnapshotDb::new(dir.path().join("target"), CONFIG).await.unwrap();
+    import_from_file(&target, &full).await.unwrap();
+    assert_same(&source, 1, &target, 1).await;
+
+    target.add_snapshot().await.unwrap();
+    assert_eq!(import_from_file(&target, &diff).await.unwrap(), vec![3, 9, 12]);
+    assert_same(&source, 1, &target, 1).await;
+    assert_same(&source, 2, &target, 2).await;
+}
+
+#[tokio::test]
+async fn test_import_rejects_invalid_archives() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = SnapshotDb::new(dir.path().join("source"), CONFIG).await.unwrap();
+    source.write(5, &snapshot_data(1, 5)).await.unwrap();
+    source.add_snapshot().await.unwrap();
+
+    let mut archive = Vec::new();
+    source.export(Some(0), 1, &mut archive).await.unwrap();
+    assert!(source.export(Some(1), 1, &mut Vec::new()).await.is_err());
+
+    let target = SnapshotDb::new(dir.path().join("target"), CONFIG).await.unwrap();
+
+    // Flipped bit in the cluster data
+    let mut corrupted = archive.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert_eq!(target.import(&mut &corrupted[..]).await.unwrap_err().kind(), ErrorKind::InvalidData);
+
+    // Truncated archive
+    let truncated = &archive[..archive.len() - 1];
+    assert_eq!(target.import(&mut &truncated[..]).await.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+    // Other layout
+    let other = SnapshotDb::new(dir.path().join("other"), SnapshotDbConfig { num_clusters: NUM_CLUSTERS * 2, ..CONFIG })
+        .await
+        .unwrap();
+    assert_eq!(other.import(&mut &archive[..]).await.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    assert_eq!(target.read(1, 5).await.unwrap(), vec![0; CLUSTER_SIZE]);
+    target.import(&mut &archive[..]).await.unwrap();
+    assert_eq!(target.read(1, 5).await.unwrap(), snapshot_data(1, 5));
+}
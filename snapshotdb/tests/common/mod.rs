@@ -0,0 +1,31 @@
+//! Database configuration and cluster contents shared by the integration tests. Tests override
+//! the fields of [`CONFIG`] they exercise.
+#![allow(dead_code)]
+
+use snapshot_db::db::SnapshotDbConfig;
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::storage::IoBackend;
+
+pub const CLUSTER_SIZE: usize = 4096;
+pub const NUM_CLUSTERS: usize = 16;
+
+pub const CONFIG: SnapshotDbConfig = SnapshotDbConfig {
+    num_clusters: NUM_CLUSTERS,
+    cluster_size: CLUSTER_SIZE,
+    checksum_block_size: Some(1024),
+    metadata_backend: MetadataBackend::Sled,
+    io_backend: IoBackend::Blocking,
+    encryption_key: None,
+    mmap_reads: false,
+};
+
+/// Random cluster contents, the same for the same seed
+pub fn cluster_data(seed: usize) -> Vec<u8> {
+    let mut rng = fastrand::Rng::with_seed(seed as u64);
+    (0..CLUSTER_SIZE).map(|_| rng.u8(..)).collect()
+}
+
+/// Contents written to a cluster in a snapshot, different for every pair
+pub fn snapshot_data(snapshot: usize, cluster_id: usize) -> Vec<u8> {
+    cluster_data(snapshot * NUM_CLUSTERS + cluster_id)
+}
@@ -0,0 +1,255 @@
+//! Crash-consistency harness: interrupts database operations at every failpoint, reopens the
+//! database and checks that it recovers to a consistent state.
+//!
+//! Run with `cargo test --features failpoints`.
+
+use std::path::Path;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::failpoints;
+use snapshot_db::fsck;
+use snapshot_db::metadata::{self, MetadataBackend};
+
+mod common;
+
+use common::{snapshot_data, CLUSTER_SIZE, CONFIG, NUM_CLUSTERS};
+
+/// Cluster written by the interrupted write
+const TARGET_CLUSTER: usize = 0;
+/// Clusters written by the interrupted batch write, one overwritten in snapshot 2 and one inherited
+const BATCH_CLUSTERS: [usize; 2] = [TARGET_CLUSTER, NUM_CLUSTERS - 1];
+
+/// Expected contents of every cluster in snapshots 0, 1 and 2 after `setup`
+fn expected(snapshot: usize, cluster_id: usize) -> Vec<u8> {
+    match snapshot {
+        0 => vec![0; CLUSTER_SIZE],
+        1 => snapshot_data(1, cluster_id),
+        _ if cluster_id < NUM_CLUSTERS / 2 => snapshot_data(2, cluster_id),
+        _ => snapshot_data(1, cluster_id),
+    }
+}
+
+/// Snapshot 1 has all clusters written and snapshot 2 (pending) overwrites half of them. The target
+/// cluster is overwritten a few times first, leaving free slots for compaction to fill.
+async fn setup(path: &Path) -> SnapshotDb {
+    let db = SnapshotDb::new(path, CONFIG).await.unwrap();
+    for snapshot in 5..10 {
+        db.write(TARGET_CLUSTER, &snapshot_data(snapshot, TARGET_CLUSTER)).await.unwrap();
+    }
+    for cluster_id in 0..NUM_CLUSTERS {
+        db.write(cluster_id, &snapshot_data(1, cluster_id)).await.unwrap();
+    }
+    db.add_snapshot().await.unwrap();
+    for cluster_id in 0..NUM_CLUSTERS / 2 {
+        db.write(cluster_id, &snapshot_data(2, cluster_id)).await.unwrap();
+    }
+    db
+}
+
+/// Runs the operation interrupted by the failpoint
+async fn interrupted_operation(db: &SnapshotDb, failpoint: &str) -> std::io::Result<()> {
+    match failpoint {
+        _ if failpoint.starts_with("write::") => db.write(TARGET_CLUSTER, &snapshot_data(3, TARGET_CLUSTER)).await,
+        _ if failpoint.starts_with("write_batch::") => {
+            let data = BATCH_CLUSTERS.map(|cluster_id| snapshot_data(3, cluster_id));
+            let writes = BATCH_CLUSTERS.iter().zip(&data).map(|(&cluster_id, data)| (cluster_id, &data[..])).collect::<Vec<_>>();
+            db.write_batch(&writes).await
+        }
+        _ if failpoint.starts_with("delete::") => db.delete(TARGET_CLUSTER).await,
+        _ if failpoint.starts_with("add_snapshot::") => db.add_snapshot().await,
+        _ if failpoint.starts_with("join_snapshot::") => db.join_snapshot().await,
+        _ if failpoint.starts_with("compact::") => db.compact(false).await.map(|_| ()),
+        _ => unreachable!("unknown failpoint {}", failpoint),
+    }
+}
+
+async fn check_recovery(path: &Path, failpoint: &str) {
+    let report = fsck::check(path, &CONFIG).unwrap();
+    assert!(report.double_referenced.is_empty(), "{}: {:?}", failpoint, report);
+    assert!(report.out_of_range.is_empty(), "{}: {:?}", failpoint, report);
+    assert!(report.missing_clusters.is_empty(), "{}: {:?}", failpoint, report);
+
+    // Leftovers of the interrupted operation are harmless, but fsck must be able to clean them up
+    fsck::repair(path, &CONFIG).unwrap();
+    let report = fsck::check(path, &CONFIG).unwrap();
+    assert!(report.is_clean(), "{}: {:?}", failpoint, report);
+
+    let interrupted_clusters: &[usize] = match failpoint {
+        _ if failpoint.starts_with("write::") || failpoint.starts_with("delete::") => &[TARGET_CLUSTER],
+        _ if failpoint.starts_with("write_batch::") => &BATCH_CLUSTERS,
+        _ => &[],
+    };
+
+    let db = SnapshotDb::new(path, CONFIG).await.unwrap();
+    let mut completed_writes = Vec::new();
+    for snapshot in report.snapshot_start..=report.snapshot_pending {
+        for cluster_id in 0..NUM_CLUSTERS {
+            let data = db.read(snapshot, cluster_id).await.unwrap();
+            let expected = expected(snapshot.min(2), cluster_id);
+
+            // An interrupted write or delete is either lost or complete
+            if snapshot == 2 && interrupted_clusters.contains(&cluster_id) {
+                let completed = match failpoint {
+                    _ if failpoint.starts_with("delete::") => vec![0; CLUSTER_SIZE],
+                    _ => snapshot_data(3, cluster_id),
+                };
+                assert!(data == expected || data == completed, "{}: torn write", failpoint);
+                completed_writes.push(data != expected);
+            } else {
+                assert_eq!(data, expected, "{}: snapshot {} cluster {}", failpoint, snapshot, cluster_id);
+            }
+        }
+    }
+    // ... and so are all writes of an interrupted batch
+    assert!(completed_writes.windows(2).all(|w| w[0] == w[1]), "{}: partial batch", failpoint);
+
+    // The allocator must not hand out slots that are still in use
+    let pending = report.snapshot_pending;
+    for cluster_id in 0..NUM_CLUSTERS {
+        db.write(cluster_id, &snapshot_data(4, cluster_id)).await.unwrap();
+    }
+    for cluster_id in 0..NUM_CLUSTERS {
+        assert_eq!(db.read(pending, cluster_id).await.unwrap(), snapshot_data(4, cluster_id), "{}", failpoint);
+        if report.snapshot_start <= 1 {
+            assert_eq!(db.read(1, cluster_id).await.unwrap(), expected(1, cluster_id), "{}", failpoint);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_recovery_at_every_failpoint() {
+    for &failpoint in failpoints::ALL {
+        let dir = tempfile::tempdir().unwrap();
+
+        {
+            let db = setup(dir.path()).await;
+            db.failpoints().arm(failpoint);
+            assert!(interrupted_operation(&db, failpoint).await.is_err(), "{} was not hit", failpoint);
+        }
+
+        check_recovery(dir.path(), failpoint).await;
+    }
+}
+
+#[tokio::test]
+async fn test_fsck_repairs_double_reference() {
+    let dir = tempfile::tempdir().unwrap();
+    drop(setup(dir.path()).await);
+
+    // Point cluster 1 of snapshot 1 to the slot of cluster 2
+    {
+        let db = metadata::open(dir.path(), CONFIG.metadata_backend).unwrap();
+        let slot = db.get_offset(1, 2).unwrap().unwrap();
+        db.set_offset(1, 1, slot).unwrap();
+        db.flush().unwrap();
+    }
+
+    let report = fsck::check(dir.path(), &CONFIG).unwrap();
+    assert_eq!(report.double_referenced.len(), 1);
+
+    fsck::repair(dir.path(), &CONFIG).unwrap();
+    assert!(fsck::check(dir.path(), &CONFIG).unwrap().is_clean());
+
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+    db.write(1, &snapshot_data(5, 1)).await.unwrap();
+    assert_eq!(db.read(1, 2).await.unwrap(), snapshot_data(1, 2));
+    assert_eq!(db.read(2, 1).await.unwrap(), snapshot_data(5, 1));
+}
+
+#[tokio::test]
+async fn test_write_batch() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = setup(dir.path()).await;
+
+    // Cluster 1 appears twice, the last write wins
+    let data = [(1, snapshot_data(3, 1)), (2, snapshot_data(3, 2)), (1, snapshot_data(4, 1)), (6, snapshot_data(3, 6))];
+    let writes = data.iter().map(|(cluster_id, data)| (*cluster_id, &data[..])).collect::<Vec<_>>();
+    db.write_batch(&writes).await.unwrap();
+    db.write_batch(&[]).await.unwrap();
+    assert!(db.write_batch(&[(3, &[0u8; 16][..])]).await.is_err());
+    drop(db);
+
+    let report = fsck::check(dir.path(), &CONFIG).unwrap();
+    assert!(report.double_referenced.is_empty() && report.out_of_range.is_empty(), "{:?}", report);
+
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+    for cluster_id in 0..NUM_CLUSTERS {
+        assert_eq!(db.read(1, cluster_id).await.unwrap(), expected(1, cluster_id));
+        let expected = match cluster_id {
+            1 => snapshot_data(4, 1),
+            2 | 6 => snapshot_data(3, cluster_id),
+            _ => expected(2, cluster_id),
+        };
+        assert_eq!(db.read(2, cluster_id).await.unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = setup(dir.path()).await;
+    db.join_snapshot().await.unwrap();
+
+    let report = db.compact(true).await.unwrap();
+    assert!(report.moved_slots > 0 && report.reclaimable_bytes > 0, "{:?}", report);
+    assert_eq!(std::fs::metadata(dir.path().join("storage")).unwrap().len(), report.file_size);
+
+    let compacted = db.compact(false).await.unwrap();
+    assert_eq!(compacted.reclaimable_bytes, report.reclaimable_bytes);
+    assert_eq!(
+        std::fs::metadata(dir.path().join("storage")).unwrap().len(),
+        report.file_size - report.reclaimable_bytes
+    );
+    let report = db.compact(true).await.unwrap();
+    assert!(report.moved_slots == 0 && report.stale_keys == 0 && report.reclaimable_bytes == 0, "{:?}", report);
+
+    for snapshot in 1..=2 {
+        for cluster_id in 0..NUM_CLUSTERS {
+            assert_eq!(db.read(snapshot, cluster_id).await.unwrap(), expected(snapshot, cluster_id));
+        }
+    }
+
+    // Freed slots are reused without touching live ones
+    db.write(TARGET_CLUSTER, &snapshot_data(4, TARGET_CLUSTER)).await.unwrap();
+    assert_eq!(db.read(1, TARGET_CLUSTER).await.unwrap(), expected(1, TARGET_CLUSTER));
+    drop(db);
+
+    let report = fsck::check(dir.path(), &CONFIG).unwrap();
+    assert!(report.double_referenced.is_empty() && report.out_of_range.is_empty(), "{:?}", report);
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+    assert_eq!(db.read(2, TARGET_CLUSTER).await.unwrap(), snapshot_data(4, TARGET_CLUSTER));
+    assert_eq!(db.read(1, 1).await.unwrap(), expected(1, 1));
+}
+
+#[cfg(feature = "redb")]
+#[tokio::test]
+async fn test_migrate_to_redb() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = setup(dir.path()).await;
+    db.join_snapshot().await.unwrap();
+    drop(db);
+    let sled_report = fsck::check(dir.path(), &CONFIG).unwrap();
+
+    {
+        let from = metadata::open(dir.path(), MetadataBackend::Sled).unwrap();
+        let to = metadata::open(dir.path(), MetadataBackend::Redb).unwrap();
+        metadata::migrate(from.as_ref(), to.as_ref()).unwrap();
+        assert!(metadata::migrate(from.as_ref(), to.as_ref()).is_err());
+    }
+
+    let config = SnapshotDbConfig { metadata_backend: MetadataBackend::Redb, ..CONFIG };
+    let report = fsck::check(dir.path(), &config).unwrap();
+    assert_eq!(format!("{:?}", report), format!("{:?}", sled_report));
+
+    let db = SnapshotDb::new(dir.path(), config).await.unwrap();
+    for cluster_id in 0..NUM_CLUSTERS {
+        assert_eq!(db.read(2, cluster_id).await.unwrap(), expected(2, cluster_id));
+    }
+    db.write(TARGET_CLUSTER, &snapshot_data(4, TARGET_CLUSTER)).await.unwrap();
+    db.compact(false).await.unwrap();
+    drop(db);
+
+    let db = SnapshotDb::new(dir.path(), config).await.unwrap();
+    assert_eq!(db.read(2, TARGET_CLUSTER).await.unwrap(), snapshot_data(4, TARGET_CLUSTER));
+    assert_eq!(db.read(1, 1).await.unwrap(), expected(1, 1));
+}
@@ -0,0 +1,65 @@
+//! Database operations on the io_uring storage backend, buffered and with direct I/O.
+#![cfg(all(feature = "io-uring", target_os = "linux"))]
+
+use std::path::Path;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::storage::IoBackend;
+
+mod common;
+
+use common::{cluster_data, CONFIG, NUM_CLUSTERS};
+
+fn config(io_backend: IoBackend) -> SnapshotDbConfig {
+    SnapshotDbConfig { io_backend, ..CONFIG }
+}
+
+async fn check_backend(path: &Path, io_backend: IoBackend) {
+    {
+        let db = SnapshotDb::new(path, config(io_backend)).await.unwrap();
+        for cluster_id in 0..NUM_CLUSTERS {
+            db.write(cluster_id, &cluster_data(cluster_id)).await.unwrap();
+        }
+        db.add_snapshot().await.unwrap();
+
+        let data = (0..4).map(|cluster_id| cluster_data(NUM_CLUSTERS + cluster_id)).collect::<Vec<_>>();
+        let writes = data.iter().enumerate().map(|(cluster_id, data)| (cluster_id, &data[..])).collect::<Vec<_>>();
+        db.write_batch(&writes).await.unwrap();
+
+        // Unaligned reads
+        assert_eq!(db.read_exact(1, 5, 100, 1000).await.unwrap(), cluster_data(5)[100..1100]);
+        assert_eq!(db.read_exact_verified(2, 2, 3000, 1096).await.unwrap(), cluster_data(NUM_CLUSTERS + 2)[3000..]);
+
+        db.join_snapshot().await.unwrap();
+        db.compact(false).await.unwrap();
+    }
+
+    // Reopen with the blocking backend, which must see the same data
+    let db = SnapshotDb::new(path, config(IoBackend::Blocking)).await.unwrap();
+    for cluster_id in 0..NUM_CLUSTERS {
+        let seed = if cluster_id < 4 { NUM_CLUSTERS + cluster_id } else { cluster_id };
+        assert_eq!(db.read(2, cluster_id).await.unwrap(), cluster_data(seed), "{} cluster {}", io_backend, cluster_id);
+    }
+}
+
+#[tokio::test]
+async fn test_uring() {
+    let dir = tempfile::tempdir().unwrap();
+    check_backend(dir.path(), IoBackend::Uring { direct_io: false }).await;
+}
+
+#[tokio::test]
+async fn test_uring_direct_io() {
+    let dir = tempfile::tempdir().unwrap();
+    let io_backend = IoBackend::Uring { direct_io: true };
+
+    // Some file systems, like tmpfs, do not support O_DIRECT
+    if let Err(err) = SnapshotDb::new(dir.path(), config(io_backend)).await {
+        eprintln!("skipping direct I/O test: {}", err);
+        return;
+    }
+    check_backend(dir.path(), io_backend).await;
+
+    let result = SnapshotDb::new(dir.path().join("unaligned"), SnapshotDbConfig { cluster_size: 1000, ..config(io_backend) }).await;
+    assert_eq!(result.err().map(|err| err.kind()), Some(std::io::ErrorKind::InvalidInput));
+}
@@ -0,0 +1,85 @@
+//! Zero-copy reads from memory mappings and the leases keeping their slots alive.
+
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::error::DbError;
+
+mod common;
+
+use common::{cluster_data, CLUSTER_SIZE, NUM_CLUSTERS};
+
+const CONFIG: SnapshotDbConfig = SnapshotDbConfig { mmap_reads: true, ..common::CONFIG };
+
+#[tokio::test]
+async fn test_read_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+
+    db.write(0, &cluster_data(0)).await.unwrap();
+    assert_eq!(db.read_bytes(1, 0).await.unwrap(), cluster_data(0));
+    assert_eq!(db.pending().await.read_bytes(1).await.unwrap(), vec![0; CLUSTER_SIZE]);
+    assert_eq!(db.read_bytes(2, 0).await.err().map(|err| DbError::from_io(&err).cloned()), Some(Some(DbError::UnknownSnapshot(2))));
+
+    // The copying path returns the same data
+    let copying = SnapshotDb::new(dir.path().join("copying"), SnapshotDbConfig { mmap_reads: false, ..CONFIG }).await.unwrap();
+    copying.write(0, &cluster_data(0)).await.unwrap();
+    assert_eq!(copying.read_bytes(1, 0).await.unwrap(), cluster_data(0));
+
+    // Corruption is caught like on copying reads
+    let slot = std::fs::read(dir.path().join("storage")).unwrap().chunks(CLUSTER_SIZE).position(|chunk| chunk == cluster_data(0)).unwrap();
+    let storage = std::fs::OpenOptions::new().write(true).open(dir.path().join("storage")).unwrap();
+    storage.write_all_at(&[0xff; 16], (slot * CLUSTER_SIZE + 2048) as u64).unwrap();
+    let err = db.read_bytes(1, 0).await.err().unwrap();
+    assert_eq!(DbError::from_io(&err), Some(&DbError::ChecksumMismatch { slot, block: 2 }));
+}
+
+fn storage_slots(path: &Path) -> u64 {
+    std::fs::metadata(path.join("storage")).unwrap().len() / CLUSTER_SIZE as u64
+}
+
+#[tokio::test]
+async fn test_mapped_slots_outlive_their_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+
+    let data = (0..NUM_CLUSTERS).map(cluster_data).collect::<Vec<_>>();
+    let writes = data.iter().enumerate().map(|(cluster_id, data)| (cluster_id, &data[..])).collect::<Vec<_>>();
+    db.write_batch(&writes).await.unwrap();
+    db.add_snapshot().await.unwrap();
+    let mapped = db.read_bytes(1, NUM_CLUSTERS - 1).await.unwrap();
+
+    // Drop every snapshot referencing the upper half of the slots, including the mapped one
+    for cluster_id in NUM_CLUSTERS / 2..NUM_CLUSTERS {
+        db.delete(cluster_id).await.unwrap();
+    }
+    db.join_snapshot().await.unwrap();
+    db.add_snapshot().await.unwrap();
+    db.join_snapshot().await.unwrap();
+
+    // Compaction keeps the storage file up to the mapped slot
+    let report = db.compact(false).await.unwrap();
+    assert_eq!(report.live_slots, NUM_CLUSTERS / 2);
+    assert_eq!(storage_slots(dir.path()), NUM_CLUSTERS as u64);
+    assert_eq!(mapped, cluster_data(NUM_CLUSTERS - 1));
+
+    // New writes do not reuse the mapped slot
+    for cluster_id in NUM_CLUSTERS / 2..NUM_CLUSTERS {
+        db.write(cluster_id, &cluster_data(NUM_CLUSTERS + cluster_id)).await.unwrap();
+    }
+    assert_eq!(mapped, cluster_data(NUM_CLUSTERS - 1));
+
+    // Once the mapping is dropped, the slot is reclaimed
+    drop(mapped);
+    for cluster_id in NUM_CLUSTERS / 2..NUM_CLUSTERS {
+        db.delete(cluster_id).await.unwrap();
+    }
+    db.add_snapshot().await.unwrap();
+    db.join_snapshot().await.unwrap();
+    db.compact(false).await.unwrap();
+    assert_eq!(storage_slots(dir.path()), NUM_CLUSTERS as u64 / 2);
+    for cluster_id in 0..NUM_CLUSTERS / 2 {
+        assert_eq!(db.read_bytes(3, cluster_id).await.unwrap(), cluster_data(cluster_id));
+    }
+}
@@ -0,0 +1,141 @@
+//! Namespaces with their own cluster layouts in one store, sharing the snapshots.
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use snapshot_db::encryption::EncryptionKey;
+use snapshot_db::error::DbError;
+use snapshot_db::metadata::MetadataBackend;
+use snapshot_db::namespaces::{NamespaceConfig, SnapshotStore, SnapshotStoreConfig};
+use snapshot_db::storage::IoBackend;
+
+fn namespace(name: &str, num_clusters: usize, cluster_size: usize) -> NamespaceConfig {
+    NamespaceConfig { name: name.to_string(), num_clusters, cluster_size, checksum_block_size: Some(256) }
+}
+
+fn config(namespaces: Vec<NamespaceConfig>) -> SnapshotStoreConfig {
+    SnapshotStoreConfig {
+        namespaces,
+        metadata_backend: MetadataBackend::Sled,
+        io_backend: IoBackend::Blocking,
+        encryption_key: None,
+        mmap_reads: false,
+    }
+}
+
+fn shards_and_proofs() -> Vec<NamespaceConfig> {
+    vec![namespace("shards", 4, 4096), namespace("proofs", 16, 512)]
+}
+
+async fn open(path: &Path, namespaces: Vec<NamespaceConfig>) -> SnapshotStore {
+    SnapshotStore::new(path, config(namespaces)).await.unwrap()
+}
+
+#[tokio::test]
+async fn test_namespaces() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let store = open(dir.path(), shards_and_proofs()).await;
+        let shards = store.namespace("shards").unwrap();
+        let proofs = store.namespace("proofs").unwrap();
+
+        shards.write(0, &[1; 4096]).await.unwrap();
+        proofs.write(15, &[2; 512]).await.unwrap();
+        assert_eq!(proofs.write(0, &[2; 4096]).await.err().map(|err| err.kind()), Some(ErrorKind::InvalidInput));
+        assert_eq!(proofs.write(16, &[2; 512]).await.err().map(|err| err.kind()), Some(ErrorKind::InvalidInput));
+
+        store.add_snapshot().await.unwrap();
+        shards.write(0, &[3; 4096]).await.unwrap();
+        assert_eq!(store.snapshots().await, 0..=2);
+        assert_eq!(shards.snapshots().await, proofs.snapshots().await);
+
+        let err = store.namespace("sealed").err().unwrap();
+        assert_eq!(DbError::from_io(&err), Some(&DbError::UnknownNamespace("sealed".to_string())));
+    }
+
+    // Every namespace keeps its data and layout after a restart
+    let store = open(dir.path(), shards_and_proofs()).await;
+    let shards = store.namespace("shards").unwrap();
+    let proofs = store.namespace("proofs").unwrap();
+    assert_eq!(shards.read(1, 0).await.unwrap(), vec![1; 4096]);
+    assert_eq!(shards.read(2, 0).await.unwrap(), vec![3; 4096]);
+    assert_eq!(proofs.read(2, 15).await.unwrap(), vec![2; 512]);
+    assert_eq!(proofs.read(2, 0).await.unwrap(), vec![0; 512]);
+
+    store.join_snapshot().await.unwrap();
+    assert_eq!(store.snapshots().await, 1..=2);
+
+    let reports = store.compact(true).await.unwrap();
+    assert_eq!(reports.keys().collect::<Vec<_>>(), ["proofs", "shards"]);
+    assert_eq!(reports["proofs"].live_slots, 1);
+    assert_eq!(reports["shards"].live_slots, 2);
+}
+
+#[tokio::test]
+async fn test_catch_up() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let store = open(dir.path(), shards_and_proofs()).await;
+        for _ in 0..3 {
+            store.add_snapshot().await.unwrap();
+        }
+        store.join_snapshot().await.unwrap();
+
+        // A namespace left behind, like after a crash in the middle of a snapshot change
+        store.namespace("shards").unwrap().add_snapshot().await.unwrap();
+        assert_eq!(store.snapshots().await, 1..=4);
+
+        store.add_snapshot().await.unwrap();
+        assert_eq!(store.namespace("proofs").unwrap().snapshots().await, 1..=6);
+        assert_eq!(store.namespace("shards").unwrap().snapshots().await, 1..=6);
+
+        // A handle blocks the join in one namespace only
+        let handle = store.namespace("shards").unwrap().get(1).await.unwrap();
+        let err = store.join_snapshot().await.err().unwrap();
+        assert_eq!(DbError::from_io(&err), Some(&DbError::SnapshotInUse(1)));
+        drop(handle);
+    }
+
+    // Lagging namespaces and new ones catch up on open
+    let mut namespaces = shards_and_proofs();
+    namespaces.push(namespace("sealed", 4, 4096));
+    let store = open(dir.path(), namespaces).await;
+    for (name, db) in store.namespaces() {
+        assert_eq!(db.snapshots().await, 2..=6, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn test_invalid_namespaces() {
+    let dir = tempfile::tempdir().unwrap();
+    for namespaces in [vec![], vec![namespace("a/b", 1, 512)], vec![namespace("a", 1, 512), namespace("a", 2, 512)]] {
+        let result = SnapshotStore::new(dir.path(), config(namespaces)).await;
+        assert_eq!(result.err().map(|err| err.kind()), Some(ErrorKind::InvalidInput));
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_namespaces() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = SnapshotStoreConfig {
+        encryption_key: Some(EncryptionKey::new([3; 32])),
+        ..config(vec![namespace("a", 1, 4096), namespace("b", 1, 4096)])
+    };
+
+    let store = SnapshotStore::new(dir.path(), config).await.unwrap();
+    for (_, db) in store.namespaces() {
+        db.write(0, &[0; 4096]).await.unwrap();
+    }
+    for (_, db) in store.namespaces() {
+        assert_eq!(db.read(1, 0).await.unwrap(), vec![0; 4096]);
+    }
+
+    // Both namespaces encrypted the same data in the same slot with the same generation, but
+    // under different keys
+    let a = std::fs::read(dir.path().join("storage-a")).unwrap();
+    let b = std::fs::read(dir.path().join("storage-b")).unwrap();
+    assert!(!a.is_empty());
+    assert_ne!(a, b);
+}
@@ -0,0 +1,117 @@
+//! Snapshot handles, listing, cluster deletion and errors for unknown snapshots and clusters.
+
+use std::io::ErrorKind;
+
+use snapshot_db::db::{SnapshotDb, SnapshotDbConfig};
+use snapshot_db::error::DbError;
+
+mod common;
+
+use common::{CLUSTER_SIZE, NUM_CLUSTERS};
+
+const CONFIG: SnapshotDbConfig = SnapshotDbConfig { checksum_block_size: None, ..common::CONFIG };
+
+fn db_error(err: &std::io::Error) -> Option<DbError> {
+    DbError::from_io(err).cloned()
+}
+
+#[tokio::test]
+async fn test_snapshot_handles() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+    assert_eq!(db.snapshots().await, 0..=1);
+
+    db.write(0, &[1; CLUSTER_SIZE]).await.unwrap();
+    db.add_snapshot().await.unwrap();
+    db.write(0, &[2; CLUSTER_SIZE]).await.unwrap();
+    assert_eq!(db.snapshots().await, 0..=2);
+
+    let committed = db.latest_committed().await.unwrap();
+    let pending = db.pending().await;
+    assert_eq!((committed.id(), pending.id()), (1, 2));
+    assert_eq!(committed.read(0).await.unwrap(), vec![1; CLUSTER_SIZE]);
+    assert_eq!(pending.read(0).await.unwrap(), vec![2; CLUSTER_SIZE]);
+    assert_eq!(pending.read_exact(0, 10, 4).await.unwrap(), vec![2; 4]);
+
+    // A handle keeps its snapshot from being joined
+    let oldest = db.get(0).await.unwrap();
+    let copy = oldest.clone();
+    drop(oldest);
+    let err = db.join_snapshot().await.unwrap_err();
+    assert_eq!(db_error(&err), Some(DbError::SnapshotInUse(0)));
+    assert_eq!(copy.read(0).await.unwrap(), vec![0; CLUSTER_SIZE]);
+    drop(copy);
+
+    db.join_snapshot().await.unwrap();
+    assert_eq!(db.snapshots().await, 1..=2);
+    assert_eq!(committed.read(0).await.unwrap(), vec![1; CLUSTER_SIZE]);
+
+    let err = db.join_snapshot().await.unwrap_err();
+    assert_eq!(db_error(&err), Some(DbError::SnapshotInUse(1)));
+    drop(committed);
+    db.join_snapshot().await.unwrap();
+
+    // Only the pending snapshot is left
+    assert!(db.latest_committed().await.is_none());
+    assert_eq!(db.join_snapshot().await.unwrap_err().kind(), ErrorKind::InvalidInput);
+    drop(pending);
+}
+
+#[tokio::test]
+async fn test_delete() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+        // Nothing is reserved up front
+        assert_eq!(std::fs::metadata(dir.path().join("storage")).unwrap().len(), 0);
+
+        db.write(0, &[1; CLUSTER_SIZE]).await.unwrap();
+        db.write(1, &[2; CLUSTER_SIZE]).await.unwrap();
+        db.add_snapshot().await.unwrap();
+        db.delete(0).await.unwrap();
+        db.delete(2).await.unwrap();
+
+        assert_eq!(db.read(1, 0).await.unwrap(), vec![1; CLUSTER_SIZE]);
+        assert_eq!(db.read(2, 0).await.unwrap(), vec![0; CLUSTER_SIZE]);
+        assert_eq!(db.read_exact(2, 0, 10, 4).await.unwrap(), vec![0; 4]);
+        assert_eq!(db.changed_clusters(2, Some(1)).await.unwrap(), vec![0]);
+
+        let err = db.delete(NUM_CLUSTERS).await.unwrap_err();
+        assert_eq!(db_error(&err), Some(DbError::UnknownCluster(NUM_CLUSTERS)));
+    }
+
+    // Deletions survive a restart, and the freed slot is reused once no snapshot refers to it
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+    assert_eq!(db.read(2, 0).await.unwrap(), vec![0; CLUSTER_SIZE]);
+    assert_eq!(db.read(2, 1).await.unwrap(), vec![2; CLUSTER_SIZE]);
+    db.join_snapshot().await.unwrap();
+    db.join_snapshot().await.unwrap();
+    db.write(3, &[3; CLUSTER_SIZE]).await.unwrap();
+    assert_eq!(db.compact(true).await.unwrap().live_slots, 2);
+    assert_eq!(db.read(2, 1).await.unwrap(), vec![2; CLUSTER_SIZE]);
+    assert_eq!(db.read(2, 3).await.unwrap(), vec![3; CLUSTER_SIZE]);
+}
+
+#[tokio::test]
+async fn test_unknown_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = SnapshotDb::new(dir.path(), CONFIG).await.unwrap();
+
+    let err = db.get(5).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert_eq!(db_error(&err), Some(DbError::UnknownSnapshot(5)));
+    assert_eq!(db_error(&db.read(5, 0).await.unwrap_err()), Some(DbError::UnknownSnapshot(5)));
+
+    let err = db.read(1, NUM_CLUSTERS).await.unwrap_err();
+    assert_eq!(db_error(&err), Some(DbError::UnknownCluster(NUM_CLUSTERS)));
+    assert_eq!(db_error(&db.pending().await.read_exact(NUM_CLUSTERS, 0, 1).await.unwrap_err()), Some(DbError::UnknownCluster(NUM_CLUSTERS)));
+
+    let err = db.write(NUM_CLUSTERS, &[0; CLUSTER_SIZE]).await.unwrap_err();
+    assert_eq!(db_error(&err), Some(DbError::UnknownCluster(NUM_CLUSTERS)));
+    let err = db.write_batch(&[(0, &[0; CLUSTER_SIZE][..]), (NUM_CLUSTERS + 1, &[0; CLUSTER_SIZE][..])]).await.unwrap_err();
+    assert_eq!(db_error(&err), Some(DbError::UnknownCluster(NUM_CLUSTERS + 1)));
+
+    // Failed writes do not change data
+    assert_eq!(db.read(1, 0).await.unwrap(), vec![0; CLUSTER_SIZE]);
+}
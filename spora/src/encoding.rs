@@ -0,0 +1,241 @@
+//! Binary format of SPoRA proofs, openings and solutions
+//!
+//! Proofs leave the process to be submitted to the contract and sent between nodes, so their
+//! encoding is fixed: a format version byte followed by the bincode encoding of the value, with
+//! fixed-size little-endian integers. Field elements are encoded as their canonical `u32`.
+//!
+//! Decoding rejects unknown versions, trailing bytes and inputs above [`MAX_ENCODED_SIZE`].
+//!
+//! The FRI and Merkle proofs inside [`SporaProof`] are encoded through the serde layout of the
+//! Plonky3 types, so the format is only stable for the pinned Plonky3 revision. Updating Plonky3
+//! must come with a check of the test vectors below and a new [`SPORA_FORMAT_VERSION`] if they change.
+
+use core::fmt;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{SealedShardProof, SolutionProof, SporaOpen, SporaProof};
+
+/// Version of the encoding written by `to_bytes`
+///
+/// Version 2 adds the sealed cluster commitment and the sealing cluster root to [`SporaProof`].
+pub const SPORA_FORMAT_VERSION: u8 = 2;
+
+/// Upper bound of the encoded size accepted when decoding
+pub const MAX_ENCODED_SIZE: u64 = 16 << 20;
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Empty,
+    UnsupportedVersion(u8),
+    Malformed(Box<bincode::ErrorKind>),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty input"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported format version {}", version),
+            DecodeError::Malformed(err) => write!(f, "malformed input: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut bytes = vec![SPORA_FORMAT_VERSION];
+    bytes.extend(options().serialize(value).expect("Encoding into memory cannot fail"));
+    bytes
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, DecodeError> {
+    let (&version, payload) = bytes.split_first().ok_or(DecodeError::Empty)?;
+    if version != SPORA_FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    options().with_limit(MAX_ENCODED_SIZE).deserialize(payload).map_err(DecodeError::Malformed)
+}
+
+fn encoded_size<T: Serialize>(value: &T) -> usize {
+    1 + options().serialized_size(value).expect("Encoding into memory cannot fail") as usize
+}
+
+impl SporaProof {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+
+    /// Length of `to_bytes` without encoding the proof
+    pub fn encoded_size(&self) -> usize {
+        encoded_size(self)
+    }
+}
+
+impl SporaOpen {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+
+    /// Length of `to_bytes`, the same for every opening
+    pub fn encoded_size(&self) -> usize {
+        encoded_size(self)
+    }
+}
+
+impl SolutionProof {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+}
+
+impl SealedShardProof {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        decode(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rlc::fixture::sealed_cluster;
+    use crate::{prove_spora_cluster, verify_spora_cluster};
+    use libc_print::std_name::println;
+    use p3_field::PrimeField32;
+    use primitives::{array_to_quadval, ProtocolParams, Val};
+    use sha3::{Digest, Sha3_256};
+
+    /// Opening of `open_vector_value()` in format version 2
+    const SPORA_OPEN_V2: [u8; 33] = [
+        0x02,
+        0x04, 0x03, 0x02, 0x01, 0x08, 0x07, 0x06, 0x05, 0x0c, 0x0b, 0x0a, 0x09, 0x10, 0x0f, 0x0e, 0x0d,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xfe, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x40,
+    ];
+
+    /// SHA3-256 of the proof of `test_proof_digest`. Proofs depend on the pinned Plonky3 revision,
+    /// so this has to be generated with it: run the test and copy the printed digest.
+    const SPORA_PROOF_V2_SHA3: Option<[u8; 32]> = None;
+
+    fn open_vector_value() -> SporaOpen {
+        SporaOpen {
+            sealed_cluster_opening: array_to_quadval([Val::new(0x01020304), Val::new(0x05060708), Val::new(0x090a0b0c), Val::new(0x0d0e0f10)]),
+            sealing_cluster_opening: array_to_quadval([Val::new(0), Val::new(1), Val::new(0x7ffffffe), Val::new(0x40000000)]),
+        }
+    }
+
+    #[test]
+    fn test_open_vector() {
+        let open = open_vector_value();
+        assert_eq!(open.to_bytes(), SPORA_OPEN_V2);
+        assert_eq!(open.encoded_size(), SPORA_OPEN_V2.len());
+
+        let decoded = SporaOpen::from_bytes(&SPORA_OPEN_V2).unwrap();
+        assert_eq!(decoded.sealed_cluster_opening, open.sealed_cluster_opening);
+        assert_eq!(decoded.sealing_cluster_opening, open.sealing_cluster_opening);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert!(matches!(SporaOpen::from_bytes(&[]), Err(DecodeError::Empty)));
+
+        let mut bytes = SPORA_OPEN_V2.to_vec();
+        bytes[0] = 1;
+        assert!(matches!(SporaOpen::from_bytes(&bytes), Err(DecodeError::UnsupportedVersion(1))));
+
+        assert!(matches!(SporaOpen::from_bytes(&SPORA_OPEN_V2[..32]), Err(DecodeError::Malformed(_))));
+
+        let mut bytes = SPORA_OPEN_V2.to_vec();
+        bytes.push(0);
+        assert!(matches!(SporaOpen::from_bytes(&bytes), Err(DecodeError::Malformed(_))));
+    }
+
+    /// Layout of a proof at the test sizes: the version, the two commitments, then the Plonky3
+    /// proofs, then the indices as little-endian `u64`
+    #[test]
+    fn test_proof_vector() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (_, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+        let bytes = proof.to_bytes();
+
+        let sealed_cluster_commit = options().serialize(&proof.sealed_cluster_commit).unwrap();
+        let sealing_cluster_root = options().serialize(&proof.sealing_cluster_root).unwrap();
+        assert_eq!(sealed_cluster_commit.len(), 32);
+
+        assert_eq!(bytes[0], SPORA_FORMAT_VERSION);
+        assert_eq!(bytes[1..33], sealed_cluster_commit);
+        assert_eq!(bytes[33..65], sealing_cluster_root);
+        assert_eq!(bytes[bytes.len() - 16..], [1, 0, 0, 0, 0, 0, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Commitments are field elements in canonical form
+        for (chunk, value) in sealed_cluster_commit.chunks(4).zip(proof.sealed_cluster_commit.as_ref()) {
+            assert_eq!(u32::from_le_bytes(chunk.try_into().unwrap()), value.as_canonical_u32());
+        }
+    }
+
+    /// Pins the whole encoded proof at the test sizes, so that any change of the Plonky3 proofs or
+    /// their serialization shows up as a format change
+    #[test]
+    #[ignore = "SPORA_PROOF_V2_SHA3 isn't generated yet"]
+    fn test_proof_digest() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (_, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+        let digest: [u8; 32] = Sha3_256::digest(proof.to_bytes()).into();
+
+        println!("SPoRA proof v{} digest: {:02x?}", SPORA_FORMAT_VERSION, digest);
+        assert_eq!(Some(digest), SPORA_PROOF_V2_SHA3);
+    }
+
+    #[test]
+    fn test_proof_round_trip() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (open, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+
+        let bytes = proof.to_bytes();
+        assert_eq!(bytes.len(), proof.encoded_size());
+        let decoded = SporaProof::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.cluster_in_fragment_index, 1);
+        assert_eq!(decoded.quad_in_cluster_index, 42);
+
+        let open_bytes = open.to_bytes();
+        assert_eq!(SporaOpen::from_bytes(&open_bytes).unwrap().to_bytes(), open_bytes);
+
+        // Decoded proofs still verify
+        let open = SporaOpen::from_bytes(&open_bytes).unwrap();
+        assert!(verify_spora_cluster(&params, open, decoded, sealed.source_cluster_commit, sealed.sealing_fragment_root));
+
+        println!("Proof: {} bytes, opening: {} bytes", bytes.len(), open_bytes.len());
+    }
+}
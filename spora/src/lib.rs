@@ -8,9 +8,16 @@ mod types;
 mod prover;
 mod rlc;
 mod verifier;
+mod encoding;
+mod solution;
+mod shard_proof;
 
 pub use storage::*;
+pub use types::Nonce;
 pub use spora::*;
 pub use prover::*;
 pub use rlc::*;
-pub use verifier::*;
\ No newline at end of file
+pub use verifier::*;
+pub use encoding::*;
+pub use solution::*;
+pub use shard_proof::*;
\ No newline at end of file
@@ -10,6 +10,9 @@
 //! 
 //! Stored_Cluster = Payload_Cluster * Hash(Payload_Cluster_FRI_Commitment, Sealing_Fragment_Merkle_Root) + Sealing_Cluster
 //! 
+//! The hash is [`crate::derive_r`]. The verifier only knows the payload commitment and the sealing fragment root,
+//! so the proof carries the commitment of the stored cluster and the Merkle root of the sealing cluster.
+//! 
 //! This structure allow us easy overwritting the separate Clusters without recomputing the whole stored Fragment.
 //! 
 //! Here is an issue: The data type is Mersenne31, but RLC over Mersenne31 is unsafe.
@@ -19,12 +22,16 @@
 
 
 use primitives::{array_to_quadval, quadval_to_array, Challenge, Hash, Poseidon2Challenger, Poseidon2Pcs, QuadVal, Val, POSEIDON2_PCS, poseidon2_perm, POSEIDON2_MMCS};
-use primitives::{CLUSTER_SIZE, FRAGMENT_SIZE};
+use p3_challenger::CanObserve;
+use primitives::ProtocolParams;
 use p3_field::{AbstractExtensionField, Field};
 use p3_commit::{Pcs, Mmcs};
 use p3_matrix::dense::RowMajorMatrix;
 use alloc::vec::Vec;
 use alloc::vec;
+use core::fmt;
+use crate::{commit_cluster, derive_r};
+use serde::{Deserialize, Serialize};
 
 
 type MerkleProof = Vec<[Val; 8]>;
@@ -32,7 +39,13 @@ type FriProof = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::Proof;
 
 
 
+/// Leaves the process encoded with [`SporaProof::to_bytes`]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct SporaProof {
+    /// FRI commitment of the sealed cluster
+    pub(crate) sealed_cluster_commit: Hash,
+    /// Merkle root of the sealing cluster, opened in the fragment
+    pub(crate) sealing_cluster_root: Hash,
     /// FRI proof for the source cluster opening
     pub(crate) source_and_sealed_cluster_proof: FriProof,
     /// Merkle proof for the sealing cluster opening
@@ -45,28 +58,60 @@ pub struct SporaProof {
     pub(crate) quad_in_cluster_index: usize,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SporaOpen {
     pub(crate) sealed_cluster_opening: QuadVal,
     pub(crate) sealing_cluster_opening: QuadVal,
 }
 
+/// Inputs which can't be proven, because they don't belong to the sealing they claim
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProveError {
+    /// The sealing cluster isn't the one committed in the sealing fragment
+    SealingClusterMismatch,
+    /// `sealed_r` isn't derived from the source cluster and the sealing fragment
+    WrongSealingCoefficient,
+    /// The opened quadruple isn't in the cluster, or the cluster isn't in the fragment
+    IndexOutOfRange,
+    /// The protocol parameters fail [`ProtocolParams::validate`]
+    InvalidParams(&'static str),
+}
+
+impl fmt::Display for ProveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProveError::SealingClusterMismatch => write!(f, "sealing cluster doesn't match the sealing fragment"),
+            ProveError::WrongSealingCoefficient => write!(f, "sealed_r isn't derived from the source cluster commit and the sealing fragment root"),
+            ProveError::IndexOutOfRange => write!(f, "opened quadruple or cluster index is out of range"),
+            ProveError::InvalidParams(reason) => write!(f, "invalid protocol parameters: {}", reason),
+        }
+    }
+}
+
+impl core::error::Error for ProveError {}
+
 pub fn prove_spora_cluster(
+    params: &ProtocolParams,
     sealed_r: QuadVal,
     sealed_cluster_data: &[Val],
     sealing_fragment_cluster_hashes: &[Hash],
     sealing_cluster_data: &[QuadVal],
     cluster_in_fragment_index: usize,
     quad_in_cluster_index: usize,
-) -> (SporaOpen, SporaProof) {
+) -> Result<(SporaOpen, SporaProof), ProveError> {
     // TODO: Derive magic numbers from types
-    
-    assert!(sealed_cluster_data.len() as u64 == CLUSTER_SIZE, "Sealed cluster data length should be equal to cluster size");
-    assert!(sealing_cluster_data.len() as u64 * 4 == CLUSTER_SIZE, "Sealing cluster data length should be equal to cluster size");
-    assert!(sealing_fragment_cluster_hashes.len() as u64*CLUSTER_SIZE == FRAGMENT_SIZE, "Wrong number of clusters in fragment");
+
+    params.validate().map_err(ProveError::InvalidParams)?;
+    assert!(sealed_cluster_data.len() == params.shard_size(), "Sealed cluster data length should be equal to cluster size");
+    assert!(sealing_cluster_data.len() * 4 == params.shard_size(), "Sealing cluster data length should be equal to cluster size");
+    assert!(sealing_fragment_cluster_hashes.len() == params.shards_per_fragment(), "Wrong number of clusters in fragment");
+    if quad_in_cluster_index >= params.shard_size() / 4 || cluster_in_fragment_index >= params.shards_per_fragment() {
+        return Err(ProveError::IndexOutOfRange);
+    }
     
     let inverse_r = sealed_r.inverse();
 
-    let source_cluster_data = (0..CLUSTER_SIZE as usize/4).flat_map(|i| {
+    let source_cluster_data = (0..params.shard_size()/4).flat_map(|i| {
         let sealed_cluster_quad = array_to_quadval(sealed_cluster_data[i*4..i*4+4].try_into().unwrap());
         let sealing_cluster_quad = sealing_cluster_data[i];
         let source_cluster_quad = (sealed_cluster_quad - sealing_cluster_quad) * inverse_r;
@@ -77,19 +122,11 @@ pub fn prove_spora_cluster(
     let sealing_cluster_opening = sealing_cluster_data[quad_in_cluster_index];
     let sealed_cluster_opening = array_to_quadval(sealed_cluster_data[quad_in_cluster_index*4..quad_in_cluster_index*4+4].try_into().unwrap());
 
-    let source_cluster_column = RowMajorMatrix::new_col(source_cluster_data);
-
-    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, CLUSTER_SIZE as usize);
-
+    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, params.shard_size());
 
     // TODO: Optimize Plonky3 for single column matrices
-    let (_, source_cluster_prover_data) = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::commit(&POSEIDON2_PCS, vec![(domain, source_cluster_column)]);
-
-    // compute sealed_cluster_proof
-
-    let sealed_cluster_column = RowMajorMatrix::new_col(sealed_cluster_data.to_vec());
-    let (_, sealed_cluster_prover_data) = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::commit(&POSEIDON2_PCS, vec![(domain, sealed_cluster_column)]);
-
+    let (source_cluster_commit, source_cluster_prover_data) = commit_cluster(&source_cluster_data);
+    let (sealed_cluster_commit, sealed_cluster_prover_data) = commit_cluster(sealed_cluster_data);
 
     let opening_points = (quad_in_cluster_index*4..quad_in_cluster_index*4+4).map(|i| {
         let point = domain.nth_point(i);
@@ -98,6 +135,8 @@ pub fn prove_spora_cluster(
     }).collect::<Vec<_>>();
 
     let mut challenger = Poseidon2Challenger::new(poseidon2_perm());
+    challenger.observe(source_cluster_commit);
+    challenger.observe(sealed_cluster_commit);
 
     let (_, source_and_sealed_cluster_proof) = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::open(
         &POSEIDON2_PCS, 
@@ -114,36 +153,38 @@ pub fn prove_spora_cluster(
 
     let (_, sealing_cluster_proof) = POSEIDON2_MMCS.open_batch(quad_in_cluster_index, &sealing_cluster_prover_data);
 
-    assert!(sealing_fragment_cluster_hashes[cluster_in_fragment_index] == sealing_cluster_commit, "Sealing fragment cluster hash should be equal to commit");
+    if sealing_fragment_cluster_hashes[cluster_in_fragment_index] != sealing_cluster_commit {
+        return Err(ProveError::SealingClusterMismatch);
+    }
 
     let sealing_fragment_matrix_data = sealing_fragment_cluster_hashes.iter().copied().flatten().collect::<Vec<_>>();
     let sealing_fragment_matrix = RowMajorMatrix::new(sealing_fragment_matrix_data, 8);
 
-    let (_, fragment_prover_data) = POSEIDON2_MMCS.commit_matrix(sealing_fragment_matrix);
+    let (sealing_fragment_root, fragment_prover_data) = POSEIDON2_MMCS.commit_matrix(sealing_fragment_matrix);
 
     let (_, fragment_proof) = POSEIDON2_MMCS.open_batch(cluster_in_fragment_index, &fragment_prover_data);
 
-    
-    
-
-    // TODO: assert, that sealed_rlc is derived correctly
-
+    if derive_r(source_cluster_commit, sealing_fragment_root) != sealed_r {
+        return Err(ProveError::WrongSealingCoefficient);
+    }
 
     // Opening data is (sealed_cluster_opening, sealing_cluster_opening)
     // Proof is composite of opening quadval, merkle proofs, fri proof, cluster_in_fragment_index and quad_in_cluster_index
 
 
-    (
+    Ok((
         SporaOpen {
             sealed_cluster_opening,
             sealing_cluster_opening,
         },
         SporaProof {
+            sealed_cluster_commit,
+            sealing_cluster_root: sealing_cluster_commit,
             source_and_sealed_cluster_proof,
             sealing_cluster_proof,
             fragment_proof,
             cluster_in_fragment_index,
             quad_in_cluster_index,
         }
-    )
+    ))
 }
\ No newline at end of file
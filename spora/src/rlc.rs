@@ -1,5 +1,13 @@
-use primitives::{array_to_quadval, quadval_to_array, Challenge, Hash, Poseidon2Challenger, Poseidon2Pcs, QuadVal, Val, POSEIDON2_PCS, poseidon2_perm};
-use primitives::CLUSTER_SIZE;
+//! Sealing of a cluster with a random linear combination
+//!
+//! The stored cluster is `sealed = source * r + sealing`, computed over quadruples of values as
+//! [`QuadVal`]s. `r` is sampled by a Poseidon2 challenger after observing the FRI commitment of the
+//! source cluster and the Merkle root of the sealing fragment, see [`derive_r`]. The sealer, the
+//! prover and the verifier all derive it this way, so a sealed cluster only verifies against the
+//! source commitment and the sealing fragment it was sealed with.
+
+use primitives::{array_to_quadval, quadval_to_array, Challenge, Hash, Poseidon2Challenger, Poseidon2Pcs, Poseidon2PcsProverData, QuadVal, Val, POSEIDON2_PCS, poseidon2_perm};
+use primitives::ProtocolParams;
 use p3_commit::Pcs;
 use p3_challenger::{CanObserve, CanSample};
 use p3_matrix::dense::RowMajorMatrix;
@@ -7,23 +15,32 @@ use alloc::vec::Vec;
 use alloc::vec;
 
 
-pub fn rlc(source_cluster: &[Val], sealing_cluster: &[QuadVal], sealing_fragment_root: Hash) -> (QuadVal, Vec<Val> ) {
-    assert!(source_cluster.len() as u64 == CLUSTER_SIZE, "Payload cluster size should be equal to cluster size");
-    assert!(sealing_cluster.len() as u64 * 4 == CLUSTER_SIZE, "Sealing cluster size should be equal to cluster size");
-
+/// FRI commitment of a cluster as a single column
+pub fn commit_cluster(cluster: &[Val]) -> (Hash, Poseidon2PcsProverData) {
+    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, cluster.len());
+    let column = RowMajorMatrix::new_col(cluster.to_vec());
+    <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::commit(&POSEIDON2_PCS, vec![(domain, column)])
+}
 
-    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, CLUSTER_SIZE as usize);
-    let source_cluster_column = RowMajorMatrix::new_col(source_cluster.to_vec());
-    let (commit, _) = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::commit(&POSEIDON2_PCS, vec![(domain, source_cluster_column)]);
-    
+/// Coefficient of the source cluster in the sealed cluster
+pub fn derive_r(source_cluster_commit: Hash, sealing_fragment_root: Hash) -> QuadVal {
     let mut challenger = Poseidon2Challenger::new(poseidon2_perm());
 
-    challenger.observe(commit);
+    challenger.observe(source_cluster_commit);
     challenger.observe(sealing_fragment_root);
 
-    let r = array_to_quadval(challenger.sample_array::<4>());
+    array_to_quadval(challenger.sample_array::<4>())
+}
+
+/// Seals a cluster of `params.shard_size()` values
+pub fn rlc(params: &ProtocolParams, source_cluster: &[Val], sealing_cluster: &[QuadVal], sealing_fragment_root: Hash) -> (QuadVal, Vec<Val> ) {
+    assert!(source_cluster.len() == params.shard_size(), "Payload cluster size should be equal to cluster size");
+    assert!(sealing_cluster.len() * 4 == params.shard_size(), "Sealing cluster size should be equal to cluster size");
 
-    let sealed_cluster = (0..CLUSTER_SIZE as usize/4).flat_map(|i| {
+    let (commit, _) = commit_cluster(source_cluster);
+    let r = derive_r(commit, sealing_fragment_root);
+
+    let sealed_cluster = (0..params.shard_size()/4).flat_map(|i| {
         let source_cluster_quad = array_to_quadval(source_cluster[i*4..i*4+4].try_into().unwrap());
         let sealing_cluster_quad = sealing_cluster[i];
         let sealed_cluster_quad = source_cluster_quad * r + sealing_cluster_quad;
@@ -31,4 +48,49 @@ pub fn rlc(source_cluster: &[Val], sealing_cluster: &[QuadVal], sealing_fragment
     }).collect::<Vec<_>>();
 
     (r, sealed_cluster)
-}
\ No newline at end of file
+}
+
+/// A cluster sealed with [`rlc`], with everything needed to prove and verify it
+#[cfg(test)]
+pub(crate) mod fixture {
+    use super::*;
+    use p3_commit::Mmcs;
+    use primitives::POSEIDON2_MMCS;
+
+    pub(crate) struct SealedCluster {
+        pub source_cluster_commit: Hash,
+        pub sealing_cluster: Vec<QuadVal>,
+        pub sealing_fragment_cluster_hashes: Vec<Hash>,
+        pub sealing_fragment_root: Hash,
+        pub r: QuadVal,
+        pub sealed_cluster: Vec<Val>,
+    }
+
+    pub(crate) fn sealed_cluster(params: &ProtocolParams, cluster_in_fragment_index: usize) -> SealedCluster {
+        let source_cluster = (0..params.shard_size() as u32).map(|i| Val::new(i.wrapping_mul(0x9e3779b9) % 0x7fffffff)).collect::<Vec<_>>();
+        let sealing_cluster = (0..params.shard_size() as u32 / 4)
+            .map(|i| array_to_quadval([Val::new(4 * i), Val::new(4 * i + 1), Val::new(4 * i + 2), Val::new(4 * i + 3)]))
+            .collect::<Vec<_>>();
+
+        // Only the opened cluster of the fragment matters, the others get arbitrary hashes
+        let sealing_cluster_matrix = RowMajorMatrix::new(sealing_cluster.iter().copied().flat_map(quadval_to_array).collect(), 4);
+        let (sealing_cluster_root, _) = POSEIDON2_MMCS.commit_matrix(sealing_cluster_matrix);
+        let sealing_fragment_cluster_hashes = (0..params.shards_per_fragment() as u32)
+            .map(|i| if i as usize == cluster_in_fragment_index { sealing_cluster_root } else { Hash::from([Val::new(i); 8]) })
+            .collect::<Vec<_>>();
+        let fragment_matrix = RowMajorMatrix::new(sealing_fragment_cluster_hashes.iter().copied().flatten().collect(), 8);
+        let (sealing_fragment_root, _) = POSEIDON2_MMCS.commit_matrix(fragment_matrix);
+
+        let (r, sealed_cluster) = rlc(params, &source_cluster, &sealing_cluster, sealing_fragment_root);
+        let (source_cluster_commit, _) = commit_cluster(&source_cluster);
+
+        SealedCluster {
+            source_cluster_commit,
+            sealing_cluster,
+            sealing_fragment_cluster_hashes,
+            sealing_fragment_root,
+            r,
+            sealed_cluster,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Proofs that the shards of a [`CommittedStorage`] are sealed
+//!
+//! A node commits to the Merkle roots of its sealed shards, see [`CommittedStorage::shard_roots`].
+//! A [`SealedShardProof`] opens a quadruple of a sealed shard against its root, and proves with
+//! [`prove_spora_cluster`] that it is the quadruple of a source shard with a known FRI commitment,
+//! sealed with the node's sealing fragment. The quadruple is derived from the root of the shard, so
+//! the prover can't choose it.
+//!
+//! The proof is a spot check: a shard which is only partly sealed passes it if the derived
+//! quadruple is one of the sealed ones.
+
+use primitives::{quadval_to_array, Hash, Poseidon2Challenger, ProtocolParams, QuadVal, Val, POSEIDON2_MMCS, poseidon2_perm};
+use p3_challenger::{CanObserve, CanSampleBits};
+use p3_commit::Mmcs;
+use p3_matrix::Dimensions;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::solution::STORAGE_ROW_WIDTH;
+use crate::{prove_spora_cluster, verify_spora_cluster, CommittedStorage, ProveError, SporaOpen, SporaProof};
+
+type MerkleProof = Vec<[Val; 8]>;
+
+/// Leaves the process encoded with [`SealedShardProof::to_bytes`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SealedShardProof {
+    pub(crate) open: SporaOpen,
+    pub(crate) proof: SporaProof,
+    /// Merkle leaf of the shard holding the opened quadruple
+    pub(crate) row: Vec<Val>,
+    pub(crate) row_proof: MerkleProof,
+}
+
+/// Quadruple of a sealed shard opened by its proof, derived from the Merkle root of the shard
+pub fn sealed_shard_quad(params: &ProtocolParams, shard_root: Hash) -> usize {
+    let mut challenger = Poseidon2Challenger::new(poseidon2_perm());
+    challenger.observe(shard_root);
+    challenger.sample_bits(params.log_shard_size() - 2)
+}
+
+impl CommittedStorage {
+    /// Proves that shard `index` of the storage is sealed with `sealed_r` and the sealing cluster
+    /// `cluster_in_fragment_index` of a fragment, like [`prove_spora_cluster`]
+    pub fn prove_sealed_shard(
+        &self,
+        params: &ProtocolParams,
+        index: usize,
+        sealed_r: QuadVal,
+        sealing_fragment_cluster_hashes: &[Hash],
+        sealing_cluster_data: &[QuadVal],
+        cluster_in_fragment_index: usize,
+    ) -> Result<SealedShardProof, ProveError> {
+        params.validate().map_err(ProveError::InvalidParams)?;
+        assert!(index < self.num_shards(), "Shard index should be in the storage");
+
+        let quad_in_cluster_index = sealed_shard_quad(params, self.shard_roots()[index]);
+        let prover_data = self.shard_data(index);
+        let sealed_cluster_data = &POSEIDON2_MMCS.get_matrices(prover_data)[0].values;
+        let (open, proof) = prove_spora_cluster(
+            params,
+            sealed_r,
+            sealed_cluster_data,
+            sealing_fragment_cluster_hashes,
+            sealing_cluster_data,
+            cluster_in_fragment_index,
+            quad_in_cluster_index,
+        )?;
+
+        let (mut rows, row_proof) = POSEIDON2_MMCS.open_batch(quad_in_cluster_index * 4 / STORAGE_ROW_WIDTH, prover_data);
+
+        Ok(SealedShardProof { open, proof, row: rows.remove(0), row_proof })
+    }
+}
+
+/// Checks that the shard with the Merkle root `shard_root` is the source cluster committed with
+/// `source_cluster_commit`, sealed with the sealing cluster `cluster_in_fragment_index` of the
+/// sealing fragment with the root `sealing_fragment_root`
+pub fn verify_sealed_shard(
+    params: &ProtocolParams,
+    shard_root: Hash,
+    proof: SealedShardProof,
+    source_cluster_commit: Hash,
+    sealing_fragment_root: Hash,
+    cluster_in_fragment_index: usize,
+) -> bool {
+    if params.validate().is_err() || params.shard_size() < STORAGE_ROW_WIDTH {
+        return false;
+    }
+
+    let quad_in_cluster_index = sealed_shard_quad(params, shard_root);
+    if proof.proof.quad_in_cluster_index != quad_in_cluster_index
+        || proof.proof.cluster_in_fragment_index != cluster_in_fragment_index
+        || proof.row.len() != STORAGE_ROW_WIDTH
+    {
+        return false;
+    }
+
+    // The opened sealed quadruple is the one committed in the shard root
+    let dimensions = vec![Dimensions { width: STORAGE_ROW_WIDTH, height: params.shard_size() / STORAGE_ROW_WIDTH }];
+    let row_index = quad_in_cluster_index * 4 / STORAGE_ROW_WIDTH;
+    if POSEIDON2_MMCS.verify_batch(&shard_root, &dimensions, row_index, &[proof.row.clone()], &proof.row_proof).is_err() {
+        return false;
+    }
+    let offset = quad_in_cluster_index * 4 % STORAGE_ROW_WIDTH;
+    if proof.row[offset..offset + 4] != quadval_to_array(proof.open.sealed_cluster_opening) {
+        return false;
+    }
+
+    verify_spora_cluster(params, proof.open, proof.proof, source_cluster_commit, sealing_fragment_root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rlc::fixture::sealed_cluster;
+
+    fn other_shard(params: &ProtocolParams) -> Vec<Val> {
+        (0..params.shard_size() as u32).map(|i| Val::new(i * 7)).collect()
+    }
+
+    #[test]
+    fn test_prove_verify_sealed_shard() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let storage = CommittedStorage::from_shards(vec![other_shard(&params), sealed.sealed_cluster.clone()]);
+        let shard_root = storage.shard_roots()[1];
+
+        let proof = storage.prove_sealed_shard(&params, 1, sealed.r, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1).unwrap();
+        assert!(verify_sealed_shard(&params, shard_root, proof.clone(), sealed.source_cluster_commit, sealed.sealing_fragment_root, 1));
+
+        // Another shard, another cluster of the fragment, another source or sealing
+        assert!(!verify_sealed_shard(&params, storage.shard_roots()[0], proof.clone(), sealed.source_cluster_commit, sealed.sealing_fragment_root, 1));
+        assert!(!verify_sealed_shard(&params, shard_root, proof.clone(), sealed.source_cluster_commit, sealed.sealing_fragment_root, 0));
+        let other_commit = Hash::from([Val::new(7); 8]);
+        assert!(!verify_sealed_shard(&params, shard_root, proof.clone(), other_commit, sealed.sealing_fragment_root, 1));
+        assert!(!verify_sealed_shard(&params, shard_root, proof, sealed.source_cluster_commit, other_commit, 1));
+    }
+
+    #[test]
+    fn test_unsealed_shard_is_not_proven() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let storage = CommittedStorage::from_shards(vec![other_shard(&params)]);
+
+        let result = storage.prove_sealed_shard(&params, 0, sealed.r, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1);
+        assert!(matches!(result, Err(ProveError::WrongSealingCoefficient)));
+    }
+}
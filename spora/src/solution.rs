@@ -0,0 +1,345 @@
+//! Solutions of SPoRA which can be checked without the storage
+//!
+//! A miner commits to its storage with [`CommittedStorage`] before the challenge of the epoch is
+//! known. A solution opens the sampled values against the Merkle root of the storage, so the verifier
+//! recomputes the sampled indices and the complexity from the challenge and the nonce alone.
+//!
+//! The storage is a sequence of shards of the same size, each committed with its own Merkle tree,
+//! and a tree over the roots of the shards. The number of shards is padded with zero shards to a
+//! power of two, so the root is the one of a single tree over all values. The roots of the shards
+//! are enough to recompute the root with [`storage_root`], and a changed shard only rehashes itself
+//! and its path to the root.
+
+use primitives::{Hash, Poseidon2Mmcs, Val, POSEIDON2_COMPRESS, POSEIDON2_MMCS};
+use p3_commit::Mmcs;
+use p3_matrix::dense::RowMajorMatrix;
+use p3_matrix::Dimensions;
+use p3_symmetric::PseudoCompressionFunction;
+use alloc::vec;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::spora::{complexity, sample_indices, SPoRAConfig};
+use crate::storage::UnstructuredStorageReader;
+use crate::types::Nonce;
+
+/// Number of values in a leaf of the storage Merkle tree
+pub const STORAGE_ROW_WIDTH: usize = 8;
+
+type MerkleProof = Vec<[Val; 8]>;
+type StorageProverData = <Poseidon2Mmcs as Mmcs<Val>>::ProverData<RowMajorMatrix<Val>>;
+
+/// Leaves the process encoded with [`SolutionProof::to_bytes`]
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SolutionProof {
+    /// Openings of the sampled values, in the order they are sampled
+    pub(crate) openings: Vec<SampleOpening>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SampleOpening {
+    /// Merkle leaf containing the sampled value
+    pub(crate) row: Vec<Val>,
+    pub(crate) proof: MerkleProof,
+}
+
+/// Storage committed with a Merkle tree, which can be mined on and prove its solutions
+pub struct CommittedStorage {
+    /// Merkle trees of the shards, the storage is their concatenation
+    shards: Vec<StorageProverData>,
+    /// Tree of the zero shard padding the number of shards, created once it is needed
+    padding: Option<(Hash, StorageProverData)>,
+    /// Tree over the roots of the padded shards, from the roots of the shards up to the root
+    layers: Vec<Vec<Hash>>,
+    log_shard_len: usize,
+}
+
+impl CommittedStorage {
+    pub fn new(values: Vec<Val>) -> Self {
+        Self::from_shards(vec![values])
+    }
+
+    /// Commits to the concatenation of the shards, which have the same size
+    pub fn from_shards(shards: Vec<Vec<Val>>) -> Self {
+        assert!(!shards.is_empty(), "Storage should have at least one shard");
+        let shard_len = shards[0].len();
+        assert!(shard_len.is_power_of_two() && shard_len >= STORAGE_ROW_WIDTH, "Shard size should be a power of two of at least one row");
+
+        let mut storage = Self { shards: Vec::new(), padding: None, layers: vec![Vec::new()], log_shard_len: shard_len.ilog2() as usize };
+        for values in shards {
+            let (root, prover_data) = storage.commit_shard(values);
+            storage.shards.push(prover_data);
+            storage.layers[0].push(root);
+        }
+        storage.build_layers();
+
+        storage
+    }
+
+    pub fn root(&self) -> Hash {
+        self.layers[self.layers.len() - 1][0]
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Merkle roots of the shards, without the padding
+    pub fn shard_roots(&self) -> &[Hash] {
+        &self.layers[0][..self.shards.len()]
+    }
+
+    /// Replaces the values of a shard
+    pub fn update_shard(&mut self, index: usize, values: Vec<Val>) {
+        let (root, prover_data) = self.commit_shard(values);
+        self.shards[index] = prover_data;
+
+        let mut index = index;
+        self.layers[0][index] = root;
+        for level in 1..self.layers.len() {
+            index /= 2;
+            self.layers[level][index] = compress(self.layers[level - 1][2 * index], self.layers[level - 1][2 * index + 1]);
+        }
+    }
+
+    /// Appends a shard at the end of the storage
+    pub fn push_shard(&mut self, values: Vec<Val>) {
+        let (root, prover_data) = self.commit_shard(values);
+        self.shards.push(prover_data);
+        self.layers[0].truncate(self.shards.len() - 1);
+        self.layers[0].push(root);
+        self.build_layers();
+    }
+
+    /// Removes a shard and moves the last shard in its place
+    pub fn swap_remove_shard(&mut self, index: usize) {
+        assert!(self.shards.len() > 1, "Storage should keep at least one shard");
+        self.shards.swap_remove(index);
+        self.layers[0].truncate(self.shards.len() + 1);
+        self.layers[0].swap_remove(index);
+        self.build_layers();
+    }
+
+    pub fn prove(&self, config: &SPoRAConfig, nonce: Nonce) -> SolutionProof {
+        assert!(config.log_storage_size() == self.log_len(), "Config should sample the committed storage");
+
+        let log_shard_rows = self.log_shard_len - STORAGE_ROW_WIDTH.ilog2() as usize;
+        let openings = sample_indices(config, nonce).into_iter().map(|index| {
+            let row_index = index as usize / STORAGE_ROW_WIDTH;
+            let shard = row_index >> log_shard_rows;
+
+            // The path in the shard continues with the path of the shard root to the root
+            let (mut rows, mut proof) = POSEIDON2_MMCS.open_batch(row_index & ((1 << log_shard_rows) - 1), self.shard_data(shard));
+            let mut node = shard;
+            for layer in &self.layers[..self.layers.len() - 1] {
+                proof.push(layer[node ^ 1].into());
+                node /= 2;
+            }
+
+            SampleOpening { row: rows.remove(0), proof }
+        }).collect();
+
+        SolutionProof { openings }
+    }
+
+    fn commit_shard(&self, values: Vec<Val>) -> (Hash, StorageProverData) {
+        assert!(values.len() == 1 << self.log_shard_len, "Shards should have the same size");
+        POSEIDON2_MMCS.commit_matrix(RowMajorMatrix::new(values, STORAGE_ROW_WIDTH))
+    }
+
+    /// Rebuilds the tree above the roots of the shards, after the number of shards changed
+    fn build_layers(&mut self) {
+        let padded_len = self.shards.len().next_power_of_two();
+        if padded_len > self.shards.len() && self.padding.is_none() {
+            self.padding = Some(zero_shard(self.log_shard_len));
+        }
+
+        let mut roots = core::mem::take(&mut self.layers).swap_remove(0);
+        if let Some((padding_root, _)) = &self.padding {
+            roots.resize(padded_len, *padding_root);
+        }
+        self.layers = tree_layers(roots);
+    }
+
+    pub(crate) fn shard_data(&self, shard: usize) -> &StorageProverData {
+        match self.shards.get(shard) {
+            Some(prover_data) => prover_data,
+            None => &self.padding.as_ref().expect("Shards past the end are padding").1,
+        }
+    }
+}
+
+impl UnstructuredStorageReader for CommittedStorage {
+    fn read(&self, index: u64) -> Val {
+        let shard = (index >> self.log_shard_len) as usize;
+        POSEIDON2_MMCS.get_matrices(self.shard_data(shard))[0].values[index as usize & ((1 << self.log_shard_len) - 1)]
+    }
+
+    fn log_len(&self) -> usize {
+        self.log_shard_len + self.layers.len() - 1
+    }
+}
+
+/// Root of a [`CommittedStorage`] made of shards of `2^log_shard_len` values with these roots
+pub fn storage_root(shard_roots: &[Hash], log_shard_len: usize) -> Hash {
+    let mut roots = shard_roots.to_vec();
+    let padded_len = roots.len().next_power_of_two();
+    if padded_len > roots.len() {
+        roots.resize(padded_len, zero_shard(log_shard_len).0);
+    }
+
+    let layers = tree_layers(roots);
+    layers[layers.len() - 1][0]
+}
+
+/// Logarithm of the number of values of a [`CommittedStorage`] made of `num_shards` shards of
+/// `2^log_shard_len` values
+pub fn storage_log_len(num_shards: usize, log_shard_len: usize) -> usize {
+    log_shard_len + num_shards.next_power_of_two().ilog2() as usize
+}
+
+fn zero_shard(log_shard_len: usize) -> (Hash, StorageProverData) {
+    POSEIDON2_MMCS.commit_matrix(RowMajorMatrix::new(vec![Val::new(0); 1 << log_shard_len], STORAGE_ROW_WIDTH))
+}
+
+fn compress(left: Hash, right: Hash) -> Hash {
+    POSEIDON2_COMPRESS.compress([left.into(), right.into()]).into()
+}
+
+/// Layers of the Merkle tree over a power of two of roots, the last one holds the root
+fn tree_layers(roots: Vec<Hash>) -> Vec<Vec<Hash>> {
+    let mut layers = vec![roots];
+    while layers[layers.len() - 1].len() > 1 {
+        let layer = layers[layers.len() - 1].chunks(2).map(|pair| compress(pair[0], pair[1])).collect();
+        layers.push(layer);
+    }
+    layers
+}
+
+/// Checks the openings of a solution against the root of the committed storage and returns the
+/// complexity it reaches, or `None` if the proof is invalid
+pub fn verify_solution(config: &SPoRAConfig, storage_root: Hash, nonce: Nonce, proof: &SolutionProof) -> Option<usize> {
+    if config.log_storage_size() < STORAGE_ROW_WIDTH.ilog2() as usize || nonce.as_u64() >= config.max_nonce() {
+        return None;
+    }
+
+    let indices = sample_indices(config, nonce);
+    if proof.openings.len() != indices.len() {
+        return None;
+    }
+
+    let dimensions = vec![Dimensions { width: STORAGE_ROW_WIDTH, height: 1 << (config.log_storage_size() - STORAGE_ROW_WIDTH.ilog2() as usize) }];
+
+    let mut values = Vec::with_capacity(indices.len());
+    for (index, opening) in indices.into_iter().zip(&proof.openings) {
+        if opening.row.len() != STORAGE_ROW_WIDTH {
+            return None;
+        }
+
+        let row_index = index as usize / STORAGE_ROW_WIDTH;
+        if POSEIDON2_MMCS.verify_batch(&storage_root, &dimensions, row_index, &[opening.row.clone()], &opening.proof).is_err() {
+            return None;
+        }
+
+        values.push(opening.row[index as usize % STORAGE_ROW_WIDTH]);
+    }
+
+    Some(complexity(&values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spora::spora;
+    use crate::storage::SimpleTestingStorageEmulator;
+
+    const LOG_STORAGE_SIZE: usize = 12;
+    const LOG_SHARD_SIZE: usize = 10;
+
+    fn committed_storage() -> CommittedStorage {
+        let emulator = SimpleTestingStorageEmulator::new(LOG_STORAGE_SIZE);
+        CommittedStorage::new((0..1 << LOG_STORAGE_SIZE).map(|index| emulator.read(index)).collect())
+    }
+
+    #[test]
+    fn test_prove_verify_solution() {
+        let storage = committed_storage();
+        let config = SPoRAConfig::new(Hash::from([Val::new(3); 8]), 256, 4, 8, LOG_STORAGE_SIZE);
+
+        let solutions = spora(&config, &storage);
+        assert!(!solutions.is_empty());
+
+        for (nonce, complexity) in solutions {
+            let proof = storage.prove(&config, nonce);
+            assert_eq!(verify_solution(&config, storage.root(), nonce, &proof), Some(complexity));
+
+            let decoded = SolutionProof::from_bytes(&proof.to_bytes()).unwrap();
+            assert_eq!(verify_solution(&config, storage.root(), nonce, &decoded), Some(complexity));
+
+            // The openings of the proof belong to the sampled indices of its nonce only
+            let other_nonce = Nonce::new((nonce.as_u64() + 1) % config.max_nonce());
+            assert_eq!(verify_solution(&config, storage.root(), other_nonce, &proof), None);
+
+            // And to the committed storage only
+            assert_eq!(verify_solution(&config, Hash::from([Val::new(5); 8]), nonce, &proof), None);
+
+            let mut tampered = proof.clone();
+            tampered.openings[0].row[0] += Val::new(1);
+            assert_eq!(verify_solution(&config, storage.root(), nonce, &tampered), None);
+        }
+    }
+
+    fn shard(seed: u32) -> Vec<Val> {
+        (0..1 << LOG_SHARD_SIZE).map(|index| Val::new(seed * 7919 + index)).collect()
+    }
+
+    #[test]
+    fn test_sharded_storage() {
+        // Four shards make the same tree as their concatenation
+        let shards = (0..4).map(shard).collect::<Vec<_>>();
+        let sharded = CommittedStorage::from_shards(shards.clone());
+        let whole = CommittedStorage::new(shards.concat());
+        assert_eq!(sharded.root(), whole.root());
+        assert_eq!(sharded.log_len(), LOG_STORAGE_SIZE);
+        assert_eq!(storage_root(sharded.shard_roots(), LOG_SHARD_SIZE), sharded.root());
+
+        // Three shards are padded with a zero shard
+        let storage = CommittedStorage::from_shards(shards[..3].to_vec());
+        let mut padded = shards[..3].concat();
+        padded.resize(1 << LOG_STORAGE_SIZE, Val::new(0));
+        assert_eq!(storage.root(), CommittedStorage::new(padded).root());
+        assert_eq!(storage.log_len(), storage_log_len(3, LOG_SHARD_SIZE));
+        assert_eq!(storage_root(storage.shard_roots(), LOG_SHARD_SIZE), storage.root());
+
+        let config = SPoRAConfig::new(Hash::from([Val::new(3); 8]), 256, 4, 8, storage.log_len());
+        let solutions = spora(&config, &storage);
+        assert!(!solutions.is_empty());
+        for (nonce, complexity) in solutions {
+            let proof = storage.prove(&config, nonce);
+            assert_eq!(verify_solution(&config, storage.root(), nonce, &proof), Some(complexity));
+        }
+    }
+
+    #[test]
+    fn test_update_sharded_storage() {
+        let mut storage = CommittedStorage::from_shards((0..3).map(shard).collect());
+
+        storage.update_shard(1, shard(10));
+        assert_eq!(storage.root(), CommittedStorage::from_shards(vec![shard(0), shard(10), shard(2)]).root());
+
+        storage.push_shard(shard(11));
+        storage.push_shard(shard(12));
+        assert_eq!(storage.num_shards(), 5);
+        assert_eq!(storage.log_len(), LOG_SHARD_SIZE + 3);
+        let expected = CommittedStorage::from_shards(vec![shard(0), shard(10), shard(2), shard(11), shard(12)]);
+        assert_eq!(storage.root(), expected.root());
+        assert_eq!(storage.read(4 << LOG_SHARD_SIZE), shard(12)[0]);
+        assert_eq!(storage.read(6 << LOG_SHARD_SIZE), Val::new(0));
+
+        storage.swap_remove_shard(0);
+        storage.swap_remove_shard(3);
+        assert_eq!(storage.shard_roots(), CommittedStorage::from_shards(vec![shard(12), shard(10), shard(2)]).shard_roots());
+        assert_eq!(storage.root(), CommittedStorage::from_shards(vec![shard(12), shard(10), shard(2)]).root());
+        assert_eq!(storage.log_len(), LOG_STORAGE_SIZE);
+    }
+}
@@ -1,11 +1,10 @@
-use p3_maybe_rayon::prelude::*;
-
-use primitives::{Poseidon2Challenger, POSEIDON2_PERM, poseidon2_hash_slice};
+use primitives::{Hash, Poseidon2Challenger, Val, POSEIDON2_PERM, poseidon2_hash_slice};
 
 use p3_field::PrimeField32;
 use p3_challenger::{CanObserve, CanSampleBits};
 
 use alloc::vec::Vec;
+use core::ops::Range;
 
 use itertools::Itertools;
 
@@ -15,12 +14,39 @@ use crate::types::Nonce;
 
 #[derive(Clone, Copy, Debug)]
 pub struct SPoRAConfig {
+    /// Random challenge of the epoch, so that solutions can't be found in advance
+    challenge: Hash,
     max_nonce: u64,
     log_complexity: usize,
     n_samples: usize,
     log_storage_size: usize
 }
 
+impl SPoRAConfig {
+    pub fn new(challenge: Hash, max_nonce: u64, log_complexity: usize, n_samples: usize, log_storage_size: usize) -> Self {
+        Self { challenge, max_nonce, log_complexity, n_samples, log_storage_size }
+    }
+
+    pub fn challenge(&self) -> Hash {
+        self.challenge
+    }
+
+    pub fn max_nonce(&self) -> u64 {
+        self.max_nonce
+    }
+
+    pub fn log_complexity(&self) -> usize {
+        self.log_complexity
+    }
+
+    pub fn n_samples(&self) -> usize {
+        self.n_samples
+    }
+
+    pub fn log_storage_size(&self) -> usize {
+        self.log_storage_size
+    }
+}
 
 
 
@@ -43,26 +69,43 @@ fn sample_index(challenger: &mut Poseidon2Challenger, log_size:usize) -> u64 {
 
 
 
-// Return finding complexity
-fn spora_with_nonce(config:&SPoRAConfig, nonce:Nonce, storage: &impl UnstructuredStorageReader) -> usize {
+/// Storage indices sampled for the nonce, derived from the challenge and the nonce only
+pub fn sample_indices(config:&SPoRAConfig, nonce:Nonce) -> Vec<u64> {
     let mut challenger = Poseidon2Challenger::new(POSEIDON2_PERM.clone());
 
+    challenger.observe(config.challenge);
     challenger.observe(nonce.as_mersenne_31_word());
 
-    let values = (0..config.n_samples)
-        .map(|_| {
-            let index = sample_index(&mut challenger, config.log_storage_size);
-            storage.read(index)
-        }).collect_vec();
-
-    let hash = poseidon2_hash_slice(&values);
+    (0..config.n_samples)
+        .map(|_| sample_index(&mut challenger, config.log_storage_size))
+        .collect_vec()
+}
 
+/// Complexity reached by the sampled values
+pub fn complexity(values: &[Val]) -> usize {
+    let hash = poseidon2_hash_slice(values);
 
     hash.as_ref()[0].as_canonical_u32().leading_zeros() as usize - 1
 }
 
+// Return finding complexity
+fn spora_with_nonce(config:&SPoRAConfig, nonce:Nonce, storage: &impl UnstructuredStorageReader) -> usize {
+    let values = sample_indices(config, nonce).into_iter()
+        .map(|index| storage.read(index))
+        .collect_vec();
+
+    complexity(&values)
+}
+
 pub fn spora(config:&SPoRAConfig, storage: &impl UnstructuredStorageReader) -> Vec<(Nonce,usize)> {
-    (0..config.max_nonce).into_par_iter().map(|nonce| {
+    spora_range(config, 0..config.max_nonce, storage)
+}
+
+// Searches a part of the nonces on the calling thread, so that miners can split the work and
+// control the number of threads themselves
+pub fn spora_range(config:&SPoRAConfig, nonces: Range<u64>, storage: &impl UnstructuredStorageReader) -> Vec<(Nonce,usize)> {
+    assert!(nonces.end <= config.max_nonce, "Nonce out of range");
+    nonces.map(|nonce| {
         let nonce = Nonce::new(nonce);
         let complexity = spora_with_nonce(config, nonce, storage);
         (nonce, complexity)
@@ -79,6 +122,7 @@ mod tests {
     #[test]
     fn test_spora() {
         let config = SPoRAConfig {
+            challenge: Hash::from([Val::new(1); 8]),
             max_nonce: 1024,
             log_complexity: 9,
             n_samples: 10,
@@ -91,5 +135,18 @@ mod tests {
 
         println!("Result: {:?}", result);
 
+        // Splitting the nonces finds the same solutions
+        let split = [0..300, 300..1024].into_iter()
+            .flat_map(|nonces| spora_range(&config, nonces, &storage))
+            .map(|(nonce, complexity)| (nonce.as_u64(), complexity))
+            .collect::<Vec<_>>();
+        let result = result.into_iter().map(|(nonce, complexity)| (nonce.as_u64(), complexity)).collect::<Vec<_>>();
+        assert_eq!(split, result);
+
+        // Another challenge samples other values
+        let other = SPoRAConfig { challenge: Hash::from([Val::new(2); 8]), ..config };
+        let nonce = Nonce::new(7);
+        assert_ne!(sample_indices(&config, nonce), sample_indices(&other, nonce));
+        assert!(sample_indices(&config, nonce).iter().all(|&index| index < 1 << config.log_storage_size));
     }
 }
\ No newline at end of file
@@ -1,21 +1,25 @@
-use primitives::{array_to_quadval, quadval_to_array, Challenge, Hash, Poseidon2Challenger, Poseidon2Pcs, POSEIDON2_PCS, poseidon2_perm, POSEIDON2_MMCS};
-use primitives::{CLUSTER_SIZE, FRAGMENT_SIZE};
+use primitives::{quadval_to_array, Challenge, Hash, Poseidon2Challenger, Poseidon2Pcs, POSEIDON2_PCS, poseidon2_perm, POSEIDON2_MMCS};
+use primitives::ProtocolParams;
 use p3_field::{AbstractExtensionField, Field};
 use p3_commit::{Pcs, Mmcs};
-use p3_challenger::{CanObserve, CanSample};
+use p3_challenger::CanObserve;
 use p3_matrix::Dimensions;
 use alloc::vec::Vec;
 use alloc::vec;
 use itertools::izip;
-use crate::{SporaProof, SporaOpen};
+use crate::{derive_r, SporaProof, SporaOpen};
 
-pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_commit: Hash,sealed_cluster_commit: Hash, sealing_fragment_root: Hash, sealing_cluster_root: Hash) -> bool {
+/// Checks an opening of a sealed cluster against the commitment of its source cluster and the root
+/// of the sealing fragment it was sealed with
+pub fn verify_spora_cluster(params: &ProtocolParams, open: SporaOpen, proof: SporaProof, source_cluster_commit: Hash, sealing_fragment_root: Hash) -> bool {
     let SporaOpen {
         sealed_cluster_opening,
         sealing_cluster_opening,
     } = open;
     
     let SporaProof {
+        sealed_cluster_commit,
+        sealing_cluster_root,
         source_and_sealed_cluster_proof,
         sealing_cluster_proof,
         fragment_proof,
@@ -23,18 +27,20 @@ pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_c
         quad_in_cluster_index,
     } = proof;
 
-    let r = {
-        let mut challenger = Poseidon2Challenger::new(poseidon2_perm());
+    if params.validate().is_err() {
+        return false;
+    }
 
-        challenger.observe(sealed_cluster_commit);
-        challenger.observe(sealing_fragment_root);
+    // The indices come from the prover, out of range ones would make the openings panic
+    if quad_in_cluster_index >= params.shard_size() / 4 || cluster_in_fragment_index >= params.shards_per_fragment() {
+        return false;
+    }
 
-        array_to_quadval(challenger.sample_array::<4>())
-    };
+    let r = derive_r(source_cluster_commit, sealing_fragment_root);
 
     let source_cluster_opening = (sealed_cluster_opening - sealing_cluster_opening) * r.inverse();
 
-    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, CLUSTER_SIZE as usize);
+    let domain = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::natural_domain_for_degree(&POSEIDON2_PCS, params.shard_size());
 
     let opening_points = (quad_in_cluster_index*4..quad_in_cluster_index*4+4).map(|i| {
         let point = domain.nth_point(i);
@@ -46,16 +52,15 @@ pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_c
     let sealed_opening_points_with_values = izip!(opening_points, quadval_to_array(sealed_cluster_opening)).map(|(point, value)| (point, vec![Challenge::from_base(value)])).collect::<Vec<_>>();
 
     let mut challenger = Poseidon2Challenger::new(poseidon2_perm());
+    challenger.observe(source_cluster_commit);
+    challenger.observe(sealed_cluster_commit);
 
-    // verify FRI opening
+    // verify FRI opening, the prover committed to the source and the sealed cluster separately
     let source_and_sealed_cluster_proof_result = <Poseidon2Pcs as Pcs<Challenge, Poseidon2Challenger>>::verify(&POSEIDON2_PCS, 
-        vec![(
-            source_cluster_commit, 
-            vec![
-                (domain, source_opening_points_with_values),
-                (domain, sealed_opening_points_with_values)
-            ]
-        )], 
+        vec![
+            (source_cluster_commit, vec![(domain, source_opening_points_with_values)]),
+            (sealed_cluster_commit, vec![(domain, sealed_opening_points_with_values)]),
+        ], 
         &source_and_sealed_cluster_proof, &mut challenger);
     
     // if result is Err, return false
@@ -64,7 +69,7 @@ pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_c
     }
 
 
-    let sealing_cluster_dimensions = vec![Dimensions {width: 1, height: CLUSTER_SIZE as usize/4}];
+    let sealing_cluster_dimensions = vec![Dimensions {width: 4, height: params.shard_size()/4}];
     
     let sealing_cluster_proof_result = POSEIDON2_MMCS.verify_batch(&sealing_cluster_root, &sealing_cluster_dimensions, quad_in_cluster_index, &[quadval_to_array(sealing_cluster_opening).into()], &sealing_cluster_proof);
 
@@ -72,7 +77,7 @@ pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_c
         return false;
     }
 
-    let fragment_dimensions = vec![Dimensions {width: 1, height: (FRAGMENT_SIZE / CLUSTER_SIZE) as usize}];
+    let fragment_dimensions = vec![Dimensions {width: 8, height: params.shards_per_fragment()}];
 
     let fragment_proof_result = POSEIDON2_MMCS.verify_batch(&sealing_fragment_root, &fragment_dimensions, cluster_in_fragment_index, &[sealing_cluster_root.as_ref().into()], &fragment_proof);
 
@@ -83,3 +88,71 @@ pub fn verify_spora_cluster(open: SporaOpen, proof: SporaProof, source_cluster_c
     true
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rlc::fixture::sealed_cluster;
+    use crate::{prove_spora_cluster, ProveError};
+    use p3_field::AbstractField;
+    use primitives::{QuadVal, Val};
+
+    #[test]
+    fn test_seal_prove_verify() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (open, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+
+        assert!(verify_spora_cluster(&params, open.clone(), proof.clone(), sealed.source_cluster_commit, sealed.sealing_fragment_root));
+
+        // Another source cluster derives another r
+        let other_commit = Hash::from([Val::new(7); 8]);
+        assert!(!verify_spora_cluster(&params, open.clone(), proof.clone(), other_commit, sealed.sealing_fragment_root));
+
+        // So does another sealing fragment, whose root doesn't contain the sealing cluster either
+        assert!(!verify_spora_cluster(&params, open.clone(), proof.clone(), sealed.source_cluster_commit, other_commit));
+
+        let tampered = SporaOpen { sealed_cluster_opening: open.sealed_cluster_opening + QuadVal::one(), ..open };
+        assert!(!verify_spora_cluster(&params, tampered, proof, sealed.source_cluster_commit, sealed.sealing_fragment_root));
+
+        // The prover refuses a sealing coefficient which isn't derived from the commitments
+        let result = prove_spora_cluster(&params, sealed.r + QuadVal::one(), &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42);
+        assert!(matches!(result, Err(ProveError::WrongSealingCoefficient)));
+
+        // And a sealing cluster which isn't in the sealing fragment
+        let result = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 0, 42);
+        assert!(matches!(result, Err(ProveError::SealingClusterMismatch)));
+
+        // Indices past the cluster or the fragment are rejected, not opened
+        let result = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, params.shard_size() / 4);
+        assert!(matches!(result, Err(ProveError::IndexOutOfRange)));
+        let result = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, params.shards_per_fragment(), 42);
+        assert!(matches!(result, Err(ProveError::IndexOutOfRange)));
+    }
+
+    #[test]
+    fn test_out_of_range_indices() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (open, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+
+        let past_cluster = SporaProof { quad_in_cluster_index: params.shard_size() / 4, ..proof.clone() };
+        assert!(!verify_spora_cluster(&params, open.clone(), past_cluster, sealed.source_cluster_commit, sealed.sealing_fragment_root));
+
+        let past_fragment = SporaProof { cluster_in_fragment_index: params.shards_per_fragment(), ..proof };
+        assert!(!verify_spora_cluster(&params, open, past_fragment, sealed.source_cluster_commit, sealed.sealing_fragment_root));
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let params = ProtocolParams::test();
+        let sealed = sealed_cluster(&params, 1);
+        let (open, proof) = prove_spora_cluster(&params, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42).unwrap();
+
+        // Fragments smaller than a shard
+        let invalid = ProtocolParams { log_fragment_size: 4, ..params };
+        assert!(!verify_spora_cluster(&invalid, open, proof, sealed.source_cluster_commit, sealed.sealing_fragment_root));
+        let result = prove_spora_cluster(&invalid, sealed.r, &sealed.sealed_cluster, &sealed.sealing_fragment_cluster_hashes, &sealed.sealing_cluster, 1, 42);
+        assert!(matches!(result, Err(ProveError::InvalidParams(_))));
+    }
+}